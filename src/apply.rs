@@ -0,0 +1,325 @@
+use crate::{mode::Mode, quote, repo};
+use anyhow::{bail, ensure, Context};
+use std::{fs, iter::Peekable, os::unix::fs::PermissionsExt};
+
+/// How many context lines may be ignored at each end of a hunk that doesn't match as is, because the
+/// surroundings of the change were modified since the patch was made.
+const MAX_FUZZ: usize = 2;
+
+/// The changes to one file.
+struct FilePatch {
+    /// None for a created file.
+    old_path: Option<String>,
+    /// None for a deleted file.
+    new_path: Option<String>,
+    /// From `new file mode` or `new mode`.
+    new_mode: Option<u32>,
+    hunks: Vec<Hunk>,
+}
+
+/// A `@@ -<old start>,<old count> +<new start>,<new count> @@` section.
+struct Hunk {
+    old_start: usize,
+    lines: Vec<Line>,
+}
+
+/// A hunk line, with its new line unless it's the last line of a file without one.
+enum Line {
+    Context(String),
+    Removed(String),
+    Added(String),
+}
+
+impl Line {
+    fn before(&self) -> Option<&str> {
+        match self {
+            Line::Context(text) | Line::Removed(text) => Some(text),
+            Line::Added(_) => None,
+        }
+    }
+
+    fn after(&self) -> Option<&str> {
+        match self {
+            Line::Context(text) | Line::Added(text) => Some(text),
+            Line::Removed(_) => None,
+        }
+    }
+
+    fn text_mut(&mut self) -> &mut String {
+        match self {
+            Line::Context(text) | Line::Removed(text) | Line::Added(text) => text,
+        }
+    }
+}
+
+/// Apply a unified diff, as made by `git diff` or `diff -u`, to the files of the working tree.
+///
+/// Like `git apply`, it's all or nothing: every hunk is checked before any file is written.
+pub(super) fn apply(patch: &str) -> anyhow::Result<()> {
+    let work_tree = repo::work_tree()?;
+    let patches = parse(patch)?;
+    ensure!(!patches.is_empty(), "No valid patches in input");
+
+    let mut results = Vec::new();
+    for patch in &patches {
+        let old_content = match &patch.old_path {
+            Some(path) => fs::read_to_string(work_tree.join(path))
+                .context(format!("{path}: No such file or directory"))?,
+            None => {
+                if let Some(path) = &patch.new_path {
+                    ensure!(
+                        !work_tree.join(path).exists(),
+                        "{path}: already exists in working directory"
+                    );
+                }
+                String::new()
+            }
+        };
+        let path = patch.new_path.as_ref().or(patch.old_path.as_ref());
+        let path = path.context("patch without file name")?;
+        let new_content = apply_hunks(path, &old_content, &patch.hunks)?;
+        ensure!(
+            patch.new_path.is_some() || new_content.is_empty(),
+            "{path}: removal patch leaves file contents"
+        );
+        results.push((patch, new_content));
+    }
+
+    for (patch, content) in results {
+        let Some(path) = &patch.new_path else {
+            if let Some(path) = &patch.old_path {
+                fs::remove_file(work_tree.join(path)).context(format!("removing {path}"))?;
+            }
+            continue;
+        };
+        let full_path = work_tree.join(path);
+        if let Some(parent) = full_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&full_path, content).context(format!("writing {path}"))?;
+        if let Some(mode) = patch.new_mode {
+            let permissions = if mode == Mode::Executable.bits() {
+                0o755
+            } else {
+                0o644
+            };
+            fs::set_permissions(&full_path, fs::Permissions::from_mode(permissions))?;
+        }
+    }
+    Ok(())
+}
+
+fn parse(patch: &str) -> anyhow::Result<Vec<FilePatch>> {
+    let mut lines = patch.split_inclusive('\n').peekable();
+    let mut patches = Vec::new();
+    // What the `diff --git` header says about the file, until its `---` and `+++` lines.
+    let mut pending: Option<FilePatch> = None;
+    while let Some(line) = lines.next() {
+        let line_content = line.trim_end_matches('\n');
+        if let Some(names) = line_content.strip_prefix("diff --git ") {
+            patches.extend(pending.take().filter(is_header_only));
+            // Without a rename, the name is the same on both sides: `a/<name> b/<name>`.
+            let name = names
+                .strip_prefix("a/")
+                .and_then(|rest| rest.get(..rest.len().saturating_sub(3) / 2))
+                .filter(|name| names.ends_with(&format!(" b/{name}")))
+                .map(str::to_string);
+            pending = Some(FilePatch {
+                old_path: name.clone(),
+                new_path: name,
+                new_mode: None,
+                hunks: Vec::new(),
+            });
+        } else if let Some(mode) = line_content
+            .strip_prefix("new file mode ")
+            .or_else(|| line_content.strip_prefix("new mode "))
+        {
+            let pending = pending.as_mut().context("mode outside of a diff --git")?;
+            pending.new_mode = Some(u32::from_str_radix(mode, 8).context("invalid mode")?);
+            if line_content.starts_with("new file mode ") {
+                pending.old_path = None;
+            }
+        } else if line_content.starts_with("deleted file mode ") {
+            pending
+                .as_mut()
+                .context("mode outside of a diff --git")?
+                .new_path = None;
+        } else if line_content.starts_with("rename from ")
+            || line_content.starts_with("copy from ")
+            || line_content.starts_with("GIT binary patch")
+            || line_content.starts_with("Binary files ")
+        {
+            bail!("unsupported patch: {line_content}");
+        } else if let Some(old) = line_content.strip_prefix("--- ") {
+            let new = lines
+                .next()
+                .and_then(|line| line.trim_end_matches('\n').strip_prefix("+++ "))
+                .context("malformed patch: '---' line without '+++' line")?;
+            let mut patch = pending.take().unwrap_or(FilePatch {
+                old_path: None,
+                new_path: None,
+                new_mode: None,
+                hunks: Vec::new(),
+            });
+            patch.old_path = parse_path(old)?;
+            patch.new_path = parse_path(new)?;
+            while let Some(header) = lines.next_if(|line| line.starts_with("@@ ")) {
+                patch.hunks.push(parse_hunk(header, &mut lines)?);
+            }
+            patches.push(patch);
+        }
+    }
+    patches.extend(pending.filter(is_header_only));
+    Ok(patches)
+}
+
+// A git patch without hunks still creates or deletes an empty file, or changes its mode.
+fn is_header_only(patch: &FilePatch) -> bool {
+    patch.old_path.is_none() || patch.new_path.is_none() || patch.new_mode.is_some()
+}
+
+// The name of a `---` or `+++` line without its leading `a/` or `b/`, None for `/dev/null`.
+fn parse_path(path: &str) -> anyhow::Result<Option<String>> {
+    let path = if path.starts_with('"') {
+        quote::unquote_path(path).context(format!("invalid quoted path {path}"))?
+    } else {
+        // `diff -u` follows the name with a tab and the modification time.
+        path.split('\t').next().unwrap_or(path).to_string()
+    };
+    if path == "/dev/null" {
+        return Ok(None);
+    }
+    let (_, path) = path.split_once('/').context(format!(
+        "invalid path {path}: no leading directory to strip"
+    ))?;
+    Ok(Some(path.to_string()))
+}
+
+fn parse_hunk<'a>(
+    header: &str,
+    lines: &mut Peekable<impl Iterator<Item = &'a str>>,
+) -> anyhow::Result<Hunk> {
+    let header = header.trim_end_matches('\n');
+    let ranges = header
+        .strip_prefix("@@ -")
+        .and_then(|rest| rest.split_once(" @@"))
+        .and_then(|(ranges, _)| ranges.split_once(" +"));
+    let Some((old, new)) = ranges else {
+        bail!("malformed hunk header {header}");
+    };
+    let (old_start, mut old_count) = parse_range(old)?;
+    let (_, mut new_count) = parse_range(new)?;
+
+    let mut hunk_lines: Vec<Line> = Vec::new();
+    let truncated = || format!("corrupt patch: truncated hunk {header}");
+    while old_count > 0 || new_count > 0 {
+        let line = lines.next().with_context(truncated)?;
+        // Some editors strip the space of empty context lines.
+        let (marker, text) = if line == "\n" {
+            (' ', line)
+        } else {
+            let marker = line.chars().next().with_context(truncated)?;
+            (marker, &line[1..])
+        };
+        let text = text.to_string();
+        match marker {
+            ' ' => {
+                old_count = old_count.checked_sub(1).with_context(truncated)?;
+                new_count = new_count.checked_sub(1).with_context(truncated)?;
+                hunk_lines.push(Line::Context(text));
+            }
+            '-' => {
+                old_count = old_count.checked_sub(1).with_context(truncated)?;
+                hunk_lines.push(Line::Removed(text));
+            }
+            '+' => {
+                new_count = new_count.checked_sub(1).with_context(truncated)?;
+                hunk_lines.push(Line::Added(text));
+            }
+            '\\' => strip_last_new_line(&mut hunk_lines),
+            _ => bail!(truncated()),
+        }
+    }
+    // `\ No newline at end of file` after the last line.
+    if lines.next_if(|line| line.starts_with('\\')).is_some() {
+        strip_last_new_line(&mut hunk_lines);
+    }
+    Ok(Hunk {
+        old_start,
+        lines: hunk_lines,
+    })
+}
+
+fn strip_last_new_line(lines: &mut [Line]) {
+    if let Some(line) = lines.last_mut() {
+        let text = line.text_mut();
+        if text.ends_with('\n') {
+            text.pop();
+        }
+    }
+}
+
+// `<start>,<count>`, the count being 1 when omitted.
+fn parse_range(range: &str) -> anyhow::Result<(usize, usize)> {
+    let (start, count) = range.split_once(',').unwrap_or((range, "1"));
+    Ok((
+        start
+            .parse()
+            .context(format!("invalid hunk range {range}"))?,
+        count
+            .parse()
+            .context(format!("invalid hunk range {range}"))?,
+    ))
+}
+
+// The content once the hunks are applied. A hunk is looked for at the position it announces, shifted by how
+// much the previous hunks moved, then at the closest position where it matches.
+fn apply_hunks(path: &str, content: &str, hunks: &[Hunk]) -> anyhow::Result<String> {
+    let mut lines: Vec<&str> = content.split_inclusive('\n').collect();
+    let mut offset = 0;
+    // Hunks are in order and don't overlap.
+    let mut min_position = 0;
+    for hunk in hunks {
+        let leading = count_context(hunk.lines.iter());
+        let trailing = count_context(hunk.lines.iter().rev());
+        let mut applied = false;
+        for fuzz in 0..=MAX_FUZZ.min(leading.max(trailing)) {
+            let skip_start = fuzz.min(leading);
+            let skip_end = fuzz.min(trailing).min(hunk.lines.len() - skip_start);
+            let body = &hunk.lines[skip_start..hunk.lines.len() - skip_end];
+            let old: Vec<&str> = body.iter().filter_map(Line::before).collect();
+            let new: Vec<&str> = body.iter().filter_map(Line::after).collect();
+            // A hunk without old lines inserts after its start line.
+            let start = if old.is_empty() {
+                hunk.old_start
+            } else {
+                hunk.old_start.saturating_sub(1)
+            } + skip_start;
+            let expected = start as isize + offset;
+            let Some(position) = find_lines(&lines, &old, expected, min_position) else {
+                continue;
+            };
+            offset = position as isize - start as isize;
+            min_position = position + new.len();
+            lines.splice(position..position + old.len(), new);
+            applied = true;
+            break;
+        }
+        ensure!(applied, "patch failed: {path}:{}", hunk.old_start);
+    }
+    Ok(lines.concat())
+}
+
+fn count_context<'a>(lines: impl Iterator<Item = &'a Line>) -> usize {
+    lines
+        .take_while(|line| matches!(line, Line::Context(_)))
+        .count()
+}
+
+// The position closest to `expected` where `old` is found, not before `min_position`.
+fn find_lines(lines: &[&str], old: &[&str], expected: isize, min_position: usize) -> Option<usize> {
+    let last = lines.len().checked_sub(old.len())?;
+    (min_position..=last)
+        .filter(|&position| lines[position..position + old.len()] == *old)
+        .min_by_key(|&position| (position as isize - expected).abs())
+}
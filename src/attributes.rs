@@ -0,0 +1,249 @@
+use crate::{quote, repo};
+use anyhow::Context;
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::Path,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+// The `.gitattributes` files don't change while a command runs, each one is only parsed once.
+static RULES: OnceLock<Mutex<HashMap<String, Arc<Vec<Rule>>>>> = OnceLock::new();
+
+/// The state of an attribute for a path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(super) enum State {
+    /// `<name>`
+    Set,
+    /// `-<name>`
+    Unset,
+    /// `<name>=<value>`
+    Value(String),
+}
+
+/// A line of a `.gitattributes` file: a pattern then the attributes of the matching paths.
+struct Rule {
+    pattern: String,
+    /// Without a slash the pattern matches the file name at any depth, otherwise the path relative to the
+    /// directory of the `.gitattributes` file.
+    match_name: bool,
+    /// In order, `None` being `!<name>`, which makes the attribute unspecified again.
+    attributes: Vec<(String, Option<State>)>,
+}
+
+/// The value of the attribute `name` for `path`, relative to the top of the working tree. None when it is
+/// unspecified.
+///
+/// Like git, `$GIT_DIR/info/attributes` comes first, then the `.gitattributes` file of the closest directory up
+/// to the top of the working tree. In a file, the last matching line wins.
+pub(super) fn get(path: &str, name: &str) -> anyhow::Result<Option<State>> {
+    let mut dirs = vec![""];
+    dirs.extend(path.match_indices('/').map(|(i, _)| &path[..i]));
+    let info = rules("", &repo::git_dir()?.join("info").join("attributes"))?;
+    let mut sources = vec![("", info)];
+    for dir in dirs.into_iter().rev() {
+        let file = repo::work_tree()?.join(dir).join(".gitattributes");
+        sources.push((dir, rules(dir, &file)?));
+    }
+
+    for (dir, rules) in sources {
+        let relative = match dir {
+            "" => path,
+            dir => &path[dir.len() + 1..],
+        };
+        let file_name = relative.rsplit('/').next().unwrap_or(relative);
+        for rule in rules.iter().rev() {
+            let subject = if rule.match_name { file_name } else { relative };
+            if !wildmatch(rule.pattern.as_bytes(), subject.as_bytes()) {
+                continue;
+            }
+            if let Some((_, value)) = rule
+                .attributes
+                .iter()
+                .rev()
+                .find(|(attribute, _)| attribute == name)
+            {
+                return Ok(value.clone());
+            }
+        }
+    }
+    Ok(None)
+}
+
+// The rules of an attributes file, `key` naming it in the cache.
+fn rules(key: &str, file: &Path) -> anyhow::Result<Arc<Vec<Rule>>> {
+    let key = format!("{key}:{}", file.display());
+    let cache = RULES.get_or_init(Default::default);
+    if let Some(rules) = cache
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(&key)
+    {
+        return Ok(rules.clone());
+    }
+    let rules = match fs::read_to_string(file) {
+        Ok(content) => parse(&content),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err).context(format!("reading {file:?}")),
+    };
+    let rules = Arc::new(rules);
+    cache
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(key, rules.clone());
+    Ok(rules)
+}
+
+fn parse(content: &str) -> Vec<Rule> {
+    let mut rules = Vec::new();
+    for line in content.lines() {
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (pattern, rest) = if let Some(quoted) = line.strip_prefix('"') {
+            // The closing quote is the first one not escaped.
+            let end = quoted
+                .char_indices()
+                .scan(false, |escaped, (i, char)| {
+                    let closing = char == '"' && !*escaped;
+                    *escaped = char == '\\' && !*escaped;
+                    Some((i, closing))
+                })
+                .find(|(_, closing)| *closing)
+                .map(|(i, _)| i + 2);
+            let Some(end) = end else {
+                continue;
+            };
+            let Some(pattern) = quote::unquote_path(&line[..end]) else {
+                continue;
+            };
+            (pattern, &line[end..])
+        } else {
+            let (pattern, rest) = line
+                .split_once(|char: char| char.is_ascii_whitespace())
+                .unwrap_or((line, ""));
+            (pattern.to_string(), rest)
+        };
+        // Git ignores negative patterns and macro definitions outside of the built-in `binary`.
+        if pattern.starts_with('!') || pattern.starts_with("[attr]") {
+            continue;
+        }
+        // A pattern ending with a slash only matches directories, which have no attributes.
+        if pattern.ends_with('/') {
+            continue;
+        }
+        let match_name = !pattern.contains('/');
+        let pattern = pattern.strip_prefix('/').unwrap_or(&pattern).to_string();
+
+        let mut attributes = Vec::new();
+        for attribute in rest.split_ascii_whitespace() {
+            if attribute == "binary" {
+                attributes.push(("binary".to_string(), Some(State::Set)));
+                for unset in ["diff", "merge", "text"] {
+                    attributes.push((unset.to_string(), Some(State::Unset)));
+                }
+            } else if let Some(name) = attribute.strip_prefix('-') {
+                attributes.push((name.to_string(), Some(State::Unset)));
+            } else if let Some(name) = attribute.strip_prefix('!') {
+                attributes.push((name.to_string(), None));
+            } else if let Some((name, value)) = attribute.split_once('=') {
+                attributes.push((name.to_string(), Some(State::Value(value.to_string()))));
+            } else {
+                attributes.push((attribute.to_string(), Some(State::Set)));
+            }
+        }
+        rules.push(Rule {
+            pattern,
+            match_name,
+            attributes,
+        });
+    }
+    rules
+}
+
+/// Whether `text` matches the glob `pattern`, with the syntax shared by `.gitignore` and `.gitattributes`:
+/// `*` and `?` don't match a slash, `**` matches across directories, `[...]` is a set of characters and `\`
+/// escapes the next one.
+pub(super) fn wildmatch(pattern: &[u8], text: &[u8]) -> bool {
+    matches(pattern, text, true)
+}
+
+/// Like [`wildmatch`] but `*`, `?` and sets also match a slash, as git matches ref names against patterns.
+pub(super) fn wildmatch_name(pattern: &[u8], text: &[u8]) -> bool {
+    matches(pattern, text, false)
+}
+
+// With `pathname`, only `**` matches across slashes.
+fn matches(pattern: &[u8], text: &[u8], pathname: bool) -> bool {
+    let wildmatch = |pattern, text| matches(pattern, text, pathname);
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => match rest {
+            [] => true,
+            // `**/` matches any number of directories, none included.
+            [b'/', rest @ ..] => {
+                wildmatch(rest, text)
+                    || text
+                        .iter()
+                        .enumerate()
+                        .any(|(i, &byte)| byte == b'/' && wildmatch(rest, &text[i + 1..]))
+            }
+            _ => wildmatch(&pattern[1..], text),
+        },
+        [b'*', rest @ ..] => {
+            for i in 0..=text.len() {
+                if wildmatch(rest, &text[i..]) {
+                    return true;
+                }
+                if pathname && text.get(i) == Some(&b'/') {
+                    break;
+                }
+            }
+            false
+        }
+        [b'?', rest @ ..] => match text {
+            [byte, text @ ..] if !pathname || *byte != b'/' => wildmatch(rest, text),
+            _ => false,
+        },
+        [b'[', rest @ ..] => match (text, match_set(rest, text.first().copied(), pathname)) {
+            ([_, text @ ..], Some((true, rest))) => wildmatch(rest, text),
+            (_, Some((false, _))) => false,
+            // Without its closing bracket, the `[` is taken literally.
+            (_, None) => text.first() == Some(&b'[') && wildmatch(rest, &text[1..]),
+            ([], Some(_)) => false,
+        },
+        [b'\\', escaped, rest @ ..] => text.first() == Some(escaped) && wildmatch(rest, &text[1..]),
+        [byte, rest @ ..] => text.first() == Some(byte) && wildmatch(rest, &text[1..]),
+    }
+}
+
+// Whether `byte` is in the set starting after a `[`, and the pattern after the set. None if the set isn't
+// closed. With `pathname`, a slash is never in the set.
+fn match_set(set: &[u8], byte: Option<u8>, pathname: bool) -> Option<(bool, &[u8])> {
+    let (negated, mut set) = match set {
+        [b'!' | b'^', rest @ ..] => (true, rest),
+        _ => (false, set),
+    };
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        match set {
+            [] => return None,
+            [b']', rest @ ..] if !first => {
+                let matched =
+                    byte.is_some_and(|byte| !pathname || byte != b'/') && matched != negated;
+                return Some((matched, rest));
+            }
+            [start, b'-', end, rest @ ..] if *end != b']' => {
+                matched |= byte.is_some_and(|byte| (*start..=*end).contains(&byte));
+                set = rest;
+            }
+            [b'\\', escaped, rest @ ..] | [escaped, rest @ ..] => {
+                matched |= byte == Some(*escaped);
+                set = rest;
+            }
+        }
+        first = false;
+    }
+}
@@ -0,0 +1,76 @@
+use crate::ObjectKind;
+use anyhow::{bail, Context};
+use std::io::{self, Write};
+
+/// What is known about an object when printing its batch line.
+pub(super) struct ObjectInfo<'a> {
+    /// The object name, possibly abbreviated.
+    pub(super) name: &'a str,
+    pub(super) kind: ObjectKind,
+    pub(super) size: u64,
+    /// Size of the compressed object in the store: its loose file, or its entry in a pack.
+    pub(super) disk_size: u64,
+}
+
+enum Atom {
+    Name,
+    Kind,
+    Size,
+    DiskSize,
+}
+
+enum Part {
+    Literal(String),
+    Atom(Atom),
+}
+
+/// The line printed for each object by `cat-file --batch` and `--batch-check`, such as
+/// `%(objectname) %(objecttype) %(objectsize)`.
+pub(super) struct BatchFormat {
+    parts: Vec<Part>,
+}
+
+impl BatchFormat {
+    /// Format used when none is given, same as git.
+    pub(super) const DEFAULT: &'static str = "%(objectname) %(objecttype) %(objectsize)";
+
+    pub(super) fn parse(format: &str) -> anyhow::Result<Self> {
+        let mut parts = Vec::new();
+        let mut rest = format;
+        while let Some(start) = rest.find("%(") {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find(')')
+                .context(format!("unterminated format element: {}", &rest[start..]))?;
+            let atom = match &rest[start + 2..start + end] {
+                "objectname" => Atom::Name,
+                "objecttype" => Atom::Kind,
+                "objectsize" => Atom::Size,
+                "objectsize:disk" => Atom::DiskSize,
+                _ => bail!("unknown format element: {}", &rest[start..=start + end]),
+            };
+            parts.push(Part::Atom(atom));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+        Ok(Self { parts })
+    }
+
+    /// Write the line for the object, without the trailing newline.
+    pub(super) fn write(&self, out: &mut impl Write, info: &ObjectInfo) -> io::Result<()> {
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => write!(out, "{literal}")?,
+                Part::Atom(Atom::Name) => write!(out, "{}", info.name)?,
+                Part::Atom(Atom::Kind) => write!(out, "{}", info.kind)?,
+                Part::Atom(Atom::Size) => write!(out, "{}", info.size)?,
+                Part::Atom(Atom::DiskSize) => write!(out, "{}", info.disk_size)?,
+            }
+        }
+        Ok(())
+    }
+}
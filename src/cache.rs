@@ -0,0 +1,127 @@
+use crate::{hex_hash::GitHexHash, ObjectKind};
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Read},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+/// Number of objects remembered, the least recently used is forgotten first.
+const CAPACITY: usize = 1024;
+/// Larger bodies aren't kept, only their header.
+pub(super) const MAX_CACHED_BODY: u64 = 16 * 1024;
+
+// Objects never change once written, so the cache lives as long as the command.
+static CACHE: OnceLock<Mutex<ObjectCache>> = OnceLock::new();
+
+/// The objects read recently, so walking trees sharing sub-trees or blobs doesn't inflate them again.
+#[derive(Default)]
+struct ObjectCache {
+    objects: HashMap<GitHexHash, CachedObject>,
+    // Incremented on each access, to find the least recently used object.
+    clock: u64,
+}
+
+struct CachedObject {
+    kind: ObjectKind,
+    size: u64,
+    body: Option<Arc<[u8]>>,
+    last_used: u64,
+}
+
+fn with_cache<T>(f: impl FnOnce(&mut ObjectCache) -> T) -> T {
+    let mut cache = CACHE
+        .get_or_init(Default::default)
+        .lock()
+        // The cache is only a shortcut, what a panicking thread left is still valid.
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    cache.clock += 1;
+    f(&mut cache)
+}
+
+impl ObjectCache {
+    fn get(&mut self, hash: &GitHexHash) -> Option<&CachedObject> {
+        let clock = self.clock;
+        let object = self.objects.get_mut(hash)?;
+        object.last_used = clock;
+        Some(object)
+    }
+}
+
+/// The kind and size of an object, if cached.
+pub(super) fn header(hash: &GitHexHash) -> Option<(ObjectKind, u64)> {
+    with_cache(|cache| cache.get(hash).map(|object| (object.kind, object.size)))
+}
+
+/// The kind and body of an object, if cached.
+pub(super) fn body(hash: &GitHexHash) -> Option<(ObjectKind, Arc<[u8]>)> {
+    with_cache(|cache| {
+        let object = cache.get(hash)?;
+        Some((object.kind, object.body.clone()?))
+    })
+}
+
+/// Remember an object, along with its body if small enough.
+pub(super) fn insert(hash: &GitHexHash, kind: ObjectKind, size: u64, body: Option<Arc<[u8]>>) {
+    let body = body.filter(|_| size <= MAX_CACHED_BODY);
+    with_cache(|cache| {
+        let last_used = cache.clock;
+        if let Some(object) = cache.objects.get_mut(hash) {
+            object.last_used = last_used;
+            // Don't forget a body we have because this time only the header was read.
+            if body.is_some() {
+                object.body = body;
+            }
+            return;
+        }
+        if cache.objects.len() >= CAPACITY {
+            let oldest = cache
+                .objects
+                .iter()
+                .min_by_key(|(_, object)| object.last_used)
+                .map(|(hash, _)| hash.clone());
+            if let Some(oldest) = oldest {
+                cache.objects.remove(&oldest);
+            }
+        }
+        cache.objects.insert(
+            hash.clone(),
+            CachedObject {
+                kind,
+                size,
+                body,
+                last_used,
+            },
+        );
+    });
+}
+
+/// The body of an object, either from the cache or inflated from its file.
+pub(super) enum ObjectBody<R> {
+    Cached(io::Cursor<Arc<[u8]>>),
+    Streamed(R),
+}
+
+impl<R: Read> Read for ObjectBody<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Cached(reader) => reader.read(buf),
+            Self::Streamed(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl<R: BufRead> BufRead for ObjectBody<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match self {
+            Self::Cached(reader) => reader.fill_buf(),
+            Self::Streamed(reader) => reader.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        match self {
+            Self::Cached(reader) => reader.consume(amt),
+            Self::Streamed(reader) => reader.consume(amt),
+        }
+    }
+}
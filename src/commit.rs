@@ -0,0 +1,176 @@
+use crate::{date::GitDate, hex_hash::GitHexHash, shallow, ObjectReader};
+use anyhow::{bail, Context};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashSet},
+    io::BufRead,
+};
+
+/// The parts of a commit object we use.
+///
+/// A commit is a list of `<name> <value>` headers (`tree`, `parent`, `author`, ...), an empty line, then the
+/// message. A header value spanning several lines, like a signature, continues on lines starting with a space.
+pub(super) struct Commit {
+    pub(super) tree: GitHexHash,
+    pub(super) parents: Vec<GitHexHash>,
+    /// `<name> <<email>> <date>`, empty if missing.
+    pub(super) author: String,
+    pub(super) committer: String,
+    /// The ASCII armored signature of the `gpgsig` header, for signed commits.
+    pub(super) signature: Option<String>,
+    pub(super) message: String,
+}
+
+impl Commit {
+    pub(super) fn read(hash: &GitHexHash) -> anyhow::Result<Self> {
+        let ObjectReader::Commit(reader) = ObjectReader::from_sha1(hash.clone())? else {
+            bail!("{hash} is not a valid 'commit' object");
+        };
+        Self::parse(reader).context(format!("parsing commit {hash}"))
+    }
+
+    pub(super) fn parse(reader: impl BufRead) -> anyhow::Result<Self> {
+        let mut tree = None;
+        let mut parents = Vec::new();
+        let mut signature = None;
+        let mut author = String::new();
+        let mut committer = String::new();
+        let (headers, message) = parse_headers(reader)?;
+        for (name, value) in headers {
+            match name.as_str() {
+                "tree" => tree = Some(value.as_str().try_into()?),
+                "parent" => parents.push(value.as_str().try_into()?),
+                "author" => author = value,
+                "committer" => committer = value,
+                // `gpgsig-sha256` signs the SHA-256 version of a commit in repositories with both formats.
+                "gpgsig" | "gpgsig-sha256" => signature = Some(value),
+                _ => {}
+            }
+        }
+        Ok(Self {
+            tree: tree.context("missing tree header")?,
+            parents,
+            author,
+            committer,
+            signature,
+            message,
+        })
+    }
+}
+
+/// Split a commit or tag object into its `<name> <value>` headers and its message, after the first empty line.
+///
+/// The continuation lines of a multi-line value are joined with `\n`, without their leading space.
+pub(super) fn parse_headers(
+    reader: impl BufRead,
+) -> anyhow::Result<(Vec<(String, String)>, String)> {
+    let mut headers: Vec<(String, String)> = Vec::new();
+    let mut lines = reader.lines();
+    for line in lines.by_ref() {
+        let line = line?;
+        if line.is_empty() {
+            break;
+        }
+        if let Some(continuation) = line.strip_prefix(' ') {
+            let (_, value) = headers
+                .last_mut()
+                .context("continuation line without header")?;
+            value.push('\n');
+            value.push_str(continuation);
+            continue;
+        }
+        let (name, value) = line.split_once(' ').unwrap_or((&line, ""));
+        headers.push((name.to_string(), value.to_string()));
+    }
+    let message = lines.collect::<Result<Vec<_>, _>>()?.join("\n");
+    Ok((headers, message))
+}
+
+/// The content of a commit without its signature, which is what was signed.
+pub(super) fn signed_payload(content: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(content.len());
+    let mut in_headers = true;
+    let mut in_signature = false;
+    for line in content.split_inclusive(|&byte| byte == b'\n') {
+        if in_headers {
+            if line == b"\n" {
+                in_headers = false;
+            } else if line.starts_with(b" ") && in_signature {
+                continue;
+            } else {
+                in_signature = line.starts_with(b"gpgsig ") || line.starts_with(b"gpgsig-sha256 ");
+                if in_signature {
+                    continue;
+                }
+            }
+        }
+        payload.extend_from_slice(line);
+    }
+    payload
+}
+
+/// Split an `author` or `committer` header into the identity, `<name> <<email>>`, and the date.
+pub(super) fn parse_ident(value: &str) -> (&str, Option<GitDate>) {
+    match value.rfind('>') {
+        Some(end) => (
+            &value[..end + 1],
+            GitDate::parse(value[end + 1..].trim()).ok(),
+        ),
+        None => (value, None),
+    }
+}
+
+/// The commits reachable from `starts`, the most recently committed first, like `git rev-list`.
+///
+/// It is a walk following the dates rather than a topological sort: with skewed clocks a parent can be listed
+/// before its child, as with git.
+pub(super) fn walk(starts: Vec<GitHexHash>) -> anyhow::Result<Vec<(GitHexHash, Commit)>> {
+    let shallow = shallow::shallow_commits()?;
+    let mut seen = HashSet::new();
+    // The queue is ordered by date, then by insertion order among commits of the same date.
+    let mut queue = BinaryHeap::new();
+    let mut pending: Vec<Option<(GitHexHash, Commit)>> = Vec::new();
+    let mut discovered = starts;
+    let mut commits = Vec::new();
+    loop {
+        for hash in discovered.drain(..) {
+            if !seen.insert(hash.clone()) {
+                continue;
+            }
+            let commit = Commit::read(&hash)?;
+            let date = parse_ident(&commit.committer)
+                .1
+                .map_or(0, |date| date.seconds());
+            queue.push((date, Reverse(pending.len())));
+            pending.push(Some((hash, commit)));
+        }
+        let Some((_, Reverse(index))) = queue.pop() else {
+            break;
+        };
+        let (hash, commit) = pending[index].take().context("commit walked twice")?;
+        if !shallow.contains(&hash) {
+            discovered.extend(commit.parents.iter().cloned());
+        }
+        commits.push((hash, commit));
+    }
+    Ok(commits)
+}
+
+/// All the commits reachable from `start`, itself included.
+///
+/// In a shallow repository, the walk stops at the boundary commits since their parents are missing.
+pub(super) fn ancestors(start: &GitHexHash) -> anyhow::Result<HashSet<GitHexHash>> {
+    let shallow = shallow::shallow_commits()?;
+    let mut seen = HashSet::new();
+    let mut pending = vec![start.clone()];
+    while let Some(hash) = pending.pop() {
+        if seen.contains(&hash) {
+            continue;
+        }
+        if !shallow.contains(&hash) {
+            pending.extend(Commit::read(&hash)?.parents);
+        }
+        seen.insert(hash);
+    }
+    Ok(seen)
+}
@@ -0,0 +1,244 @@
+use crate::repo;
+use anyhow::{bail, ensure, Context};
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// The merged git configuration: the global one overridden by the repository one.
+pub(super) fn config() -> anyhow::Result<&'static Config> {
+    if let Some(config) = CONFIG.get() {
+        return Ok(config);
+    }
+    let mut config = Config::default();
+    if let Some(global) = global_config_path() {
+        config.read_file(&global)?;
+    }
+    // Reading the configuration shouldn't prevent commands working outside of a repository.
+    if let Ok(git_dir) = repo::git_dir() {
+        config.read_file(&git_dir.join("config"))?;
+    }
+    Ok(CONFIG.get_or_init(|| config))
+}
+
+// The repository extensions we support, then the ones git only accepts with `core.repositoryFormatVersion = 1`.
+const SUPPORTED_EXTENSIONS: [&str; 3] = ["noop", "noop-v1", "objectformat"];
+const V1_ONLY_EXTENSIONS: [&str; 3] = ["noop-v1", "objectformat", "worktreeconfig"];
+
+/// Check that the repository uses a format we understand, like git before using one: its
+/// `core.repositoryFormatVersion` is at most 1 and, from version 1, its `extensions.*` are all supported. An
+/// unknown extension changes how the repository must be read or written, we'd rather not touch it.
+pub(super) fn check_repository_format(git_dir: &Path) -> anyhow::Result<()> {
+    // Only the repository configuration declares its format.
+    let mut config = Config::default();
+    config.read_file(&git_dir.join("config"))?;
+    let version: u32 = match config.get("core.repositoryFormatVersion") {
+        Some(value) => value.trim().parse().ok().context(format!(
+            "bad numeric config value '{value}' for 'core.repositoryformatversion'"
+        ))?,
+        None => 0,
+    };
+    ensure!(
+        version <= 1,
+        "Expected git repo version <= 1, found {version}"
+    );
+    let mut extensions = Vec::new();
+    for (key, _) in &config.entries {
+        if let Some(extension) = key.strip_prefix("extensions.") {
+            if !extensions.contains(&extension) {
+                extensions.push(extension);
+            }
+        }
+    }
+    // Version 0 predates extensions: they are ignored, except the ones only valid from version 1.
+    let (problem, rejected) = if version == 0 {
+        let rejected = extensions
+            .into_iter()
+            .filter(|extension| V1_ONLY_EXTENSIONS.contains(extension));
+        (
+            "repo version is 0, but v1-only",
+            rejected.collect::<Vec<_>>(),
+        )
+    } else {
+        let rejected = extensions
+            .into_iter()
+            .filter(|extension| !SUPPORTED_EXTENSIONS.contains(extension));
+        ("unknown repository", rejected.collect())
+    };
+    if !rejected.is_empty() {
+        let plural = if rejected.len() > 1 { "s" } else { "" };
+        bail!(
+            "{problem} extension{plural} found:\n\t{}",
+            rejected.join("\n\t")
+        );
+    }
+    Ok(())
+}
+
+fn global_config_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| Path::new(&home).join(".gitconfig"))
+}
+
+/// Variables of git config files, in the order they were read.
+#[derive(Default, Debug)]
+pub(super) struct Config {
+    // Keys are in their canonical form: lowercase section and variable name, subsection left as is.
+    // A `None` value is a variable without `=`, which means true for booleans.
+    entries: Vec<(String, Option<String>)>,
+}
+
+impl Config {
+    fn read_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        match fs::read_to_string(path) {
+            Ok(content) => self
+                .parse(&content)
+                .context(format!("in {}", path.display())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err).context(format!("reading {path:?}")),
+        }
+    }
+
+    fn parse(&mut self, content: &str) -> anyhow::Result<()> {
+        let mut section = None;
+        for (line_number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(['#', ';']) {
+                continue;
+            }
+            if let Some(header) = line.strip_prefix('[') {
+                let (header, rest) = header
+                    .split_once(']')
+                    .context(format!("bad config line {}", line_number + 1))?;
+                let name = parse_section_header(header)?;
+                // `[section] key = value` on the same line is valid.
+                let rest = rest.trim();
+                if !rest.is_empty() && !rest.starts_with(['#', ';']) {
+                    self.push_variable(&name, rest)?;
+                }
+                section = Some(name);
+                continue;
+            }
+            let Some(section) = &section else {
+                bail!(
+                    "bad config line {}: variable outside of a section",
+                    line_number + 1
+                );
+            };
+            self.push_variable(section, line)?;
+        }
+        Ok(())
+    }
+
+    fn push_variable(&mut self, section: &str, line: &str) -> anyhow::Result<()> {
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(parse_value(value)?)),
+            None => (line.split(['#', ';']).next().unwrap_or(line).trim(), None),
+        };
+        self.entries
+            .push((format!("{section}.{}", name.to_lowercase()), value));
+        Ok(())
+    }
+
+    /// Value of a variable such as `core.autocrlf` or `remote.origin.url`. The last definition wins.
+    pub(super) fn get(&self, key: &str) -> Option<&str> {
+        let key = canonical_key(key);
+        self.entries
+            .iter()
+            .rev()
+            .find(|(name, _)| *name == key)
+            .map(|(_, value)| value.as_deref().unwrap_or("true"))
+    }
+
+    /// Boolean variable, with the same accepted spellings as git.
+    pub(super) fn get_bool(&self, key: &str) -> anyhow::Result<Option<bool>> {
+        let Some(value) = self.get(key) else {
+            return Ok(None);
+        };
+        Ok(Some(match value.to_lowercase().as_str() {
+            "true" | "yes" | "on" | "1" => true,
+            "false" | "no" | "off" | "0" | "" => false,
+            _ => bail!("bad boolean config value '{value}' for '{key}'"),
+        }))
+    }
+}
+
+// `core` or `remote "origin"`. The deprecated `[section.subsection]` syntax is also accepted.
+fn parse_section_header(header: &str) -> anyhow::Result<String> {
+    let header = header.trim();
+    Ok(match header.split_once(char::is_whitespace) {
+        Some((section, subsection)) => {
+            let subsection = subsection
+                .trim()
+                .strip_prefix('"')
+                .and_then(|subsection| subsection.strip_suffix('"'))
+                .context(format!("bad section header [{header}]"))?;
+            let subsection = subsection.replace("\\\"", "\"").replace("\\\\", "\\");
+            format!("{}.{subsection}", section.to_lowercase())
+        }
+        None => match header.split_once('.') {
+            Some((section, subsection)) => {
+                format!("{}.{}", section.to_lowercase(), subsection.to_lowercase())
+            }
+            None => header.to_lowercase(),
+        },
+    })
+}
+
+// Handles quotes, escape sequences and trailing comments.
+fn parse_value(raw: &str) -> anyhow::Result<String> {
+    let mut value = String::new();
+    let mut in_quotes = false;
+    // Whitespace is only kept when followed by something else than a comment.
+    let mut pending_space = String::new();
+    let mut chars = raw.trim_start().chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                value.push_str(&pending_space);
+                pending_space.clear();
+                in_quotes = !in_quotes;
+            }
+            '\\' => {
+                value.push_str(&pending_space);
+                pending_space.clear();
+                match chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('b') => {
+                        value.pop();
+                    }
+                    Some(c @ ('"' | '\\')) => value.push(c),
+                    Some(c) => bail!("invalid escape sequence \\{c} in config value"),
+                    None => bail!("line continuations in config values are not supported"),
+                }
+            }
+            '#' | ';' if !in_quotes => break,
+            c if c.is_whitespace() && !in_quotes => pending_space.push(c),
+            c => {
+                value.push_str(&pending_space);
+                pending_space.clear();
+                value.push(c);
+            }
+        }
+    }
+    if in_quotes {
+        bail!("unterminated quote in config value");
+    }
+    Ok(value)
+}
+
+// Section and variable names are case insensitive, subsections aren't.
+fn canonical_key(key: &str) -> String {
+    let (section, rest) = key.split_once('.').unwrap_or((key, ""));
+    match rest.rsplit_once('.') {
+        Some((subsection, name)) => format!(
+            "{}.{subsection}.{}",
+            section.to_lowercase(),
+            name.to_lowercase()
+        ),
+        None => format!("{}.{}", section.to_lowercase(), rest.to_lowercase()),
+    }
+}
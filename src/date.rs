@@ -0,0 +1,197 @@
+use anyhow::{bail, Context};
+use jiff::{
+    civil,
+    fmt::rfc2822,
+    tz::{Offset, TimeZone},
+    Timestamp, Zoned,
+};
+use std::{env, fmt};
+
+/// A point in time along with the offset of the time zone it was recorded in, as found in commits, tags and
+/// reflogs: `<seconds since epoch> <+HHMM>`.
+pub(super) struct GitDate {
+    timestamp: Timestamp,
+    offset: Offset,
+}
+
+impl GitDate {
+    pub(super) fn now() -> Self {
+        let now = Zoned::now();
+        Self {
+            timestamp: now.timestamp(),
+            offset: now.offset(),
+        }
+    }
+
+    /// The date set by an environment variable such as `GIT_AUTHOR_DATE`, or now.
+    pub(super) fn from_env(var: &str) -> anyhow::Result<Self> {
+        match env::var(var) {
+            Ok(date) => {
+                Self::parse(&date).context(format!("invalid date format '{date}' in {var}"))
+            }
+            Err(_) => Ok(Self::now()),
+        }
+    }
+
+    /// Parse a date in one of the formats git accepts:
+    /// - its internal format, `<seconds since epoch> <+HHMM>`, optionally prefixed by `@`,
+    /// - RFC 2822, `Thu, 07 Apr 2005 22:13:13 +0200`,
+    /// - ISO 8601, `2005-04-07T22:13:13` with an optional offset, the local time zone otherwise.
+    pub(super) fn parse(input: &str) -> anyhow::Result<Self> {
+        let input = input.trim();
+        let (date, offset) = match input
+            .rsplit_once(' ')
+            .and_then(|(date, offset)| Some((date, parse_offset(offset)?)))
+        {
+            Some((date, offset)) => (date, Some(offset)),
+            None => (input, None),
+        };
+
+        let seconds = date.strip_prefix('@').unwrap_or(date);
+        if let Ok(seconds) = seconds.parse::<i64>() {
+            return Ok(Self {
+                timestamp: Timestamp::from_second(seconds)?,
+                offset: offset.unwrap_or(Offset::UTC),
+            });
+        }
+        if let Ok(zoned) = rfc2822::parse(input) {
+            return Ok(Self {
+                timestamp: zoned.timestamp(),
+                offset: zoned.offset(),
+            });
+        }
+        // The offset may also be glued to the time: `2005-04-07T22:13:13+02:00` or `...Z`.
+        let (date, offset) = match offset {
+            Some(offset) => (date, Some(offset)),
+            None => split_glued_offset(date),
+        };
+        // `T` or a space between the date and the time.
+        let Ok(datetime) = date.replacen(' ', "T", 1).parse::<civil::DateTime>() else {
+            bail!("unsupported date format");
+        };
+        let zoned = match offset {
+            Some(offset) => datetime.to_zoned(TimeZone::fixed(offset))?,
+            None => datetime.to_zoned(TimeZone::system())?,
+        };
+        Ok(Self {
+            timestamp: zoned.timestamp(),
+            offset: zoned.offset(),
+        })
+    }
+}
+
+impl GitDate {
+    /// Seconds since the epoch.
+    pub(super) fn seconds(&self) -> i64 {
+        self.timestamp.as_second()
+    }
+
+    /// The default format of `git log`, `Thu Apr 7 22:13:13 2005 +0200`, in the recorded time zone.
+    pub(super) fn to_human(&self) -> String {
+        self.timestamp
+            .to_zoned(TimeZone::fixed(self.offset))
+            .strftime("%a %b %-d %H:%M:%S %Y %z")
+            .to_string()
+    }
+}
+
+impl fmt::Display for GitDate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let offset = self.offset.seconds();
+        let sign = if offset < 0 { '-' } else { '+' };
+        let minutes = offset.unsigned_abs() / 60;
+        write!(
+            f,
+            "{} {sign}{:02}{:02}",
+            self.timestamp.as_second(),
+            minutes / 60,
+            minutes % 60
+        )
+    }
+}
+
+// `+HHMM`, `-HH:MM` or `Z`.
+fn parse_offset(offset: &str) -> Option<Offset> {
+    if offset == "Z" {
+        return Some(Offset::UTC);
+    }
+    let sign = match offset.as_bytes().first()? {
+        b'+' => 1,
+        b'-' => -1,
+        _ => return None,
+    };
+    let digits = offset[1..].replacen(':', "", 1);
+    if digits.len() != 4 || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let hours: i32 = digits[..2].parse().ok()?;
+    let minutes: i32 = digits[2..].parse().ok()?;
+    Offset::from_seconds(sign * (hours * 3600 + minutes * 60)).ok()
+}
+
+// Splits `2005-04-07T22:13:13+02:00` into the date time and its offset. The sign is looked for after the
+// date, whose dashes aren't offsets.
+fn split_glued_offset(date: &str) -> (&str, Option<Offset>) {
+    if let Some(date) = date.strip_suffix('Z') {
+        return (date, Some(Offset::UTC));
+    }
+    let Some(time_start) = date.find(['T', ' ']) else {
+        return (date, None);
+    };
+    let Some(position) = date[time_start..].rfind(['+', '-']) else {
+        return (date, None);
+    };
+    let (datetime, offset) = date.split_at(time_start + position);
+    match parse_offset(offset) {
+        Some(offset) => (datetime, Some(offset)),
+        None => (date, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GitDate;
+
+    fn parse(date: &str) -> String {
+        GitDate::parse(date).unwrap().to_string()
+    }
+
+    #[test]
+    fn epoch() {
+        assert_eq!(parse("1112904793 +0200"), "1112904793 +0200");
+        assert_eq!(parse("@1112904793 +0200"), "1112904793 +0200");
+        assert_eq!(parse("@1112904793 -0730"), "1112904793 -0730");
+        assert_eq!(parse("@1112904793"), "1112904793 +0000");
+        assert_eq!(parse("  @0 +0000\n"), "0 +0000");
+    }
+
+    #[test]
+    fn rfc2822() {
+        assert_eq!(parse("Thu, 07 Apr 2005 22:13:13 +0200"), "1112904793 +0200");
+        assert_eq!(parse("Thu, 7 Apr 2005 22:13:13 -0730"), "1112938993 -0730");
+    }
+
+    #[test]
+    fn iso8601() {
+        assert_eq!(parse("2005-04-07T22:13:13 +0200"), "1112904793 +0200");
+        assert_eq!(parse("2005-04-07 22:13:13 +02:00"), "1112904793 +0200");
+        assert_eq!(parse("2005-04-07T22:13:13+02:00"), "1112904793 +0200");
+        assert_eq!(parse("2005-04-07T22:13:13+0200"), "1112904793 +0200");
+        assert_eq!(parse("2005-04-07T20:13:13Z"), "1112904793 +0000");
+        assert_eq!(parse("2005-04-07T22:13:13-07:30"), "1112938993 -0730");
+    }
+
+    #[test]
+    fn human() {
+        let date = GitDate::parse("@1112904793 +0200").unwrap();
+        assert_eq!(date.to_human(), "Thu Apr 7 22:13:13 2005 +0200");
+        assert_eq!(date.seconds(), 1112904793);
+    }
+
+    #[test]
+    fn invalid() {
+        for date in ["", "yesterday", "2005-13-07T22:13:13", "@12ab +0200"] {
+            assert!(GitDate::parse(date).is_err(), "{date:?}");
+        }
+    }
+}
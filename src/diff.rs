@@ -0,0 +1,462 @@
+use crate::{hex_hash::GitHexHash, quote};
+use std::io::Write;
+
+/// Lines of unchanged content shown around the changes.
+const CONTEXT: usize = 3;
+/// Like git, a file with a NUL byte in its beginning is binary.
+const BINARY_CHECK_LEN: usize = 8000;
+
+/// One side of a file diff.
+pub(super) struct Side {
+    pub(super) mode: u32,
+    pub(super) hash: GitHexHash,
+    pub(super) content: Vec<u8>,
+}
+
+/// Write the diff of a file in git's format: a `diff --git` header, then the changes as a unified diff.
+/// `None` is a missing side, for a created or deleted file.
+pub(super) fn write_file_diff(
+    out: &mut impl Write,
+    path: &str,
+    old: Option<&Side>,
+    new: Option<&Side>,
+    quote_non_ascii: bool,
+) -> anyhow::Result<()> {
+    let a_path = quote::quote_path(&format!("a/{path}"), quote_non_ascii).into_owned();
+    let b_path = quote::quote_path(&format!("b/{path}"), quote_non_ascii).into_owned();
+    writeln!(out, "diff --git {a_path} {b_path}")?;
+    match (old, new) {
+        (None, Some(new)) => writeln!(out, "new file mode {:o}", new.mode)?,
+        (Some(old), None) => writeln!(out, "deleted file mode {:o}", old.mode)?,
+        (Some(old), Some(new)) if old.mode != new.mode => {
+            writeln!(out, "old mode {:o}", old.mode)?;
+            writeln!(out, "new mode {:o}", new.mode)?;
+        }
+        _ => {}
+    }
+    let old_hash = old.map(|side| side.hash.clone());
+    let new_hash = new.map(|side| side.hash.clone());
+    if old_hash == new_hash {
+        // Only the mode changed.
+        return Ok(());
+    }
+    let null = "0000000";
+    let old_name = match &old_hash {
+        Some(hash) => hash.abbreviate(7)?,
+        None => null,
+    };
+    let new_name = match &new_hash {
+        Some(hash) => hash.abbreviate(7)?,
+        None => null,
+    };
+    write!(out, "index {old_name}..{new_name}")?;
+    match (old, new) {
+        (Some(old), Some(new)) if old.mode == new.mode => writeln!(out, " {:o}", old.mode)?,
+        _ => writeln!(out)?,
+    }
+
+    let old_content = old.map_or(&[][..], |side| &side.content);
+    let new_content = new.map_or(&[][..], |side| &side.content);
+    // A created or deleted empty file has no content to show.
+    if old_content.is_empty() && new_content.is_empty() {
+        return Ok(());
+    }
+    let old_label = if old.is_some() {
+        a_path
+    } else {
+        "/dev/null".to_string()
+    };
+    let new_label = if new.is_some() {
+        b_path
+    } else {
+        "/dev/null".to_string()
+    };
+    if is_binary(old_content) || is_binary(new_content) {
+        writeln!(out, "Binary files {old_label} and {new_label} differ")?;
+        return Ok(());
+    }
+    writeln!(out, "--- {old_label}")?;
+    writeln!(out, "+++ {new_label}")?;
+    write_hunks(out, old_content, new_content)
+}
+
+fn is_binary(content: &[u8]) -> bool {
+    content[..content.len().min(BINARY_CHECK_LEN)].contains(&0)
+}
+
+/// The lines of a file, with their new line.
+pub(super) fn lines(content: &[u8]) -> Vec<&[u8]> {
+    content.split_inclusive(|&byte| byte == b'\n').collect()
+}
+
+/// A change between the two files: `old_len` lines removed at `old_start` and `new_len` added at `new_start`.
+pub(super) struct Change {
+    pub(super) old_start: usize,
+    pub(super) old_len: usize,
+    pub(super) new_start: usize,
+    pub(super) new_len: usize,
+}
+
+/// The changes from the lines `a` to the lines `b`, in order, as shown by their diff.
+pub(super) fn diff_lines(a: &[&[u8]], b: &[&[u8]]) -> Vec<Change> {
+    let (mut removed, mut added) = myers(a, b);
+    compact(a, &mut removed, &added);
+    compact(b, &mut added, &removed);
+    changes(&removed, &added)
+}
+
+fn write_hunks(out: &mut impl Write, old: &[u8], new: &[u8]) -> anyhow::Result<()> {
+    let a = lines(old);
+    let b = lines(new);
+    let changes = diff_lines(&a, &b);
+
+    // The function context is kept from a hunk to the next when none is found in between, like git.
+    let mut function = None;
+    let mut function_limit = None;
+    let mut i = 0;
+    while i < changes.len() {
+        // Changes close enough to share their context are in the same hunk.
+        let mut last = i;
+        while last + 1 < changes.len()
+            && changes[last + 1].old_start - (changes[last].old_start + changes[last].old_len)
+                <= 2 * CONTEXT
+        {
+            last += 1;
+        }
+        let (first_change, last_change) = (&changes[i], &changes[last]);
+        let old_start = first_change.old_start.saturating_sub(CONTEXT);
+        let new_start = first_change.new_start.saturating_sub(CONTEXT);
+        let old_end = (last_change.old_start + last_change.old_len + CONTEXT).min(a.len());
+        let new_end = (last_change.new_start + last_change.new_len + CONTEXT).min(b.len());
+
+        if let Some(line) = find_function(&a, old_start, function_limit) {
+            function = Some(line);
+        }
+        function_limit = Some(old_start);
+        write!(
+            out,
+            "@@ -{} +{} @@",
+            range(old_start, old_end - old_start),
+            range(new_start, new_end - new_start)
+        )?;
+        match function {
+            Some(line) => {
+                out.write_all(b" ")?;
+                out.write_all(line)?;
+                out.write_all(b"\n")?;
+            }
+            None => writeln!(out)?,
+        }
+
+        let mut old_line = old_start;
+        for change in &changes[i..=last] {
+            for line in &a[old_line..change.old_start] {
+                write_line(out, b' ', line)?;
+            }
+            for line in &a[change.old_start..change.old_start + change.old_len] {
+                write_line(out, b'-', line)?;
+            }
+            for line in &b[change.new_start..change.new_start + change.new_len] {
+                write_line(out, b'+', line)?;
+            }
+            old_line = change.old_start + change.old_len;
+        }
+        for line in &a[old_line..old_end] {
+            write_line(out, b' ', line)?;
+        }
+        i = last + 1;
+    }
+    Ok(())
+}
+
+// `<start>,<count>`, the start being the line before for an empty range, and the count omitted when 1.
+fn range(start: usize, count: usize) -> String {
+    match count {
+        0 => format!("{start},0"),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{count}", start + 1),
+    }
+}
+
+fn write_line(out: &mut impl Write, marker: u8, line: &[u8]) -> anyhow::Result<()> {
+    out.write_all(&[marker])?;
+    out.write_all(line)?;
+    if !line.ends_with(b"\n") {
+        out.write_all(b"\n\\ No newline at end of file\n")?;
+    }
+    Ok(())
+}
+
+// The last line before `start`, and after `limit`, that looks like the start of a function: git's default
+// is a line starting with a letter, `_` or `$`. Truncated to 80 bytes, without trailing spaces.
+fn find_function<'a>(lines: &[&'a [u8]], start: usize, limit: Option<usize>) -> Option<&'a [u8]> {
+    let lower = limit.map_or(0, |limit| limit + 1);
+    (lower..start).rev().find_map(|i| {
+        let line = lines[i];
+        let first = *line.first()?;
+        if !(first.is_ascii_alphabetic() || first == b'_' || first == b'$') {
+            return None;
+        }
+        let line = &line[..line.len().min(80)];
+        let end = line
+            .iter()
+            .rposition(|byte| !byte.is_ascii_whitespace())
+            .map_or(0, |end| end + 1);
+        Some(&line[..end])
+    })
+}
+
+/// The lines removed from `a` and added in `b` by a shortest edit script (Myers' algorithm).
+fn myers(a: &[&[u8]], b: &[&[u8]]) -> (Vec<bool>, Vec<bool>) {
+    let mut removed = vec![false; a.len()];
+    let mut added = vec![false; b.len()];
+    // The common beginning and end aren't part of the search.
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (a_mid, b_mid) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    let (n, m) = (a_mid.len() as isize, b_mid.len() as isize);
+    let max = n + m;
+    let offset = max + 1;
+    // `v[k]` is the furthest x reached on diagonal k = x - y.
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    let mut trace = Vec::new();
+    'search: for d in 0..=max {
+        trace.push(v.clone());
+        for k in (-d..=d).step_by(2) {
+            let index = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[index - 1] < v[index + 1]) {
+                v[index + 1]
+            } else {
+                v[index - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a_mid[x as usize] == b_mid[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[index] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+
+    // Back from the end, each step of the trace is a removal or an addition followed by common lines.
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let previous_k =
+            if k == -d || (k != d && v[(k - 1 + offset) as usize] < v[(k + 1 + offset) as usize]) {
+                k + 1
+            } else {
+                k - 1
+            };
+        let previous_x = v[(previous_k + offset) as usize];
+        let previous_y = previous_x - previous_k;
+        while x > previous_x && y > previous_y {
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            if x == previous_x {
+                added[prefix + previous_y as usize] = true;
+            } else {
+                removed[prefix + previous_x as usize] = true;
+            }
+        }
+        x = previous_x;
+        y = previous_y;
+    }
+    (removed, added)
+}
+
+/// A run of changed lines `start..end` in one file, empty between two unchanged lines.
+struct Group {
+    start: usize,
+    end: usize,
+}
+
+/// Slide the groups of changed lines of a file to where git puts them, `changed` being indexed from 1 with
+/// unchanged sentinels at both ends.
+struct Groups<'a> {
+    changed: Vec<bool>,
+    lines: &'a [&'a [u8]],
+}
+
+impl Groups<'_> {
+    fn is_changed(&self, line: usize) -> bool {
+        self.changed[line + 1]
+    }
+
+    fn set_changed(&mut self, line: usize, changed: bool) {
+        self.changed[line + 1] = changed;
+    }
+
+    fn first(&self) -> Group {
+        let mut end = 0;
+        while self.is_changed(end) {
+            end += 1;
+        }
+        Group { start: 0, end }
+    }
+
+    fn next(&self, group: &mut Group) -> bool {
+        if group.end == self.lines.len() {
+            return false;
+        }
+        group.start = group.end + 1;
+        group.end = group.start;
+        while self.is_changed(group.end) {
+            group.end += 1;
+        }
+        true
+    }
+
+    fn previous(&self, group: &mut Group) -> bool {
+        if group.start == 0 {
+            return false;
+        }
+        group.end = group.start - 1;
+        group.start = group.end;
+        while group.start > 0 && self.is_changed(group.start - 1) {
+            group.start -= 1;
+        }
+        true
+    }
+
+    fn slide_down(&mut self, group: &mut Group) -> bool {
+        if group.end < self.lines.len() && self.lines[group.start] == self.lines[group.end] {
+            self.set_changed(group.start, false);
+            self.set_changed(group.end, true);
+            group.start += 1;
+            group.end += 1;
+            while self.is_changed(group.end) {
+                group.end += 1;
+            }
+            return true;
+        }
+        false
+    }
+
+    fn slide_up(&mut self, group: &mut Group) -> bool {
+        if group.start > 0 && self.lines[group.start - 1] == self.lines[group.end - 1] {
+            group.start -= 1;
+            group.end -= 1;
+            self.set_changed(group.start, true);
+            self.set_changed(group.end, false);
+            while group.start > 0 && self.is_changed(group.start - 1) {
+                group.start -= 1;
+            }
+            return true;
+        }
+        false
+    }
+}
+
+/// Move the ambiguous changes of a file like git does (`xdl_change_compact`) so diffs look the same: a
+/// group of changes goes as low as possible, unless it can end next to a change of the other file.
+fn compact(lines: &[&[u8]], changed: &mut Vec<bool>, other_changed: &[bool]) {
+    let mut groups = Groups {
+        changed: [false]
+            .into_iter()
+            .chain(changed.iter().copied())
+            .chain([false])
+            .collect(),
+        lines,
+    };
+    let other = Groups {
+        changed: [false]
+            .into_iter()
+            .chain(other_changed.iter().copied())
+            .chain([false])
+            .collect(),
+        lines: &[],
+    };
+    let other_len = other_changed.len();
+    let other_next = |group: &mut Group| {
+        if group.end == other_len {
+            return;
+        }
+        group.start = group.end + 1;
+        group.end = group.start;
+        while other.is_changed(group.end) {
+            group.end += 1;
+        }
+    };
+
+    let mut group = groups.first();
+    let mut other_group = other.first();
+    loop {
+        if group.end != group.start {
+            let mut end_matching_other = None;
+            let mut earliest_end;
+            loop {
+                let size = group.end - group.start;
+                while groups.slide_up(&mut group) {
+                    other.previous(&mut other_group);
+                }
+                earliest_end = group.end;
+                if other_group.end > other_group.start {
+                    end_matching_other = Some(group.end);
+                }
+                while groups.slide_down(&mut group) {
+                    other_next(&mut other_group);
+                    if other_group.end > other_group.start {
+                        end_matching_other = Some(group.end);
+                    }
+                }
+                // Sliding merged the group with another one, try again with the bigger group.
+                if size == group.end - group.start {
+                    break;
+                }
+            }
+            if group.end != earliest_end {
+                if let Some(end) = end_matching_other {
+                    while group.end > end && other_group.end == other_group.start {
+                        groups.slide_up(&mut group);
+                        other.previous(&mut other_group);
+                    }
+                }
+            }
+        }
+        if !groups.next(&mut group) {
+            break;
+        }
+        other_next(&mut other_group);
+    }
+    *changed = groups.changed[1..=lines.len()].to_vec();
+}
+
+/// The changes, in order, from the changed lines of both files.
+fn changes(removed: &[bool], added: &[bool]) -> Vec<Change> {
+    let mut changes = Vec::new();
+    let (mut i, mut j) = (removed.len(), added.len());
+    while i > 0 || j > 0 {
+        if (i > 0 && removed[i - 1]) || (j > 0 && added[j - 1]) {
+            let (old_end, new_end) = (i, j);
+            while i > 0 && removed[i - 1] {
+                i -= 1;
+            }
+            while j > 0 && added[j - 1] {
+                j -= 1;
+            }
+            changes.push(Change {
+                old_start: i,
+                old_len: old_end - i,
+                new_start: j,
+                new_len: new_end - j,
+            });
+        } else {
+            i -= 1;
+            j -= 1;
+        }
+    }
+    changes.reverse();
+    changes
+}
@@ -0,0 +1,71 @@
+use crate::{config, repo};
+use anyhow::{bail, ensure, Context};
+use std::{
+    fs,
+    io::Write,
+    process::{Command, Stdio},
+};
+
+/// Check that `signature`, ASCII armored, signs `payload`. What gpg reports about the signer goes to stderr,
+/// as with git.
+///
+/// Like git, the program is `gpg.program`, `gpg` by default.
+pub(super) fn verify(payload: &[u8], signature: &str) -> anyhow::Result<()> {
+    let program = config::config()?.get("gpg.program").unwrap_or("gpg");
+    // gpg reads the signature from a file and the signed data from stdin. Like git, the file is new and in the
+    // git directory, rather than at a predictable path of the shared temporary directory.
+    let (signature_path, mut signature_file) =
+        crate::create_temp_file(repo::git_dir()?, ".git_vtag_tmp")?;
+    let output = (|| {
+        writeln!(signature_file, "{signature}").context(format!("writing {signature_path:?}"))?;
+        drop(signature_file);
+        let mut child = Command::new(program)
+            .args(["--status-fd=1", "--verify"])
+            .arg(&signature_path)
+            .arg("-")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .context(format!("cannot run {program}"))?;
+        child
+            .stdin
+            .take()
+            .context("gpg stdin")?
+            .write_all(payload)?;
+        Ok::<_, anyhow::Error>(child.wait_with_output()?)
+    })();
+    let _ = fs::remove_file(&signature_path);
+    let output = output?;
+    // The exit status alone isn't enough, gpg succeeds on some bad signatures.
+    let status = String::from_utf8_lossy(&output.stdout);
+    ensure!(
+        output.status.success() && status.contains("[GNUPG:] GOODSIG "),
+        "could not verify the signature"
+    );
+    Ok(())
+}
+
+/// The ASCII armored detached signature of `payload` by `key`, a key id or a user id like `John Doe
+/// <johndoe@example.com>`.
+pub(super) fn sign(payload: &[u8], key: &str) -> anyhow::Result<String> {
+    let program = config::config()?.get("gpg.program").unwrap_or("gpg");
+    let mut child = Command::new(program)
+        .args(["--status-fd=2", "-bsau", key])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(format!("cannot run {program}"))?;
+    child
+        .stdin
+        .take()
+        .context("gpg stdin")?
+        .write_all(payload)?;
+    let output = child.wait_with_output()?;
+    let status = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() || !status.contains("[GNUPG:] SIG_CREATED ") {
+        eprint!("{status}");
+        bail!("gpg failed to sign the data");
+    }
+    String::from_utf8(output.stdout).context("gpg signature is not UTF-8")
+}
@@ -0,0 +1,111 @@
+use crate::{config, hex_hash::GitHexHash, sha256::Sha256};
+use anyhow::bail;
+use sha1::{Digest, Sha1};
+use std::io::{self, Write};
+
+/// The hash function naming the objects of a repository, set by `extensions.objectFormat`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum HashAlgorithm {
+    Sha1,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// The object format of the current repository.
+    pub(super) fn current() -> anyhow::Result<Self> {
+        Self::parse(
+            config::config()?
+                .get("extensions.objectFormat")
+                .unwrap_or("sha1"),
+        )
+    }
+
+    pub(super) fn parse(name: &str) -> anyhow::Result<Self> {
+        Ok(match name.to_lowercase().as_str() {
+            "sha1" => HashAlgorithm::Sha1,
+            "sha256" => HashAlgorithm::Sha256,
+            _ => bail!("unknown object format '{name}'"),
+        })
+    }
+
+    /// Length of the digest in bytes, as stored in tree objects.
+    pub(super) fn raw_len(self) -> usize {
+        match self {
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Sha256 => 32,
+        }
+    }
+
+    /// Length of the digest in hexadecimal representation.
+    pub(super) fn hex_len(self) -> usize {
+        self.raw_len() * 2
+    }
+
+    pub(super) fn hasher(self) -> Hasher {
+        match self {
+            HashAlgorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+
+    /// The all-zero hash, standing for a missing object such as the old value of a created ref.
+    pub(super) fn null_hash(self) -> GitHexHash {
+        "0".repeat(self.hex_len())
+            .as_str()
+            .try_into()
+            .expect("valid hash")
+    }
+
+    /// Name of the tree without any entry.
+    pub(super) fn empty_tree(self) -> GitHexHash {
+        let hex = match self {
+            HashAlgorithm::Sha1 => "4b825dc642cb6eb9a060e54bf8d69288fbee4904",
+            HashAlgorithm::Sha256 => {
+                "6ef19b41225c5369f1c104d45d8d85efa9b057b53b14b4b9b939dd74decc5321"
+            }
+        };
+        hex.try_into().expect("valid hash")
+    }
+}
+
+impl std::fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HashAlgorithm::Sha1 => write!(f, "sha1"),
+            HashAlgorithm::Sha256 => write!(f, "sha256"),
+        }
+    }
+}
+
+/// Incremental hash computation with the algorithm of the repository.
+pub(super) enum Hasher {
+    Sha1(Sha1),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    pub(super) fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    pub(super) fn finalize(self) -> GitHexHash {
+        match self {
+            Hasher::Sha1(hasher) => GitHexHash::from_raw(&hasher.finalize()),
+            Hasher::Sha256(hasher) => GitHexHash::from_raw(&hasher.finalize()),
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
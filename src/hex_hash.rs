@@ -1,51 +1,167 @@
-use anyhow::anyhow;
-use std::{fmt, path::PathBuf};
+use crate::{hash::HashAlgorithm, pack, repo};
+use anyhow::{bail, Context};
+use std::{fmt, fs, path::PathBuf};
+
+/// Git refuses to use abbreviated hashes shorter than this.
+pub(super) const MIN_ABBREV_LEN: usize = 4;
+
+/// Length of a SHA-1 in hexadecimal representation.
+const SHA1_HEX_LEN: usize = 40;
+/// Length of a SHA-256 in hexadecimal representation.
+const SHA256_HEX_LEN: usize = 64;
 
 // Motivation for this struct
 // - Currently `clap` doesn't support parsing into `Box<[char; 40]>`
 // - Box<[char; 40]> is unergonomic because there is no AsRef<Path> for Box<[char;N]>.
 // (Because that's not how Path are represented internally)
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub(super) struct GitHexHash {
-    // A SHA-1 has in it's hexadecimal representation.
-    // We could also represent this as Box<[u8; 20]> with the real hash value
+    // A SHA-1 (40 characters) or SHA-256 (64 characters) hash in it's hexadecimal representation.
+    // We could also represent this as Box<[u8]> with the real hash value
     // but as we mostly uses this as a Path this more convenient to do it this way.
-    hex: Box<[u8; 40]>,
+    hex: Box<[u8]>,
 }
 
 impl GitHexHash {
-    pub(super) fn to_path(&self) -> PathBuf {
+    /// Build from the raw bytes of the digest, as stored in tree objects.
+    pub(super) fn from_raw(raw: &[u8]) -> Self {
+        let hex = base16ct::lower::encode_string(raw);
+        Self {
+            hex: hex.into_bytes().into_boxed_slice(),
+        }
+    }
+
+    /// The raw bytes of the digest, as stored in tree objects.
+    pub(super) fn to_raw(&self) -> Vec<u8> {
+        // Can't fail, we only store valid hex digits.
+        base16ct::mixed::decode_vec(self.as_str()).expect("valid hex")
+    }
+
+    /// Path of the loose object in the repository objects directory, where it's written.
+    pub(super) fn to_path(&self) -> anyhow::Result<PathBuf> {
         let (dir, rest) = self.as_str().split_at(2);
-        PathBuf::from(".git/objects").join(dir).join(rest)
+        Ok(repo::objects_dir()?.join(dir).join(rest))
+    }
+
+    /// Path of the existing loose object, either in the repository or in one of its alternates.
+    pub(super) fn find_path(&self) -> anyhow::Result<Option<PathBuf>> {
+        let (dir, rest) = self.as_str().split_at(2);
+        Ok(repo::object_dirs()?
+            .iter()
+            .map(|objects_dir| objects_dir.join(dir).join(rest))
+            .find(|path| path.is_file()))
+    }
+
+    /// Whether the object is in the store, either loose or in a pack.
+    pub(super) fn exists(&self) -> anyhow::Result<bool> {
+        Ok(self.find_path()?.is_some() || pack::contains(self)?)
+    }
+
+    /// Shortest prefix of at least `min_len` characters that doesn't match any other object of the store.
+    pub(super) fn abbreviate(&self, min_len: usize) -> anyhow::Result<&str> {
+        let hex = self.as_str();
+        let mut len = min_len.clamp(MIN_ABBREV_LEN, hex.len());
+        let (dir, rest) = hex.split_at(2);
+        for objects_dir in repo::object_dirs()? {
+            let dir = objects_dir.join(dir);
+            if !dir.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(dir)? {
+                let name = entry?.file_name();
+                let Some(name) = name.to_str() else {
+                    continue;
+                };
+                if name == rest {
+                    continue;
+                }
+                let common = name
+                    .bytes()
+                    .zip(rest.bytes())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                // The directory name is part of the prefix.
+                len = len.max(2 + common + 1);
+            }
+        }
+        // Packed objects sharing less than `len` digits don't make it longer.
+        for other in pack::find_prefix(&hex[..len])? {
+            let common = other
+                .as_str()
+                .bytes()
+                .zip(hex.bytes())
+                .take_while(|(a, b)| a == b)
+                .count();
+            if common < hex.len() {
+                len = len.max(common + 1);
+            }
+        }
+        Ok(&hex[..len.min(hex.len())])
     }
 
     pub(super) fn as_str(&self) -> &str {
         // TODO: maybe just representing this as a `str` would be more convenient?
         // SAFETY: We know self.hex only contains valid ASCII characters
-        unsafe { std::str::from_utf8_unchecked(self.hex.as_slice()) }
+        unsafe { std::str::from_utf8_unchecked(&self.hex) }
     }
 }
 
+/// All the loose objects of the repository objects directory, not its alternates, in no particular order.
+///
+/// Objects are in `<2 first hex digits>/<the others>`, other directories such as `pack` or `info` and temporary
+/// files are skipped.
+pub(super) fn loose_objects() -> anyhow::Result<impl Iterator<Item = GitHexHash>> {
+    let hex_len = HashAlgorithm::current()?.hex_len();
+    let objects_dir = repo::objects_dir()?;
+    let dirs = fs::read_dir(&objects_dir).context(format!("reading {objects_dir:?}"))?;
+    Ok(dirs
+        .filter_map(Result::ok)
+        .filter_map(|dir| {
+            let prefix = dir.file_name().into_string().ok()?;
+            let is_fan_out =
+                prefix.len() == 2 && prefix.bytes().all(|byte| byte.is_ascii_hexdigit());
+            is_fan_out.then(|| (prefix, dir.path()))
+        })
+        .flat_map(move |(prefix, dir)| {
+            fs::read_dir(dir)
+                .into_iter()
+                .flatten()
+                .filter_map(Result::ok)
+                .filter_map(move |entry| {
+                    let rest = entry.file_name().into_string().ok()?;
+                    if prefix.len() + rest.len() != hex_len {
+                        return None;
+                    }
+                    GitHexHash::try_from(format!("{prefix}{rest}").as_str()).ok()
+                })
+        }))
+}
+
 impl TryFrom<&str> for GitHexHash {
     type Error = anyhow::Error;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        let hash = value
-            .as_bytes()
-            .iter()
-            .map(|&c| {
-                if c.is_ascii_hexdigit() {
-                    Ok(c)
-                } else {
-                    Err(anyhow!("'{c}' is not ASCII hex digit"))
-                }
-            })
-            .collect::<Result<Vec<_>, _>>()?;
-        let hex = hash
-            .into_boxed_slice()
-            .try_into()
-            .map_err(|bad: Box<[u8]>| anyhow!("wrong hash length: {}", bad.len()))?;
-        Ok(Self { hex })
+        if let Some(c) = value.chars().find(|c| !c.is_ascii_hexdigit()) {
+            bail!("Not a valid object name {value}: '{c}' is not a hex digit");
+        }
+        let len = value.len();
+        if len != SHA1_HEX_LEN && len != SHA256_HEX_LEN {
+            // Outside of a repository, either length is fine.
+            let expected =
+                HashAlgorithm::current().map_or(SHA1_HEX_LEN, |algorithm| algorithm.hex_len());
+            if len > expected {
+                bail!("Not a valid object name {value}: too long, a full hash has {expected} hex digits");
+            }
+            if len < MIN_ABBREV_LEN {
+                bail!("Not a valid object name {value}: too short, use at least {MIN_ABBREV_LEN} hex digits");
+            }
+            bail!(
+                "Not a valid object name {value}: too short for a full hash of {expected} hex digits, did you mean an abbreviation?"
+            );
+        }
+        Ok(Self {
+            hex: value.as_bytes().into(),
+        })
     }
 }
 
@@ -0,0 +1,70 @@
+use anyhow::{bail, ensure};
+use std::io::{BufRead, BufReader};
+
+/// An HTTP response whose body is streamed from the connection.
+pub(super) struct Response {
+    pub(super) content_type: Option<String>,
+    pub(super) body: Box<dyn BufRead>,
+}
+
+/// A blocking `GET`, following redirects.
+pub(super) fn get(url: &str) -> anyhow::Result<Response> {
+    let (status, response) = request(ureq::get(url), url, &[])?;
+    check_status(url, status)?;
+    Ok(response)
+}
+
+/// Like [`get`], but `None` when the server answers `404 Not Found`, as plain web servers do for missing files.
+pub(super) fn get_if_found(url: &str) -> anyhow::Result<Option<Response>> {
+    let (status, response) = request(ureq::get(url), url, &[])?;
+    if status == 404 {
+        return Ok(None);
+    }
+    check_status(url, status)?;
+    Ok(Some(response))
+}
+
+/// A blocking `POST` of `body`, `accept` being the expected content type of the response.
+pub(super) fn post(
+    url: &str,
+    content_type: &str,
+    accept: &str,
+    body: &[u8],
+) -> anyhow::Result<Response> {
+    let post = ureq::post(url)
+        .set("Content-Type", content_type)
+        .set("Accept", accept);
+    let (status, response) = request(post, url, body)?;
+    check_status(url, status)?;
+    Ok(response)
+}
+
+fn check_status(url: &str, status: u16) -> anyhow::Result<()> {
+    ensure!(
+        status == 200,
+        "unable to access '{url}': The requested URL returned error: {status}"
+    );
+    Ok(())
+}
+
+// Returns the status along with the response: error statuses are left to the callers, some of them expect a
+// `404 Not Found`.
+fn request(request: ureq::Request, url: &str, body: &[u8]) -> anyhow::Result<(u16, Response)> {
+    let request = request.set("User-Agent", "git/codecrafters");
+    let result = if body.is_empty() {
+        request.call()
+    } else {
+        request.send_bytes(body)
+    };
+    let response = match result {
+        Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+        Err(ureq::Error::Transport(transport)) => match transport.message() {
+            Some(message) => bail!("unable to access '{url}': {}: {message}", transport.kind()),
+            None => bail!("unable to access '{url}': {}", transport.kind()),
+        },
+    };
+    let status = response.status();
+    let content_type = response.header("Content-Type").map(str::to_string);
+    let body = Box::new(BufReader::new(response.into_reader()));
+    Ok((status, Response { content_type, body }))
+}
@@ -0,0 +1,61 @@
+use crate::config;
+use std::{env, fmt};
+
+// Used when neither the environment nor the configuration name anyone.
+const DEFAULT_NAME: &str = "John Doe";
+const DEFAULT_EMAIL: &str = "johndoe@example.com";
+
+/// Who an identity is recorded for: git tells apart the author of the changes from who committed them.
+#[derive(Clone, Copy)]
+pub(super) enum Role {
+    Author,
+    Committer,
+}
+
+impl Role {
+    fn as_str(self) -> &'static str {
+        match self {
+            Role::Author => "author",
+            Role::Committer => "committer",
+        }
+    }
+}
+
+/// A name and an email, written `Name <email>` in commits and reflogs.
+pub(super) struct Identity {
+    pub(super) name: String,
+    pub(super) email: String,
+}
+
+impl Identity {
+    /// The identity of `role`, the name and the email each taken from the first of:
+    /// - `GIT_AUTHOR_NAME` and `GIT_AUTHOR_EMAIL`, or `GIT_COMMITTER_NAME` and `GIT_COMMITTER_EMAIL`,
+    /// - the `author.name` and `author.email` configuration, or `committer.name` and `committer.email`,
+    /// - the `user.name` and `user.email` configuration,
+    /// - John Doe, johndoe@example.com.
+    pub(super) fn resolve(role: Role) -> anyhow::Result<Self> {
+        let config = config::config()?;
+        let role = role.as_str();
+        let lookup = |field: &str, default: &str| {
+            env::var(format!(
+                "GIT_{}_{}",
+                role.to_uppercase(),
+                field.to_uppercase()
+            ))
+            .ok()
+            .or_else(|| config.get(&format!("{role}.{field}")).map(str::to_string))
+            .or_else(|| config.get(&format!("user.{field}")).map(str::to_string))
+            .unwrap_or_else(|| default.to_string())
+        };
+        Ok(Self {
+            name: lookup("name", DEFAULT_NAME),
+            email: lookup("email", DEFAULT_EMAIL),
+        })
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} <{}>", self.name, self.email)
+    }
+}
@@ -0,0 +1,151 @@
+use crate::{attributes::wildmatch, config, repo};
+use anyhow::Context;
+use std::{
+    collections::HashMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+// The ignore files don't change while a command runs, each one is only parsed once.
+static PATTERNS: OnceLock<Mutex<HashMap<PathBuf, Arc<Vec<Pattern>>>>> = OnceLock::new();
+
+/// A line of an ignore file.
+struct Pattern {
+    pattern: String,
+    /// `!<pattern>`: a path matching it is not ignored after all.
+    negated: bool,
+    /// `<pattern>/` only matches directories.
+    dir_only: bool,
+    /// Without a slash the pattern matches the file name at any depth, otherwise the path relative to the
+    /// directory of the ignore file.
+    match_name: bool,
+}
+
+/// Whether `path`, relative to the top of the working tree, is ignored like git does with `--exclude-standard`.
+/// A path in an ignored directory is ignored too, no pattern can bring it back.
+///
+/// The `.gitignore` file of the closest directory comes first, up to the top of the working tree, then
+/// `$GIT_DIR/info/exclude` and the global excludes file. In a file, the last matching line wins.
+pub(super) fn is_ignored(path: &str, is_dir: bool) -> anyhow::Result<bool> {
+    for (i, _) in path.match_indices('/') {
+        if is_excluded(&path[..i], true)? {
+            return Ok(true);
+        }
+    }
+    is_excluded(path, is_dir)
+}
+
+// The patterns matching `path` itself, without looking at its directories.
+fn is_excluded(path: &str, is_dir: bool) -> anyhow::Result<bool> {
+    let work_tree = repo::work_tree()?;
+    let mut sources = Vec::new();
+    for (i, _) in path.rmatch_indices('/') {
+        let dir = &path[..i];
+        sources.push((dir, patterns(&work_tree.join(dir).join(".gitignore"))?));
+    }
+    sources.push(("", patterns(&work_tree.join(".gitignore"))?));
+    sources.push((
+        "",
+        patterns(&repo::git_dir()?.join("info").join("exclude"))?,
+    ));
+    if let Some(file) = global_excludes_file()? {
+        sources.push(("", patterns(&file)?));
+    }
+
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    for (dir, patterns) in sources {
+        let relative = match dir {
+            "" => path,
+            dir => &path[dir.len() + 1..],
+        };
+        let matched = patterns.iter().rev().find(|pattern| {
+            let subject = if pattern.match_name {
+                file_name
+            } else {
+                relative
+            };
+            (is_dir || !pattern.dir_only)
+                && wildmatch(pattern.pattern.as_bytes(), subject.as_bytes())
+        });
+        if let Some(pattern) = matched {
+            return Ok(!pattern.negated);
+        }
+    }
+    Ok(false)
+}
+
+/// `core.excludesFile`, by default `$XDG_CONFIG_HOME/git/ignore` or `~/.config/git/ignore`.
+fn global_excludes_file() -> anyhow::Result<Option<PathBuf>> {
+    let home = env::var_os("HOME").map(PathBuf::from);
+    if let Some(file) = config::config()?.get("core.excludesFile") {
+        return Ok(Some(match (file.strip_prefix("~/"), &home) {
+            (Some(rest), Some(home)) => home.join(rest),
+            _ => PathBuf::from(file),
+        }));
+    }
+    Ok(
+        match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+            Some(dir) => Some(Path::new(&dir).join("git").join("ignore")),
+            None => home.map(|home| home.join(".config").join("git").join("ignore")),
+        },
+    )
+}
+
+fn patterns(file: &Path) -> anyhow::Result<Arc<Vec<Pattern>>> {
+    let cache = PATTERNS.get_or_init(Default::default);
+    if let Some(patterns) = cache
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(file)
+    {
+        return Ok(patterns.clone());
+    }
+    let patterns = match fs::read_to_string(file) {
+        Ok(content) => parse(&content),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err).context(format!("reading {file:?}")),
+    };
+    let patterns = Arc::new(patterns);
+    cache
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(file.to_path_buf(), patterns.clone());
+    Ok(patterns)
+}
+
+fn parse(content: &str) -> Vec<Pattern> {
+    let mut patterns = Vec::new();
+    for line in content.lines() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // Trailing spaces are ignored unless escaped with a backslash, which the matching takes as an escape.
+        // So is the backslash of a leading `\#` or `\!`.
+        let trimmed = line.trim_end_matches(' ');
+        let line = if trimmed.ends_with('\\') && trimmed.len() < line.len() {
+            &line[..trimmed.len() + 1]
+        } else {
+            trimmed
+        };
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        if line.is_empty() {
+            continue;
+        }
+        let match_name = !line.contains('/');
+        patterns.push(Pattern {
+            pattern: line.strip_prefix('/').unwrap_or(line).to_string(),
+            negated,
+            dir_only,
+            match_name,
+        });
+    }
+    patterns
+}
@@ -0,0 +1,558 @@
+use crate::{hash::HashAlgorithm, hex_hash::GitHexHash, repo};
+use anyhow::{anyhow, bail, ensure, Context};
+use std::{
+    fs,
+    io::{self, Write},
+    ops::{Deref, DerefMut},
+    os::unix::fs::MetadataExt,
+    path::PathBuf,
+};
+
+// Fixed size part of an entry before the hash: ctime, mtime, dev, ino, mode, uid, gid and size on 32 bits.
+const STAT_LEN: usize = 40;
+const NAME_MASK: u16 = 0x0fff;
+const EXTENDED_FLAG: u16 = 0x4000;
+
+/// The staging area, `.git/index`.
+pub(super) struct Index {
+    version: u32,
+    /// Sorted by path then stage.
+    pub(super) entries: Vec<IndexEntry>,
+    /// From the `TREE` extension, the directories are invalidated as their entries change.
+    pub(super) cache_tree: Option<CacheTree>,
+}
+
+/// The index of the repository held with its `.lock` file, so no other process updates it until the changes are
+/// committed. Dropping it without committing releases the lock and leaves the index untouched, on errors too.
+pub(super) struct LockedIndex {
+    index: Index,
+    path: PathBuf,
+    lock: PathBuf,
+    file: fs::File,
+    committed: bool,
+}
+
+/// The hashes of the trees of the index directories, saved by git so writing a tree doesn't hash again
+/// the directories that didn't change.
+#[derive(Clone)]
+pub(super) struct CacheTree {
+    /// None when invalidated by a change of the entries under the directory.
+    pub(super) hash: Option<GitHexHash>,
+    /// Number of index entries under the directory.
+    pub(super) entry_count: usize,
+    /// The sub-directories, by name.
+    pub(super) children: Vec<(String, CacheTree)>,
+}
+
+impl CacheTree {
+    pub(super) fn child(&self, name: &str) -> Option<&CacheTree> {
+        self.children
+            .iter()
+            .find(|(child, _)| child == name)
+            .map(|(_, tree)| tree)
+    }
+
+    /// Forget the hashes of the directories containing `path`, which changed.
+    fn invalidate(&mut self, path: &str) {
+        self.hash = None;
+        if let Some((dir, rest)) = path.split_once('/') {
+            if let Some((_, child)) = self.children.iter_mut().find(|(name, _)| name == dir) {
+                child.invalidate(rest);
+            }
+        }
+    }
+
+    /// The hashes of the valid trees, which are reachable objects for git.
+    pub(super) fn hashes(&self) -> Vec<GitHexHash> {
+        let mut hashes: Vec<GitHexHash> = self.hash.iter().cloned().collect();
+        for (_, child) in &self.children {
+            hashes.extend(child.hashes());
+        }
+        hashes
+    }
+
+    fn write(&self, name: &str, data: &mut Vec<u8>) {
+        data.extend_from_slice(name.as_bytes());
+        data.push(0);
+        let entry_count = match self.hash {
+            Some(_) => self.entry_count as i64,
+            None => -1,
+        };
+        data.extend_from_slice(format!("{entry_count} {}\n", self.children.len()).as_bytes());
+        if let Some(hash) = &self.hash {
+            data.extend_from_slice(&hash.to_raw());
+        }
+        // Git looks the children up by a binary search on their name length, then the name.
+        let mut children: Vec<_> = self.children.iter().collect();
+        children.sort_by(|(a, _), (b, _)| (a.len(), a).cmp(&(b.len(), b)));
+        for (name, child) in children {
+            child.write(name, data);
+        }
+    }
+
+    // Each directory is `<path>\0<entry count> <number of children>\n`, followed by its tree hash unless the
+    // entry count is -1, then its children the same way.
+    fn parse(data: &[u8], offset: &mut usize, raw_len: usize) -> anyhow::Result<(String, Self)> {
+        let name = read_cstr(data, *offset)?.to_string();
+        *offset += name.len() + 1;
+        let rest = data.get(*offset..).context("truncated TREE extension")?;
+        let line_len = rest
+            .iter()
+            .position(|&byte| byte == b'\n')
+            .context("truncated TREE extension")?;
+        let line = std::str::from_utf8(&rest[..line_len])?;
+        *offset += line_len + 1;
+        let (entry_count, children_count) = line
+            .split_once(' ')
+            .context(format!("invalid TREE extension line {line}"))?;
+        let entry_count: i64 = entry_count.parse()?;
+        let children_count: usize = children_count.parse()?;
+        let hash = if entry_count >= 0 {
+            let raw = data
+                .get(*offset..*offset + raw_len)
+                .context("truncated TREE extension")?;
+            *offset += raw_len;
+            Some(GitHexHash::from_raw(raw))
+        } else {
+            None
+        };
+        let children = (0..children_count)
+            .map(|_| Self::parse(data, offset, raw_len))
+            .collect::<anyhow::Result<_>>()?;
+        Ok((
+            name,
+            Self {
+                hash,
+                entry_count: entry_count.max(0) as usize,
+                children,
+            },
+        ))
+    }
+}
+
+pub(super) struct IndexEntry {
+    pub(super) stat: Stat,
+    /// Unix mode: 100644, 100755, 120000 (symbolic link) or 160000 (gitlink).
+    pub(super) mode: u32,
+    pub(super) hash: GitHexHash,
+    /// Non-zero for the sides of a conflicted merge.
+    pub(super) stage: u8,
+    // Version 3 flags such as skip-worktree and intent-to-add, kept as is.
+    extended_flags: u16,
+    pub(super) path: String,
+}
+
+/// File metadata cached in the index to notice changes without hashing the content, truncated to 32 bits.
+#[derive(Default, PartialEq, Eq)]
+pub(super) struct Stat {
+    ctime: (u32, u32),
+    mtime: (u32, u32),
+    dev: u32,
+    ino: u32,
+    uid: u32,
+    gid: u32,
+    size: u32,
+}
+
+impl Stat {
+    pub(super) fn from_metadata(metadata: &fs::Metadata) -> Self {
+        Self {
+            ctime: (metadata.ctime() as u32, metadata.ctime_nsec() as u32),
+            mtime: (metadata.mtime() as u32, metadata.mtime_nsec() as u32),
+            dev: metadata.dev() as u32,
+            ino: metadata.ino() as u32,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            size: metadata.size() as u32,
+        }
+    }
+}
+
+impl IndexEntry {
+    pub(super) fn new(path: String, mode: u32, hash: GitHexHash, stat: Stat) -> Self {
+        Self {
+            stat,
+            mode,
+            hash,
+            stage: 0,
+            extended_flags: 0,
+            path,
+        }
+    }
+}
+
+impl Index {
+    fn empty() -> Self {
+        Self {
+            version: 2,
+            entries: Vec::new(),
+            cache_tree: None,
+        }
+    }
+
+    /// Read the index of the repository, empty if there is none yet.
+    pub(super) fn read() -> anyhow::Result<Self> {
+        let path = repo::index_file()?;
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::empty()),
+            Err(err) => return Err(err).context(format!("reading {path:?}")),
+        };
+        Self::parse(&data, HashAlgorithm::current()?)
+            .map_err(|err| anyhow!("index file corrupt: {err:#}"))
+    }
+
+    // "DIRC", the version and the number of entries, then the entries, optional extensions and the checksum
+    // of everything before it.
+    fn parse(data: &[u8], algorithm: HashAlgorithm) -> anyhow::Result<Self> {
+        let raw_len = algorithm.raw_len();
+        ensure!(data.len() >= 12 + raw_len, "index file too short");
+        let (content, checksum) = data.split_at(data.len() - raw_len);
+        let mut hasher = algorithm.hasher();
+        hasher.update(content);
+        ensure!(
+            hasher.finalize() == GitHexHash::from_raw(checksum),
+            "bad index file sha1 signature"
+        );
+
+        ensure!(&content[..4] == b"DIRC", "bad signature");
+        let version = read_u32(content, 4)?;
+        ensure!((2..=4).contains(&version), "bad index version {version}");
+        let count = read_u32(content, 8)?;
+
+        let mut entries = Vec::with_capacity(count as usize);
+        let mut offset = 12;
+        let mut previous_path = String::new();
+        for _ in 0..count {
+            let start = offset;
+            let stat = Stat {
+                ctime: (read_u32(content, offset)?, read_u32(content, offset + 4)?),
+                mtime: (
+                    read_u32(content, offset + 8)?,
+                    read_u32(content, offset + 12)?,
+                ),
+                dev: read_u32(content, offset + 16)?,
+                ino: read_u32(content, offset + 20)?,
+                uid: read_u32(content, offset + 28)?,
+                gid: read_u32(content, offset + 32)?,
+                size: read_u32(content, offset + 36)?,
+            };
+            let mode = read_u32(content, offset + 24)?;
+            offset += STAT_LEN;
+            let hash = GitHexHash::from_raw(
+                content
+                    .get(offset..offset + raw_len)
+                    .context("truncated entry")?,
+            );
+            offset += raw_len;
+            let flags = read_u16(content, offset)?;
+            offset += 2;
+            let mut extended_flags = 0;
+            if flags & EXTENDED_FLAG != 0 {
+                ensure!(version >= 3, "extended flags in a version {version} index");
+                extended_flags = read_u16(content, offset)?;
+                offset += 2;
+            }
+
+            let path = if version == 4 {
+                // The path is compressed against the previous one: the number of bytes to remove from its end,
+                // then the suffix to append.
+                let (strip, n) = read_varint(&content[offset..])?;
+                offset += n;
+                let kept = previous_path
+                    .len()
+                    .checked_sub(strip as usize)
+                    .context("invalid path compression")?;
+                let suffix = read_cstr(content, offset)?;
+                offset += suffix.len() + 1;
+                format!("{}{suffix}", &previous_path[..kept])
+            } else {
+                let name_len = usize::from(flags & NAME_MASK);
+                let path = read_cstr(content, offset)?;
+                // Longer names don't fit in the flags, the length is then NAME_MASK.
+                ensure!(
+                    name_len == NAME_MASK as usize || name_len == path.len(),
+                    "bad name length for {path}"
+                );
+                offset += path.len() + 1;
+                // Entries are padded with NULs to a multiple of 8 bytes.
+                offset = start + (offset - start).div_ceil(8) * 8;
+                path.to_string()
+            };
+            previous_path.clone_from(&path);
+            entries.push(IndexEntry {
+                stat,
+                mode,
+                hash,
+                stage: ((flags >> 12) & 0b11) as u8,
+                extended_flags,
+                path,
+            });
+        }
+
+        // Each extension is a 4 bytes signature and the length of its data.
+        let mut cache_tree = None;
+        while offset < content.len() {
+            let signature = content
+                .get(offset..offset + 4)
+                .context("truncated extension")?;
+            let len = read_u32(content, offset + 4)? as usize;
+            offset += 8;
+            let data = content
+                .get(offset..offset + len)
+                .context("truncated extension")?;
+            offset += len;
+            match signature {
+                b"TREE" => {
+                    let mut tree_offset = 0;
+                    let (_, tree) = CacheTree::parse(data, &mut tree_offset, raw_len)
+                        .context("invalid TREE extension")?;
+                    cache_tree = Some(tree);
+                }
+                // Extensions starting with an uppercase letter are optional, only an optimization.
+                [b'A'..=b'Z', ..] => {}
+                _ => bail!(
+                    "index uses {} extension, which we do not understand",
+                    String::from_utf8_lossy(signature)
+                ),
+            }
+        }
+        Ok(Self {
+            version,
+            entries,
+            cache_tree,
+        })
+    }
+
+    /// Add or replace the entry for a path, dropping its conflict stages if any.
+    pub(super) fn insert(&mut self, entry: IndexEntry) {
+        self.remove(&entry.path);
+        let position = self
+            .entries
+            .partition_point(|existing| existing.path.as_bytes() < entry.path.as_bytes());
+        self.entries.insert(position, entry);
+    }
+
+    /// Record a conflict: the stages of a path, 1 to 3 for the base, ours and theirs, replace its entry.
+    pub(super) fn insert_conflict(&mut self, stages: Vec<IndexEntry>) {
+        let Some(path) = stages.first().map(|entry| entry.path.clone()) else {
+            return;
+        };
+        self.remove(&path);
+        let position = self
+            .entries
+            .partition_point(|existing| existing.path.as_bytes() < path.as_bytes());
+        self.entries.splice(position..position, stages);
+    }
+
+    /// Remove all the stages of a path, returns whether it was in the index.
+    pub(super) fn remove(&mut self, path: &str) -> bool {
+        if let Some(cache_tree) = &mut self.cache_tree {
+            cache_tree.invalidate(path);
+        }
+        let len = self.entries.len();
+        self.entries.retain(|entry| entry.path != path);
+        self.entries.len() != len
+    }
+
+    pub(super) fn get(&self, path: &str) -> Option<&IndexEntry> {
+        self.entries
+            .iter()
+            .find(|entry| entry.path == path && entry.stage == 0)
+    }
+
+    /// Lock the index of the repository then read it, to change it.
+    ///
+    /// Like git, taking the lock fails if `index.lock` exists: another process is changing the index.
+    pub(super) fn lock() -> anyhow::Result<LockedIndex> {
+        let path = repo::index_file()?;
+        let mut lock = path.clone().into_os_string();
+        lock.push(".lock");
+        let lock = PathBuf::from(lock);
+        // `create_new` makes the lock exclusive: another process updating the index at the same time fails here.
+        let file = match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock)
+        {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => bail!(
+                "Unable to create '{}': File exists.\n\n\
+                Another git process seems to be running in this repository. If it still fails, a git process\n\
+                may have crashed in this repository earlier: remove the file manually to continue.",
+                lock.display()
+            ),
+            Err(err) => return Err(err).context(format!("unable to create {lock:?}")),
+        };
+        // Created first so a failed read doesn't leave the lock behind.
+        let mut locked = LockedIndex {
+            index: Self::empty(),
+            path,
+            lock,
+            file,
+            committed: false,
+        };
+        locked.index = Self::read()?;
+        Ok(locked)
+    }
+
+    // Only the `TREE` extension is kept, git rebuilds the others when needed.
+    fn serialize(&self) -> anyhow::Result<Vec<u8>> {
+        let algorithm = HashAlgorithm::current()?;
+        // Extended flags require version 3.
+        let version = if self.version == 2 && self.entries.iter().any(|e| e.extended_flags != 0) {
+            3
+        } else {
+            self.version
+        };
+
+        let mut data = Vec::new();
+        data.extend_from_slice(b"DIRC");
+        data.extend_from_slice(&version.to_be_bytes());
+        data.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+        let mut previous_path = "";
+        for entry in &self.entries {
+            let start = data.len();
+            let stat = &entry.stat;
+            for value in [
+                stat.ctime.0,
+                stat.ctime.1,
+                stat.mtime.0,
+                stat.mtime.1,
+                stat.dev,
+                stat.ino,
+                entry.mode,
+                stat.uid,
+                stat.gid,
+                stat.size,
+            ] {
+                data.extend_from_slice(&value.to_be_bytes());
+            }
+            data.extend_from_slice(&entry.hash.to_raw());
+            let mut flags =
+                (u16::from(entry.stage) << 12) | entry.path.len().min(NAME_MASK as usize) as u16;
+            if entry.extended_flags != 0 {
+                flags |= EXTENDED_FLAG;
+            }
+            data.extend_from_slice(&flags.to_be_bytes());
+            if entry.extended_flags != 0 {
+                data.extend_from_slice(&entry.extended_flags.to_be_bytes());
+            }
+            if version == 4 {
+                let common = previous_path
+                    .bytes()
+                    .zip(entry.path.bytes())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                write_varint(&mut data, (previous_path.len() - common) as u64);
+                data.extend_from_slice(&entry.path.as_bytes()[common..]);
+                data.push(0);
+            } else {
+                data.extend_from_slice(entry.path.as_bytes());
+                // At least one NUL, then padding to a multiple of 8 bytes.
+                let padded_len = (data.len() - start + 1).div_ceil(8) * 8;
+                data.resize(start + padded_len, 0);
+            }
+            previous_path = &entry.path;
+        }
+        if let Some(cache_tree) = &self.cache_tree {
+            let mut extension = Vec::new();
+            cache_tree.write("", &mut extension);
+            data.extend_from_slice(b"TREE");
+            data.extend_from_slice(&(extension.len() as u32).to_be_bytes());
+            data.extend_from_slice(&extension);
+        }
+        let mut hasher = algorithm.hasher();
+        hasher.update(&data);
+        data.extend_from_slice(&hasher.finalize().to_raw());
+        Ok(data)
+    }
+}
+
+impl LockedIndex {
+    /// Replace the index of the repository with the changes and release the lock.
+    ///
+    /// Written in `index.lock` then renamed, so readers never see a partially written index.
+    pub(super) fn commit(&mut self) -> anyhow::Result<()> {
+        let data = self.index.serialize()?;
+        self.file
+            .write_all(&data)
+            .context(format!("writing {:?}", self.lock))?;
+        fs::rename(&self.lock, &self.path).context("updating the index")?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Deref for LockedIndex {
+    type Target = Index;
+
+    fn deref(&self) -> &Index {
+        &self.index
+    }
+}
+
+impl DerefMut for LockedIndex {
+    fn deref_mut(&mut self) -> &mut Index {
+        &mut self.index
+    }
+}
+
+impl Drop for LockedIndex {
+    fn drop(&mut self) {
+        // Once renamed by `commit`, the lock is the new index.
+        if !self.committed {
+            let _ = fs::remove_file(&self.lock);
+        }
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> anyhow::Result<u32> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .context("unexpected end of index")?;
+    Ok(u32::from_be_bytes(bytes.try_into()?))
+}
+
+fn read_u16(data: &[u8], offset: usize) -> anyhow::Result<u16> {
+    let bytes = data
+        .get(offset..offset + 2)
+        .context("unexpected end of index")?;
+    Ok(u16::from_be_bytes(bytes.try_into()?))
+}
+
+fn read_cstr(data: &[u8], offset: usize) -> anyhow::Result<&str> {
+    let rest = data.get(offset..).context("unexpected end of index")?;
+    let len = rest
+        .iter()
+        .position(|&byte| byte == 0)
+        .context("unterminated path")?;
+    Ok(std::str::from_utf8(&rest[..len])?)
+}
+
+fn write_varint(data: &mut Vec<u8>, mut value: u64) {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    value >>= 7;
+    while value != 0 {
+        value -= 1;
+        bytes.push(0x80 | (value & 0x7f) as u8);
+        value >>= 7;
+    }
+    data.extend(bytes.iter().rev());
+}
+
+// The offset encoding of git: 7 bits per byte, adding one to each continuation so there is a single encoding
+// for each value.
+fn read_varint(data: &[u8]) -> anyhow::Result<(u64, usize)> {
+    let mut value = 0u64;
+    for (i, &byte) in data.iter().enumerate() {
+        if i > 0 {
+            value += 1;
+        }
+        value = (value << 7) | u64::from(byte & 0x7f);
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+    bail!("unterminated variable length integer")
+}
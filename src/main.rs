@@ -1,73 +1,466 @@
-use anyhow::{bail, ensure, Context};
-use clap::{Parser, Subcommand};
+use anyhow::{anyhow, bail, ensure, Context};
+use batch::{BatchFormat, ObjectInfo};
+use cache::ObjectBody;
+use clap::{ArgGroup, Parser, Subcommand};
+use commit::Commit;
 use core::fmt;
-use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use date::GitDate;
+use flate2::{bufread::ZlibDecoder, write::ZlibEncoder, Compression};
+use hash::{HashAlgorithm, Hasher};
 use hex_hash::GitHexHash;
-use jiff::Zoned;
-use sha1::{Digest, Sha1};
+use ident::{Identity, Role};
+use index::{CacheTree, Index, IndexEntry, LockedIndex, Stat};
+use mmap::ObjectFile;
+use mode::Mode;
+use progress::Progress;
+use protocol::FetchRequest;
+use quote::quote_path;
+use rev::Followed;
 use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
     env,
     ffi::CStr,
     fs,
-    io::{self, BufRead, BufReader, Read, Write},
+    io::{self, BufRead, BufReader, IsTerminal, Read, Seek, SeekFrom, Write},
+    os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    thread,
 };
+use tag::Tag;
+use tree::{TreeEntries, TreeEntry};
+use verbosity::Verbosity;
 
+mod apply;
+mod attributes;
+mod batch;
+mod cache;
+mod commit;
+mod config;
+mod date;
+mod diff;
+mod gpg;
+mod hash;
 mod hex_hash;
+mod http;
+mod ident;
+mod ignore;
+mod index;
+mod merge;
+mod mmap;
+mod mode;
+mod nfc;
+mod pack;
+mod progress;
+mod protocol;
+mod quote;
+mod refs;
+mod repo;
+mod rev;
+mod sha256;
+mod shallow;
+mod status;
+mod tag;
+mod tree;
+mod verbosity;
 
 fn main() {
     if let Err(err) = try_main() {
-        // We try to format the errors as git does.
+        // We try to format the errors as git does, with the same exit code.
         eprintln!("fatal: {err}");
+        process::exit(128);
     }
 }
 
 fn try_main() -> anyhow::Result<()> {
     let args = Args::parse();
+    if let Some(git_dir) = args.git_dir {
+        repo::set_git_dir(git_dir);
+    }
+    let _ = MAX_OBJECT_SIZE.set(args.max_object_size);
+    verbosity::set(if args.quiet {
+        Verbosity::Quiet
+    } else if args.verbose {
+        Verbosity::Verbose
+    } else {
+        Verbosity::Normal
+    });
 
     match args.command {
-        Command::Init => {
-            fs::create_dir(".git").unwrap();
-            fs::create_dir(".git/objects").unwrap();
-            fs::create_dir(".git/refs").unwrap();
-            fs::write(".git/HEAD", "ref: refs/heads/main\n").unwrap();
-            println!("Initialized git directory");
-        }
-        Command::CatFile { hash, pretty_print } => {
-            ensure!(
-                pretty_print,
-                "We only handle the pretty print option -p for now"
-            );
-
-            let object = ObjectReader::from_sha1(hash)?;
-            match object {
-                ObjectReader::Blob(mut reader) => {
-                    io::copy(&mut reader, &mut io::stdout())
-                        .context("piping object content to stdout")?;
+        Command::IndexPack { pack } => {
+            index_pack(&pack)?;
+        }
+        Command::Init { object_format } => {
+            let git_dir = repo::explicit_git_dir().unwrap_or_else(|| PathBuf::from(".git"));
+            init(&git_dir, object_format)?;
+            verbosity::info("Initialized git directory");
+        }
+        Command::Clone {
+            url,
+            directory,
+            depth,
+        } => {
+            clone(&url, directory, depth)?;
+        }
+        Command::CatFile {
+            object,
+            pretty_print: _,
+            show_type,
+            show_size,
+            allow_unknown_type,
+            batch,
+            batch_check: _,
+            format,
+            filter_cmd,
+            filters,
+            path,
+            follow,
+            follow_symlinks,
+            abbrev,
+        } => {
+            // Without an object, we are in one of the batch modes which read them from stdin.
+            let Some(mut object) = object else {
+                let format = BatchFormat::parse(format.as_deref().unwrap_or(BatchFormat::DEFAULT))?;
+                return cat_file_batch(batch, &format, follow_symlinks, abbrev);
+            };
+            if follow {
+                object = show_tags(&object, &mut io::stdout())?.to_string();
+            }
+            // The filters of `<rev>:<path>` are the ones of its path.
+            let smudge_path = match path {
+                Some(path) => Some(path),
+                None if filters => {
+                    let (_, path) = object.split_once(':').context(format!(
+                        "<object>:<path> required, only <object> '{object}' given"
+                    ))?;
+                    Some(path.to_string())
                 }
-                ObjectReader::Tree(_) => bail!("we don't know how to print tree"),
+                None => None,
+            };
+            cat_file(
+                &object,
+                show_type,
+                show_size,
+                allow_unknown_type,
+                filter_cmd.as_deref(),
+                smudge_path.as_deref(),
+                &mut io::stdout(),
+            )?;
+        }
+        Command::Apply { patch } => {
+            let patch = match patch {
+                Some(path) => fs::read_to_string(&path).context(format!("reading {path:?}"))?,
+                None => io::read_to_string(io::stdin())?,
+            };
+            apply::apply(&patch)?;
+        }
+        Command::Fetch { remote, depth } => {
+            if !fetch(&remote, depth)? {
+                process::exit(1);
             }
         }
-        Command::HashObject { file, write } => {
-            let sha1 = hash_object(&file, write)?;
-            let sha1 = base16ct::lower::encode_string(&sha1);
+        Command::HashObject {
+            file,
+            write,
+            dry_run,
+            stdin: _,
+            stdin_paths,
+            with_header,
+            options,
+        } => {
+            let _ = DRY_RUN.set(dry_run);
+            if stdin_paths {
+                return hash_stdin_paths(write, &options);
+            }
+            let sha1 = match (file, with_header) {
+                (Some(file), true) => {
+                    let opened_file = fs::File::open(&file).context(format!("open {file:?}"))?;
+                    hash_serialized(BufReader::new(opened_file), write)?
+                }
+                (None, true) => hash_serialized(io::stdin().lock(), write)?,
+                (Some(file), false) => hash_object(&file, write, &options)?,
+                (None, false) => hash_stdin(write, &options)?,
+            };
             println!("{sha1}");
         }
-        Command::LsTree { hash, name_only } => {
-            print_tree(hash, name_only)?;
+        Command::Status {
+            porcelain,
+            json,
+            nul_terminated,
+        } => {
+            let format = if json {
+                status::Format::Json
+            } else if porcelain || nul_terminated {
+                status::Format::Porcelain { nul_terminated }
+            } else {
+                status::Format::Long
+            };
+            status::status(format)?;
+        }
+        Command::LsFiles {
+            cached,
+            others,
+            exclude_standard,
+            stage,
+        } => {
+            ls_files(cached || stage || !others, others, exclude_standard, stage)?;
+        }
+        Command::LsRemote { repository } => {
+            let url = remote_url(&repository)?;
+            let mut stdout = io::stdout().lock();
+            for (name, hash) in protocol::discover_refs(&url)?.refs {
+                writeln!(stdout, "{hash}\t{name}")?;
+            }
+        }
+        Command::Log { revs, abbrev } => {
+            log(&revs, &abbrev)?;
+        }
+        Command::Merge { commit } => {
+            merge::merge(&commit)?;
+        }
+        Command::MergeBase { one, two, all } => {
+            let one = peel_to_commit(rev::resolve(&one)?)?;
+            let two = peel_to_commit(rev::resolve(&two)?)?;
+            let bases = merge::merge_bases(&one, &two)?;
+            // Like git, no common ancestor is only told by the exit code.
+            if bases.is_empty() {
+                process::exit(1);
+            }
+            let shown = if all { bases.len() } else { 1 };
+            for base in &bases[..shown] {
+                println!("{base}");
+            }
+        }
+        Command::RevParse { verify, revs } => {
+            // Only the revisions that don't resolve are reported as such, not a broken repository.
+            repo::git_dir()?;
+            if verify {
+                let hash = match revs.as_slice() {
+                    [rev] => rev::resolve(rev).ok(),
+                    _ => None,
+                };
+                match hash {
+                    Some(hash) => println!("{hash}"),
+                    None if verbosity::get() == Verbosity::Quiet => process::exit(1),
+                    None => bail!("Needed a single revision"),
+                }
+            } else {
+                let mut stdout = io::stdout().lock();
+                for rev in revs {
+                    let hash = rev::resolve(&rev).map_err(|_| {
+                        anyhow!("ambiguous argument '{rev}': unknown revision or path not in the working tree.")
+                    })?;
+                    writeln!(stdout, "{hash}")?;
+                }
+            }
+        }
+        Command::RevList { commits, abbrev } => {
+            let mut stdout = io::stdout().lock();
+            for (hash, _) in commit::walk(resolve_commits(&commits)?)? {
+                writeln!(stdout, "{}", abbrev.commit(&hash)?)?;
+            }
+        }
+        Command::LsTree { hash, options } => {
+            print_tree(hash, options, &mut io::stdout().lock())?;
         }
-        Command::WriteTree => {
-            let working_dir = env::current_dir()?;
-            let sha1 = write_tree(&working_dir)?;
-            let sha1 = base16ct::lower::encode_string(&sha1);
+        Command::WriteTree {
+            progress,
+            no_progress,
+            dry_run,
+            prefix,
+        } => {
+            let _ = DRY_RUN.set(dry_run);
+            let working_dir = match &prefix {
+                Some(prefix) => {
+                    let dir = prefix.trim_end_matches('/');
+                    // Like git, the prefix is a path of the tree as is, `.` or `..` aren't resolved.
+                    let valid = dir.is_empty()
+                        || dir
+                            .split('/')
+                            .all(|component| !matches!(component, "" | "." | ".." | ".git"));
+                    let full_path = repo::work_tree()?.join(dir);
+                    ensure!(
+                        valid && full_path.is_dir(),
+                        "git-write-tree: prefix {prefix} not found"
+                    );
+                    full_path
+                }
+                None => env::current_dir()?,
+            };
+            // Like git, only report progress to a human watching the terminal unless asked otherwise.
+            let show_progress = (progress || io::stderr().is_terminal())
+                && !no_progress
+                && verbosity::get() > Verbosity::Quiet;
+            let mut progress = Progress::new("Hashing files", show_progress);
+            let sha1 = write_tree(&working_dir, &mut progress)?;
+            progress.finish();
+            // An empty directory has no files git would track.
+            if let Some(prefix) = prefix.filter(|prefix| !prefix.is_empty()) {
+                ensure!(
+                    sha1 != HashAlgorithm::current()?.empty_tree(),
+                    "git-write-tree: prefix {prefix} not found"
+                );
+            }
             println!("{sha1}");
         }
         Command::CommitTree {
             tree_hash,
             parent_hash,
             message,
+            gpg_sign,
+        } => {
+            let hash = commit_tree(tree_hash, parent_hash, message, gpg_sign.as_deref())?;
+            println!("{hash}");
+        }
+        Command::CheckoutIndex {
+            all,
+            force,
+            prefix,
+            paths,
+        } => {
+            checkout_index(all, force, prefix.as_deref(), &paths)?;
+        }
+        Command::Commit { message, gpg_sign } => {
+            commit(message, gpg_sign.as_deref())?;
+        }
+        Command::PackObjects {
+            stdout,
+            window,
+            depth,
+            base_name,
+        } => {
+            pack_objects(stdout, window, depth, base_name.as_deref())?;
+        }
+        Command::UnpackObjects => {
+            pack::receive(&mut io::stdin().lock(), usize::MAX)?;
+        }
+        Command::NameRev { revs } => {
+            name_rev(&revs)?;
+        }
+        Command::Prune { dry_run } => {
+            prune(dry_run)?;
+        }
+        Command::Reflog { name } => {
+            show_reflog(&name)?;
+        }
+        Command::Reset {
+            soft,
+            mixed: _,
+            hard,
+            force,
+            commit,
+        } => {
+            let mode = if soft {
+                ResetMode::Soft
+            } else if hard {
+                ResetMode::Hard
+            } else {
+                ResetMode::Mixed
+            };
+            reset(&commit, mode, force)?;
+        }
+        Command::Restore {
+            source,
+            staged,
+            worktree,
+            paths,
+        } => {
+            // The working tree is restored by default, unless only the index is asked for.
+            let worktree = worktree || !staged;
+            restore(&paths, source.as_deref(), staged, worktree)?;
+        }
+        Command::Rm {
+            cached,
+            force,
+            paths,
+        } => {
+            rm(&paths, cached, force)?;
+        }
+        Command::SymbolicRef {
+            name,
+            target,
+            short,
+        } => {
+            symbolic_ref(&name, target.as_deref(), short)?;
+        }
+        Command::VerifyCommit { commits } => {
+            for commit in commits {
+                verify_commit(&commit)?;
+            }
+        }
+        Command::VerifyTag { tags } => {
+            for tag in tags {
+                verify_tag(&tag)?;
+            }
+        }
+        Command::UpdateIndex {
+            add,
+            remove,
+            cacheinfo,
+            refresh,
+            paths,
+        } => {
+            update_index(add, remove, cacheinfo, refresh, &paths)?;
+        }
+        Command::ShowIndex { index } => {
+            show_index(index.as_deref())?;
+        }
+        Command::ShowRef {
+            heads,
+            tags,
+            dereference,
+        } => {
+            show_ref(heads, tags, dereference)?;
+        }
+        Command::Branch {
+            rename,
+            force_rename,
+            names,
+        } => {
+            if rename || force_rename {
+                let (old, new) = match names.as_slice() {
+                    [new] => (None, new),
+                    [old, new] => (Some(old.as_str()), new),
+                    _ => bail!("branch name required"),
+                };
+                rename_branch(old, new, force_rename)?;
+            } else {
+                list_branches()?;
+            }
+        }
+        Command::Tag {
+            list: _,
+            delete,
+            names,
         } => {
-            commit_tree(tree_hash, parent_hash, message)?;
+            if delete {
+                delete_tags(&names)?;
+            } else {
+                list_tags(&names)?;
+            }
+        }
+        Command::Diff {
+            cached,
+            exit_code,
+            quiet,
+            paths,
+        } => {
+            let differs = diff(cached, quiet, &paths)?;
+            if differs && (exit_code || quiet) {
+                process::exit(1);
+            }
+        }
+        Command::Describe { commit } => {
+            let commit = match commit {
+                Some(commit) => commit,
+                None => refs::read_ref("HEAD")?.context("HEAD does not point to a commit")?,
+            };
+            println!("{}", describe(commit)?);
         }
     };
     Ok(())
@@ -77,345 +470,3758 @@ fn try_main() -> anyhow::Result<()> {
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// Path to the repository (the `.git` directory), instead of discovering it from the current directory.
+    #[arg(long)]
+    git_dir: Option<PathBuf>,
+    /// Refuse to read objects whose header announces more bytes than this.
+    #[arg(long, value_name = "bytes", default_value_t = DEFAULT_MAX_OBJECT_SIZE)]
+    max_object_size: u64,
+    /// Only report errors, not what the command did.
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Also report details, like the objects written.
+    #[arg(short, long, global = true)]
+    verbose: bool,
     #[command(subcommand)]
     command: Command,
 }
 
 #[derive(Subcommand, Debug)]
 enum Command {
+    /// Apply a unified diff to the files of the working tree.
+    Apply {
+        /// The patch to apply, read from stdin when missing.
+        patch: Option<PathBuf>,
+    },
+    /// List branches, or rename one.
+    #[command(group(ArgGroup::new("rename_mode").args(["rename", "force_rename"])))]
+    Branch {
+        /// Rename a branch, the current one when only the new name is given.
+        #[arg(short = 'm', long = "move")]
+        rename: bool,
+        /// Rename a branch even if the new name already exists.
+        #[arg(short = 'M')]
+        force_rename: bool,
+        /// `[<old>] <new>` when renaming.
+        #[arg(requires = "rename_mode", num_args = 0..=2)]
+        names: Vec<String>,
+    },
+    #[command(group(ArgGroup::new("mode").required(true)))]
+    #[command(group(ArgGroup::new("batch_mode").args(["batch", "batch_check"])))]
     CatFile {
-        /// SHA-1 hash of the object in hexadecimal representation.
-        #[arg(value_parser = parse_hash)]
-        hash: GitHexHash,
-        #[arg(short)]
+        /// The object to show: a hash, a ref such as `HEAD` or `main`, or `<rev>:<path>`.
+        #[arg(required_unless_present = "batch_mode", conflicts_with = "batch_mode")]
+        object: Option<String>,
+        #[arg(short, group = "mode")]
         pretty_print: bool,
+        /// Show the object type.
+        #[arg(short = 't', group = "mode")]
+        show_type: bool,
+        /// Show the object size.
+        #[arg(short = 's', group = "mode")]
+        show_size: bool,
+        /// Allow -t and -s to work on objects of unknown type, to debug corrupt objects.
+        #[arg(long, conflicts_with = "pretty_print")]
+        allow_unknown_type: bool,
+        /// Print information and content of the objects named on stdin, one per line.
+        #[arg(long, group = "mode")]
+        batch: bool,
+        /// Like --batch but without the content.
+        #[arg(long, group = "mode")]
+        batch_check: bool,
+        /// Line printed for each object in batch modes, with `%(objectname)`, `%(objecttype)`, `%(objectsize)`
+        /// and `%(objectsize:disk)` placeholders.
+        #[arg(long, value_name = "format", requires = "batch_mode")]
+        format: Option<String>,
+        /// In batch modes, follow the symbolic links of `<rev>:<path>` inside the tree. A link leading out of it
+        /// is reported as `symlink <size>` followed by its target.
+        #[arg(long, requires = "batch_mode")]
+        follow_symlinks: bool,
+        /// In batch modes, show the shortest unique object name prefix of at least `n` hexdigits.
+        #[arg(
+            long,
+            value_name = "n",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "7",
+            requires = "batch_mode"
+        )]
+        abbrev: Option<usize>,
+        /// With -p, show a blob through a shell command reading it on stdin, such as `pdftotext - -`.
+        #[arg(long, value_name = "command", conflicts_with_all = ["show_type", "show_size", "batch_mode"])]
+        filter_cmd: Option<String>,
+        /// Show a blob as it would be checked out, through the line ending conversion and smudge filter of its
+        /// path. The object must be `<rev>:<path>`, unless --path is given.
+        #[arg(long, group = "mode", conflicts_with = "filter_cmd")]
+        filters: bool,
+        /// With --filters, the path whose filters apply, from the top of the working tree.
+        #[arg(long, requires = "filters")]
+        path: Option<String>,
+        /// With -p on an annotated tag, summarize the tag then show the object it points to, like `git show`.
+        #[arg(long, conflicts_with_all = ["show_type", "show_size", "batch_mode", "filters"])]
+        follow: bool,
+    },
+    /// Copy files from the index to the working tree.
+    CheckoutIndex {
+        /// Check out all the files of the index.
+        #[arg(short, long, conflicts_with = "paths")]
+        all: bool,
+        /// Overwrite existing files.
+        #[arg(short, long)]
+        force: bool,
+        /// Prepended to the path of the written files, `<dir>/` to write them in another directory.
+        #[arg(long, value_name = "string")]
+        prefix: Option<String>,
+        paths: Vec<PathBuf>,
+    },
+    /// Copy a remote repository in a new directory and check out its default branch.
+    Clone {
+        url: String,
+        /// Where to clone, named after the repository by default.
+        directory: Option<PathBuf>,
+        /// Only fetch the last `depth` commits of the history.
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        depth: Option<u32>,
+    },
+    /// Record the content of the index as a new commit on the current branch.
+    Commit {
+        #[arg(short, long)]
+        message: String,
+        /// Sign the commit with GPG, using the given key or `user.signingKey`, the committer identity otherwise.
+        #[arg(short = 'S', long, num_args = 0..=1, require_equals = true, default_missing_value = "")]
+        gpg_sign: Option<String>,
     },
     CommitTree {
         #[arg(value_parser = parse_hash)]
         tree_hash: GitHexHash,
+        /// Parent commits, none for a root commit.
         #[arg(short, long, value_parser = parse_hash)]
-        parent_hash: GitHexHash,
+        parent_hash: Vec<GitHexHash>,
         #[arg(short, long)]
         message: String,
+        /// Sign the commit with GPG, using the given key or `user.signingKey`, the committer identity otherwise.
+        #[arg(short = 'S', long, num_args = 0..=1, require_equals = true, default_missing_value = "")]
+        gpg_sign: Option<String>,
+    },
+    /// Name a commit after the closest annotated tag it descends from.
+    Describe {
+        /// The commit to describe, HEAD by default.
+        #[arg(value_parser = parse_hash)]
+        commit: Option<GitHexHash>,
+    },
+    /// Show the changes of the working tree not staged yet, or the staged changes.
+    Diff {
+        /// Show the changes staged in the index against HEAD instead, what the next commit would record.
+        #[arg(long, visible_alias = "staged")]
+        cached: bool,
+        /// Exit with status 1 when there are changes, 0 otherwise.
+        #[arg(long)]
+        exit_code: bool,
+        /// Show nothing, only exit with status 1 when there are changes like with --exit-code.
+        #[arg(long)]
+        quiet: bool,
+        /// Only show the changes of these files or directories.
+        paths: Vec<PathBuf>,
+    },
+    /// Download the branches of a remote and update its remote-tracking refs.
+    Fetch {
+        /// Name of the remote, as configured with `remote.<name>.url`.
+        #[arg(default_value = "origin")]
+        remote: String,
+        /// Deepen or shorten the history to the last `depth` commits of each fetched branch.
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        depth: Option<u32>,
     },
     /// Create blob object from file.
     HashObject {
-        file: PathBuf,
+        #[arg(required_unless_present_any = ["stdin", "stdin_paths"], conflicts_with_all = ["stdin", "stdin_paths"])]
+        file: Option<PathBuf>,
         #[arg(short)]
         write: bool,
+        /// With `-w`, go through writing the objects but leave them out of the object store.
+        #[arg(long)]
+        dry_run: bool,
+        /// Read the content from stdin instead of a file.
+        #[arg(long, conflicts_with = "stdin_paths")]
+        stdin: bool,
+        /// Hash the files whose paths are read from stdin, one per line.
+        #[arg(long)]
+        stdin_paths: bool,
+        /// The input is a whole serialized object, `<type> <size>\0` then the content, hashed without adding a
+        /// header. Checks that an object read back names the same object.
+        #[arg(long, conflicts_with_all = ["stdin_paths", "object_type", "auto", "literally", "path"])]
+        with_header: bool,
+        #[command(flatten)]
+        options: HashObjectOptions,
+    },
+    /// Write the `.idx` of a pack, naming all its objects.
+    IndexPack { pack: PathBuf },
+    Init {
+        /// Hash function naming the objects: `sha1` or `sha256`.
+        #[arg(long, value_parser = HashAlgorithm::parse, default_value = "sha1")]
+        object_format: HashAlgorithm,
+    },
+    /// Show the commit history.
+    Log {
+        /// Where to start, HEAD by default.
+        #[arg(default_value = "HEAD")]
+        revs: Vec<String>,
+        #[command(flatten)]
+        abbrev: AbbrevOptions,
+    },
+    /// List the files of the index, or the untracked files of the working tree.
+    LsFiles {
+        /// Show the files of the index, the default.
+        #[arg(short, long)]
+        cached: bool,
+        /// Show the untracked files, relative to the top of the working tree.
+        #[arg(short, long)]
+        others: bool,
+        /// Leave out the files ignored by `.gitignore`, `$GIT_DIR/info/exclude` and the global excludes file.
+        #[arg(long)]
+        exclude_standard: bool,
+        /// Show the mode, hash and stage of the files of the index.
+        #[arg(short, long)]
+        stage: bool,
+    },
+    /// List the references of a remote repository.
+    LsRemote {
+        /// URL of the repository, or name of a configured remote.
+        repository: String,
     },
-    Init,
     LsTree {
         #[arg(value_parser = parse_hash)]
         hash: GitHexHash,
+        #[command(flatten)]
+        options: LsTreeOptions,
+    },
+    /// Join the history of a commit into the current branch: the branch is fast-forwarded when it's behind,
+    /// otherwise the changes of both sides are merged in a commit having both as parents.
+    Merge { commit: String },
+    /// The best common ancestor of two commits, what merging them starts from.
+    MergeBase {
+        one: String,
+        two: String,
+        /// All the best common ancestors, there are several after criss-cross merges.
+        #[arg(long)]
+        all: bool,
+    },
+    /// Name commits after the closest ref they can be reached from, like `tags/v1.0~3`.
+    NameRev {
+        #[arg(required = true)]
+        revs: Vec<String>,
+    },
+    /// Write the objects named on stdin, one per line, in a pack.
+    PackObjects {
+        /// Write the pack to stdout instead of `<base-name>-<hash>.pack` and its `.idx`.
+        #[arg(long, conflicts_with = "base_name")]
+        stdout: bool,
+        /// Try to store each object as a delta against this many similar objects, 0 writes them whole.
+        #[arg(long, default_value_t = 0)]
+        window: usize,
+        /// The longest chain of deltas to reach an object.
+        #[arg(long, default_value_t = 50)]
+        depth: usize,
+        #[arg(required_unless_present = "stdout")]
+        base_name: Option<PathBuf>,
+    },
+    /// Delete the loose objects that can't be reached from any ref, HEAD or the index.
+    Prune {
+        /// List the objects that would be deleted, with their type, without deleting them.
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+    /// Store the objects of the pack read from stdin as loose objects.
+    UnpackObjects,
+    /// List the previous values of a ref, most recent first.
+    Reflog {
+        #[arg(default_value = "HEAD")]
+        name: String,
+    },
+    /// Print the hash of the objects named by revisions.
+    RevParse {
+        /// Check that there is exactly one revision and that it names an object. With --quiet, only the exit
+        /// code tells when it doesn't.
+        #[arg(long)]
+        verify: bool,
+        revs: Vec<String>,
+    },
+    /// List the commits reachable from the given ones, the most recent first.
+    RevList {
+        #[arg(required = true)]
+        commits: Vec<String>,
+        #[command(flatten)]
+        abbrev: AbbrevOptions,
+    },
+    /// Move the current branch to a commit, also resetting the index and the working tree depending on the mode.
+    #[command(group(ArgGroup::new("mode").args(["soft", "mixed", "hard"])))]
+    Reset {
+        /// Only move the branch, keeping the index and the working tree.
+        #[arg(long)]
+        soft: bool,
+        /// Also reset the index to the commit, keeping the working tree. The default.
+        #[arg(long)]
+        mixed: bool,
+        /// Also reset the working tree, discarding all the changes to the tracked files.
+        #[arg(long)]
+        hard: bool,
+        /// With --hard, discard even the changes that aren't committed.
+        #[arg(short, long, requires = "hard")]
+        force: bool,
+        #[arg(default_value = "HEAD")]
+        commit: String,
+    },
+    /// Restore files of the working tree from the index, or the index from HEAD, without moving HEAD.
+    Restore {
+        /// Restore from the tree of this commit instead.
+        #[arg(short, long)]
+        source: Option<String>,
+        /// Restore the index.
+        #[arg(short = 'S', long)]
+        staged: bool,
+        /// Restore the working tree, the default without --staged.
+        #[arg(short = 'W', long)]
+        worktree: bool,
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Remove files from the working tree and from the index.
+    Rm {
+        /// Only remove from the index, keeping the working tree files.
+        #[arg(long)]
+        cached: bool,
+        /// Remove even if the files have changes that would be lost.
+        #[arg(short, long)]
+        force: bool,
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// List the objects of a pack index as `<offset> <hash> (<crc32>)`, read from stdin unless a file is given.
+    ShowIndex { index: Option<PathBuf> },
+    /// List references with the object they point to.
+    ShowRef {
+        /// Only show branches.
+        #[arg(long)]
+        heads: bool,
+        /// Only show tags.
+        #[arg(long)]
+        tags: bool,
+        /// Also show the object annotated tags point to, as `<ref>^{}`.
+        #[arg(short, long)]
+        dereference: bool,
+    },
+    /// Show the changes staged, the changes not staged yet and the untracked files.
+    Status {
+        /// One `XY <path>` line per file for scripts, git's stable format: the change staged in the index then
+        /// the one in the working tree.
+        #[arg(long)]
+        porcelain: bool,
+        /// A JSON array of objects with the `path`, `index_status` and `worktree_status` of the files, plus the
+        /// `orig_path` of a renamed one.
+        #[arg(long, conflicts_with_all = ["porcelain", "nul_terminated"])]
+        json: bool,
+        /// End the lines with NUL without quoting the paths, a renamed file followed by its original path.
+        /// Implies --porcelain.
+        #[arg(short = 'z')]
+        nul_terminated: bool,
+    },
+    /// List tags, or delete them.
+    Tag {
+        /// List the tags, only those matching one of the patterns if any. The default without `--delete`.
+        #[arg(short, long, conflicts_with = "delete")]
+        list: bool,
+        /// Delete the given tags.
+        #[arg(short, long)]
+        delete: bool,
+        /// Shell glob patterns with `--list`, tag names with `--delete`.
+        names: Vec<String>,
+    },
+    /// Read which ref a symbolic ref such as HEAD points to, or change it.
+    SymbolicRef {
+        name: String,
+        /// The full name of the ref to point to, like `refs/heads/main`.
+        target: Option<String>,
+        /// Show `main` rather than `refs/heads/main`.
+        #[arg(long, conflicts_with = "target")]
+        short: bool,
+    },
+    /// Change the index entries directly.
+    UpdateIndex {
+        /// Add the files that aren't in the index yet.
         #[arg(long)]
-        name_only: bool,
+        add: bool,
+        /// Remove the files that no longer exist in the working tree.
+        #[arg(long)]
+        remove: bool,
+        /// Insert an entry without touching the working tree.
+        #[arg(long, value_name = "mode>,<object>,<path", value_parser = parse_cacheinfo)]
+        cacheinfo: Vec<CacheInfo>,
+        /// Update the stat data of the entries whose content didn't change.
+        #[arg(long)]
+        refresh: bool,
+        paths: Vec<PathBuf>,
+    },
+    /// Check the GPG signature of commits.
+    VerifyCommit {
+        #[arg(required = true)]
+        commits: Vec<String>,
+    },
+    /// Check the GPG signature of annotated tags.
+    VerifyTag {
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+    WriteTree {
+        /// Report the number of hashed files on stderr, the default when it is a terminal.
+        #[arg(long, overrides_with = "no_progress")]
+        progress: bool,
+        #[arg(long, overrides_with = "progress")]
+        no_progress: bool,
+        /// Only print the hash of the tree, without storing any object.
+        #[arg(long)]
+        dry_run: bool,
+        /// Write the tree of this directory, relative to the top of the working tree, like `sub/dir/`.
+        #[arg(long, value_name = "prefix")]
+        prefix: Option<String>,
     },
-    WriteTree,
 }
 
-fn parse_hash(input: &str) -> anyhow::Result<GitHexHash> {
-    GitHexHash::try_from(input)
+#[derive(clap::Args, Debug, Default)]
+struct LsTreeOptions {
+    #[arg(long)]
+    name_only: bool,
+    /// Only show the object names, one per line.
+    #[arg(long, conflicts_with = "name_only")]
+    object_only: bool,
+    /// End the entries with NUL instead of a new line, and don't quote the paths.
+    #[arg(short = 'z')]
+    nul_terminated: bool,
+    /// Recurse into sub-trees.
+    #[arg(short)]
+    recursive: bool,
+    /// Show the shortest unique object name prefix of at least `n` hexdigits.
+    #[arg(long, value_name = "n", num_args = 0..=1, require_equals = true, default_missing_value = "7")]
+    abbrev: Option<usize>,
+    /// Show the paths from the top of the working tree, not relative to the current directory.
+    #[arg(long)]
+    full_name: bool,
+    /// Show the whole tree whatever the current directory, paths being from the top. Implies --full-name.
+    #[arg(long)]
+    full_tree: bool,
+    /// Only show the entries matching these paths, relative to the current directory.
+    paths: Vec<String>,
 }
 
-struct ObjectHasher<W> {
-    hash: Sha1,
-    writer: W,
+/// How `log` and `rev-list` show the commit hashes.
+#[derive(clap::Args, Debug)]
+struct AbbrevOptions {
+    /// Show the shortest unique prefix of the commit hashes, of at least 7 hexdigits.
+    #[arg(long)]
+    abbrev_commit: bool,
+    /// Always show full hashes, even in the `Merge:` lines of `log`. Wins over --abbrev-commit.
+    #[arg(long)]
+    no_abbrev: bool,
 }
 
-impl<W> Write for ObjectHasher<W>
-where
-    W: Write,
-{
-    // We only have to iterate through the value once to compute the hash
-    // and compress the data.
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let n = self.writer.write(buf)?;
-        self.hash.update(&buf[..n]);
-        Ok(n)
+impl AbbrevOptions {
+    /// The name of a listed commit, full unless asked otherwise.
+    fn commit<'a>(&self, hash: &'a GitHexHash) -> anyhow::Result<&'a str> {
+        self.abbreviate(hash, self.abbrev_commit)
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.writer.flush()
+    /// The name of another object, such as a parent, abbreviated unless asked otherwise.
+    fn other<'a>(&self, hash: &'a GitHexHash) -> anyhow::Result<&'a str> {
+        self.abbreviate(hash, true)
     }
 
-    // TODO: impl write_vectored
+    fn abbreviate<'a>(&self, hash: &'a GitHexHash, abbrev: bool) -> anyhow::Result<&'a str> {
+        if abbrev && !self.no_abbrev {
+            hash.abbreviate(7)
+        } else {
+            Ok(hash.as_str())
+        }
+    }
 }
 
-enum Entry {
-    Dir,
-    File,
+#[derive(clap::Args, Debug, Default)]
+struct HashObjectOptions {
+    /// Type of the object to create, `blob` by default.
+    #[arg(short = 't', value_name = "type")]
+    object_type: Option<String>,
+    /// Guess the type of the object from its content: a serialized commit, tag or tree, otherwise a blob.
+    #[arg(long, conflicts_with = "object_type")]
+    auto: bool,
+    /// Hash the content as is, without filters and allowing any object type. Useful to create broken objects.
+    #[arg(long)]
+    literally: bool,
+    /// Filter the content as if it were this file of the working tree. The content of `--stdin` is otherwise
+    /// hashed as is.
+    #[arg(long, conflicts_with = "literally")]
+    path: Option<PathBuf>,
 }
 
-fn write_tree(dir: &Path) -> anyhow::Result<sha1::digest::Output<sha1::Sha1>> {
-    let mut tree_entries = Vec::new();
-    let mut names_len = 0;
-    for entry in fs::read_dir(dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        if path.file_name().is_some_and(|name| name == ".git") {
-            continue;
-        }
+fn init(git_dir: &Path, object_format: HashAlgorithm) -> anyhow::Result<()> {
+    fs::create_dir(git_dir).context(format!("creating {git_dir:?}"))?;
+    fs::create_dir(git_dir.join("objects"))?;
+    fs::create_dir(git_dir.join("refs"))?;
+    fs::write(git_dir.join("HEAD"), "ref: refs/heads/main\n")?;
+    // Extensions such as the object format require the version 1 of the repository format.
+    let config = match object_format {
+        HashAlgorithm::Sha1 => "[core]\n\trepositoryformatversion = 0\n".to_string(),
+        _ => format!(
+            "[core]\n\trepositoryformatversion = 1\n[extensions]\n\tobjectFormat = {object_format}\n"
+        ),
+    };
+    fs::write(git_dir.join("config"), config)?;
+    Ok(())
+}
 
-        if path.is_dir() {
-            let sha1 = write_tree(&path)?;
-            let file_name = entry.file_name().into_string().unwrap();
-            // minus one because the mode of dir rectory are encoded will less byte.
-            names_len += file_name.len() - 1;
-            tree_entries.push((sha1, file_name, Entry::Dir))
-        } else {
-            // Each files are a blob object.
-            let sha1 = hash_object(&entry.path(), true)?;
-            let file_name = entry.file_name().into_string().unwrap();
-            names_len += file_name.len();
-            tree_entries.push((sha1, file_name, Entry::File))
+fn clone(url: &str, directory: Option<PathBuf>, depth: Option<u32>) -> anyhow::Result<()> {
+    // `https://host/path/repo.git` is cloned in `repo`.
+    let directory = directory.unwrap_or_else(|| {
+        let name = url.trim_end_matches('/').rsplit('/').next().unwrap_or(url);
+        PathBuf::from(name.strip_suffix(".git").unwrap_or(name))
+    });
+    ensure!(
+        !directory.exists() || fs::read_dir(&directory)?.next().is_none(),
+        "destination path '{}' already exists and is not an empty directory.",
+        directory.display()
+    );
+    verbosity::status(format_args!("Cloning into '{}'...", directory.display()));
+    let advertisement = protocol::discover_refs(url)?;
+
+    fs::create_dir_all(&directory).context(format!("creating {directory:?}"))?;
+    // From now on the new repository is the one discovered from the current directory.
+    env::set_current_dir(&directory)?;
+    let git_dir = PathBuf::from(".git");
+    init(&git_dir, HashAlgorithm::Sha1)?;
+    let mut config = fs::OpenOptions::new()
+        .append(true)
+        .open(git_dir.join("config"))?;
+    write!(
+        config,
+        "[remote \"origin\"]\n\turl = {url}\n\tfetch = +refs/heads/*:refs/remotes/origin/*\n"
+    )?;
+
+    let mut wants = Vec::new();
+    for (name, hash) in &advertisement.refs {
+        if !name.ends_with("^{}") && !wants.contains(hash) {
+            wants.push(hash.clone());
         }
     }
-    let tmp_path = env::temp_dir().join("tmp_tree");
+    if !wants.is_empty() {
+        let request = FetchRequest {
+            wants,
+            depth,
+            ..Default::default()
+        };
+        let count = if advertisement.dumb {
+            ensure!(
+                depth.is_none(),
+                "dumb http transport does not support shallow capabilities"
+            );
+            protocol::fetch_dumb(url, &request.wants)?
+        } else {
+            let mut fetched = protocol::fetch_pack(url, &advertisement, &request)?;
+            // Like git, a cloned pack is always kept.
+            let count = pack::receive(&mut fetched.pack, 0)?;
+            shallow::update_shallow_commits(fetched.shallow, &fetched.unshallow)?;
+            count
+        };
+        verbosity::status(format_args!("Received {count} objects."));
+    }
 
-    let tmp = fs::File::create(&tmp_path)?;
-    // We don't use `BufWriter` here because, quite surprisingly, ZlibEncoder `Write` implementation already use a buffer.
-    let mut hasher = ObjectHasher {
-        hash: Sha1::new(),
-        writer: ZlibEncoder::new(tmp, Compression::default()),
-    };
-    // 20 the sha1, 6 the mode, 1 the \0 and 1 the whitespace
-    let entries_len = names_len + tree_entries.len() * (20 + 6 + 1 + 1);
-    write!(hasher, "tree {entries_len}\0")?;
-    tree_entries.sort_unstable_by(|a, b| a.1.cmp(&b.1));
-    for (sha1, file_name, kind) in tree_entries {
-        match kind {
-            // By observing git, the leading 0 displayed for dir mode is note encoded.
-            Entry::Dir => write!(hasher, "40000")?,
-            Entry::File => write!(hasher, "100644")?,
+    let reflog_message = format!("clone: from {url}");
+    for (name, hash) in &advertisement.refs {
+        if let Some(branch) = name.strip_prefix("refs/heads/") {
+            refs::update_ref(
+                &format!("refs/remotes/origin/{branch}"),
+                hash,
+                &reflog_message,
+            )?;
+        } else if name.starts_with("refs/tags/") && !name.ends_with("^{}") {
+            refs::update_ref(name, hash, &reflog_message)?;
         }
-        write!(hasher, " {file_name}\0")?;
-        hasher.write_all(&sha1)?;
     }
 
-    let _ = hasher.writer.finish()?;
-    let hash = hasher.hash.finalize();
-    let sha1 = base16ct::lower::encode_string(&hash);
-
-    let (dir, rest) = sha1.split_at(2);
-    let parent = PathBuf::from(".git/objects").join(dir);
-    let object_path = parent.join(rest);
-    fs::create_dir_all(&parent).context(format!("creating {parent:?}"))?;
-    fs::rename(tmp_path, object_path)?;
-
-    Ok(hash)
-}
-
-fn commit_tree(
-    tree_hash: GitHexHash,
-    parent_hash: GitHexHash,
-    message: String,
-) -> anyhow::Result<()> {
-    let tmp_path = env::temp_dir().join("tmp_tree");
-
-    let tmp = fs::File::create(&tmp_path)?;
-    // We don't use `BufWriter` here because, quite surprisingly, ZlibEncoder `Write` implementation already use a buffer.
-    let mut hasher = ObjectHasher {
-        hash: Sha1::new(),
-        writer: ZlibEncoder::new(tmp, Compression::default()),
+    // Servers tell which branch HEAD points to with the `symref` capability, otherwise we guess from the hash.
+    let head = advertisement
+        .refs
+        .iter()
+        .find(|(name, _)| name == "HEAD")
+        .map(|(_, hash)| hash);
+    let head_branch = advertisement
+        .capabilities
+        .iter()
+        .find_map(|capability| capability.strip_prefix("symref=HEAD:"))
+        .map(str::to_string)
+        .or_else(|| {
+            advertisement
+                .refs
+                .iter()
+                .find(|(name, hash)| name.starts_with("refs/heads/") && Some(hash) == head)
+                .map(|(name, _)| name.clone())
+        });
+    let (Some(head), Some(head_branch)) = (head, head_branch) else {
+        eprintln!("warning: You appear to have cloned an empty repository.");
+        return Ok(());
     };
-    const AUTHOR: &str = "John Doe";
-    const EMAIL: &str = "johndoe@example.com";
-    let now = jiff::Timestamp::now().as_second().to_string();
-    // TODO: find a way to padd this value like git
-    // 1732376559 +0100
-    let _offset = Zoned::now().offset().to_string();
-
-    // We pre-compute the length ahead of time so we don't have to write in a temporary buffer to compute the length.
-    let length: usize = 5 // tree
-        + 40
-        + 1
-        // parent
-        + 7
-        + 40
-        + 1
-        // author
-        + 7
-        + AUTHOR.as_bytes().len()
-        + 2
-        + EMAIL.as_bytes().len()
-        + 2
-        + now.len()
-        + 6
-        + 1
-        // commiter
-        + 9
-        + AUTHOR.as_bytes().len()
-        + 2
-        + EMAIL.as_bytes().len()
-        + 2
-        + now.len()
-        + 6
-        + 1
-        // new line
-        + 1
-        // message
-        + message.as_bytes().len()
-        // new line
-        + 1;
-    write!(hasher, "commit {length}\0")?;
-    writeln!(hasher, "tree {tree_hash}")?;
-    writeln!(hasher, "parent {parent_hash}")?;
-    writeln!(hasher, "author {AUTHOR} <{EMAIL}> {now} +0000")?;
-    writeln!(hasher, "commiter {AUTHOR} <{EMAIL}> {now} +0000")?;
-    writeln!(hasher)?;
-    writeln!(hasher, "{message}")?;
-    let _ = hasher.writer.finish()?;
-
-    let hash = hasher.hash.finalize();
-    let sha1 = base16ct::lower::encode_string(&hash);
-
-    let (dir, rest) = sha1.split_at(2);
-    let parent = PathBuf::from(".git/objects").join(dir);
-    let object_path = parent.join(rest);
-    fs::create_dir_all(&parent).context(format!("creating {parent:?}"))?;
-    fs::rename(tmp_path, object_path)?;
-
-    println!("{sha1}");
-    Ok(())
+    let branch = head_branch
+        .strip_prefix("refs/heads/")
+        .unwrap_or(&head_branch);
+    refs::write_symbolic_ref("HEAD", &head_branch)?;
+    refs::update_ref(&head_branch, head, &reflog_message)?;
+    refs::write_symbolic_ref(
+        "refs/remotes/origin/HEAD",
+        &format!("refs/remotes/origin/{branch}"),
+    )?;
+    write!(
+        config,
+        "[branch \"{branch}\"]\n\tremote = origin\n\tmerge = {head_branch}\n"
+    )?;
+    checkout_tree(&Commit::read(head)?.tree)
 }
 
-fn hash_object(file: &Path, write: bool) -> anyhow::Result<sha1::digest::Output<sha1::Sha1>> {
-    // 1. Add the header
-    // 2. Hash the object and compress it at the same time (so we need to read the whole file once). The compression is directly writen to a tmp file to avoid loading the whole file in memory
-    // 3. Rename the temp file with the hash name
-    Ok(if write {
-        // Getting length ahead won't work with stdin. We also hope that the file don't get modified until we write it,
-        // otherwise we could encode a bad length
-        let file_len = fs::metadata(file).context("get {file:?} metadata")?.len();
-        let mut opened_file = fs::File::open(file).context("open {file:?}")?;
-        let tmp_path = env::temp_dir().join("tempfile");
-
-        let tmp = fs::File::create(&tmp_path)?;
-        let archive = ZlibEncoder::new(tmp, Compression::default());
-        let mut archive = ObjectHasher {
-            hash: Sha1::new(),
-            writer: archive,
-        };
-        write!(archive, "blob {}\0", file_len)?;
-        io::copy(&mut opened_file, &mut archive)?;
-        let _ = archive.writer.finish()?;
-        let hash = archive.hash.finalize();
-        let sha1 = base16ct::lower::encode_string(&hash);
-
-        let (dir, rest) = sha1.split_at(2);
-        let parent = PathBuf::from(".git/objects").join(dir);
-        let object_path = parent.join(rest);
-        fs::create_dir_all(&parent).context(format!("creating {parent:?}"))?;
-        fs::rename(tmp_path, object_path)?;
-        hash
-    } else {
-        // We don't want to read the whole file into memory to compute the len, so we use stat.
-        let file_len = fs::metadata(file)?.len();
-        let mut file = fs::File::open(file)?;
-        let mut hasher = Sha1::new();
-        write!(hasher, "blob {file_len}\0")?;
-        io::copy(&mut file, &mut hasher)?;
-        hasher.finalize()
-    })
+/// The refspec fetching `remote`, `remote.<remote>.fetch` or `+refs/heads/*:refs/remotes/<remote>/*` by default:
+/// whether it has the leading `+` allowing non fast-forward updates, its source and its destination.
+fn fetch_refspec(remote: &str) -> anyhow::Result<(bool, String, String)> {
+    let default_refspec = format!("+refs/heads/*:refs/remotes/{remote}/*");
+    let config = config::config()?;
+    let refspec = config
+        .get(&format!("remote.{remote}.fetch"))
+        .unwrap_or(&default_refspec);
+    let force = refspec.starts_with('+');
+    let (source, destination) = refspec
+        .trim_start_matches('+')
+        .split_once(':')
+        .context(format!("invalid refspec '{refspec}'"))?;
+    Ok((force, source.to_string(), destination.to_string()))
 }
 
-// Here there is not separator between the entries of the tree, they all start by a number but this could
-// be melted with the sha1 bytes, so we can't have a "split on separator" approach. In other words the format is not self describing.
-fn print_tree(hash: GitHexHash, name_only: bool) -> anyhow::Result<()> {
-    let object = ObjectReader::from_sha1(hash)?;
-
-    let ObjectReader::Tree(mut reader) = object else {
-        bail!("not a tree object");
-    };
-
-    let mut mode_buf = Vec::with_capacity(6);
-    let mut name_buf = Vec::new();
-    let mut hash_buf = [0; 20];
-
-    let mut stdout = io::stdout().lock();
-    // Each entry is <mode> <name>\0 sha1
-    loop {
-        name_buf.clear();
-        mode_buf.clear();
-        let n = reader.read_until(b' ', &mut mode_buf)?;
-        if n == 0 {
-            break;
+/// The local ref a refspec fetches the remote ref `name` into, a `*` in the source matching any part of it.
+fn map_ref(source: &str, destination: &str, name: &str) -> Option<String> {
+    match source.split_once('*') {
+        Some((prefix, suffix)) => {
+            let matched = name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+            Some(destination.replacen('*', matched, 1))
         }
+        None => (name == source).then(|| destination.to_string()),
+    }
+}
 
-        // Why they encode the mode in ASCII and not as an integer?
-        let mode = std::str::from_utf8(&mode_buf[..mode_buf.len() - 1]).context("reading mode")?;
+/// Below this number of objects a fetched pack is exploded into loose objects, `fetch.unpackLimit` or
+/// `transfer.unpackLimit`, 100 by default.
+fn unpack_limit() -> anyhow::Result<usize> {
+    let config = config::config()?;
+    for key in ["fetch.unpackLimit", "transfer.unpackLimit"] {
+        if let Some(value) = config.get(key) {
+            return value.trim().parse().ok().context(format!(
+                "bad numeric config value '{value}' for '{}'",
+                key.to_ascii_lowercase()
+            ));
+        }
+    }
+    Ok(100)
+}
 
-        let n = reader
-            .read_until(0, &mut name_buf)
-            .context("reading the header")?;
-        let name = CStr::from_bytes_with_nul(&name_buf[..n])
-            .context("reading name")?
-            .to_str()?;
+/// Fetch the refs of `remote` and the objects they need, returns whether all the refs could be updated: like git,
+/// a ref whose history was rewritten is only replaced when the refspec starts with `+`.
+fn fetch(remote: &str, depth: Option<u32>) -> anyhow::Result<bool> {
+    let config = config::config()?;
+    let url = config
+        .get(&format!("remote.{remote}.url"))
+        .context(format!("'{remote}' does not appear to be a git repository"))?;
+    let (force, source, destination) = fetch_refspec(remote)?;
 
-        reader.read_exact(&mut hash_buf).context("reading hash")?;
-        let hex_hash = base16ct::lower::encode_string(&hash_buf);
-        if name_only {
-            writeln!(stdout, "{name}")?;
+    let advertisement = protocol::discover_refs(url)?;
+    let mut updates = Vec::new();
+    let mut wants = Vec::new();
+    for (name, hash) in &advertisement.refs {
+        let Some(local) = map_ref(&source, &destination, name) else {
+            continue;
+        };
+        // With a depth, the history we already have may need deepening.
+        if (depth.is_some() || !hash.exists()?) && !wants.contains(hash) {
+            wants.push(hash.clone());
+        }
+        updates.push((name, local, hash));
+    }
+    if !wants.is_empty() {
+        let mut haves = Vec::new();
+        for (_, hash) in refs::list_refs("refs/")? {
+            if !haves.contains(&hash) {
+                haves.push(hash);
+            }
+        }
+        let request = FetchRequest {
+            wants,
+            haves,
+            depth,
+        };
+        let count = if advertisement.dumb {
+            ensure!(
+                depth.is_none(),
+                "dumb http transport does not support shallow capabilities"
+            );
+            protocol::fetch_dumb(url, &request.wants)?
         } else {
-            let object = ObjectReader::from_sha1(hex_hash.as_str().try_into()?)?;
-            // In git on Linux (and windows for version >= V1.7.10) the CStr is encoded as UTF-8. However, by default
-            // git ls-tree won't print the unicode symbole if not ASCII, it will escape the symbols in octal
-            // representation.
-            write!(stdout, "{mode:0>6} {object} {hex_hash}    ")?;
-            for byte in name.as_bytes() {
-                if byte.is_ascii() {
-                    let char = char::from(*byte);
-                    write!(stdout, "{char}")?;
-                } else {
-                    write!(stdout, "\\{byte:o}")?;
-                }
+            let mut fetched = protocol::fetch_pack(url, &advertisement, &request)?;
+            let count = pack::receive(&mut fetched.pack, unpack_limit()?)?;
+            shallow::update_shallow_commits(fetched.shallow, &fetched.unshallow)?;
+            count
+        };
+        verbosity::status(format_args!("Received {count} objects."));
+    }
+
+    let mut rejected = false;
+    let mut header_printed = false;
+    let mut report = |line: String| {
+        if !header_printed {
+            verbosity::status(format_args!("From {url}"));
+            header_printed = true;
+        }
+        verbosity::status(line);
+    };
+    for (name, local, hash) in updates {
+        let short_name = name.strip_prefix("refs/heads/").unwrap_or(name);
+        let short_local = local.strip_prefix("refs/remotes/").unwrap_or(&local);
+        let reflog_message = match refs::read_ref(&local)? {
+            Some(old) if old == *hash => continue,
+            Some(old) if commit::ancestors(hash)?.contains(&old) => {
+                report(format!(
+                    "   {}..{}  {short_name:<10} -> {short_local}",
+                    old.abbreviate(7)?,
+                    hash.abbreviate(7)?
+                ));
+                format!("fetch {remote}: fast-forward")
             }
+            // The history was rewritten, only replaced with a `+` refspec.
+            Some(old) if force => {
+                report(format!(
+                    " + {}...{} {short_name:<10} -> {short_local}  (forced update)",
+                    old.abbreviate(7)?,
+                    hash.abbreviate(7)?
+                ));
+                format!("fetch {remote}: forced-update")
+            }
+            Some(_) => {
+                report(format!(
+                    " ! [rejected]        {short_name:<10} -> {short_local}  (non-fast-forward)"
+                ));
+                rejected = true;
+                continue;
+            }
+            None => {
+                report(format!(
+                    " * [new branch]      {short_name:<10} -> {short_local}"
+                ));
+                format!("fetch {remote}: storing head")
+            }
+        };
+        refs::update_ref(&local, hash, &reflog_message)?;
+    }
+    // Like git, tags pointing into the fetched history come along, thanks to the `include-tag` capability.
+    for (name, hash) in &advertisement.refs {
+        let Some(tag) = name.strip_prefix("refs/tags/") else {
+            continue;
+        };
+        if tag.ends_with("^{}") || refs::read_ref(name)?.is_some() || !hash.exists()? {
+            continue;
+        }
+        report(format!(" * [new tag]         {tag:<10} -> {tag}"));
+        refs::update_ref(name, hash, &format!("fetch {remote}: storing head"))?;
+    }
+    Ok(!rejected)
+}
 
-            writeln!(stdout)?;
+/// `show-index`: like git, the objects are listed in the order of the index, sorted by name.
+fn show_index(index: Option<&Path>) -> anyhow::Result<()> {
+    let data = match index {
+        Some(path) => fs::read(path).context(format!("reading {path:?}"))?,
+        None => {
+            let mut data = Vec::new();
+            io::stdin().lock().read_to_end(&mut data)?;
+            data
         }
+    };
+    let index = pack::PackIndex::parse(data)?;
+    let mut stdout = io::stdout().lock();
+    for position in 0..index.len() {
+        writeln!(
+            stdout,
+            "{} {} ({:08x})",
+            index.offset(position)?,
+            index.hash(position),
+            index.crc32(position)
+        )?;
     }
+    Ok(())
+}
 
+fn index_pack(pack: &Path) -> anyhow::Result<()> {
+    ensure!(
+        pack.extension()
+            .is_some_and(|extension| extension == "pack"),
+        "packfile name '{}' does not end with '.pack'",
+        pack.display()
+    );
+    let (mut entries, checksum) = pack::index_pack_file(pack, false)?;
+    let index_path = pack.with_extension("idx");
+    let index_file = io::BufWriter::new(
+        fs::File::create(&index_path).context(format!("creating {index_path:?}"))?,
+    );
+    pack::write_index(&mut entries, &checksum, index_file)?;
+    println!("{checksum}");
     Ok(())
 }
 
-// Each object have an header
-// <kind> <size>\0
-// The size is the length of the content following the header.
-enum ObjectReader<R> {
+/// `pack-objects`: the input is like `rev-list --objects`, a name per line optionally followed by a path.
+fn pack_objects(
+    stdout: bool,
+    window: usize,
+    depth: usize,
+    base_name: Option<&Path>,
+) -> anyhow::Result<()> {
+    let mut objects = Vec::new();
+    // The paths help finding similar objects to delta against.
+    let mut paths = Vec::new();
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        let Some(name) = line.split_whitespace().next() else {
+            continue;
+        };
+        let hash = GitHexHash::try_from(name)?;
+        if !objects.contains(&hash) {
+            objects.push(hash);
+            let path = line.trim_start()[name.len()..].trim_start();
+            paths.push(path.to_string());
+        }
+    }
+
+    let Some(base_name) = base_name.filter(|_| !stdout) else {
+        pack::write_pack(&objects, &paths, window, depth, io::stdout().lock())?;
+        return Ok(());
+    };
+    // Named after the pack checksum, which is only known once written.
+    let tmp_path = PathBuf::from(format!("{}-tmp.pack", base_name.display()));
+    let pack_file = io::BufWriter::new(fs::File::create(&tmp_path)?);
+    let (mut entries, checksum) = pack::write_pack(&objects, &paths, window, depth, pack_file)?;
+    fs::rename(
+        &tmp_path,
+        format!("{}-{checksum}.pack", base_name.display()),
+    )?;
+    let index_file = io::BufWriter::new(fs::File::create(format!(
+        "{}-{checksum}.idx",
+        base_name.display()
+    ))?);
+    pack::write_index(&mut entries, &checksum, index_file)?;
+    println!("{checksum}");
+    Ok(())
+}
+
+fn prune(dry_run: bool) -> anyhow::Result<()> {
+    let reachable = reachable_objects()?;
+    for hash in hex_hash::loose_objects()? {
+        if reachable.contains(&hash) {
+            continue;
+        }
+        if dry_run {
+            println!("{hash} {}", ObjectReader::header(&hash)?.0);
+            continue;
+        }
+        let path = hash.to_path()?;
+        fs::remove_file(&path).context(format!("removing {hash}"))?;
+        // Like git, drop the directories we emptied. Fails harmlessly if some objects are left.
+        if let Some(dir) = path.parent() {
+            let _ = fs::remove_dir(dir);
+        }
+    }
+    Ok(())
+}
+
+/// All the objects reachable from the refs, their reflogs, HEAD and the index, walking commits, trees and tags.
+///
+/// Any object of the history that can't be read is an error: we'd rather not prune than lose objects.
+fn reachable_objects() -> anyhow::Result<HashSet<GitHexHash>> {
+    let shallow = shallow::shallow_commits()?;
+    let mut pending: Vec<GitHexHash> = refs::list_refs("refs/")?
+        .into_iter()
+        .map(|(_, hash)| hash)
+        .collect();
+    pending.extend(refs::read_ref("HEAD")?);
+    collect_reflog_objects(&repo::git_dir()?.join("logs"), &mut pending)?;
+    // Gitlinks point to commits of another repository.
+    let index = Index::read()?;
+    pending.extend(
+        index
+            .entries
+            .iter()
+            .filter(|entry| entry.mode != Mode::Gitlink.bits())
+            .map(|entry| entry.hash.clone()),
+    );
+    // Like git, the trees of the index cache are kept, they may be committed soon.
+    pending.extend(index.cache_tree.iter().flat_map(CacheTree::hashes));
+
+    let mut reachable = HashSet::new();
+    while let Some(hash) = pending.pop() {
+        if reachable.contains(&hash) {
+            continue;
+        }
+        let object =
+            ObjectReader::from_sha1(hash.clone()).context(format!("unable to read {hash}"))?;
+        match object {
+            ObjectReader::Blob(_) => {}
+            ObjectReader::Tree(reader) => {
+                for entry in TreeEntries::new(reader)? {
+                    let entry = entry?;
+                    if entry.is_gitlink() {
+                        continue;
+                    }
+                    // Blobs have nothing to walk, no need to read them.
+                    if entry.is_tree() {
+                        pending.push(entry.hash);
+                    } else {
+                        reachable.insert(entry.hash);
+                    }
+                }
+            }
+            ObjectReader::Commit(_) => {
+                let commit = Commit::read(&hash)?;
+                pending.push(commit.tree);
+                // The parents of the shallow boundary are missing.
+                if !shallow.contains(&hash) {
+                    pending.extend(commit.parents);
+                }
+            }
+            ObjectReader::Tag(reader) => {
+                let tag = Tag::parse(reader).context(format!("parsing tag {hash}"))?;
+                pending.push(tag.object);
+            }
+        }
+        reachable.insert(hash);
+    }
+    Ok(reachable)
+}
+
+// The reflogs are `.git/logs/<ref>`.
+fn collect_reflog_objects(dir: &Path, objects: &mut Vec<GitHexHash>) -> anyhow::Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    let logs_dir = repo::git_dir()?.join("logs");
+    let null_hash = HashAlgorithm::current()?.null_hash();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_reflog_objects(&path, objects)?;
+            continue;
+        }
+        let name = path
+            .strip_prefix(&logs_dir)?
+            .to_str()
+            .context("ref name is not valid UTF-8")?;
+        for entry in refs::read_reflog(name)? {
+            // The old value of a created ref is the null hash.
+            objects.extend(
+                [entry.old, entry.new]
+                    .into_iter()
+                    .filter(|hash| *hash != null_hash),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Write the files of a tree in the working tree and record them in the index.
+fn checkout_tree(tree: &GitHexHash) -> anyhow::Result<()> {
+    let work_tree = repo::work_tree()?;
+    let mut index = Index::lock()?;
+    for (path, mode, hash) in flatten_tree(tree)? {
+        let target = work_tree.join(&path);
+        let mut entry = IndexEntry::new(path, mode, hash, Stat::default());
+        checkout_entry(&entry, &target)?;
+        entry.stat = Stat::from_metadata(&fs::symlink_metadata(&target)?);
+        index.insert(entry);
+    }
+    index.commit()
+}
+
+/// The files of a tree and its sub-trees, as `(path, mode, hash)` in index order.
+fn flatten_tree(tree: &GitHexHash) -> anyhow::Result<Vec<(String, u32, GitHexHash)>> {
+    fn collect(
+        tree: &GitHexHash,
+        prefix: &str,
+        files: &mut Vec<(String, u32, GitHexHash)>,
+    ) -> anyhow::Result<()> {
+        let ObjectReader::Tree(reader) = ObjectReader::from_sha1(tree.clone())? else {
+            bail!("{tree} is not a valid 'tree' object");
+        };
+        for entry in TreeEntries::new(reader)? {
+            let entry = entry?;
+            let path = format!("{prefix}{}", entry.name);
+            if entry.is_tree() {
+                collect(&entry.hash, &format!("{path}/"), files)?;
+            } else {
+                files.push((path, Mode::parse(&entry.mode)?.bits(), entry.hash));
+            }
+        }
+        Ok(())
+    }
+    let mut files = Vec::new();
+    collect(tree, "", &mut files)?;
+    Ok(files)
+}
+
+fn parse_hash(input: &str) -> anyhow::Result<GitHexHash> {
+    GitHexHash::try_from(input)
+}
+
+/// An index entry given on the command line as `<mode>,<object>,<path>`.
+#[derive(Clone, Debug)]
+struct CacheInfo {
+    mode: u32,
+    hash: GitHexHash,
+    path: PathBuf,
+}
+
+fn parse_cacheinfo(input: &str) -> anyhow::Result<CacheInfo> {
+    let mut parts = input.splitn(3, ',');
+    let (Some(mode), Some(hash), Some(path)) = (parts.next(), parts.next(), parts.next()) else {
+        bail!("option 'cacheinfo' expects <mode>,<sha1>,<path>");
+    };
+    let mode = Mode::parse(mode)?;
+    ensure!(
+        mode != Mode::Tree,
+        "invalid mode {} for {path}",
+        mode.as_str()
+    );
+    let mode = mode.bits();
+    Ok(CacheInfo {
+        mode,
+        hash: hash.try_into()?,
+        path: PathBuf::from(path),
+    })
+}
+
+struct ObjectHasher<W> {
+    hash: Hasher,
+    writer: W,
+}
+
+impl<W> Write for ObjectHasher<W>
+where
+    W: Write,
+{
+    // We only have to iterate through the value once to compute the hash
+    // and compress the data.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        self.hash.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    // TODO: impl write_vectored
+}
+
+impl HashObjectOptions {
+    /// The type of the object to create, guessed from the content with `--auto`.
+    fn kind(&self, content: impl FnOnce() -> anyhow::Result<Vec<u8>>) -> anyhow::Result<String> {
+        if self.auto {
+            return Ok(detect_kind(&content()?)?.to_string());
+        }
+        let kind = self.object_type.as_deref().unwrap_or("blob");
+        if !self.literally {
+            ObjectKind::parse(kind)?;
+        }
+        Ok(kind.to_string())
+    }
+}
+
+// Objects other than blobs have a recognizable structure: commits and tags start with their first header,
+// trees are a sequence of `<mode> <name>\0<raw hash>` with valid modes. Everything else, including the
+// ambiguous empty content, is a blob.
+fn detect_kind(content: &[u8]) -> anyhow::Result<ObjectKind> {
+    let hex_len = HashAlgorithm::current()?.hex_len();
+    let starts_with_header = |name: &str| {
+        content
+            .strip_prefix(format!("{name} ").as_bytes())
+            .and_then(|rest| rest.get(..hex_len + 1))
+            .and_then(|line| line.strip_suffix(b"\n"))
+            .is_some_and(|hash| hash.iter().all(u8::is_ascii_hexdigit))
+    };
+    if starts_with_header("tree") {
+        return Ok(ObjectKind::Commit);
+    }
+    if starts_with_header("object") {
+        return Ok(ObjectKind::Tag);
+    }
+    if content.is_empty() {
+        return Ok(ObjectKind::Blob);
+    }
+    let is_tree = TreeEntries::new(content)?.all(|entry| {
+        entry.is_ok_and(|entry| {
+            Mode::parse(&entry.mode).is_ok_and(|mode| mode.as_str() == entry.mode)
+        })
+    });
+    Ok(if is_tree {
+        ObjectKind::Tree
+    } else {
+        ObjectKind::Blob
+    })
+}
+
+fn write_tree(dir: &Path, progress: &mut Progress) -> anyhow::Result<GitHexHash> {
+    let algorithm = HashAlgorithm::current()?;
+    let mut tree_entries = Vec::new();
+    let precompose = config::config()?
+        .get_bool("core.precomposeUnicode")?
+        .unwrap_or(false);
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.file_name().is_some_and(|name| name == ".git") {
+            continue;
+        }
+        let file_name = entry.file_name().into_string().unwrap();
+        // macOS returns decomposed names while git stores them precomposed, otherwise the tree hashes would
+        // differ from the ones computed on other platforms.
+        let file_name = if precompose {
+            nfc::precompose(&file_name)
+        } else {
+            file_name
+        };
+
+        // Not followed: a symbolic link is a blob of its target, even when it points to a directory.
+        if entry.file_type()?.is_symlink() {
+            let target = fs::read_link(&path)?;
+            let target = target.to_str().context("non UTF-8 symbolic link")?;
+            let sha1 = hash_content("blob", target.len() as u64, &mut target.as_bytes(), true)?;
+            progress.tick();
+            tree_entries.push((sha1, file_name, Mode::Symlink));
+        } else if let Some(submodule) = repo::open_dot_git(&path.join(".git"))? {
+            // A nested repository is a submodule, recorded as the commit its HEAD points to.
+            let commit = refs::read_ref_at(&submodule, "HEAD")?
+                .context(format!("submodule {path:?} has no commit checked out"))?;
+            tree_entries.push((commit, file_name, Mode::Gitlink));
+        } else if path.is_dir() {
+            let sha1 = write_tree(&path, progress)?;
+            // Git doesn't track directories, only their content: an empty directory has no entry in its parent.
+            if sha1 == algorithm.empty_tree() {
+                continue;
+            }
+            tree_entries.push((sha1, file_name, Mode::Tree))
+        } else {
+            // Each files are a blob object.
+            let sha1 = hash_object(&entry.path(), true, &HashObjectOptions::default())?;
+            progress.tick();
+            let mode = regular_file_mode(&entry.metadata()?, None)?;
+            tree_entries.push((sha1, file_name, mode))
+        }
+    }
+    write_tree_object(tree_entries)
+}
+
+/// Write the tree object of the index entries, `entries` being their paths relative to the tree.
+///
+/// The directories whose hash is still valid in `cache` aren't written again. Returns the hash of the tree
+/// and the up to date cache.
+fn write_index_tree(
+    entries: &[(&str, &IndexEntry)],
+    cache: Option<&CacheTree>,
+) -> anyhow::Result<(GitHexHash, CacheTree)> {
+    if let Some(cache) = cache.filter(|cache| cache.entry_count == entries.len()) {
+        if let Some(hash) = &cache.hash {
+            if hash.exists()? {
+                return Ok((hash.clone(), cache.clone()));
+            }
+        }
+    }
+    let mut tree_entries = Vec::new();
+    let mut children_cache = Vec::new();
+    let mut i = 0;
+    while i < entries.len() {
+        let (path, entry) = entries[i];
+        let Some((dir, _)) = path.split_once('/') else {
+            tree_entries.push((
+                entry.hash.clone(),
+                path.to_string(),
+                Mode::from_bits(entry.mode)?,
+            ));
+            i += 1;
+            continue;
+        };
+        // The index is sorted by path so the content of a directory is contiguous.
+        let children = entries[i..]
+            .iter()
+            .map_while(|(path, entry)| {
+                let (child_dir, rest) = path.split_once('/')?;
+                (child_dir == dir).then_some((rest, *entry))
+            })
+            .collect::<Vec<_>>();
+        i += children.len();
+        let (hash, child_cache) =
+            write_index_tree(&children, cache.and_then(|cache| cache.child(dir)))?;
+        tree_entries.push((hash, dir.to_string(), Mode::Tree));
+        children_cache.push((dir.to_string(), child_cache));
+    }
+    let hash = write_tree_object(tree_entries)?;
+    let cache = CacheTree {
+        hash: Some(hash.clone()),
+        entry_count: entries.len(),
+        children: children_cache,
+    };
+    Ok((hash, cache))
+}
+
+fn write_tree_object(
+    mut tree_entries: Vec<(GitHexHash, String, Mode)>,
+) -> anyhow::Result<GitHexHash> {
+    let algorithm = HashAlgorithm::current()?;
+    // The mode, 1 the whitespace, the name, 1 the \0 and the raw hash
+    let entries_len: usize = tree_entries
+        .iter()
+        .map(|(_, file_name, mode)| {
+            mode.as_str().len() + 1 + file_name.len() + 1 + algorithm.raw_len()
+        })
+        .sum();
+    // Git sorts directories as if their name ended with a slash.
+    let sort_key = |(_, file_name, mode): &(GitHexHash, String, Mode)| {
+        let suffix = (*mode == Mode::Tree).then_some(b'/');
+        file_name.bytes().chain(suffix).collect::<Vec<_>>()
+    };
+    tree_entries.sort_unstable_by_key(sort_key);
+    write_object("tree", entries_len as u64, |out| {
+        for (sha1, file_name, mode) in tree_entries {
+            write!(out, "{} {file_name}\0", mode.as_str())?;
+            out.write_all(&sha1.to_raw())?;
+        }
+        Ok(())
+    })
+}
+
+/// Store the object `<kind> <len>\0<body>`, `body` writing its content. Returns its hash.
+///
+/// The object is hashed while it is compressed to a temporary file, then moved in place by [`store_object`].
+fn write_object(
+    kind: &str,
+    len: u64,
+    body: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+) -> anyhow::Result<GitHexHash> {
+    let (tmp_path, tmp) = temp_object_file()?;
+    // We don't use `BufWriter` here because, quite surprisingly, ZlibEncoder `Write` implementation already use a buffer.
+    let mut hasher = ObjectHasher {
+        hash: HashAlgorithm::current()?.hasher(),
+        writer: ZlibEncoder::new(tmp, Compression::default()),
+    };
+    let header = format!("{kind} {len}\0");
+    hasher.write_all(header.as_bytes())?;
+    let written = body(&mut hasher)
+        .context(format!("writing {tmp_path:?}"))
+        .map(|()| hasher.writer.total_in() - header.len() as u64);
+    // A body of the wrong length would be a corrupt object.
+    let checked = written.and_then(|written| {
+        ensure!(
+            written == len,
+            "{kind} object is {written} bytes long instead of {len}"
+        );
+        Ok(())
+    });
+    if let Err(err) = checked {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err);
+    }
+    let _ = hasher.writer.finish()?;
+    let hash = hasher.hash.finalize();
+    store_object(&tmp_path, &hash)?;
+    Ok(hash)
+}
+
+// The `objects/<xx>` directories known to exist: when writing many objects, each one is only created once.
+static OBJECT_SUBDIRS: OnceLock<Mutex<HashSet<PathBuf>>> = OnceLock::new();
+
+// Numbers the temporary files created by this process.
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// A new file to write an object in before [`store_object`] moves it in place.
+///
+/// Like git, it is in the objects directory so the move is a rename on the same file system.
+fn temp_object_file() -> anyhow::Result<(PathBuf, fs::File)> {
+    create_temp_file(&repo::objects_dir()?, "tmp_obj")
+}
+
+/// Create a new file in `dir`, named `<prefix>_<pid>_<n>`, open for reading and writing.
+///
+/// The file must not exist yet: a link planted there isn't followed and the file isn't shared with another
+/// process. A name that is already taken is skipped.
+fn create_temp_file(dir: &Path, prefix: &str) -> anyhow::Result<(PathBuf, fs::File)> {
+    loop {
+        let n = TEMP_FILES.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("{prefix}_{}_{n}", process::id()));
+        match fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(file) => return Ok((path, file)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => {
+                return Err(err).context(format!("unable to create temporary file {path:?}"))
+            }
+        }
+    }
+}
+
+/// Move a freshly written object from its temporary location into the object store, unless it is a dry run.
+///
+/// Objects are content-addressed: if one with the same hash is already stored, it has the same content so
+/// we keep it untouched. This also avoids overwriting the read-only files git creates.
+fn store_object(tmp_path: &Path, hash: &GitHexHash) -> anyhow::Result<()> {
+    let object_path = hash.to_path()?;
+    let parent = object_path.parent().context("object path without parent")?;
+    if DRY_RUN.get().copied().unwrap_or(false) || hash.exists()? {
+        fs::remove_file(tmp_path).context(format!("removing {tmp_path:?}"))?;
+        return Ok(());
+    }
+    // Like git, objects are read-only (0444) so they aren't modified by accident.
+    let mut permissions = fs::metadata(tmp_path)?.permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(tmp_path, permissions).context(format!("making {tmp_path:?} read-only"))?;
+    let mut created_dirs = OBJECT_SUBDIRS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if !created_dirs.contains(parent) {
+        fs::create_dir_all(parent).context(format!("creating {parent:?}"))?;
+        created_dirs.insert(parent.to_path_buf());
+    }
+    drop(created_dirs);
+    fs::rename(tmp_path, &object_path)?;
+    verbosity::verbose(format_args!("wrote {hash} to {}", object_path.display()));
+    Ok(())
+}
+
+fn commit_tree(
+    tree_hash: GitHexHash,
+    parent_hash: Vec<GitHexHash>,
+    message: String,
+    signing_key: Option<&str>,
+) -> anyhow::Result<GitHexHash> {
+    // Catch scripting mistakes such as swapping the tree and parent arguments before writing a broken commit.
+    let (kind, _) = ObjectReader::header(&tree_hash)?;
+    ensure!(
+        kind == ObjectKind::Tree,
+        "{tree_hash} is not a valid 'tree' object"
+    );
+    let parents = parent_hash
+        .into_iter()
+        .map(peel_to_commit)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let author_date = GitDate::from_env("GIT_AUTHOR_DATE")?;
+    let committer_date = GitDate::from_env("GIT_COMMITTER_DATE")?;
+
+    // The whole body is buffered: a signature covers everything but itself, and its length is only known once
+    // made.
+    let mut headers = format!("tree {tree_hash}\n");
+    for parent in &parents {
+        headers.push_str(&format!("parent {parent}\n"));
+    }
+    let author = Identity::resolve(Role::Author)?;
+    let committer = Identity::resolve(Role::Committer)?;
+    headers.push_str(&format!("author {author} {author_date}\n"));
+    headers.push_str(&format!("committer {committer} {committer_date}\n"));
+    let mut body = format!("{headers}\n{message}\n");
+    if let Some(key) = signing_key {
+        let key = match key {
+            "" => config::config()?
+                .get("user.signingkey")
+                .map_or_else(|| committer.to_string(), str::to_string),
+            key => key.to_string(),
+        };
+        let signature = gpg::sign(body.as_bytes(), &key)?;
+        // Right after the committer, each line of the signature after the first one indented by a space.
+        let signature = signature.trim_end_matches('\n').replace('\n', "\n ");
+        body = format!("{headers}gpgsig {signature}\n\n{message}\n");
+    }
+    write_object("commit", body.len() as u64, |out| {
+        out.write_all(body.as_bytes())
+    })
+}
+
+/// Commit the index on top of HEAD and move the current branch to the new commit.
+fn commit(message: String, signing_key: Option<&str>) -> anyhow::Result<()> {
+    let mut index = Index::lock()?;
+    ensure!(
+        index.entries.iter().all(|entry| entry.stage == 0),
+        "committing is not possible because you have unmerged files."
+    );
+    let entries = index
+        .entries
+        .iter()
+        .map(|entry| (entry.path.as_str(), entry))
+        .collect::<Vec<_>>();
+    let (tree, cache_tree) = write_index_tree(&entries, index.cache_tree.as_ref())?;
+    // Saved so the next commit only writes the directories that changed.
+    index.cache_tree = Some(cache_tree);
+    index.commit()?;
+
+    let head = refs::read_ref("HEAD")?;
+    // Concluding a merge left with conflicts, the merged commit is the second parent.
+    let merge_head = match fs::read_to_string(repo::git_dir()?.join("MERGE_HEAD")) {
+        Ok(merge_head) => Some(GitHexHash::try_from(merge_head.trim_end())?),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err).context("reading MERGE_HEAD"),
+    };
+    let unchanged = match &head {
+        Some(head) => Commit::read(head)?.tree == tree,
+        None => index.entries.is_empty(),
+    };
+    ensure!(!unchanged || merge_head.is_some(), "nothing to commit");
+
+    let summary = message.lines().next().unwrap_or_default().to_string();
+    let is_root = head.is_none();
+    let is_merge = merge_head.is_some();
+    let parents = head.into_iter().chain(merge_head).collect();
+    let hash = commit_tree(tree, parents, message, signing_key)?;
+    // With a detached HEAD, the commit is only referenced by HEAD.
+    let branch = refs::head_target()?;
+    let kind = if is_root {
+        "commit (initial)"
+    } else if is_merge {
+        "commit (merge)"
+    } else {
+        "commit"
+    };
+    refs::update_ref(
+        branch.as_deref().unwrap_or("HEAD"),
+        &hash,
+        &format!("{kind}: {summary}"),
+    )?;
+    remove_merge_state()?;
+
+    let branch = branch.as_deref().map_or("detached HEAD", |branch| {
+        branch.strip_prefix("refs/heads/").unwrap_or(branch)
+    });
+    let root = if is_root { " (root-commit)" } else { "" };
+    verbosity::info(format_args!(
+        "[{branch}{root} {}] {summary}",
+        hash.abbreviate(7)?
+    ));
+    Ok(())
+}
+
+fn show_ref(heads: bool, tags: bool, dereference: bool) -> anyhow::Result<()> {
+    let mut refs = Vec::new();
+    if heads {
+        refs.extend(refs::list_refs("refs/heads/")?);
+    }
+    if tags {
+        refs.extend(refs::list_refs("refs/tags/")?);
+    }
+    if !heads && !tags {
+        refs = refs::list_refs("refs/")?;
+    }
+    ensure!(!refs.is_empty(), "no refs found");
+
+    let packed_refs = if dereference {
+        refs::packed_refs()?
+    } else {
+        Vec::new()
+    };
+    let mut stdout = io::stdout().lock();
+    for (name, hash) in refs {
+        writeln!(stdout, "{hash} {name}")?;
+        let peeled = packed_refs
+            .iter()
+            .find(|packed| packed.name == name)
+            .and_then(|packed| packed.peeled.as_ref());
+        if let Some(peeled) = peeled {
+            writeln!(stdout, "{peeled} {name}^{{}}")?;
+        }
+    }
+    Ok(())
+}
+
+/// With `--quiet`, a ref that isn't symbolic, like a detached HEAD, isn't an error.
+fn symbolic_ref(name: &str, target: Option<&str>, short: bool) -> anyhow::Result<()> {
+    if let Some(target) = target {
+        ensure!(
+            name != "HEAD" || target.starts_with("refs/"),
+            "Refusing to point HEAD outside of refs/"
+        );
+        return refs::write_symbolic_ref(name, target);
+    }
+    match refs::read_symbolic_ref(name)? {
+        Some(target) if short => println!("{}", refs::shorten_name(&target)),
+        Some(target) => println!("{target}"),
+        None if verbosity::get() == Verbosity::Quiet => {}
+        None => bail!("ref {name} is not a symbolic ref"),
+    }
+    Ok(())
+}
+
+fn show_reflog(name: &str) -> anyhow::Result<()> {
+    let full_name =
+        rev::dwim_ref(name)?.context(format!("ambiguous argument '{name}': unknown revision"))?;
+    let mut out = io::stdout().lock();
+    for (i, entry) in refs::read_reflog(&full_name)?.iter().rev().enumerate() {
+        writeln!(
+            out,
+            "{} {name}@{{{i}}}: {}",
+            entry.new.abbreviate(7)?,
+            entry.message
+        )?;
+    }
+    Ok(())
+}
+
+/// `name-rev`: only first parents are followed. Like git, names based on tags are preferred over the others,
+/// then the closest one wins.
+fn name_rev(revs: &[String]) -> anyhow::Result<()> {
+    let targets = revs
+        .iter()
+        .map(|rev| Ok(peel_to_commit(rev::resolve(rev)?).ok()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let shallow = shallow::shallow_commits()?;
+    // For each target: whether the name is based on a tag, the distance, the ref and whether it is annotated.
+    let mut names: Vec<Option<(bool, usize, String, bool)>> = vec![None; targets.len()];
+    for (name, hash) in refs::list_refs("refs/")? {
+        let annotated = ObjectReader::header(&hash)?.0 == ObjectKind::Tag;
+        // Tags can point to trees or blobs, which don't name commits.
+        let Ok(mut commit) = peel_to_commit(hash) else {
+            continue;
+        };
+        let from_tag = name.starts_with("refs/tags/");
+        for distance in 0.. {
+            for (target, best) in targets.iter().zip(&mut names) {
+                if target.as_ref() != Some(&commit) {
+                    continue;
+                }
+                let is_better = best
+                    .as_ref()
+                    .map_or(true, |(best_from_tag, best_distance, ..)| {
+                        (from_tag, std::cmp::Reverse(distance))
+                            > (*best_from_tag, std::cmp::Reverse(*best_distance))
+                    });
+                if is_better {
+                    *best = Some((from_tag, distance, name.clone(), annotated));
+                }
+            }
+            if shallow.contains(&commit) {
+                break;
+            }
+            let Some(parent) = Commit::read(&commit)?.parents.into_iter().next() else {
+                break;
+            };
+            commit = parent;
+        }
+    }
+
+    let mut out = io::stdout().lock();
+    for (rev, name) in revs.iter().zip(names) {
+        let Some((_, distance, name, annotated)) = name else {
+            writeln!(out, "{rev} undefined")?;
+            continue;
+        };
+        let name = name
+            .strip_prefix("refs/heads/")
+            .or_else(|| name.strip_prefix("refs/"))
+            .unwrap_or(&name);
+        match distance {
+            // `^0` tells the tag itself isn't the commit.
+            0 if annotated => writeln!(out, "{rev} {name}^0")?,
+            0 => writeln!(out, "{rev} {name}")?,
+            _ => writeln!(out, "{rev} {name}~{distance}")?,
+        }
+    }
+    Ok(())
+}
+
+/// The commits named by revisions, following annotated tags.
+fn resolve_commits(revs: &[String]) -> anyhow::Result<Vec<GitHexHash>> {
+    revs.iter()
+        .map(|rev| peel_to_commit(rev::resolve(rev)?))
+        .collect()
+}
+
+/// `ls-files`: the untracked files with `others`, then the index entries with `cached`, along with their mode,
+/// hash and stage when `stage` is set.
+fn ls_files(cached: bool, others: bool, exclude_standard: bool, stage: bool) -> anyhow::Result<()> {
+    let index = Index::read()?;
+    let quote_non_ascii = config::config()?
+        .get_bool("core.quotePath")?
+        .unwrap_or(true);
+    let mut stdout = io::stdout().lock();
+    // Like git, the untracked files come first.
+    if others {
+        for path in untracked_files(&index, exclude_standard)? {
+            writeln!(stdout, "{}", quote_path(&path, quote_non_ascii))?;
+        }
+    }
+    if cached {
+        // An unmerged file is listed once per stage, like git.
+        for entry in &index.entries {
+            let path = quote_path(&entry.path, quote_non_ascii);
+            if stage {
+                writeln!(
+                    stdout,
+                    "{:o} {} {}\t{path}",
+                    entry.mode, entry.hash, entry.stage
+                )?;
+            } else {
+                writeln!(stdout, "{path}")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The files of the working tree missing from the index, relative to its top and sorted. A repository inside it
+/// is listed as its directory, `sub/`, without looking at its files. With `exclude_standard`, the ignored files
+/// are left out and the ignored directories aren't even walked.
+fn untracked_files(index: &Index, exclude_standard: bool) -> anyhow::Result<Vec<String>> {
+    let work_tree = repo::work_tree()?;
+    let tracked: HashSet<&str> = index
+        .entries
+        .iter()
+        .map(|entry| entry.path.as_str())
+        .collect();
+    let mut untracked = Vec::new();
+    // Directories to walk, as prefixes ending with a slash.
+    let mut pending = vec![String::new()];
+    while let Some(dir) = pending.pop() {
+        let full_dir = work_tree.join(&dir);
+        for entry in fs::read_dir(&full_dir).context(format!("reading {full_dir:?}"))? {
+            let entry = entry?;
+            // Git can't name a file that isn't UTF-8 in this repository anyway.
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if name == ".git" {
+                continue;
+            }
+            let path = format!("{dir}{name}");
+            if tracked.contains(path.as_str()) {
+                continue;
+            }
+            // Symbolic links are files, never followed.
+            let is_dir = entry.file_type()?.is_dir();
+            if exclude_standard && ignore::is_ignored(&path, is_dir)? {
+                continue;
+            }
+            if !is_dir {
+                untracked.push(path);
+            } else if repo::open_dot_git(&entry.path().join(".git"))?.is_some() {
+                untracked.push(format!("{path}/"));
+            } else {
+                pending.push(format!("{path}/"));
+            }
+        }
+    }
+    untracked.sort();
+    Ok(untracked)
+}
+
+/// `log` in git's default format, `medium`.
+fn log(revs: &[String], abbrev: &AbbrevOptions) -> anyhow::Result<()> {
+    let mut stdout = io::stdout().lock();
+    for (i, (hash, commit)) in commit::walk(resolve_commits(revs)?)?.iter().enumerate() {
+        if i > 0 {
+            writeln!(stdout)?;
+        }
+        writeln!(stdout, "commit {}", abbrev.commit(hash)?)?;
+        if commit.parents.len() > 1 {
+            let parents = commit
+                .parents
+                .iter()
+                .map(|parent| abbrev.other(parent))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            writeln!(stdout, "Merge: {}", parents.join(" "))?;
+        }
+        let (author, date) = commit::parse_ident(&commit.author);
+        writeln!(stdout, "Author: {author}")?;
+        if let Some(date) = date {
+            writeln!(stdout, "Date:   {}", date.to_human())?;
+        }
+        writeln!(stdout)?;
+        for line in commit.message.lines() {
+            writeln!(stdout, "    {line}")?;
+        }
+    }
+    Ok(())
+}
+
+/// Write the name, tagger and message of the annotated tags `object` leads to, and return the first object
+/// that isn't a tag.
+fn show_tags(object: &str, out: &mut dyn Write) -> anyhow::Result<GitHexHash> {
+    let mut hash = rev::resolve(object)?;
+    while ObjectReader::header(&hash)?.0 == ObjectKind::Tag {
+        let tag = Tag::read(&hash)?;
+        writeln!(out, "tag {}", tag.name)?;
+        let (tagger, date) = commit::parse_ident(&tag.tagger);
+        writeln!(out, "Tagger: {tagger}")?;
+        if let Some(date) = date {
+            writeln!(out, "Date:   {}", date.to_human())?;
+        }
+        writeln!(out, "\n{}\n", tag.message)?;
+        hash = tag.object;
+    }
+    Ok(hash)
+}
+
+/// Like `git diff`: the changes of the working tree against the index, or with `cached` the changes of the
+/// index against HEAD, returns whether there are any. Both content and mode changes are shown, unless `quiet`
+/// where nothing is and the first change is enough.
+fn diff(cached: bool, quiet: bool, paths: &[PathBuf]) -> anyhow::Result<bool> {
+    let index = Index::read()?;
+    let pathspecs = paths
+        .iter()
+        .map(|path| Ok(pathspec(path)?.1))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let selected = |path: &str| {
+        pathspecs.is_empty()
+            || pathspecs
+                .iter()
+                .any(|pathspec| matches_pathspec(path, pathspec))
+    };
+    let quote_non_ascii = config::config()?
+        .get_bool("core.quotePath")?
+        .unwrap_or(true);
+    let (changes, mut work_tree_contents) = changed_files(&index, cached, selected)?;
+
+    let mut stdout = io::stdout().lock();
+    let mut differs = false;
+    for (path, sides) in changes {
+        if sides.as_ref().is_some_and(|[old, new]| old == new) {
+            continue;
+        }
+        differs = true;
+        if quiet {
+            break;
+        }
+        let Some([old, new]) = sides else {
+            writeln!(stdout, "* Unmerged path {path}")?;
+            continue;
+        };
+        let old = old
+            .map(|(mode, hash)| stored_side(mode, hash))
+            .transpose()?;
+        let new = match (new, work_tree_contents.remove(&path)) {
+            (Some((mode, hash)), Some(content)) => Some(diff::Side {
+                mode,
+                hash,
+                content,
+            }),
+            (new, _) => new
+                .map(|(mode, hash)| stored_side(mode, hash))
+                .transpose()?,
+        };
+        diff::write_file_diff(
+            &mut stdout,
+            &path,
+            old.as_ref(),
+            new.as_ref(),
+            quote_non_ascii,
+        )?;
+    }
+    Ok(differs)
+}
+
+/// The mode and hash of a file on each side of a comparison, `None` on the side without it.
+type Sides = [Option<(u32, GitHexHash)>; 2];
+
+/// The sides of the files compared, `None` for an unmerged file.
+type Changes = BTreeMap<String, Option<Sides>>;
+
+/// The files of the index compared with the working tree, or with HEAD when `cached`, among the `selected`
+/// paths, unchanged ones possibly included with equal sides. The working tree content read to hash a file is
+/// returned too, so it isn't read again.
+fn changed_files(
+    index: &Index,
+    cached: bool,
+    selected: impl Fn(&str) -> bool,
+) -> anyhow::Result<(Changes, HashMap<String, Vec<u8>>)> {
+    let work_tree = repo::work_tree()?;
+    let mut changes = Changes::new();
+    let mut work_tree_contents = HashMap::new();
+    if cached {
+        if let Some(head) = refs::read_ref("HEAD")? {
+            for (path, mode, hash) in flatten_tree(&Commit::read(&head)?.tree)? {
+                if selected(&path) {
+                    changes.insert(path, Some([Some((mode, hash)), None]));
+                }
+            }
+        }
+    }
+    for entry in index.entries.iter().filter(|entry| selected(&entry.path)) {
+        if entry.stage != 0 {
+            changes.insert(entry.path.clone(), None);
+            continue;
+        }
+        if cached {
+            if let Some(sides) = changes
+                .entry(entry.path.clone())
+                .or_insert(Some([None, None]))
+            {
+                sides[1] = Some((entry.mode, entry.hash.clone()));
+            }
+            continue;
+        }
+        // Submodules aren't looked into.
+        if entry.mode == Mode::Gitlink.bits() {
+            continue;
+        }
+        let full_path = work_tree.join(&entry.path);
+        let new = match fs::symlink_metadata(&full_path) {
+            Ok(metadata) if !metadata.is_dir() => {
+                if Stat::from_metadata(&metadata) == entry.stat {
+                    continue;
+                }
+                let (mode, content) = if metadata.is_symlink() {
+                    let target = fs::read_link(&full_path)?;
+                    let target = target.to_str().context("non UTF-8 symbolic link")?;
+                    (Mode::Symlink.bits(), target.as_bytes().to_vec())
+                } else {
+                    let content = match normalize_crlf(&full_path, &full_path)? {
+                        Some(normalized) => normalized,
+                        None => fs::read(&full_path).context(format!("reading {full_path:?}"))?,
+                    };
+                    let mode = regular_file_mode(&metadata, Some(entry.mode))?;
+                    (mode.bits(), content)
+                };
+                let hash =
+                    hash_content("blob", content.len() as u64, &mut content.as_slice(), false)?;
+                work_tree_contents.insert(entry.path.clone(), content);
+                Some((mode, hash))
+            }
+            _ => None,
+        };
+        changes.insert(
+            entry.path.clone(),
+            Some([Some((entry.mode, entry.hash.clone())), new]),
+        );
+    }
+    Ok((changes, work_tree_contents))
+}
+
+/// A side of a diff from the object store. A submodule shows as the commit it points to, like with git.
+fn stored_side(mode: u32, hash: GitHexHash) -> anyhow::Result<diff::Side> {
+    let content = if mode == Mode::Gitlink.bits() {
+        format!("Subproject commit {hash}\n").into_bytes()
+    } else {
+        let mut content = Vec::new();
+        ObjectReader::from_sha1(hash.clone())?
+            .into_body_reader()
+            .read_to_end(&mut content)
+            .context(format!("reading blob {hash}"))?;
+        content
+    };
+    Ok(diff::Side {
+        mode,
+        hash,
+        content,
+    })
+}
+
+fn verify_commit(rev: &str) -> anyhow::Result<()> {
+    let hash = rev::resolve(rev)?;
+    let object = ObjectReader::from_sha1(hash)?;
+    let ObjectReader::Commit(mut reader) = object else {
+        bail!("{rev}: cannot verify a non-commit object of type {object}");
+    };
+    let mut content = Vec::new();
+    reader.read_to_end(&mut content)?;
+    let signature = Commit::parse(content.as_slice())?
+        .signature
+        .context(format!("{rev}: no signature found"))?;
+    gpg::verify(&commit::signed_payload(&content), &signature)
+}
+
+fn verify_tag(rev: &str) -> anyhow::Result<()> {
+    let hash = rev::resolve(rev)?;
+    let object = ObjectReader::from_sha1(hash)?;
+    let ObjectReader::Tag(mut reader) = object else {
+        bail!("{rev}: cannot verify a non-tag object of type {object}.");
+    };
+    let mut content = Vec::new();
+    reader.read_to_end(&mut content)?;
+    let signature = Tag::parse(content.as_slice())?
+        .signature
+        .context(format!("{rev}: no signature found"))?;
+    gpg::verify(tag::signed_payload(&content), &signature)
+}
+
+fn list_branches() -> anyhow::Result<()> {
+    let current = refs::head_target()?;
+    let mut stdout = io::stdout().lock();
+    for (name, _) in refs::list_refs("refs/heads/")? {
+        let marker = if current.as_ref() == Some(&name) {
+            '*'
+        } else {
+            ' '
+        };
+        let short_name = name.strip_prefix("refs/heads/").unwrap_or(&name);
+        writeln!(stdout, "{marker} {short_name}")?;
+    }
+    Ok(())
+}
+
+// Without `old`, the current branch is renamed, which may not have any commit yet.
+fn rename_branch(old: Option<&str>, new: &str, force: bool) -> anyhow::Result<()> {
+    let current = refs::head_target()?;
+    let old_name = match old {
+        Some(old) => format!("refs/heads/{old}"),
+        None => current
+            .clone()
+            .context("cannot rename the current branch while not on any branch")?,
+    };
+    let new_name = format!("refs/heads/{new}");
+    ensure!(
+        refs::is_valid_name(&new_name),
+        "'{new}' is not a valid branch name"
+    );
+    let Some(hash) = refs::read_ref(&old_name)? else {
+        if current.as_ref() == Some(&old_name) {
+            return refs::write_symbolic_ref("HEAD", &new_name);
+        }
+        bail!("No branch named '{}'.", refs::shorten_name(&old_name));
+    };
+    if new_name == old_name {
+        return Ok(());
+    }
+    ensure!(
+        force || refs::read_ref(&new_name)?.is_none(),
+        "a branch named '{new}' already exists"
+    );
+    refs::rename_ref(&old_name, &new_name, &hash)
+}
+
+// Sorted by name, like `git tag -l`.
+fn list_tags(patterns: &[String]) -> anyhow::Result<()> {
+    let mut stdout = io::stdout().lock();
+    for (name, _) in refs::list_refs("refs/tags/")? {
+        let short_name = name.strip_prefix("refs/tags/").unwrap_or(&name);
+        let matches = patterns.is_empty()
+            || patterns.iter().any(|pattern| {
+                attributes::wildmatch_name(pattern.as_bytes(), short_name.as_bytes())
+            });
+        if matches {
+            writeln!(stdout, "{short_name}")?;
+        }
+    }
+    Ok(())
+}
+
+fn delete_tags(names: &[String]) -> anyhow::Result<()> {
+    for name in names {
+        let Some(old) = refs::delete_ref(&format!("refs/tags/{name}"))? else {
+            bail!("tag '{name}' not found.");
+        };
+        verbosity::info(format!("Deleted tag '{name}' (was {})", old.abbreviate(7)?));
+    }
+    Ok(())
+}
+
+/// `<tag>-<n>-g<abbreviated hash>` where `n` is the number of commits since the closest annotated tag, or only
+/// `<tag>` when the commit is tagged.
+fn describe(commit: GitHexHash) -> anyhow::Result<String> {
+    let commit = peel_to_commit(commit)?;
+    let mut tagged = Vec::new();
+    for (name, hash) in refs::list_refs("refs/tags/")? {
+        // Lightweight tags point directly to the commit, they are only used with `git describe --tags`.
+        if ObjectReader::header(&hash)?.0 != ObjectKind::Tag {
+            continue;
+        }
+        let name = name.strip_prefix("refs/tags/").unwrap_or(&name).to_string();
+        tagged.push((name, peel_to_commit(hash)?));
+    }
+    ensure!(
+        !tagged.is_empty(),
+        "No names found, cannot describe anything."
+    );
+
+    let ancestors = commit::ancestors(&commit)?;
+    let mut best: Option<(usize, String)> = None;
+    for (name, tagged_commit) in tagged {
+        if !ancestors.contains(&tagged_commit) {
+            continue;
+        }
+        // The commits reachable from the described commit but not from the tag.
+        let depth = ancestors.len() - commit::ancestors(&tagged_commit)?.len();
+        if best
+            .as_ref()
+            .map_or(true, |(best_depth, _)| depth < *best_depth)
+        {
+            best = Some((depth, name));
+        }
+    }
+    let Some((depth, name)) = best else {
+        bail!("No annotated tags can describe '{commit}'.");
+    };
+    if depth == 0 {
+        return Ok(name);
+    }
+    Ok(format!("{name}-{depth}-g{}", commit.abbreviate(7)?))
+}
+
+/// The entry at `path` (`dir/file`) in a tree or its sub-trees.
+fn find_tree_entry(tree: &GitHexHash, path: &str) -> anyhow::Result<Option<TreeEntry>> {
+    let mut tree = tree.clone();
+    let mut components = path.split('/').peekable();
+    while let Some(name) = components.next() {
+        let ObjectReader::Tree(reader) = ObjectReader::from_sha1(tree.clone())? else {
+            bail!("{tree} is not a valid 'tree' object");
+        };
+        let mut found = None;
+        for entry in TreeEntries::new(reader)? {
+            let entry = entry?;
+            if entry.name == name {
+                found = Some(entry);
+                break;
+            }
+        }
+        let Some(entry) = found else {
+            return Ok(None);
+        };
+        if components.peek().is_none() {
+            return Ok(Some(entry));
+        }
+        if !entry.is_tree() {
+            return Ok(None);
+        }
+        tree = entry.hash;
+    }
+    Ok(None)
+}
+
+/// Follow annotated tags until reaching a commit.
+fn peel_to_commit(hash: GitHexHash) -> anyhow::Result<GitHexHash> {
+    let mut current = hash;
+    loop {
+        match ObjectReader::from_sha1(current.clone())? {
+            ObjectReader::Commit(_) => return Ok(current),
+            ObjectReader::Tag(reader) => {
+                let tag = Tag::parse(reader).context(format!("parsing tag {current}"))?;
+                ensure!(
+                    matches!(tag.kind, ObjectKind::Commit | ObjectKind::Tag),
+                    "{current} is not a valid 'commit' object"
+                );
+                current = tag.object;
+            }
+            _ => bail!("{current} is not a valid 'commit' object"),
+        }
+    }
+}
+
+fn hash_object(
+    file: &Path,
+    write: bool,
+    options: &HashObjectOptions,
+) -> anyhow::Result<GitHexHash> {
+    // 1. Add the header
+    // 2. Hash the object and compress it at the same time (so we need to read the whole file once). The compression is directly writen to a tmp file to avoid loading the whole file in memory
+    // 3. Rename the temp file with the hash name
+    let kind = options.kind(|| fs::read(file).context(format!("reading {file:?}")))?;
+    let kind = kind.as_str();
+    // Only blobs are file contents, subject to filters.
+    let normalized = if options.literally || kind != "blob" {
+        None
+    } else {
+        normalize_crlf(file, options.path.as_deref().unwrap_or(file))?
+    };
+    let (file_len, mut content): (u64, Box<dyn Read>) = match normalized {
+        // Normalization changes the length, so we can't use the file metadata.
+        Some(normalized) => (
+            normalized.len() as u64,
+            Box::new(io::Cursor::new(normalized)),
+        ),
+        None => {
+            // Getting length ahead won't work with stdin. We also hope that the file don't get modified until we write it,
+            // otherwise we could encode a bad length.
+            // We don't want to read the whole file into memory to compute the len, so we use stat.
+            let file_len = fs::metadata(file)
+                .context(format!("get {file:?} metadata"))?
+                .len();
+            let opened_file = fs::File::open(file).context(format!("open {file:?}"))?;
+            (file_len, Box::new(opened_file))
+        }
+    };
+    hash_content(kind, file_len, &mut content, write)
+}
+
+/// `hash-object --with-header`: the header is read from the input instead of being added, and the content must
+/// have the size it announces.
+fn hash_serialized(mut input: impl BufRead, write: bool) -> anyhow::Result<GitHexHash> {
+    let (kind, size) = read_raw_header(&mut input).context("reading the object header")?;
+    let kind = ObjectKind::parse(&kind)?;
+    let mut content = ExactSize {
+        inner: input,
+        remaining: size,
+    };
+    hash_content(&kind.to_string(), size, &mut content, write)
+}
+
+/// `hash-object --stdin-paths`: a hash is printed as soon as each path is read, so the command can be driven
+/// interactively.
+fn hash_stdin_paths(write: bool, options: &HashObjectOptions) -> anyhow::Result<()> {
+    let mut out = io::stdout().lock();
+    for line in io::stdin().lock().lines() {
+        // Only the line terminator is removed, whitespace can be part of the file name.
+        let path = line.context("reading paths from stdin")?;
+        let hash = hash_object(Path::new(&path), write, options)
+            .map_err(|err| anyhow!("could not hash '{path}': {}", err.root_cause()))?;
+        writeln!(out, "{hash}")?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+/// `hash-object --stdin`: the header comes first and holds the length, which we only know after reading
+/// everything.
+///
+/// Rather than holding the content in memory, it is spooled uncompressed to a temporary file while counting
+/// its bytes, then streamed from there like a regular file. Writing the header afterwards in place isn't an
+/// option: header and content are a single zlib stream, and the hash covers the header first.
+fn hash_stdin(write: bool, options: &HashObjectOptions) -> anyhow::Result<GitHexHash> {
+    let (spool_path, mut spool) = create_temp_file(&env::temp_dir(), "stdin-tempfile")?;
+    // Unlinked right away, the open handle is enough to read it back.
+    let _ = fs::remove_file(&spool_path);
+    let len = io::copy(&mut io::stdin().lock(), &mut spool).context("reading stdin")?;
+    spool.seek(SeekFrom::Start(0))?;
+    let kind = options.kind(|| {
+        let mut content = Vec::new();
+        spool.read_to_end(&mut content)?;
+        spool.seek(SeekFrom::Start(0))?;
+        Ok(content)
+    })?;
+    // Like git, the content is only filtered when we know where it belongs.
+    let action = match &options.path {
+        Some(path) if kind == "blob" => crlf_action(tree_path(path).as_deref())?,
+        _ => CrlfAction::None,
+    };
+    if action != CrlfAction::None {
+        let mut content = Vec::new();
+        spool.read_to_end(&mut content)?;
+        if let Some(normalized) = crlf_to_lf(&content, action) {
+            return hash_content(
+                &kind,
+                normalized.len() as u64,
+                &mut normalized.as_slice(),
+                write,
+            );
+        }
+        spool.seek(SeekFrom::Start(0))?;
+    }
+    hash_content(&kind, len, &mut spool, write)
+}
+
+/// Hash `<kind> <len>\0<content>`, and store it when `write` is set.
+fn hash_content(
+    kind: &str,
+    file_len: u64,
+    content: &mut dyn Read,
+    write: bool,
+) -> anyhow::Result<GitHexHash> {
+    let algorithm = HashAlgorithm::current()?;
+    Ok(if write {
+        write_object(kind, file_len, |out| io::copy(content, out).map(|_| ()))?
+    } else {
+        let mut hasher = algorithm.hasher();
+        write!(hasher, "{kind} {file_len}\0")?;
+        io::copy(content, &mut hasher)?;
+        hasher.finalize()
+    })
+}
+
+fn update_index(
+    add: bool,
+    remove: bool,
+    cacheinfo: Vec<CacheInfo>,
+    refresh: bool,
+    paths: &[PathBuf],
+) -> anyhow::Result<()> {
+    let mut index = Index::lock()?;
+    for CacheInfo { mode, hash, path } in cacheinfo {
+        let (_, path) = work_tree_path(&path)?;
+        // Not backed by a file, so there is no stat data: git will compare the content on the next refresh.
+        index.insert(IndexEntry::new(path, mode, hash, Stat::default()));
+    }
+    for path in paths {
+        let (full_path, path) = work_tree_path(path)?;
+        let metadata = match fs::symlink_metadata(&full_path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                ensure!(
+                    remove,
+                    "Unable to process path {path}: does not exist and --remove not passed"
+                );
+                index.remove(&path);
+                continue;
+            }
+            Err(err) => return Err(err).context(format!("reading {full_path:?}")),
+        };
+        ensure!(
+            add || index.get(&path).is_some(),
+            "Unable to process path {path}: cannot add to the index - missing --add option?"
+        );
+        let staged_mode = index.get(&path).map(|entry| entry.mode);
+        index.insert(index_entry(&full_path, path, &metadata, staged_mode, true)?);
+    }
+    if refresh {
+        refresh_index(&mut index)?;
+    }
+    index.commit()
+}
+
+/// Hash a file of the working tree and build its index entry, storing the blob when `write` is set.
+fn index_entry(
+    full_path: &Path,
+    path: String,
+    metadata: &fs::Metadata,
+    staged_mode: Option<u32>,
+    write: bool,
+) -> anyhow::Result<IndexEntry> {
+    ensure!(
+        !metadata.is_dir(),
+        "{path}: is a directory - add files inside instead"
+    );
+    let (mode, hash) = if metadata.is_symlink() {
+        // A symbolic link is stored as a blob of its target.
+        let target = fs::read_link(full_path)?;
+        let target = target.to_str().context("non UTF-8 symbolic link")?;
+        (
+            Mode::Symlink.bits(),
+            hash_content("blob", target.len() as u64, &mut target.as_bytes(), write)?,
+        )
+    } else {
+        (
+            regular_file_mode(metadata, staged_mode)?.bits(),
+            hash_object(full_path, write, &HashObjectOptions::default())?,
+        )
+    };
+    Ok(IndexEntry::new(
+        path,
+        mode,
+        hash,
+        Stat::from_metadata(metadata),
+    ))
+}
+
+/// The mode of a regular file, executable or not. With `core.fileMode=false`, for file systems without an
+/// executable bit, it isn't trusted: the mode already staged is kept, 100644 for new files.
+fn regular_file_mode(metadata: &fs::Metadata, staged_mode: Option<u32>) -> anyhow::Result<Mode> {
+    if !config::config()?.get_bool("core.fileMode")?.unwrap_or(true) {
+        return Ok(match staged_mode.map(Mode::from_bits) {
+            Some(Ok(Mode::Executable)) => Mode::Executable,
+            _ => Mode::RegularFile,
+        });
+    }
+    Ok(if metadata.permissions().mode() & 0o111 != 0 {
+        Mode::Executable
+    } else {
+        Mode::RegularFile
+    })
+}
+
+/// Update the stat data of the entries whose content didn't change, so later commands don't hash them again.
+fn refresh_index(index: &mut LockedIndex) -> anyhow::Result<()> {
+    let work_tree = repo::work_tree()?;
+    let mut needs_update = false;
+    for entry in index.entries.iter_mut().filter(|entry| entry.stage == 0) {
+        let full_path = work_tree.join(&entry.path);
+        let Ok(metadata) = fs::symlink_metadata(&full_path) else {
+            println!("{}: needs update", entry.path);
+            needs_update = true;
+            continue;
+        };
+        let stat = Stat::from_metadata(&metadata);
+        if stat == entry.stat {
+            continue;
+        }
+        if !is_modified(entry, &full_path, &metadata)? {
+            entry.stat = stat;
+        } else {
+            println!("{}: needs update", entry.path);
+            needs_update = true;
+        }
+    }
+    // Like git, the stat data of the up to date entries is saved anyway.
+    if needs_update {
+        index.commit()?;
+        bail!("some files need update");
+    }
+    Ok(())
+}
+
+/// Whether the working tree file differs from its index entry, in content or mode.
+fn is_modified(
+    entry: &IndexEntry,
+    full_path: &Path,
+    metadata: &fs::Metadata,
+) -> anyhow::Result<bool> {
+    // A submodule only changes when another commit is checked out in it, it isn't hashed like a file. Not
+    // checked out, it's an empty directory.
+    if entry.mode == Mode::Gitlink.bits() {
+        if !metadata.is_dir() {
+            return Ok(true);
+        }
+        return Ok(match repo::open_dot_git(&full_path.join(".git"))? {
+            Some(submodule) => {
+                refs::read_ref_at(&submodule, "HEAD")?.is_some_and(|head| head != entry.hash)
+            }
+            None => false,
+        });
+    }
+    // Same stat data, the file wasn't touched since it was staged.
+    if Stat::from_metadata(metadata) == entry.stat {
+        return Ok(false);
+    }
+    let fresh = index_entry(
+        full_path,
+        entry.path.clone(),
+        metadata,
+        Some(entry.mode),
+        false,
+    )?;
+    Ok(fresh.hash != entry.hash || fresh.mode != entry.mode)
+}
+
+/// Remove files from the index and, unless `cached` is set, from the working tree.
+///
+/// Like git, without `force` nothing is removed if one of the files has changes that would be lost.
+fn rm(paths: &[PathBuf], cached: bool, force: bool) -> anyhow::Result<()> {
+    let mut index = Index::lock()?;
+    let head_tree = match refs::read_ref("HEAD")? {
+        Some(head) => Some(Commit::read(&head)?.tree),
+        None => None,
+    };
+
+    let mut removed = Vec::new();
+    for path in paths {
+        let (full_path, path) = work_tree_path(path)?;
+        let entry = index
+            .get(&path)
+            .context(format!("pathspec '{path}' did not match any files"))?;
+        if !force {
+            let committed = match &head_tree {
+                Some(tree) => find_tree_entry(tree, &path)?.map(|committed| committed.hash),
+                None => None,
+            };
+            let staged = committed.as_ref() != Some(&entry.hash);
+            let modified = match fs::symlink_metadata(&full_path) {
+                Ok(metadata) => is_modified(entry, &full_path, &metadata)?,
+                // Already deleted from the working tree, there is nothing to lose.
+                Err(_) => false,
+            };
+            if staged && modified {
+                bail!("the following file has staged content different from both the\nfile and the HEAD:\n    {path}\n(use -f to force removal)");
+            }
+            if !cached {
+                ensure!(!staged, "the following file has changes staged in the index:\n    {path}\n(use --cached to keep the file, or -f to force removal)");
+                ensure!(!modified, "the following file has local modifications:\n    {path}\n(use --cached to keep the file, or -f to force removal)");
+            }
+        }
+        removed.push((full_path, path));
+    }
+
+    let work_tree = repo::work_tree()?;
+    for (full_path, path) in removed {
+        index.remove(&path);
+        verbosity::info(format_args!("rm '{path}'"));
+        if cached {
+            continue;
+        }
+        match fs::remove_file(&full_path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                return Err(err).context(format!("removing {full_path:?}"))
+            }
+            _ => {}
+        }
+        // Git doesn't track directories, so the ones left empty go away too.
+        for dir in full_path.ancestors().skip(1) {
+            if dir == work_tree || fs::remove_dir(dir).is_err() {
+                break;
+            }
+        }
+    }
+    index.commit()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ResetMode {
+    Soft,
+    Mixed,
+    Hard,
+}
+
+/// Whether `mode` is the one of a regular file, executable or not.
+fn is_regular_file(mode: u32) -> bool {
+    matches!(
+        Mode::from_bits(mode),
+        Ok(Mode::RegularFile | Mode::Executable)
+    )
+}
+
+/// Forget an interrupted merge, once committed or reset.
+fn remove_merge_state() -> anyhow::Result<()> {
+    let git_dir = repo::git_dir()?;
+    for name in ["MERGE_HEAD", "MERGE_MSG", "MERGE_MODE"] {
+        match fs::remove_file(git_dir.join(name)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                return Err(err).context(format!("removing {name}"))
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Point the current branch, or HEAD when detached, to `rev`, then update the index and working tree according
+/// to `mode`.
+///
+/// Unlike git, `--hard` refuses to discard changes that were never committed unless `force` is set: they
+/// couldn't be recovered from the reflog.
+fn reset(rev: &str, mode: ResetMode, force: bool) -> anyhow::Result<()> {
+    let commit = peel_to_commit(rev::resolve(rev)?)?;
+    let Commit { tree, message, .. } = Commit::read(&commit)?;
+    let work_tree = repo::work_tree()?;
+    let mut index = Index::lock()?;
+
+    if mode == ResetMode::Hard && !force {
+        let head_tree = match refs::read_ref("HEAD")? {
+            Some(head) => Some(Commit::read(&peel_to_commit(head)?)?.tree),
+            None => None,
+        };
+        let committed = match &head_tree {
+            Some(tree) => flatten_tree(tree)?
+                .into_iter()
+                .map(|(path, mode, hash)| (path, (mode, hash)))
+                .collect(),
+            None => HashMap::new(),
+        };
+        let mut changed = Vec::new();
+        for entry in &index.entries {
+            let staged = entry.stage != 0
+                || committed.get(&entry.path) != Some(&(entry.mode, entry.hash.clone()));
+            let full_path = work_tree.join(&entry.path);
+            let modified = match fs::symlink_metadata(&full_path) {
+                Ok(metadata) => is_modified(entry, &full_path, &metadata)?,
+                Err(_) => true,
+            };
+            if (staged || modified) && !changed.contains(&entry.path) {
+                changed.push(entry.path.clone());
+            }
+        }
+        for path in committed.keys() {
+            if index.get(path).is_none() && !changed.contains(path) {
+                changed.push(path.clone());
+            }
+        }
+        if !changed.is_empty() {
+            bail!(
+                "Your local changes to the following files would be discarded:\n\t{}\n(use --force to discard them)",
+                changed.join("\n\t")
+            );
+        }
+    }
+
+    let branch = refs::head_target()?;
+    refs::update_ref(
+        branch.as_deref().unwrap_or("HEAD"),
+        &commit,
+        &format!("reset: moving to {rev}"),
+    )?;
+    // Like git, resetting abandons a merge in progress.
+    remove_merge_state()?;
+    if mode == ResetMode::Soft {
+        return Ok(());
+    }
+
+    let files = flatten_tree(&tree)?;
+    let mut previous = std::mem::take(&mut index.entries)
+        .into_iter()
+        .map(|entry| (entry.path.clone(), entry))
+        .collect::<HashMap<_, _>>();
+    if mode == ResetMode::Hard {
+        // The tracked files that the commit doesn't have.
+        for (path, entry) in previous
+            .iter()
+            .filter(|(path, _)| !files.iter().any(|(file, _, _)| file == *path))
+        {
+            let full_path = work_tree.join(path);
+            if is_checked_out_gitlink(entry, &full_path) {
+                continue;
+            }
+            match fs::remove_file(&full_path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    return Err(err).context(format!("removing {full_path:?}"))
+                }
+                _ => {}
+            }
+            for dir in full_path.ancestors().skip(1) {
+                if dir == work_tree || fs::remove_dir(dir).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    for (path, mode_bits, hash) in files {
+        let full_path = work_tree.join(&path);
+        // The stat data stays valid for the files left as they were, so they aren't seen as modified.
+        let unchanged = previous
+            .remove(&path)
+            .filter(|entry| entry.stage == 0 && entry.mode == mode_bits && entry.hash == hash);
+        let mut entry = IndexEntry::new(path, mode_bits, hash, Stat::default());
+        if let Some(unchanged) = unchanged {
+            entry.stat = unchanged.stat;
+        }
+        if mode == ResetMode::Hard {
+            let up_to_date = match fs::symlink_metadata(&full_path) {
+                Ok(metadata) if !is_modified(&entry, &full_path, &metadata)? => {
+                    entry.stat = Stat::from_metadata(&metadata);
+                    true
+                }
+                _ => false,
+            };
+            if !up_to_date && is_checked_out_gitlink(&entry, &full_path) {
+                entry.stat = Stat::from_metadata(&fs::symlink_metadata(&full_path)?);
+            } else if !up_to_date {
+                if full_path.is_dir() && !full_path.is_symlink() {
+                    fs::remove_dir_all(&full_path)
+                } else {
+                    fs::remove_file(&full_path)
+                }
+                .or_else(|err| match err.kind() {
+                    io::ErrorKind::NotFound => Ok(()),
+                    _ => Err(err),
+                })
+                .context(format!("removing {full_path:?}"))?;
+                checkout_entry(&entry, &full_path)?;
+                entry.stat = Stat::from_metadata(&fs::symlink_metadata(&full_path)?);
+            }
+        }
+        index.insert(entry);
+    }
+    index.commit()?;
+
+    if mode == ResetMode::Hard {
+        let summary = message.lines().next().unwrap_or_default();
+        verbosity::info(format_args!(
+            "HEAD is now at {} {summary}",
+            commit.abbreviate(7)?
+        ));
+        return Ok(());
+    }
+    let mut unstaged = Vec::new();
+    for entry in &index.entries {
+        let full_path = work_tree.join(&entry.path);
+        match fs::symlink_metadata(&full_path) {
+            Ok(metadata) if !is_modified(entry, &full_path, &metadata)? => {}
+            Ok(_) => unstaged.push(format!("M\t{}", entry.path)),
+            Err(_) => unstaged.push(format!("D\t{}", entry.path)),
+        }
+    }
+    if !unstaged.is_empty() {
+        verbosity::info(format_args!(
+            "Unstaged changes after reset:\n{}",
+            unstaged.join("\n")
+        ));
+    }
+    Ok(())
+}
+
+/// Restore files of the working tree from the index, or from the tree of `source`. With `staged`, restore
+/// the index entries instead, from HEAD by default.
+fn restore(
+    paths: &[PathBuf],
+    source: Option<&str>,
+    staged: bool,
+    worktree: bool,
+) -> anyhow::Result<()> {
+    let work_tree = repo::work_tree()?;
+    let mut index = Index::lock()?;
+    // Without `--source`, the index is restored from HEAD and the working tree from the index.
+    let source = match source {
+        Some(rev) => Some(rev),
+        None if staged => Some("HEAD"),
+        None => None,
+    };
+    let source_files = match source {
+        Some(rev) => {
+            let tree = Commit::read(&peel_to_commit(rev::resolve(rev)?)?)?.tree;
+            Some(flatten_tree(&tree)?)
+        }
+        None => None,
+    };
+
+    let mut pathspecs = Vec::new();
+    for path in paths {
+        let (_, pathspec) = pathspec(path)?;
+        let known = index
+            .entries
+            .iter()
+            .map(|entry| entry.path.as_str())
+            .chain(
+                source_files
+                    .iter()
+                    .flatten()
+                    .map(|(path, _, _)| path.as_str()),
+            )
+            .any(|path| matches_pathspec(path, &pathspec));
+        ensure!(
+            known,
+            "pathspec '{}' did not match any file(s) known to git",
+            path.display()
+        );
+        pathspecs.push(pathspec);
+    }
+    let selected = |path: &str| {
+        pathspecs
+            .iter()
+            .any(|pathspec| matches_pathspec(path, pathspec))
+    };
+
+    // The entries to restore from, the ones missing there are removed.
+    let entries: Vec<IndexEntry> = match &source_files {
+        Some(files) => files
+            .iter()
+            .filter(|(path, _, _)| selected(path))
+            .map(|(path, mode, hash)| {
+                IndexEntry::new(path.clone(), *mode, hash.clone(), Stat::default())
+            })
+            .collect(),
+        None => {
+            let unmerged = index
+                .entries
+                .iter()
+                .find(|entry| entry.stage != 0 && selected(&entry.path));
+            if let Some(entry) = unmerged {
+                bail!("path '{}' is unmerged", entry.path);
+            }
+            index
+                .entries
+                .iter()
+                .filter(|entry| selected(&entry.path))
+                .map(|entry| {
+                    IndexEntry::new(
+                        entry.path.clone(),
+                        entry.mode,
+                        entry.hash.clone(),
+                        Stat::default(),
+                    )
+                })
+                .collect()
+        }
+    };
+    let removed: Vec<String> = index
+        .entries
+        .iter()
+        .filter(|entry| selected(&entry.path))
+        .filter(|entry| !entries.iter().any(|restored| restored.path == entry.path))
+        .map(|entry| entry.path.clone())
+        .collect();
+
+    if worktree {
+        for path in &removed {
+            let full_path = work_tree.join(path);
+            if index
+                .get(path)
+                .is_some_and(|entry| is_checked_out_gitlink(entry, &full_path))
+            {
+                continue;
+            }
+            match fs::remove_file(&full_path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    return Err(err).context(format!("removing {full_path:?}"))
+                }
+                _ => {}
+            }
+        }
+        for entry in &entries {
+            let full_path = work_tree.join(&entry.path);
+            if is_checked_out_gitlink(entry, &full_path) {
+                continue;
+            }
+            if full_path.is_dir() && !full_path.is_symlink() {
+                fs::remove_dir_all(&full_path)
+            } else {
+                fs::remove_file(&full_path)
+            }
+            .or_else(|err| match err.kind() {
+                io::ErrorKind::NotFound => Ok(()),
+                _ => Err(err),
+            })
+            .context(format!("removing {full_path:?}"))?;
+            checkout_entry(entry, &full_path)?;
+        }
+    }
+
+    // The index changes with `staged`, otherwise only the stat data of the files written from it.
+    for mut entry in entries {
+        let unchanged = index
+            .get(&entry.path)
+            .is_some_and(|current| current.mode == entry.mode && current.hash == entry.hash);
+        if (unchanged && !worktree) || (!unchanged && !staged) {
+            continue;
+        }
+        if worktree {
+            entry.stat = Stat::from_metadata(&fs::symlink_metadata(work_tree.join(&entry.path))?);
+        }
+        index.insert(entry);
+    }
+    if staged {
+        for path in &removed {
+            index.remove(path);
+        }
+    }
+    index.commit()
+}
+
+fn checkout_index(
+    all: bool,
+    force: bool,
+    prefix: Option<&str>,
+    paths: &[PathBuf],
+) -> anyhow::Result<()> {
+    let mut index = Index::lock()?;
+    let selected = if all {
+        index
+            .entries
+            .iter()
+            .filter(|entry| entry.stage == 0)
+            .map(|entry| entry.path.clone())
+            .collect()
+    } else {
+        let mut selected = Vec::new();
+        for path in paths {
+            let (_, path) = work_tree_path(path)?;
+            ensure!(index.get(&path).is_some(), "{path} is not in the cache");
+            selected.push(path);
+        }
+        selected
+    };
+
+    let work_tree = repo::work_tree()?;
+    let mut skipped = false;
+    for path in selected {
+        let target = work_tree.join(format!("{}{path}", prefix.unwrap_or_default()));
+        let entry = index.get(&path).context("entry vanished from the index")?;
+        if is_checked_out_gitlink(entry, &target) {
+            continue;
+        }
+        if fs::symlink_metadata(&target).is_ok() {
+            if !force {
+                eprintln!("{path} already exists, no checkout");
+                skipped = true;
+                continue;
+            }
+            // Only an empty directory makes way for a single entry, whatever is inside another one is kept.
+            if target.is_dir() && !target.is_symlink() {
+                fs::remove_dir(&target)
+            } else {
+                fs::remove_file(&target)
+            }
+            .context(format!("removing {target:?}"))?;
+        }
+        checkout_entry(entry, &target)?;
+        // Written in place, so the stat data can be refreshed and later commands see the file as unchanged.
+        if prefix.is_none() {
+            let stat = Stat::from_metadata(&fs::symlink_metadata(&target)?);
+            let entry = IndexEntry::new(path, entry.mode, entry.hash.clone(), stat);
+            index.insert(entry);
+        }
+    }
+    if prefix.is_none() {
+        index.commit()?;
+    }
+    ensure!(!skipped, "some files already exist");
+    Ok(())
+}
+
+/// Whether `full_path` is the directory of a submodule for the gitlink `entry`. Its content belongs to another
+/// repository: like git, it is never removed nor written over.
+fn is_checked_out_gitlink(entry: &IndexEntry, full_path: &Path) -> bool {
+    entry.mode == Mode::Gitlink.bits() && full_path.is_dir() && !full_path.is_symlink()
+}
+
+/// Write the content of an index entry at `target`, with its mode.
+fn checkout_entry(entry: &IndexEntry, target: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).context(format!("creating {parent:?}"))?;
+    }
+    // The content of a submodule comes from another repository, only its directory is created.
+    let mode = Mode::from_bits(entry.mode)?;
+    if mode == Mode::Gitlink {
+        if is_checked_out_gitlink(entry, target) {
+            return Ok(());
+        }
+        return fs::create_dir(target).context(format!("creating {target:?}"));
+    }
+    let ObjectReader::Blob(mut content) = ObjectReader::from_sha1(entry.hash.clone())? else {
+        bail!("{} is not a valid 'blob' object", entry.hash);
+    };
+    if mode == Mode::Symlink {
+        let mut link_target = String::new();
+        content.read_to_string(&mut link_target)?;
+        return std::os::unix::fs::symlink(link_target, target)
+            .context(format!("creating symbolic link {target:?}"));
+    }
+    let mut file = fs::File::create(target).context(format!("creating {target:?}"))?;
+    io::copy(&mut content, &mut file).context(format!("writing {target:?}"))?;
+    if mode == Mode::Executable {
+        let mut permissions = file.metadata()?.permissions();
+        permissions.set_mode(permissions.mode() | 0o111);
+        file.set_permissions(permissions)?;
+    }
+    Ok(())
+}
+
+/// The URL of a remote: `remote.<name>.url` for a configured remote, otherwise `repository` is already one.
+fn remote_url(repository: &str) -> anyhow::Result<String> {
+    if repository.contains("://") {
+        return Ok(repository.to_string());
+    }
+    Ok(config::config()?
+        .get(&format!("remote.{repository}.url"))
+        .unwrap_or(repository)
+        .to_string())
+}
+
+/// Resolve a path given on the command line, relative to the current directory, into its location on disk and
+/// its path in the index, relative to the top of the working tree.
+fn work_tree_path(path: &Path) -> anyhow::Result<(PathBuf, String)> {
+    let (full_path, relative) = pathspec(path)?;
+    ensure!(
+        !relative.is_empty(),
+        "{path:?} is the top of the working tree"
+    );
+    Ok((full_path, relative))
+}
+
+/// Like [`work_tree_path`] for a path naming files or directories, empty for the whole working tree.
+fn pathspec(path: &Path) -> anyhow::Result<(PathBuf, String)> {
+    let work_tree = repo::work_tree()?;
+    let full_path = env::current_dir()?.join(path);
+    // Lexically, like git: `sub/..` is the directory holding `sub`, even when `sub` is a symbolic link.
+    let top = normal_components(work_tree)?;
+    let components = normal_components(&full_path)?;
+    let Some(relative) = components.strip_prefix(top.as_slice()) else {
+        bail!(
+            "{0}: '{0}' is outside repository at '{1}'",
+            path.display(),
+            work_tree.display()
+        );
+    };
+    let path = relative.join("/");
+    Ok((full_path, path))
+}
+
+// The names of an absolute path, with `.` and `..` resolved.
+fn normal_components(path: &Path) -> anyhow::Result<Vec<&str>> {
+    let mut components = Vec::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::Normal(name) => {
+                components.push(name.to_str().context("non UTF-8 path")?)
+            }
+            std::path::Component::ParentDir => {
+                components.pop();
+            }
+            _ => {}
+        }
+    }
+    Ok(components)
+}
+
+/// Whether `path` is `pathspec` or one of the files in it.
+fn matches_pathspec(path: &str, pathspec: &str) -> bool {
+    pathspec.is_empty()
+        || path
+            .strip_prefix(pathspec)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Git stores text files with LF line endings: the files with the `text` or `eol` attribute, and with
+/// `core.autocrlf` set to `input` or `true` the files that look like text. `path` is where the content is in the
+/// working tree, which decides its attributes.
+///
+/// Returns the normalized content, or `None` when the file must be stored as is, so the common case
+/// keeps streaming the file.
+fn normalize_crlf(file: &Path, path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+    let action = crlf_action(tree_path(path).as_deref())?;
+    if action == CrlfAction::None {
+        return Ok(None);
+    }
+    let content = fs::read(file).context(format!("reading {file:?}"))?;
+    Ok(crlf_to_lf(&content, action))
+}
+
+/// What happens to the line endings of a file when it is stored.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CrlfAction {
+    None,
+    /// CRLF are replaced, the file is text.
+    Normalize,
+    /// CRLF are replaced when the file looks like text.
+    Auto,
+}
+
+// The path of a file from the top of the working tree, which decides its attributes. None outside of it.
+fn tree_path(path: &Path) -> Option<String> {
+    pathspec(path)
+        .ok()
+        .map(|(_, path)| path)
+        .filter(|path| !path.is_empty())
+}
+
+// Like git's `convert_attrs`: `text` decides, or `eol` which implies `text`, otherwise `core.autocrlf`. A file
+// outside of the working tree has no attributes.
+fn crlf_action(path: Option<&str>) -> anyhow::Result<CrlfAction> {
+    let (text, eol) = match path {
+        Some(path) => (attributes::get(path, "text")?, eol_attribute(path)?),
+        None => (None, None),
+    };
+    let has_eol = eol.is_some();
+    Ok(match text {
+        Some(attributes::State::Set) => CrlfAction::Normalize,
+        Some(attributes::State::Unset) => CrlfAction::None,
+        Some(attributes::State::Value(value)) if value == "auto" => CrlfAction::Auto,
+        _ if has_eol => CrlfAction::Normalize,
+        _ if autocrlf()? => CrlfAction::Auto,
+        _ => CrlfAction::None,
+    })
+}
+
+// The `eol` attribute, `lf` or `crlf`.
+fn eol_attribute(path: &str) -> anyhow::Result<Option<String>> {
+    Ok(match attributes::get(path, "eol")? {
+        Some(attributes::State::Value(eol)) if eol == "lf" || eol == "crlf" => Some(eol),
+        _ => None,
+    })
+}
+
+// Like git's `output_eol`: whether the text files at `path` get CRLF line endings in the working tree, from their
+// `eol` attribute, otherwise `core.autocrlf` then `core.eol`.
+fn writes_crlf(path: &str) -> anyhow::Result<bool> {
+    if let Some(eol) = eol_attribute(path)? {
+        return Ok(eol == "crlf");
+    }
+    let config = config::config()?;
+    Ok(match config.get("core.autocrlf") {
+        Some(value) if value.eq_ignore_ascii_case("input") => false,
+        _ if config.get_bool("core.autocrlf")?.unwrap_or(false) => true,
+        _ => config
+            .get("core.eol")
+            .is_some_and(|eol| eol.eq_ignore_ascii_case("crlf")),
+    })
+}
+
+// The content with LF line endings replaced by CRLF, `None` when there is nothing to replace. With
+// `CrlfAction::Auto`, files that look binary or already have CRLF are left alone.
+fn lf_to_crlf(content: &[u8], action: CrlfAction) -> Option<Vec<u8>> {
+    let lone_lf = |i: usize| content[i] == b'\n' && (i == 0 || content[i - 1] != b'\r');
+    if action == CrlfAction::None || !(0..content.len()).any(lone_lf) {
+        return None;
+    }
+    let has_crlf = content.windows(2).any(|pair| pair == b"\r\n");
+    if action == CrlfAction::Auto && (content.contains(&0) || has_crlf) {
+        return None;
+    }
+    let mut converted = Vec::with_capacity(content.len() + content.len() / 16);
+    for (i, &byte) in content.iter().enumerate() {
+        if lone_lf(i) {
+            converted.push(b'\r');
+        }
+        converted.push(byte);
+    }
+    Some(converted)
+}
+
+/// Write a blob as it would be checked out at `path`, from the top of the working tree: with the line
+/// endings of the working tree, then through the smudge command of its `filter` attribute.
+fn write_smudged(
+    mut blob: impl Read,
+    path: &str,
+    out: &mut (dyn Write + Send),
+) -> anyhow::Result<()> {
+    let mut content = Vec::new();
+    blob.read_to_end(&mut content)?;
+    let action = crlf_action(Some(path))?;
+    if writes_crlf(path)? {
+        if let Some(converted) = lf_to_crlf(&content, action) {
+            content = converted;
+        }
+    }
+    let smudge = match attributes::get(path, "filter")? {
+        Some(attributes::State::Value(driver)) => {
+            config::config()?.get(&format!("filter.{driver}.smudge"))
+        }
+        _ => None,
+    };
+    match smudge {
+        // Like git, `%f` is the path of the file, quoted for the shell.
+        Some(command) => {
+            let quoted = format!("'{}'", path.replace('\'', "'\\''"));
+            filter_blob(content.as_slice(), &command.replace("%f", &quoted), out)
+        }
+        None => Ok(out.write_all(&content)?),
+    }
+}
+
+fn autocrlf() -> anyhow::Result<bool> {
+    let autocrlf = config::config()?.get("core.autocrlf");
+    Ok(match autocrlf {
+        Some(value) if value.eq_ignore_ascii_case("input") => true,
+        Some(_) => config::config()?
+            .get_bool("core.autocrlf")?
+            .unwrap_or(false),
+        None => false,
+    })
+}
+
+// The content with CRLF line endings replaced by LF, `None` when there is nothing to replace.
+fn crlf_to_lf(content: &[u8], action: CrlfAction) -> Option<Vec<u8>> {
+    if action == CrlfAction::None {
+        return None;
+    }
+    // Same heuristic as git: NUL bytes or lone CR mean binary content, that we must not alter.
+    let is_binary = action == CrlfAction::Auto
+        && (content.contains(&0)
+            || content
+                .iter()
+                .enumerate()
+                .any(|(i, &byte)| byte == b'\r' && content.get(i + 1) != Some(&b'\n')));
+    if is_binary || !content.windows(2).any(|pair| pair == b"\r\n") {
+        return None;
+    }
+
+    let mut normalized = Vec::with_capacity(content.len());
+    for (i, &byte) in content.iter().enumerate() {
+        if byte == b'\r' && content.get(i + 1) == Some(&b'\n') {
+            continue;
+        }
+        normalized.push(byte);
+    }
+    Some(normalized)
+}
+
+/// Like `git ls-tree`: from a sub-directory of the working tree, only its content is shown, with paths relative
+/// to it, unless `--full-tree`.
+fn print_tree(
+    hash: GitHexHash,
+    mut options: LsTreeOptions,
+    out: &mut dyn Write,
+) -> anyhow::Result<()> {
+    // Outside a working tree, like in a bare repository, paths are from the top.
+    let current_dir = match repo::work_tree() {
+        Ok(_) if !options.full_tree => pathspec(Path::new("."))?.1,
+        _ => String::new(),
+    };
+    let mut paths = options
+        .paths
+        .iter()
+        .map(|path| tree_pathspec(&current_dir, path))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if paths.is_empty() && !current_dir.is_empty() {
+        paths.push(format!("{current_dir}/"));
+    }
+    // The top of the tree selects everything.
+    if paths.iter().any(String::is_empty) {
+        paths.clear();
+    }
+    options.paths = paths;
+    let base = if options.full_name || options.full_tree {
+        String::new()
+    } else {
+        current_dir
+    };
+    let mut printer = TreePrinter {
+        options,
+        base,
+        quote_non_ascii: config::config()?
+            .get_bool("core.quotePath")?
+            .unwrap_or(true),
+        out,
+    };
+    printer.print(hash, "")
+}
+
+struct TreePrinter<W> {
+    options: LsTreeOptions,
+    /// The directory the shown paths are relative to, empty for the top of the tree.
+    base: String,
+    quote_non_ascii: bool,
+    out: W,
+}
+
+// A path given relative to `current_dir` as a path from the top of the tree, without `.` and `..` components.
+// It keeps its trailing slash, also added when it ends with `.` or `..` since it is a directory.
+fn tree_pathspec(current_dir: &str, path: &str) -> anyhow::Result<String> {
+    let mut components: Vec<&str> = current_dir.split('/').filter(|c| !c.is_empty()).collect();
+    let mut is_dir = path.ends_with('/');
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        is_dir = path.ends_with('/');
+        match component {
+            "." => is_dir = true,
+            ".." => {
+                if components.pop().is_none() {
+                    bail!(
+                        "{path}: '{path}' is outside repository at '{}'",
+                        repo::work_tree()?.display()
+                    );
+                }
+                is_dir = true;
+            }
+            _ => components.push(component),
+        }
+    }
+    let mut pathspec = components.join("/");
+    if is_dir && !pathspec.is_empty() {
+        pathspec.push('/');
+    }
+    Ok(pathspec)
+}
+
+// `path` relative to the directory `base`, both from the top of the tree.
+fn relative_path(path: &str, base: &str) -> String {
+    if base.is_empty() {
+        return path.to_string();
+    }
+    let base: Vec<&str> = base.split('/').collect();
+    let path: Vec<&str> = path.split('/').collect();
+    let common = base
+        .iter()
+        .zip(&path)
+        .take_while(|(base, path)| base == path)
+        .count();
+    let mut relative = "../".repeat(base.len() - common);
+    relative.push_str(&path[common..].join("/"));
+    if relative.is_empty() {
+        relative.push_str("./");
+    }
+    relative
+}
+
+/// What to do with a tree entry according to the paths filter.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Selection {
+    Skip,
+    /// Only list the content of the entry, which is a tree.
+    Descend,
+    Show,
+}
+
+impl<W: Write> TreePrinter<W> {
+    fn print(&mut self, hash: GitHexHash, prefix: &str) -> anyhow::Result<()> {
+        let object = ObjectReader::from_sha1(hash)?;
+
+        let ObjectReader::Tree(reader) = object else {
+            bail!("not a tree object");
+        };
+
+        for entry in TreeEntries::new(reader)? {
+            let entry = entry?;
+            let path = format!("{prefix}{}", entry.name);
+            let selection = self.select(&path, entry.is_tree());
+            // Like git, with `-r` we show the content of the trees instead of the trees themselves.
+            if entry.is_tree()
+                && (selection == Selection::Descend
+                    || (selection == Selection::Show && self.options.recursive))
+            {
+                self.print(entry.hash, &format!("{path}/"))?;
+            } else if selection == Selection::Show {
+                self.print_entry(&entry, &path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Follows git pathspec semantics: `dir` selects the tree entry itself while `dir/` selects its content.
+    fn select(&self, path: &str, is_tree: bool) -> Selection {
+        let paths = &self.options.paths;
+        if paths.is_empty() {
+            return Selection::Show;
+        }
+        paths
+            .iter()
+            .map(|spec| {
+                let trimmed = spec.trim_end_matches('/');
+                if path == trimmed {
+                    if is_tree && spec.ends_with('/') {
+                        Selection::Descend
+                    } else {
+                        Selection::Show
+                    }
+                } else if path
+                    .strip_prefix(trimmed)
+                    .is_some_and(|rest| rest.starts_with('/'))
+                {
+                    Selection::Show
+                } else if is_tree
+                    && trimmed
+                        .strip_prefix(path)
+                        .is_some_and(|rest| rest.starts_with('/'))
+                {
+                    Selection::Descend
+                } else {
+                    Selection::Skip
+                }
+            })
+            .max()
+            .unwrap_or(Selection::Skip)
+    }
+
+    fn print_entry(&mut self, entry: &TreeEntry, path: &str) -> anyhow::Result<()> {
+        // In git on Linux (and windows for version >= V1.7.10) the CStr is encoded as UTF-8. However, by default
+        // git ls-tree won't print the unicode symbole if not ASCII, it will escape the symbols in octal
+        // representation.
+        let terminator = if self.options.nul_terminated {
+            '\0'
+        } else {
+            '\n'
+        };
+        let hash = match self.options.abbrev {
+            Some(min_len) => entry.hash.abbreviate(min_len)?,
+            None => entry.hash.as_str(),
+        };
+        if self.options.object_only {
+            write!(self.out, "{hash}{terminator}")?;
+            return Ok(());
+        }
+        let path = relative_path(path, &self.base);
+        // With `-z` the paths can't be ambiguous, they are shown as is.
+        let path = if self.options.nul_terminated {
+            Cow::Borrowed(path.as_str())
+        } else {
+            quote_path(&path, self.quote_non_ascii)
+        };
+        if self.options.name_only {
+            write!(self.out, "{path}{terminator}")?;
+            return Ok(());
+        }
+        // Like git, the type comes from the mode so the objects aren't read, the commit of a submodule isn't even
+        // in this repository. Only an unknown mode, found in a broken tree, needs a look at the object.
+        let object = match Mode::parse(&entry.mode) {
+            Ok(mode) => mode.object_kind(),
+            Err(_) => ObjectReader::header(&entry.hash)?.0,
+        };
+        write!(
+            self.out,
+            "{:0>6} {object} {hash}\t{path}{terminator}",
+            entry.mode
+        )?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ObjectKind {
+    Blob,
+    Tree,
+    Commit,
+    Tag,
+}
+
+impl ObjectKind {
+    fn parse(kind: &str) -> anyhow::Result<Self> {
+        Ok(match kind {
+            "blob" => ObjectKind::Blob,
+            "tree" => ObjectKind::Tree,
+            "commit" => ObjectKind::Commit,
+            "tag" => ObjectKind::Tag,
+            _ => bail!("unknown object kind: {kind}"),
+        })
+    }
+}
+
+impl fmt::Display for ObjectKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ObjectKind::Blob => write!(f, "blob"),
+            ObjectKind::Tree => write!(f, "tree"),
+            ObjectKind::Commit => write!(f, "commit"),
+            ObjectKind::Tag => write!(f, "tag"),
+        }
+    }
+}
+
+// Each object have an header
+// <kind> <size>\0
+// The size is the length of the content following the header.
+enum ObjectReader<R> {
     Blob(R),
     Tree(R),
+    Commit(R),
+    Tag(R),
 }
 
 impl ObjectReader<()> {
     fn from_sha1(hash: GitHexHash) -> anyhow::Result<ObjectReader<impl BufRead>> {
-        let object = hash.to_path();
-        let object = fs::File::open(&object).context(format!("opening {object:?}"))?;
-        let z_decoder = ZlibDecoder::new(object);
-        let mut z_decoder = BufReader::new(z_decoder);
-        let mut object = Vec::new();
-        // blob <size>\0<content>
-        let n = z_decoder
-            .read_until(0, &mut object)
-            .context("reading the header")?;
-        let header = CStr::from_bytes_with_nul(&object[..n])?.to_str()?;
-        let (kind, size) = header.split_once(' ').context("spliting the header")?;
-        let size = size.parse::<u64>().context("parsing the size")?;
-        // Takes protects from zip bomb.
-        let object = z_decoder.take(size);
+        // Packed objects are inflated whole, so they're read like cached ones.
+        let in_memory = match cache::body(&hash) {
+            Some(body) => Some(body),
+            None => read_packed(&hash)?,
+        };
+        let (kind, object) = match in_memory {
+            Some((kind, body)) => (kind, ObjectBody::Cached(io::Cursor::new(body))),
+            None => {
+                let (path, object) = open_object(&hash)?;
+                let mut z_decoder = BufReader::new(ZlibDecoder::new(object));
+                let (kind, size) =
+                    read_raw_header(&mut z_decoder).context(corrupt_object(&path))?;
+                let kind = ObjectKind::parse(&kind)?;
+                // The header is as untrusted as the rest, check it before reading anything based on it.
+                ensure!(
+                    size <= max_object_size(),
+                    "object {hash} is too large ({size} bytes, the limit is {})",
+                    max_object_size()
+                );
+                // Protects from zip bombs: reading stops at the announced size, and more data is an error.
+                let mut object = ExactSize {
+                    inner: z_decoder,
+                    remaining: size,
+                };
+                if size <= cache::MAX_CACHED_BODY {
+                    let mut body = Vec::with_capacity(size as usize);
+                    object
+                        .read_to_end(&mut body)
+                        .context(corrupt_object(&path))?;
+                    let body: Arc<[u8]> = body.into();
+                    cache::insert(&hash, kind, size, Some(body.clone()));
+                    (kind, ObjectBody::Cached(io::Cursor::new(body)))
+                } else {
+                    cache::insert(&hash, kind, size, None);
+                    (kind, ObjectBody::Streamed(object))
+                }
+            }
+        };
         Ok(match kind {
-            "blob" => ObjectReader::Blob(object),
-            "tree" => ObjectReader::Tree(object),
-            _ => bail!("unknown object kind: {kind}"),
+            ObjectKind::Blob => ObjectReader::Blob(object),
+            ObjectKind::Tree => ObjectReader::Tree(object),
+            ObjectKind::Commit => ObjectReader::Commit(object),
+            ObjectKind::Tag => ObjectReader::Tag(object),
         })
     }
+
+    /// Read only the `<kind> <size>` header of an object, for when the content isn't needed.
+    fn header(hash: &GitHexHash) -> anyhow::Result<(ObjectKind, u64)> {
+        if let Some(header) = cache::header(hash) {
+            return Ok(header);
+        }
+        let (kind, size) = Self::raw_header(hash)?;
+        let kind = ObjectKind::parse(&kind)?;
+        cache::insert(hash, kind, size, None);
+        Ok((kind, size))
+    }
+
+    /// Like [`ObjectReader::header`] but doesn't validate the kind.
+    fn raw_header(hash: &GitHexHash) -> anyhow::Result<(String, u64)> {
+        if hash.find_path()?.is_none() {
+            if let Some((kind, size)) = pack::read_packed_header(hash)? {
+                return Ok((kind.to_string(), size));
+            }
+        }
+        let (path, object) = open_object(hash)?;
+        // A small buffer so we don't inflate more than the beginning of the object.
+        let mut z_decoder = BufReader::with_capacity(32, ZlibDecoder::new(object));
+        read_raw_header(&mut z_decoder).context(corrupt_object(&path))
+    }
+}
+
+/// Pipe a blob to a shell command, its output going to ours. The blob is streamed: the command may start
+/// writing before reading everything.
+fn filter_blob(
+    mut blob: impl Read,
+    command: &str,
+    out: &mut (dyn Write + Send),
+) -> anyhow::Result<()> {
+    let mut child = process::Command::new("sh")
+        .args(["-c", command])
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .spawn()
+        .context(format!("running '{command}'"))?;
+    let stdin = child.stdin.take().context("command stdin")?;
+    let mut stdout = child.stdout.take().context("command stdout")?;
+    // The output is read while the blob is written, otherwise the command could block on a full pipe.
+    thread::scope(|scope| -> anyhow::Result<()> {
+        let mut stdin = stdin;
+        let output = scope.spawn(move || io::copy(&mut stdout, out));
+        match io::copy(&mut blob, &mut stdin) {
+            // The command may not read everything, like `head`.
+            Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {}
+            result => {
+                result.context(format!("piping the blob to '{command}'"))?;
+            }
+        }
+        drop(stdin);
+        output
+            .join()
+            .map_err(|_| anyhow!("reading the output of '{command}' panicked"))?
+            .context(format!("reading the output of '{command}'"))?;
+        Ok(())
+    })?;
+    let status = child.wait()?;
+    ensure!(status.success(), "filter command '{command}' failed");
+    Ok(())
+}
+
+/// `cat-file <object>`: the type or size of an object, otherwise its content, written to `out` so it can be
+/// captured. With `smudge_path`, a blob is shown as checked out at this path.
+fn cat_file(
+    object: &str,
+    show_type: bool,
+    show_size: bool,
+    allow_unknown_type: bool,
+    filter_cmd: Option<&str>,
+    smudge_path: Option<&str>,
+    out: &mut (dyn Write + Send),
+) -> anyhow::Result<()> {
+    let hash = rev::resolve(object)?;
+    // A full hash is taken as is by `rev::resolve`, the object may not exist.
+    let exists = hash.exists()?;
+    if show_type || show_size {
+        ensure!(exists, "git cat-file: could not get object info");
+        let (kind, size) = if allow_unknown_type {
+            ObjectReader::raw_header(&hash)?
+        } else {
+            let (kind, size) = ObjectReader::header(&hash)?;
+            (kind.to_string(), size)
+        };
+        if show_type {
+            writeln!(out, "{kind}")?;
+        } else {
+            writeln!(out, "{size}")?;
+        }
+        return Ok(());
+    }
+
+    ensure!(exists, "Not a valid object name {object}");
+    let object = ObjectReader::from_sha1(hash.clone())?;
+    // Trees are binary, they are shown like `ls-tree` does.
+    if let ObjectReader::Tree(_) = object {
+        let options = LsTreeOptions {
+            full_tree: true,
+            ..Default::default()
+        };
+        return print_tree(hash, options, out);
+    }
+    if let Some(command) = filter_cmd {
+        let ObjectReader::Blob(blob) = object else {
+            bail!("--filter-cmd only applies to blobs, not to a {object}");
+        };
+        return filter_blob(blob, command, out);
+    }
+    if let (Some(path), ObjectReader::Blob(_)) = (smudge_path, &object) {
+        return write_smudged(object.into_body_reader(), path, out);
+    }
+    // Streamed so we never hold the whole blob in memory. Byte for byte like git: no newline is added after a
+    // content lacking one, nor are trailing ones stripped, scripts rely on it to extract files.
+    io::copy(&mut object.into_body_reader(), out).context("writing object content")?;
+    Ok(())
+}
+
+/// `cat-file --batch` and `--batch-check`: the objects are named on stdin and each one gets a line following
+/// `format`, then its content with `--batch`.
+fn cat_file_batch(
+    contents: bool,
+    format: &BatchFormat,
+    follow_symlinks: bool,
+    abbrev: Option<usize>,
+) -> anyhow::Result<()> {
+    let mut out = io::stdout().lock();
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        let name = line.trim();
+        let hash = if follow_symlinks {
+            let followed = rev::resolve_following_symlinks(name)?;
+            // What prevented reaching an object, then the path concerned.
+            let problem = match &followed {
+                Followed::Found(_) | Followed::Missing => None,
+                Followed::Dangling => Some(("dangling", name)),
+                Followed::Loop => Some(("loop", name)),
+                Followed::NotDir => Some(("notdir", name)),
+                Followed::OutOfTree(target) => Some(("symlink", target.as_str())),
+            };
+            if let Some((problem, path)) = problem {
+                writeln!(out, "{problem} {}\n{path}", path.len())?;
+                out.flush()?;
+                continue;
+            }
+            match followed {
+                Followed::Found(hash) => Some(hash),
+                _ => None,
+            }
+        } else {
+            rev::resolve(name).ok()
+        };
+        // A loose object takes its file, a packed one its entry in the pack.
+        let found = match hash {
+            Some(hash) => match hash.find_path()? {
+                Some(path) => Some((hash, fs::metadata(&path)?.len())),
+                None => pack::disk_size(&hash)?.map(|disk_size| (hash, disk_size)),
+            },
+            None => None,
+        };
+        let Some((hash, disk_size)) = found else {
+            writeln!(out, "{name} missing")?;
+            out.flush()?;
+            continue;
+        };
+        let (kind, size) = ObjectReader::header(&hash)?;
+        let info = ObjectInfo {
+            name: match abbrev {
+                Some(min_len) => hash.abbreviate(min_len)?,
+                None => hash.as_str(),
+            },
+            kind,
+            size,
+            disk_size,
+        };
+        format.write(&mut out, &info)?;
+        writeln!(out)?;
+        if contents {
+            io::copy(
+                &mut ObjectReader::from_sha1(hash)?.into_body_reader(),
+                &mut out,
+            )?;
+            writeln!(out)?;
+        }
+        // Flushed after each object so the command can be driven interactively.
+        out.flush()?;
+    }
+    Ok(())
+}
+
+/// An object that isn't loose from the packs, cached like loose objects as it's inflated all at once.
+fn read_packed(hash: &GitHexHash) -> anyhow::Result<Option<(ObjectKind, Arc<[u8]>)>> {
+    if hash.find_path()?.is_some() {
+        return Ok(None);
+    }
+    let Some((kind, body)) = pack::read_packed(hash)? else {
+        return Ok(None);
+    };
+    let body: Arc<[u8]> = body.into();
+    cache::insert(hash, kind, body.len() as u64, Some(body.clone()));
+    Ok(Some((kind, body)))
+}
+
+fn open_object(hash: &GitHexHash) -> anyhow::Result<(PathBuf, ObjectFile)> {
+    let path = match hash.find_path()? {
+        Some(path) => path,
+        None => hash.to_path()?,
+    };
+    let object = ObjectFile::open(&path).context(format!("opening {path:?}"))?;
+    Ok((path, object))
+}
+
+const DEFAULT_MAX_OBJECT_SIZE: u64 = 4 << 30;
+
+static MAX_OBJECT_SIZE: OnceLock<u64> = OnceLock::new();
+
+// Set by `--dry-run`: the objects are written in temporary files then deleted instead of being stored.
+static DRY_RUN: OnceLock<bool> = OnceLock::new();
+
+/// Objects announcing a larger size in their header are rejected, set by `--max-object-size`.
+fn max_object_size() -> u64 {
+    MAX_OBJECT_SIZE
+        .get()
+        .copied()
+        .unwrap_or(DEFAULT_MAX_OBJECT_SIZE)
+}
+
+/// Reads exactly `remaining` bytes from `inner`: a shorter or longer content is an error.
+struct ExactSize<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: BufRead> BufRead for ExactSize<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.remaining == 0 {
+            if !self.inner.fill_buf()?.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "object is larger than its header says",
+                ));
+            }
+            return Ok(&[]);
+        }
+        let remaining = self.remaining;
+        let buf = self.inner.fill_buf()?;
+        if buf.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "object is smaller than its header says",
+            ));
+        }
+        let len = buf
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        Ok(&buf[..len])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.remaining -= amt as u64;
+        self.inner.consume(amt);
+    }
+}
+
+impl<R: BufRead> Read for ExactSize<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+// Same wording as git. Truncated files and invalid zlib streams end up here.
+fn corrupt_object(path: &Path) -> String {
+    format!("object file {} is corrupt", path.display())
+}
+
+// blob <size>\0<content>
+fn read_raw_header(z_decoder: &mut impl BufRead) -> anyhow::Result<(String, u64)> {
+    let mut header = Vec::new();
+    let n = z_decoder
+        .read_until(0, &mut header)
+        .context("reading the header")?;
+    let header = CStr::from_bytes_with_nul(&header[..n])?.to_str()?;
+    let (kind, size) = header.split_once(' ').context("spliting the header")?;
+    let size = size.parse::<u64>().context("parsing the size")?;
+    Ok((kind.to_string(), size))
+}
+
+impl<R: BufRead> ObjectReader<R> {
+    /// The object content, without the header.
+    ///
+    /// The reader is limited to the size announced in the header and decompresses lazily, so callers should
+    /// stream it (`io::copy`, `read_until`, ...) rather than `read_to_end` to support objects larger than memory.
+    fn into_body_reader(self) -> R {
+        match self {
+            ObjectReader::Blob(reader)
+            | ObjectReader::Tree(reader)
+            | ObjectReader::Commit(reader)
+            | ObjectReader::Tag(reader) => reader,
+        }
+    }
+}
+
+impl<R> ObjectReader<R> {
+    fn kind(&self) -> ObjectKind {
+        match self {
+            ObjectReader::Blob(_) => ObjectKind::Blob,
+            ObjectReader::Tree(_) => ObjectKind::Tree,
+            ObjectReader::Commit(_) => ObjectKind::Commit,
+            ObjectReader::Tag(_) => ObjectKind::Tag,
+        }
+    }
 }
 
 impl<R> fmt::Display for ObjectReader<R> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            ObjectReader::Blob(_) => write!(f, "blob"),
-            ObjectReader::Tree(_) => write!(f, "tree"),
+        self.kind().fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::MutexGuard;
+
+    // The repository is global to the process: the tests writing objects share one, one at a time.
+    fn test_repo() -> MutexGuard<'static, ()> {
+        static REPO: OnceLock<Mutex<()>> = OnceLock::new();
+        let lock = REPO.get_or_init(|| {
+            let dir = env::temp_dir().join(format!("codecrafters-git-unit-{}", process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            let status = process::Command::new("git")
+                .args(["init", "-q"])
+                .arg(&dir)
+                .status()
+                .unwrap();
+            assert!(status.success());
+            repo::set_git_dir(dir.join(".git"));
+            Mutex::new(())
+        });
+        lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write(kind: &str, body: &[u8]) -> anyhow::Result<GitHexHash> {
+        write_object(kind, body.len() as u64, |out| out.write_all(body))
+    }
+
+    fn read_back(hash: GitHexHash) -> (ObjectKind, Vec<u8>) {
+        let object = ObjectReader::from_sha1(hash).unwrap();
+        let kind = object.kind();
+        let mut body = Vec::new();
+        object.into_body_reader().read_to_end(&mut body).unwrap();
+        (kind, body)
+    }
+
+    fn temp_files() -> Vec<PathBuf> {
+        fs::read_dir(repo::objects_dir().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                path.file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .starts_with("tmp_obj")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn writes_blob() {
+        let _repo = test_repo();
+        let hash = write("blob", b"hello\n").unwrap();
+        assert_eq!(hash.as_str(), "ce013625030ba8dba906f756967f9e9ca394464a");
+        assert_eq!(read_back(hash), (ObjectKind::Blob, b"hello\n".to_vec()));
+    }
+
+    #[test]
+    fn writes_tree() {
+        let _repo = test_repo();
+        let mut tree = b"100644 hello\0".to_vec();
+        tree.extend(
+            base16ct::lower::decode_vec("ce013625030ba8dba906f756967f9e9ca394464a").unwrap(),
+        );
+        let hash = write("tree", &tree).unwrap();
+        assert_eq!(hash.as_str(), "b4d01e9b0c4a9356736dfddf8830ba9a54f5271c");
+        assert_eq!(read_back(hash), (ObjectKind::Tree, tree));
+    }
+
+    #[test]
+    fn writes_commit() {
+        let _repo = test_repo();
+        let commit = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+            author A <a@b> 0 +0000\n\
+            committer A <a@b> 0 +0000\n\
+            \n\
+            msg\n";
+        let hash = write("commit", commit).unwrap();
+        assert_eq!(hash.as_str(), "451424af2a9b5b83dadfa2c57d906dd53a07399a");
+        assert_eq!(read_back(hash), (ObjectKind::Commit, commit.to_vec()));
+    }
+
+    #[test]
+    fn rejects_length_mismatch() {
+        let _repo = test_repo();
+        for len in [5, 7] {
+            let err = write_object("blob", len, |out| out.write_all(b"hello\n")).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("blob object is 6 bytes long instead of {len}")
+            );
+            assert_eq!(temp_files(), Vec::<PathBuf>::new());
         }
     }
 }
@@ -0,0 +1,596 @@
+use crate::{
+    checkout_entry,
+    commit::Commit,
+    commit_tree,
+    diff::{self, Change},
+    flatten_tree,
+    hex_hash::GitHexHash,
+    index::{Index, IndexEntry, LockedIndex, Stat},
+    is_modified, is_regular_file,
+    mode::Mode,
+    peel_to_commit, refs, repo, rev, shallow, verbosity, write_index_tree, write_object,
+    ObjectReader,
+};
+use anyhow::{anyhow, bail, ensure, Context};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs,
+    io::{self, Read},
+    os::unix::fs::PermissionsExt,
+    process,
+};
+
+/// Length of the conflict markers, like git.
+const MARKER_LEN: usize = 7;
+
+/// The best common ancestors of two commits: the common ancestors that aren't an ancestor of another one.
+/// Usually a single commit, several after criss-cross merges, none for unrelated histories.
+pub(super) fn merge_bases(one: &GitHexHash, two: &GitHexHash) -> anyhow::Result<Vec<GitHexHash>> {
+    let shallow = shallow::shallow_commits()?;
+    let ones = ancestors(vec![one.clone()], &shallow)?;
+    let common = ancestors(vec![two.clone()], &shallow)?
+        .into_iter()
+        .filter(|commit| ones.contains(commit))
+        .collect::<HashSet<_>>();
+    // An ancestor of a common ancestor is common too, so walking from the parents of all of them finds the
+    // redundant ones at once.
+    let mut parents = Vec::new();
+    for commit in &common {
+        if !shallow.contains(commit) {
+            parents.extend(Commit::read(commit)?.parents);
+        }
+    }
+    let redundant = ancestors(parents, &shallow)?;
+    let mut bases = common
+        .into_iter()
+        .filter(|commit| !redundant.contains(commit))
+        .collect::<Vec<_>>();
+    // The order of a set changes between runs, the output shouldn't.
+    bases.sort_unstable_by(|a, b| a.as_str().cmp(b.as_str()));
+    Ok(bases)
+}
+
+/// How many commits are ancestors of `one` but not of `two`, and the other way around: how far a branch is
+/// ahead and behind its upstream.
+pub(super) fn ahead_behind(one: &GitHexHash, two: &GitHexHash) -> anyhow::Result<(usize, usize)> {
+    let shallow = shallow::shallow_commits()?;
+    let ones = ancestors(vec![one.clone()], &shallow)?;
+    let twos = ancestors(vec![two.clone()], &shallow)?;
+    Ok((
+        ones.difference(&twos).count(),
+        twos.difference(&ones).count(),
+    ))
+}
+
+// The commits and all their ancestors, except the missing parents of the shallow boundary.
+fn ancestors(
+    mut pending: Vec<GitHexHash>,
+    shallow: &HashSet<GitHexHash>,
+) -> anyhow::Result<HashSet<GitHexHash>> {
+    let mut ancestors = HashSet::new();
+    while let Some(commit) = pending.pop() {
+        if ancestors.contains(&commit) {
+            continue;
+        }
+        if !shallow.contains(&commit) {
+            pending.extend(Commit::read(&commit)?.parents);
+        }
+        ancestors.insert(commit);
+    }
+    Ok(ancestors)
+}
+
+/// Merge the changes made to `base` in `ours` and `theirs`, line by line. Returns the merged content and
+/// whether there were conflicts, the clashing changes being then both kept between conflict markers labeled
+/// with `labels`.
+pub(super) fn merge_file(
+    base: &[u8],
+    ours: &[u8],
+    theirs: &[u8],
+    labels: (&str, &str),
+) -> (Vec<u8>, bool) {
+    let base = diff::lines(base);
+    let ours = diff::lines(ours);
+    let theirs = diff::lines(theirs);
+    let our_changes = diff::diff_lines(&base, &ours);
+    let their_changes = diff::diff_lines(&base, &theirs);
+
+    let mut merged = Vec::new();
+    let mut conflicts = false;
+    let (mut i, mut j) = (0, 0);
+    let mut base_line = 0;
+    loop {
+        // The next change of either side, then all the changes of both sides overlapping or touching it.
+        let (start, mut end) = match (our_changes.get(i), their_changes.get(j)) {
+            (Some(our), Some(their)) if their.old_start < our.old_start => base_range(their),
+            (Some(change), _) | (None, Some(change)) => base_range(change),
+            (None, None) => break,
+        };
+        let (first_our, first_their) = (i, j);
+        loop {
+            if let Some(change) = our_changes.get(i).filter(|change| change.old_start <= end) {
+                end = end.max(base_range(change).1);
+                i += 1;
+            } else if let Some(change) = their_changes
+                .get(j)
+                .filter(|change| change.old_start <= end)
+            {
+                end = end.max(base_range(change).1);
+                j += 1;
+            } else {
+                break;
+            }
+        }
+        merged.extend(base[base_line..start].concat());
+        let our_side = apply(&base, &ours, &our_changes[first_our..i], start, end);
+        let their_side = apply(&base, &theirs, &their_changes[first_their..j], start, end);
+        if first_our == i || our_side == their_side {
+            merged.extend(their_side.concat());
+        } else if first_their == j {
+            merged.extend(our_side.concat());
+        } else {
+            conflicts = true;
+            write_conflict(&mut merged, &our_side, &their_side, labels);
+        }
+        base_line = end;
+    }
+    merged.extend(base[base_line..].concat());
+    (merged, conflicts)
+}
+
+// The lines of the base a change replaces.
+fn base_range(change: &Change) -> (usize, usize) {
+    (change.old_start, change.old_start + change.old_len)
+}
+
+// What a side has in place of the base lines `start..end`, given its changes within them.
+fn apply<'a>(
+    base: &[&'a [u8]],
+    side: &[&'a [u8]],
+    changes: &[Change],
+    start: usize,
+    end: usize,
+) -> Vec<&'a [u8]> {
+    let (Some(first), Some(last)) = (changes.first(), changes.last()) else {
+        return base[start..end].to_vec();
+    };
+    let side_start = first.new_start - (first.old_start - start);
+    let side_end = last.new_start + last.new_len + (end - base_range(last).1);
+    side[side_start..side_end].to_vec()
+}
+
+// The lines both sides agree on at the beginning and the end of a conflict are left out of it, like git.
+fn write_conflict(out: &mut Vec<u8>, ours: &[&[u8]], theirs: &[&[u8]], labels: (&str, &str)) {
+    let prefix = ours
+        .iter()
+        .zip(theirs)
+        .take_while(|(our, their)| our == their)
+        .count();
+    let suffix = ours[prefix..]
+        .iter()
+        .rev()
+        .zip(theirs[prefix..].iter().rev())
+        .take_while(|(our, their)| our == their)
+        .count();
+    out.extend(ours[..prefix].concat());
+    let write_lines = |out: &mut Vec<u8>, lines: &[&[u8]]| {
+        out.extend(lines.concat());
+        // The marker must start a line, even after a file without a final new line.
+        if lines.last().is_some_and(|line| !line.ends_with(b"\n")) {
+            out.push(b'\n');
+        }
+    };
+    let marker = |c: &str| c.repeat(MARKER_LEN);
+    out.extend(format!("{} {}\n", marker("<"), labels.0).as_bytes());
+    write_lines(out, &ours[prefix..ours.len() - suffix]);
+    out.extend(format!("{}\n", marker("=")).as_bytes());
+    write_lines(out, &theirs[prefix..theirs.len() - suffix]);
+    out.extend(format!("{} {}\n", marker(">"), labels.1).as_bytes());
+    out.extend(ours[ours.len() - suffix..].concat());
+}
+
+/// A file as a merge leaves it: the stages of a conflicted file go in the index, along with what's left in the
+/// working tree for the user to resolve.
+enum MergedFile {
+    Clean(u32, GitHexHash),
+    Conflict {
+        /// Base, ours and theirs, when they have the file.
+        stages: [Option<(u32, GitHexHash)>; 3],
+        work: WorkFile,
+    },
+}
+
+enum WorkFile {
+    Object(u32, GitHexHash),
+    /// Content with conflict markers, which isn't an object.
+    Merged(u32, Vec<u8>),
+}
+
+/// `merge <commit>`: like git's default, a fast-forward when HEAD is an ancestor of the commit. Otherwise the
+/// trees are merged file by file from their merge base, then the lines of the files changed on both sides. The
+/// result is committed unless there are conflicts, which are left in the index and the working tree.
+pub(super) fn merge(rev: &str) -> anyhow::Result<()> {
+    let theirs = rev::resolve(rev).map_err(|_| anyhow!("{rev} - not something we can merge"))?;
+    let theirs = peel_to_commit(theirs)?;
+    let git_dir = repo::git_dir()?;
+    ensure!(
+        !git_dir.join("MERGE_HEAD").exists(),
+        "You have not concluded your merge (MERGE_HEAD exists).\nPlease, commit your changes before you merge."
+    );
+    let mut index = Index::lock()?;
+    ensure!(
+        index.entries.iter().all(|entry| entry.stage == 0),
+        "Merging is not possible because you have unmerged files."
+    );
+    let branch = refs::head_target()?;
+    let their_files = files_by_path(&Commit::read(&theirs)?.tree)?;
+    let Some(ours) = refs::read_ref("HEAD")? else {
+        // Merging into an unborn branch only checks the commit out.
+        let result = their_files
+            .into_iter()
+            .map(|(path, (mode, hash))| (path, MergedFile::Clean(mode, hash)))
+            .collect();
+        switch_files(&mut index, &HashMap::new(), &result)?;
+        index.commit()?;
+        return refs::update_ref(
+            branch.as_deref().unwrap_or("HEAD"),
+            &theirs,
+            &format!("merge {rev}: Fast-forward"),
+        );
+    };
+    let ours = peel_to_commit(ours)?;
+    let our_files = files_by_path(&Commit::read(&ours)?.tree)?;
+    let bases = merge_bases(&ours, &theirs)?;
+    if bases.contains(&theirs) {
+        verbosity::info("Already up to date.");
+        return Ok(());
+    }
+
+    if bases.contains(&ours) {
+        verbosity::info(format_args!(
+            "Updating {}..{}",
+            ours.abbreviate(7)?,
+            theirs.abbreviate(7)?
+        ));
+        let result = their_files
+            .into_iter()
+            .map(|(path, (mode, hash))| (path, MergedFile::Clean(mode, hash)))
+            .collect();
+        switch_files(&mut index, &our_files, &result)?;
+        index.commit()?;
+        verbosity::info("Fast-forward");
+        return refs::update_ref(
+            branch.as_deref().unwrap_or("HEAD"),
+            &theirs,
+            &format!("merge {rev}: Fast-forward"),
+        );
+    }
+
+    // After criss-cross merges git merges the bases first, we merge from one of them.
+    let base = bases
+        .first()
+        .context("refusing to merge unrelated histories")?;
+    let base_files = files_by_path(&Commit::read(base)?.tree)?;
+    let MergedTree {
+        files,
+        conflicts,
+        messages,
+    } = merge_files(&base_files, &our_files, &their_files, rev)?;
+    // Like git, local changes in the way abort the merge before anything is reported.
+    switch_files(&mut index, &our_files, &files)?;
+    for message in messages {
+        verbosity::info(message);
+    }
+
+    let mut message = match rev::dwim_ref(rev)? {
+        Some(name) => match name.strip_prefix("refs/heads/") {
+            Some(branch) => format!("Merge branch '{branch}'"),
+            None if name.starts_with("refs/tags/") => {
+                format!("Merge tag '{}'", refs::shorten_name(&name))
+            }
+            None if name.starts_with("refs/remotes/") => {
+                format!(
+                    "Merge remote-tracking branch '{}'",
+                    refs::shorten_name(&name)
+                )
+            }
+            None => format!("Merge commit '{rev}'"),
+        },
+        None => format!("Merge commit '{rev}'"),
+    };
+    // Like git, merging into the main branch goes without saying.
+    if let Some(branch) = branch
+        .as_deref()
+        .and_then(|branch| branch.strip_prefix("refs/heads/"))
+    {
+        if branch != "main" && branch != "master" {
+            message.push_str(&format!(" into {branch}"));
+        }
+    }
+
+    if !conflicts.is_empty() {
+        index.commit()?;
+        fs::write(git_dir.join("MERGE_HEAD"), format!("{theirs}\n"))?;
+        fs::write(git_dir.join("MERGE_MODE"), "")?;
+        let mut merge_message = format!("{message}\n\n# Conflicts:\n");
+        for path in &conflicts {
+            merge_message.push_str(&format!("#\t{path}\n"));
+        }
+        fs::write(git_dir.join("MERGE_MSG"), merge_message)?;
+        verbosity::info("Automatic merge failed; fix conflicts and then commit the result.");
+        process::exit(1);
+    }
+    let entries = index
+        .entries
+        .iter()
+        .map(|entry| (entry.path.as_str(), entry))
+        .collect::<Vec<_>>();
+    let (tree, cache_tree) = write_index_tree(&entries, index.cache_tree.as_ref())?;
+    index.cache_tree = Some(cache_tree);
+    index.commit()?;
+    let hash = commit_tree(tree, vec![ours, theirs], message, None)?;
+    refs::update_ref(
+        branch.as_deref().unwrap_or("HEAD"),
+        &hash,
+        &format!("merge {rev}: Merge made by the 'ort' strategy."),
+    )?;
+    verbosity::info("Merge made by the 'ort' strategy.");
+    Ok(())
+}
+
+fn files_by_path(tree: &GitHexHash) -> anyhow::Result<HashMap<String, (u32, GitHexHash)>> {
+    Ok(flatten_tree(tree)?
+        .into_iter()
+        .map(|(path, mode, hash)| (path, (mode, hash)))
+        .collect())
+}
+
+/// The files of a merge of three trees.
+struct MergedTree {
+    files: BTreeMap<String, MergedFile>,
+    /// The paths in conflict.
+    conflicts: Vec<String>,
+    /// The files merged line by line and the conflicts, reported once the working tree is updated.
+    messages: Vec<String>,
+}
+
+/// Merge the files of the three trees.
+fn merge_files(
+    base: &HashMap<String, (u32, GitHexHash)>,
+    ours: &HashMap<String, (u32, GitHexHash)>,
+    theirs: &HashMap<String, (u32, GitHexHash)>,
+    their_label: &str,
+) -> anyhow::Result<MergedTree> {
+    let paths = base
+        .keys()
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .collect::<BTreeSet<_>>();
+    let mut result = BTreeMap::new();
+    let mut conflicts = Vec::new();
+    let mut messages = Vec::new();
+    for path in paths {
+        let (base, our, their) = (base.get(path), ours.get(path), theirs.get(path));
+        let stages = [base.cloned(), our.cloned(), their.cloned()];
+        let merged = match (base, our, their) {
+            _ if our == their => our
+                .cloned()
+                .map(|(mode, hash)| MergedFile::Clean(mode, hash)),
+            _ if base == our => their
+                .cloned()
+                .map(|(mode, hash)| MergedFile::Clean(mode, hash)),
+            _ if base == their => our
+                .cloned()
+                .map(|(mode, hash)| MergedFile::Clean(mode, hash)),
+            (_, Some((our_mode, our_hash)), Some((their_mode, their_hash)))
+                if is_regular_file(*our_mode) && is_regular_file(*their_mode) =>
+            {
+                let base_mode = base.map(|(mode, _)| *mode);
+                let (mode, mode_conflict) =
+                    if our_mode == their_mode || base_mode == Some(*their_mode) {
+                        (*our_mode, false)
+                    } else if base_mode == Some(*our_mode) {
+                        (*their_mode, false)
+                    } else {
+                        (*our_mode, true)
+                    };
+                let (content, content_conflict) = if our_hash == their_hash {
+                    (read_blob(our_hash)?, false)
+                } else {
+                    messages.push(format!("Auto-merging {path}"));
+                    let base_content = match base {
+                        Some((_, hash)) => read_blob(hash)?,
+                        None => Vec::new(),
+                    };
+                    merge_file(
+                        &base_content,
+                        &read_blob(our_hash)?,
+                        &read_blob(their_hash)?,
+                        ("HEAD", their_label),
+                    )
+                };
+                if content_conflict || mode_conflict {
+                    let kind = if base.is_some() { "content" } else { "add/add" };
+                    messages.push(format!("CONFLICT ({kind}): Merge conflict in {path}"));
+                    Some(MergedFile::Conflict {
+                        stages,
+                        work: WorkFile::Merged(mode, content),
+                    })
+                } else {
+                    let hash =
+                        write_object("blob", content.len() as u64, |out| out.write_all(&content))?;
+                    Some(MergedFile::Clean(mode, hash))
+                }
+            }
+            (Some(_), Some((mode, hash)), None) => {
+                messages.push(format!("CONFLICT (modify/delete): {path} deleted in {their_label} and modified in HEAD.  Version HEAD of {path} left in tree."));
+                Some(MergedFile::Conflict {
+                    stages,
+                    work: WorkFile::Object(*mode, hash.clone()),
+                })
+            }
+            (Some(_), None, Some((mode, hash))) => {
+                messages.push(format!("CONFLICT (modify/delete): {path} deleted in HEAD and modified in {their_label}.  Version {their_label} of {path} left in tree."));
+                Some(MergedFile::Conflict {
+                    stages,
+                    work: WorkFile::Object(*mode, hash.clone()),
+                })
+            }
+            // Symbolic links and submodules can't be merged, ours is kept.
+            (_, our, their) => {
+                messages.push(format!("CONFLICT (content): Merge conflict in {path}"));
+                let (mode, hash) = our.or(their).context("a file missing on both sides")?;
+                Some(MergedFile::Conflict {
+                    stages,
+                    work: WorkFile::Object(*mode, hash.clone()),
+                })
+            }
+        };
+        if let Some(merged) = merged {
+            if matches!(merged, MergedFile::Conflict { .. }) {
+                conflicts.push(path.clone());
+            }
+            result.insert(path.clone(), merged);
+        }
+    }
+    // A file on one side where the other has a directory can't be in the index at the same time.
+    for path in result.keys() {
+        let directory = format!("{path}/");
+        if let Some((other, _)) = result.range(directory.clone()..).next() {
+            ensure!(
+                !other.starts_with(&directory),
+                "merging {path}: conflicts between a file and a directory are not supported"
+            );
+        }
+    }
+    Ok(MergedTree {
+        files: result,
+        conflicts,
+        messages,
+    })
+}
+
+fn read_blob(hash: &GitHexHash) -> anyhow::Result<Vec<u8>> {
+    let mut content = Vec::new();
+    ObjectReader::from_sha1(hash.clone())?
+        .into_body_reader()
+        .read_to_end(&mut content)?;
+    Ok(content)
+}
+
+/// Update the index and the working tree from the files of HEAD, `ours`, to the files of a merge. Like git,
+/// nothing is changed if a file the merge changes has local changes, or isn't tracked but would be overwritten.
+fn switch_files(
+    index: &mut LockedIndex,
+    ours: &HashMap<String, (u32, GitHexHash)>,
+    result: &BTreeMap<String, MergedFile>,
+) -> anyhow::Result<()> {
+    let work_tree = repo::work_tree()?;
+    let mut changed = ours
+        .keys()
+        .filter(|path| !result.contains_key(*path))
+        .collect::<Vec<_>>();
+    for (path, merged) in result {
+        // Conflicts change the index even when the file is left as ours.
+        let unchanged = match merged {
+            MergedFile::Clean(mode, hash) => ours.get(path) == Some(&(*mode, hash.clone())),
+            MergedFile::Conflict { .. } => false,
+        };
+        if !unchanged {
+            changed.push(path);
+        }
+    }
+    changed.sort();
+
+    let mut local_changes = Vec::new();
+    let mut untracked = Vec::new();
+    for path in changed.iter().copied() {
+        let full_path = work_tree.join(path);
+        let metadata = fs::symlink_metadata(&full_path).ok();
+        match (index.get(path), ours.get(path)) {
+            (Some(entry), Some((mode, hash))) if entry.mode == *mode && entry.hash == *hash => {
+                let modified = match &metadata {
+                    Some(metadata) => is_modified(entry, &full_path, metadata)?,
+                    None => false,
+                };
+                if modified {
+                    local_changes.push(path.as_str());
+                }
+            }
+            (None, None) if metadata.is_some() => untracked.push(path.as_str()),
+            (None, None) => {}
+            _ => local_changes.push(path.as_str()),
+        }
+    }
+    if !local_changes.is_empty() {
+        bail!(
+            "Your local changes to the following files would be overwritten by merge:\n\t{}\nPlease commit your changes or stash them before you merge.\nAborting",
+            local_changes.join("\n\t")
+        );
+    }
+    if !untracked.is_empty() {
+        bail!(
+            "The following untracked working tree files would be overwritten by merge:\n\t{}\nPlease move or remove them before you merge.\nAborting",
+            untracked.join("\n\t")
+        );
+    }
+
+    for path in changed {
+        let full_path = work_tree.join(path);
+        match fs::remove_file(&full_path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                return Err(err).context(format!("removing {full_path:?}"))
+            }
+            _ => {}
+        }
+        let Some(merged) = result.get(path) else {
+            index.remove(path);
+            for dir in full_path.ancestors().skip(1) {
+                if dir == work_tree || fs::remove_dir(dir).is_err() {
+                    break;
+                }
+            }
+            continue;
+        };
+        match merged {
+            MergedFile::Clean(mode, hash) => {
+                let mut entry = IndexEntry::new(path.clone(), *mode, hash.clone(), Stat::default());
+                checkout_entry(&entry, &full_path)?;
+                entry.stat = Stat::from_metadata(&fs::symlink_metadata(&full_path)?);
+                index.insert(entry);
+            }
+            MergedFile::Conflict { stages, work } => {
+                match work {
+                    WorkFile::Object(mode, hash) => {
+                        let entry =
+                            IndexEntry::new(path.clone(), *mode, hash.clone(), Stat::default());
+                        checkout_entry(&entry, &full_path)?;
+                    }
+                    WorkFile::Merged(mode, content) => {
+                        if let Some(parent) = full_path.parent() {
+                            fs::create_dir_all(parent).context(format!("creating {parent:?}"))?;
+                        }
+                        fs::write(&full_path, content).context(format!("writing {full_path:?}"))?;
+                        if Mode::from_bits(*mode)? == Mode::Executable {
+                            let mut permissions = fs::metadata(&full_path)?.permissions();
+                            permissions.set_mode(permissions.mode() | 0o111);
+                            fs::set_permissions(&full_path, permissions)?;
+                        }
+                    }
+                }
+                let entries = stages
+                    .iter()
+                    .zip(1..)
+                    .filter_map(|(side, stage)| {
+                        let (mode, hash) = side.clone()?;
+                        let mut entry = IndexEntry::new(path.clone(), mode, hash, Stat::default());
+                        entry.stage = stage;
+                        Some(entry)
+                    })
+                    .collect();
+                index.insert_conflict(entries);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,87 @@
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read},
+    ops::Deref,
+    path::Path,
+};
+
+// Larger objects are streamed: mapping them would reserve as much address space, and the read syscalls we save
+// are negligible next to inflating them.
+const MAX_MAPPED_LEN: u64 = 1 << 20;
+
+/// A read-only mapping of a whole file.
+pub(super) struct Mmap(memmap2::Mmap);
+
+impl Mmap {
+    /// Map the `len` first bytes of `file`, which must not be empty.
+    pub(super) fn map(file: &fs::File, len: usize) -> io::Result<Self> {
+        // SAFETY: the file is only mapped for reading. Objects and packs are never modified in place, so the
+        // content can't change under us.
+        let map = unsafe { memmap2::MmapOptions::new().len(len).map(file)? };
+        Ok(Self(map))
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for Mmap {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+/// The compressed content of a loose object.
+///
+/// Small objects are mapped in memory, which saves the read syscalls when going through thousands of them
+/// (`fsck`, `cat-file --batch`). The others are streamed from the file.
+pub(super) enum ObjectFile {
+    Mapped(io::Cursor<Mmap>),
+    Streamed(BufReader<fs::File>),
+}
+
+impl ObjectFile {
+    pub(super) fn open(path: &Path) -> io::Result<Self> {
+        let file = fs::File::open(path)?;
+        let len = file.metadata()?.len();
+        // Empty files can't be mapped, they'll fail later as corrupt objects.
+        if len == 0 || len > MAX_MAPPED_LEN {
+            return Ok(Self::Streamed(BufReader::new(file)));
+        }
+        Ok(match Mmap::map(&file, len as usize) {
+            Ok(map) => Self::Mapped(io::Cursor::new(map)),
+            // Some file systems don't support mapping, reading works everywhere.
+            Err(_) => Self::Streamed(BufReader::new(file)),
+        })
+    }
+}
+
+impl Read for ObjectFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Mapped(reader) => reader.read(buf),
+            Self::Streamed(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl BufRead for ObjectFile {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match self {
+            Self::Mapped(reader) => reader.fill_buf(),
+            Self::Streamed(reader) => reader.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        match self {
+            Self::Mapped(reader) => reader.consume(amt),
+            Self::Streamed(reader) => reader.consume(amt),
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use crate::ObjectKind;
+use anyhow::{bail, Context};
+
+/// The mode of a tree or index entry, telling what kind of file it is.
+///
+/// Trees store it as octal ASCII, the index as a number.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Mode {
+    Tree,
+    RegularFile,
+    Executable,
+    Symlink,
+    /// A submodule, pointing to a commit of another repository.
+    Gitlink,
+}
+
+impl Mode {
+    pub(super) fn from_bits(bits: u32) -> anyhow::Result<Self> {
+        Ok(match bits {
+            0o40000 => Mode::Tree,
+            0o100644 => Mode::RegularFile,
+            0o100755 => Mode::Executable,
+            0o120000 => Mode::Symlink,
+            0o160000 => Mode::Gitlink,
+            _ => bail!("invalid mode {bits:o}"),
+        })
+    }
+
+    /// Parse an octal mode, as found in trees.
+    pub(super) fn parse(mode: &str) -> anyhow::Result<Self> {
+        let bits = u32::from_str_radix(mode, 8).context(format!("invalid mode {mode}"))?;
+        Self::from_bits(bits)
+    }
+
+    pub(super) fn bits(self) -> u32 {
+        match self {
+            Mode::Tree => 0o40000,
+            Mode::RegularFile => 0o100644,
+            Mode::Executable => 0o100755,
+            Mode::Symlink => 0o120000,
+            Mode::Gitlink => 0o160000,
+        }
+    }
+
+    /// The octal mode as written in trees.
+    // By observing git, the leading 0 displayed for dir mode is not encoded.
+    pub(super) fn as_str(self) -> &'static str {
+        match self {
+            Mode::Tree => "40000",
+            Mode::RegularFile => "100644",
+            Mode::Executable => "100755",
+            Mode::Symlink => "120000",
+            Mode::Gitlink => "160000",
+        }
+    }
+
+    /// The type of the object an entry with this mode points to.
+    pub(super) fn object_kind(self) -> ObjectKind {
+        match self {
+            Mode::Tree => ObjectKind::Tree,
+            Mode::Gitlink => ObjectKind::Commit,
+            Mode::RegularFile | Mode::Executable | Mode::Symlink => ObjectKind::Blob,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mode;
+    use crate::ObjectKind;
+
+    const MODES: [(Mode, &str, u32, ObjectKind); 5] = [
+        (Mode::Tree, "40000", 0o40000, ObjectKind::Tree),
+        (Mode::RegularFile, "100644", 0o100644, ObjectKind::Blob),
+        (Mode::Executable, "100755", 0o100755, ObjectKind::Blob),
+        (Mode::Symlink, "120000", 0o120000, ObjectKind::Blob),
+        (Mode::Gitlink, "160000", 0o160000, ObjectKind::Commit),
+    ];
+
+    #[test]
+    fn parses_and_formats_each_mode() {
+        for (mode, text, bits, _) in MODES {
+            assert_eq!(Mode::parse(text).unwrap(), mode);
+            assert_eq!(mode.as_str(), text);
+            assert_eq!(Mode::from_bits(bits).unwrap(), mode);
+            assert_eq!(mode.bits(), bits);
+        }
+    }
+
+    #[test]
+    fn parses_the_padded_tree_mode() {
+        // `ls-tree` shows the tree mode padded to 6 digits.
+        assert_eq!(Mode::parse("040000").unwrap(), Mode::Tree);
+    }
+
+    #[test]
+    fn object_kind_of_each_mode() {
+        for (mode, _, _, kind) in MODES {
+            assert_eq!(mode.object_kind(), kind);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_modes() {
+        for mode in ["", "100644x", "9", "100600", "100000"] {
+            assert!(Mode::parse(mode).is_err(), "{mode:?}");
+        }
+        assert!(Mode::from_bits(0o100664).is_err());
+    }
+}
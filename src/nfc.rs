@@ -0,0 +1,46 @@
+// Unicode canonical composition (NFC), for filenames coming decomposed (NFD) from the filesystem.
+
+use unicode_normalization::UnicodeNormalization;
+
+/// Compose the decomposed sequences of `name`. ASCII names are returned untouched without any work.
+pub(super) fn precompose(name: &str) -> String {
+    if name.is_ascii() {
+        return name.to_string();
+    }
+    name.nfc().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::precompose;
+
+    #[test]
+    fn ascii_is_untouched() {
+        assert_eq!(precompose("README.md"), "README.md");
+    }
+
+    #[test]
+    fn composes_adjacent_pairs() {
+        assert_eq!(precompose("e\u{301}te\u{301}.txt"), "\u{e9}t\u{e9}.txt");
+        assert_eq!(precompose("\u{1100}\u{1161}\u{11a8}"), "\u{ac01}");
+    }
+
+    #[test]
+    fn composes_across_non_blocking_marks() {
+        // U+0316 has a lower combining class than U+0301, so it doesn't block the composition with 'a'.
+        assert_eq!(precompose("a\u{316}\u{301}"), "\u{e1}\u{316}");
+        // The marks are canonically reordered before composing.
+        assert_eq!(precompose("a\u{301}\u{316}"), "\u{e1}\u{316}");
+    }
+
+    #[test]
+    fn keeps_blocked_marks() {
+        // Both marks have the same combining class: the second one is blocked by the first.
+        assert_eq!(precompose("a\u{308}\u{301}"), "\u{e4}\u{301}");
+    }
+
+    #[test]
+    fn keeps_composition_exclusions_decomposed() {
+        assert_eq!(precompose("\u{915}\u{93c}"), "\u{915}\u{93c}");
+    }
+}
@@ -0,0 +1,1314 @@
+use crate::{
+    hash::{HashAlgorithm, Hasher},
+    hash_content,
+    hex_hash::GitHexHash,
+    max_object_size,
+    mmap::Mmap,
+    repo, ObjectKind, ObjectReader,
+};
+use anyhow::{anyhow, bail, ensure, Context};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    io::{self, Read, Write},
+    ops::Deref,
+    path::{Path, PathBuf},
+    process,
+    sync::{Mutex, OnceLock},
+};
+
+// Object types of the pack entry headers.
+const OBJ_COMMIT: u8 = 1;
+const OBJ_TREE: u8 = 2;
+const OBJ_BLOB: u8 = 3;
+const OBJ_TAG: u8 = 4;
+const OBJ_OFS_DELTA: u8 = 6;
+const OBJ_REF_DELTA: u8 = 7;
+
+enum PackedObject {
+    Base(ObjectKind, Vec<u8>),
+    /// Delta against the object starting at this offset of the pack.
+    OfsDelta(usize, Vec<u8>),
+    /// Delta against the object with this name.
+    RefDelta(GitHexHash, Vec<u8>),
+}
+
+/// Store a pack received from `reader`, returns how many objects it has. Like git, a pack with fewer objects than
+/// `unpack_limit` is exploded into loose objects, a larger one is kept in `objects/pack` along with its `.idx`.
+///
+/// The pack is streamed to a temporary file then indexed from there, rather than parsed as it arrives: deltas
+/// can refer to objects further in the pack, so holding the objects until their base shows up would take as
+/// much memory as the pack. Indexing a file costs a second read of the pack but keeps the memory bounded, and
+/// when the pack is kept that file is the one stored.
+pub(super) fn receive(reader: &mut impl Read, unpack_limit: usize) -> anyhow::Result<usize> {
+    let pack_dir = repo::objects_dir()?.join("pack");
+    fs::create_dir_all(&pack_dir).context(format!("creating {pack_dir:?}"))?;
+    let tmp_path = pack_dir.join(format!("tmp_pack_{}", process::id()));
+    let received = receive_into(reader, unpack_limit, &tmp_path);
+    if tmp_path.exists() {
+        fs::remove_file(&tmp_path).context(format!("removing {tmp_path:?}"))?;
+    }
+    received
+}
+
+fn receive_into(
+    reader: &mut impl Read,
+    unpack_limit: usize,
+    tmp_path: &Path,
+) -> anyhow::Result<usize> {
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(tmp_path)
+        .context(format!("unable to create temporary file {tmp_path:?}"))?;
+    let mut file = io::BufWriter::new(file);
+    // The header tells whether the pack is kept before indexing it.
+    let mut header = [0; 12];
+    reader
+        .read_exact(&mut header)
+        .context("reading pack header")?;
+    let count = u32::from_be_bytes(header[8..12].try_into()?) as usize;
+    file.write_all(&header)?;
+    io::copy(reader, &mut file).context("receiving pack")?;
+    file.into_inner()
+        .map_err(|err| err.into_error())?
+        .sync_all()?;
+
+    let keep = count >= unpack_limit;
+    let (mut entries, checksum) = index_pack_file(tmp_path, !keep)?;
+    if !keep {
+        return Ok(count);
+    }
+    let pack_path = tmp_path.with_file_name(format!("pack-{checksum}.pack"));
+    let index_path = pack_path.with_extension("idx");
+    // Already received by an earlier fetch.
+    if index_path.is_file() && pack_path.is_file() {
+        return Ok(count);
+    }
+    // The index goes last: a pack without its index is ignored, an index without its pack would not be.
+    let tmp_index_path = tmp_path.with_extension("idx");
+    let index_file = io::BufWriter::new(
+        fs::File::create(&tmp_index_path).context(format!("creating {tmp_index_path:?}"))?,
+    );
+    write_index(&mut entries, &checksum, index_file)?;
+    // Like loose objects, packs are never modified.
+    for path in [tmp_path, &tmp_index_path] {
+        let mut permissions = fs::metadata(path)?.permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(path, permissions).context(format!("making {path:?} read-only"))?;
+    }
+    fs::rename(tmp_path, &pack_path).context(format!("moving pack to {pack_path:?}"))?;
+    fs::rename(&tmp_index_path, &index_path).context(format!("moving index to {index_path:?}"))?;
+    add_pack(&index_path)?;
+    Ok(count)
+}
+
+/// Name each object of the pack at `path` by applying its deltas, storing them as loose objects when `write` is
+/// set. Returns where each object is in the pack and the pack checksum.
+///
+/// The pack is `PACK`, the version, the number of objects, the objects and the checksum of everything before.
+/// Each object is a header with its type and size followed by its zlib compressed content, which is either the
+/// object itself or a delta to apply on another object.
+///
+/// The pack is mapped rather than read: a first pass only finds where each entry is, then objects are inflated
+/// one at a time when resolving them, keeping only the bases of deltas in a bounded cache.
+pub(super) fn index_pack_file(
+    path: &Path,
+    write: bool,
+) -> anyhow::Result<(Vec<PackedEntry>, GitHexHash)> {
+    let algorithm = HashAlgorithm::current()?;
+    let file = fs::File::open(path).context(format!("opening {path:?}"))?;
+    let len = file.metadata()?.len() as usize;
+    ensure!(len >= 12 + algorithm.raw_len(), "pack too short");
+    let pack = Mmap::map(&file, len).context(format!("mapping {path:?}"))?;
+    let (content, checksum) = pack.split_at(pack.len() - algorithm.raw_len());
+    let checksum = GitHexHash::from_raw(checksum);
+    let mut hasher = algorithm.hasher();
+    hasher.update(content);
+    ensure!(
+        hasher.finalize() == checksum,
+        "pack is corrupted (SHA1 mismatch)"
+    );
+    ensure!(&content[..4] == b"PACK", "bad pack signature");
+    let version = u32::from_be_bytes(content[4..8].try_into()?);
+    ensure!(
+        matches!(version, 2 | 3),
+        "unsupported pack version {version}"
+    );
+    let count = u32::from_be_bytes(content[8..12].try_into()?) as usize;
+
+    // Where each entry starts, its base and its CRC32, the content is skipped.
+    let mut objects = Vec::with_capacity(count);
+    let mut cache = DeltaBaseCache::default();
+    let mut ref_bases = HashSet::new();
+    let mut offset = 12;
+    for _ in 0..count {
+        let start = offset;
+        let header = read_entry_header(content, offset)
+            .context(format!("reading pack object at offset {offset}"))?;
+        offset = header.data_start + skip_inflated(&content[header.data_start..], header.size)?;
+        match &header.base {
+            Some(DeltaBase::Offset(base)) => {
+                cache.bases.insert(*base);
+            }
+            Some(DeltaBase::Hash(base)) => {
+                ref_bases.insert(base.clone());
+            }
+            None => {}
+        }
+        let crc32 = crc32fast::hash(&content[start..offset]);
+        objects.push((start, header.base, crc32));
+    }
+    ensure!(offset == content.len(), "garbage at the end of the pack");
+
+    // Deltas can only be applied once their base is known, which may take several rounds: the base of a ref
+    // delta can come after it.
+    let mut resolved = HashSet::with_capacity(count);
+    let mut offsets_by_hash = HashMap::with_capacity(count);
+    let mut entries = Vec::with_capacity(count);
+    let mut pending = objects;
+    while !pending.is_empty() {
+        let mut unresolved = Vec::new();
+        let pending_len = pending.len();
+        for (start, base, crc32) in pending {
+            let ready = match &base {
+                None => true,
+                Some(DeltaBase::Offset(base)) => resolved.contains(base),
+                // Thin packs refer to objects we already have.
+                Some(DeltaBase::Hash(base)) => {
+                    offsets_by_hash.contains_key(base) || base.exists()?
+                }
+            };
+            if !ready {
+                unresolved.push((start, base, crc32));
+                continue;
+            }
+            let find = |hash: &GitHexHash| Ok(offsets_by_hash.get(hash).copied());
+            let (kind, data) = read_object(content, start, find, Some(&mut cache))
+                .context(format!("resolving pack object at offset {start}"))?;
+            let hash = hash_content(
+                &kind.to_string(),
+                data.len() as u64,
+                &mut data.as_slice(),
+                write,
+            )?;
+            if ref_bases.contains(&hash) {
+                cache.bases.insert(start);
+                cache.insert(start, kind, &data);
+            }
+            entries.push(PackedEntry {
+                hash: hash.clone(),
+                offset: start as u64,
+                crc32,
+            });
+            offsets_by_hash.insert(hash, start);
+            resolved.insert(start);
+        }
+        ensure!(
+            unresolved.len() < pending_len,
+            "{} deltas with missing base objects",
+            unresolved.len()
+        );
+        pending = unresolved;
+    }
+    Ok((entries, checksum))
+}
+
+// Same default as git's `core.deltaBaseCacheLimit`.
+const DELTA_BASE_CACHE_LIMIT: usize = 96 << 20;
+
+/// The content of the objects deltas apply on, so that a base shared by many deltas is only rebuilt once. Once
+/// full, it is emptied.
+#[derive(Default)]
+struct DeltaBaseCache {
+    /// Offsets of the objects some delta applies on, the only ones kept. Known beforehand when indexing a pack,
+    /// otherwise found while following the chains.
+    bases: HashSet<usize>,
+    objects: HashMap<usize, (ObjectKind, Vec<u8>)>,
+    size: usize,
+}
+
+impl DeltaBaseCache {
+    fn get(&self, offset: usize) -> Option<(ObjectKind, &[u8])> {
+        self.objects
+            .get(&offset)
+            .map(|(kind, data)| (*kind, data.as_slice()))
+    }
+
+    fn insert(&mut self, offset: usize, kind: ObjectKind, data: &[u8]) {
+        if !self.bases.contains(&offset) || data.len() > DELTA_BASE_CACHE_LIMIT {
+            return;
+        }
+        if self.size + data.len() > DELTA_BASE_CACHE_LIMIT {
+            self.objects.clear();
+            self.size = 0;
+        }
+        if self.objects.insert(offset, (kind, data.to_vec())).is_none() {
+            self.size += data.len();
+        }
+    }
+}
+
+/// Read the object at `offset` of `pack`, following its chain of deltas down to the base. The base of a ref
+/// delta is looked up in the pack with `find`, then in the object store.
+fn read_object(
+    pack: &[u8],
+    offset: usize,
+    find: impl Fn(&GitHexHash) -> anyhow::Result<Option<usize>>,
+    mut cache: Option<&mut DeltaBaseCache>,
+) -> anyhow::Result<(ObjectKind, Vec<u8>)> {
+    let mut offset = offset;
+    // The deltas to apply, with the offset of the object each one rebuilds.
+    let mut deltas = Vec::new();
+    let (kind, mut data) = loop {
+        ensure!(deltas.len() < MAX_DELTA_CHAIN, "delta chain too long");
+        // Below the requested object, each one is the base of the delta above.
+        if let Some(cache) = cache.as_mut().filter(|_| !deltas.is_empty()) {
+            cache.bases.insert(offset);
+        }
+        if let Some((kind, data)) = cache.as_ref().and_then(|cache| cache.get(offset)) {
+            break (kind, data.to_vec());
+        }
+        let (object, _) =
+            read_packed_object(pack, offset).context(format!("reading offset {offset}"))?;
+        match object {
+            PackedObject::Base(kind, data) => {
+                if let Some(cache) = cache.as_mut() {
+                    cache.insert(offset, kind, &data);
+                }
+                break (kind, data);
+            }
+            PackedObject::OfsDelta(base, delta) => {
+                // The base is before the delta, otherwise we could loop.
+                ensure!(base < offset, "invalid delta base offset");
+                deltas.push((offset, delta));
+                offset = base;
+            }
+            PackedObject::RefDelta(base, delta) => {
+                deltas.push((offset, delta));
+                match find(&base)? {
+                    Some(base) => offset = base,
+                    None => {
+                        let object = ObjectReader::from_sha1(base)?;
+                        let kind = object.kind();
+                        let mut data = Vec::new();
+                        object.into_body_reader().read_to_end(&mut data)?;
+                        break (kind, data);
+                    }
+                }
+            }
+        }
+    };
+    for (offset, delta) in deltas.iter().rev() {
+        data = apply_delta(&data, delta)?;
+        if let Some(cache) = cache.as_mut() {
+            cache.insert(*offset, kind, &data);
+        }
+    }
+    Ok((kind, data))
+}
+
+/// The header of a pack entry, what is needed to find its content and its base without inflating it.
+struct EntryHeader {
+    kind: u8,
+    size: u64,
+    base: Option<DeltaBase>,
+    /// Offset of the zlib compressed content, after the header.
+    data_start: usize,
+}
+
+enum DeltaBase {
+    /// The object starting at this offset of the pack.
+    Offset(usize),
+    /// The object with this name.
+    Hash(GitHexHash),
+}
+
+fn read_entry_header(pack: &[u8], offset: usize) -> anyhow::Result<EntryHeader> {
+    let mut pos = offset;
+    let byte = *pack.get(pos).context("truncated pack")?;
+    pos += 1;
+    let kind = (byte >> 4) & 0b111;
+    // The size is on the 4 low bits of the first byte then 7 bits per byte, least significant first.
+    let mut size = u64::from(byte & 0x0f);
+    let mut shift = 4;
+    let mut byte = byte;
+    while byte & 0x80 != 0 {
+        byte = *pack.get(pos).context("truncated pack")?;
+        pos += 1;
+        size |= u64::from(byte & 0x7f) << shift;
+        shift += 7;
+    }
+
+    let base = match kind {
+        OBJ_OFS_DELTA => {
+            // A big endian number of 7 bits per byte, adding one to each continuation.
+            let mut byte = *pack.get(pos).context("truncated pack")?;
+            pos += 1;
+            let mut distance = usize::from(byte & 0x7f);
+            while byte & 0x80 != 0 {
+                byte = *pack.get(pos).context("truncated pack")?;
+                pos += 1;
+                distance = ((distance + 1) << 7) | usize::from(byte & 0x7f);
+            }
+            let base = offset
+                .checked_sub(distance)
+                .context("delta base offset out of the pack")?;
+            Some(DeltaBase::Offset(base))
+        }
+        OBJ_REF_DELTA => {
+            let raw_len = HashAlgorithm::current()?.raw_len();
+            let base =
+                GitHexHash::from_raw(pack.get(pos..pos + raw_len).context("truncated pack")?);
+            pos += raw_len;
+            Some(DeltaBase::Hash(base))
+        }
+        OBJ_COMMIT | OBJ_TREE | OBJ_BLOB | OBJ_TAG => None,
+        _ => bail!("invalid object type {kind}"),
+    };
+    Ok(EntryHeader {
+        kind,
+        size,
+        base,
+        data_start: pos,
+    })
+}
+
+// Returns the object and the number of bytes it takes in the pack.
+fn read_packed_object(pack: &[u8], offset: usize) -> anyhow::Result<(PackedObject, usize)> {
+    let header = read_entry_header(pack, offset)?;
+    let (data, len) = inflate(&pack[header.data_start..], header.size)?;
+    let object = match header.base {
+        Some(DeltaBase::Offset(base)) => PackedObject::OfsDelta(base, data),
+        Some(DeltaBase::Hash(base)) => PackedObject::RefDelta(base, data),
+        None => PackedObject::Base(object_kind(header.kind)?, data),
+    };
+    Ok((object, header.data_start + len - offset))
+}
+
+fn object_kind(kind: u8) -> anyhow::Result<ObjectKind> {
+    Ok(match kind {
+        OBJ_COMMIT => ObjectKind::Commit,
+        OBJ_TREE => ObjectKind::Tree,
+        OBJ_BLOB => ObjectKind::Blob,
+        OBJ_TAG => ObjectKind::Tag,
+        _ => bail!("invalid object type {kind}"),
+    })
+}
+
+// Decompress a zlib stream of the pack, returns the data and the compressed length.
+fn inflate(data: &[u8], size: u64) -> anyhow::Result<(Vec<u8>, usize)> {
+    check_size(size)?;
+    let mut decoder = ZlibDecoder::new(data);
+    let mut inflated = Vec::with_capacity(size as usize);
+    // One byte past the size is enough to tell the stream is too long, without inflating all of it.
+    decoder.by_ref().take(size + 1).read_to_end(&mut inflated)?;
+    ensure!(
+        inflated.len() as u64 == size,
+        "inflated size {} doesn't match the expected {size}",
+        inflated.len()
+    );
+    Ok((inflated, decoder.total_in() as usize))
+}
+
+// Like `inflate` without keeping the data, returns the compressed length.
+fn skip_inflated(data: &[u8], size: u64) -> anyhow::Result<usize> {
+    check_size(size)?;
+    let mut decoder = ZlibDecoder::new(data);
+    let inflated = io::copy(&mut decoder, &mut io::sink())?;
+    ensure!(
+        inflated == size,
+        "inflated size {inflated} doesn't match the expected {size}"
+    );
+    Ok(decoder.total_in() as usize)
+}
+
+fn check_size(size: u64) -> anyhow::Result<()> {
+    ensure!(
+        size <= max_object_size(),
+        "object is too large ({size} bytes, the limit is {})",
+        max_object_size()
+    );
+    Ok(())
+}
+
+/// Rebuild an object from its base and a delta.
+///
+/// The delta starts with the base and result sizes, then instructions either copying a range of the base or
+/// inserting new data.
+fn apply_delta(base: &[u8], delta: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut delta = io::Cursor::new(delta);
+    let base_size = read_size(&mut delta)?;
+    ensure!(base_size == base.len() as u64, "delta base size mismatch");
+    let result_size = read_size(&mut delta)?;
+    check_size(result_size)?;
+    let mut result = Vec::with_capacity(result_size as usize);
+
+    let mut instruction = [0; 1];
+    while delta.read(&mut instruction)? != 0 {
+        let instruction = instruction[0];
+        if instruction & 0x80 != 0 {
+            // The low 4 bits tell which bytes of the offset are present, the next 3 bits the bytes of the size.
+            let mut offset = 0usize;
+            let mut len = 0usize;
+            for i in 0..4 {
+                if instruction & (1 << i) != 0 {
+                    offset |= usize::from(read_byte(&mut delta)?) << (8 * i);
+                }
+            }
+            for i in 0..3 {
+                if instruction & (1 << (4 + i)) != 0 {
+                    len |= usize::from(read_byte(&mut delta)?) << (8 * i);
+                }
+            }
+            if len == 0 {
+                len = 0x10000;
+            }
+            let copied = base
+                .get(offset..offset + len)
+                .context("delta copies out of the base")?;
+            result.extend_from_slice(copied);
+        } else if instruction != 0 {
+            let start = result.len();
+            result.resize(start + usize::from(instruction), 0);
+            delta
+                .read_exact(&mut result[start..])
+                .context("truncated delta")?;
+        } else {
+            bail!("invalid delta instruction 0");
+        }
+    }
+    ensure!(
+        result.len() as u64 == result_size,
+        "delta result size mismatch"
+    );
+    Ok(result)
+}
+
+// Sizes of the delta header: 7 bits per byte, least significant first.
+fn read_size(reader: &mut impl Read) -> anyhow::Result<u64> {
+    let mut size = 0;
+    let mut shift = 0;
+    loop {
+        ensure!(shift < 64, "delta size too large");
+        let byte = read_byte(reader)?;
+        size |= u64::from(byte & 0x7f) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(size);
+        }
+    }
+}
+
+fn read_byte(reader: &mut impl Read) -> anyhow::Result<u8> {
+    let mut byte = [0; 1];
+    reader.read_exact(&mut byte).context("truncated delta")?;
+    Ok(byte[0])
+}
+
+// Same limit as git for the delta chains of an object, protects from a cycle of ref deltas.
+const MAX_DELTA_CHAIN: usize = 10_000;
+
+// Packs never change once written, they're found on the first lookup and the ones received later are added.
+// They stay mapped until the command exits.
+static PACKS: OnceLock<Mutex<Vec<&'static Pack>>> = OnceLock::new();
+
+/// A parsed `.idx`, naming the objects of a pack and where they are. See [`write_index`] for the format.
+pub(super) struct PackIndex<D> {
+    data: D,
+    raw_len: usize,
+}
+
+// Where the sorted names start, after the signature, the version and the fanout table.
+const INDEX_NAMES: usize = 8 + 256 * 4;
+
+impl<D: Deref<Target = [u8]>> PackIndex<D> {
+    pub(super) fn parse(data: D) -> anyhow::Result<Self> {
+        let raw_len = HashAlgorithm::current()?.raw_len();
+        ensure!(data.len() >= INDEX_NAMES, "unable to read header");
+        ensure!(
+            data[..4] == *b"\xfftOc" && data[4..8] == 2u32.to_be_bytes(),
+            "unknown index version"
+        );
+        let index = Self { data, raw_len };
+        // Counts by first byte, which can't decrease: the lookups rely on them to stay within the names.
+        ensure!(
+            (0..255).all(|byte| index.fanout(byte) <= index.fanout(byte + 1)),
+            "corrupt index: non-monotonic fanout table"
+        );
+        ensure!(
+            index.data.len() >= INDEX_NAMES + index.len() * (raw_len + 8) + 2 * raw_len,
+            "index file is too small for its {} objects",
+            index.len()
+        );
+        Ok(index)
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.fanout(255)
+    }
+
+    // Number of objects whose first byte is at most `byte`.
+    fn fanout(&self, byte: u8) -> usize {
+        let start = 8 + usize::from(byte) * 4;
+        u32::from_be_bytes(self.data[start..start + 4].try_into().expect("4 bytes")) as usize
+    }
+
+    /// Name of the object at `position`, in the order of the index: sorted by name.
+    pub(super) fn hash(&self, position: usize) -> GitHexHash {
+        let start = INDEX_NAMES + position * self.raw_len;
+        GitHexHash::from_raw(&self.data[start..start + self.raw_len])
+    }
+
+    /// CRC32 of the whole pack entry of the object at `position`.
+    pub(super) fn crc32(&self, position: usize) -> u32 {
+        let start = INDEX_NAMES + self.len() * self.raw_len + position * 4;
+        u32::from_be_bytes(self.data[start..start + 4].try_into().expect("4 bytes"))
+    }
+
+    /// Offset in the pack of the object at `position`. Offsets are after the names and the CRC32s, with the large
+    /// ones in a table of 64 bits offsets.
+    pub(super) fn offset(&self, position: usize) -> anyhow::Result<u64> {
+        let offsets = INDEX_NAMES + self.len() * (self.raw_len + 4);
+        let start = offsets + position * 4;
+        let offset = u32::from_be_bytes(self.data[start..start + 4].try_into()?);
+        if offset & 0x8000_0000 == 0 {
+            return Ok(u64::from(offset));
+        }
+        let start = offsets + self.len() * 4 + (offset & 0x7fff_ffff) as usize * 8;
+        let large = self
+            .data
+            .get(start..start + 8)
+            .context("large offset out of the index")?;
+        Ok(u64::from_be_bytes(large.try_into()?))
+    }
+
+    /// Offset in the pack of the object, found by a binary search of the sorted names.
+    pub(super) fn find(&self, hash: &GitHexHash) -> anyhow::Result<Option<u64>> {
+        let raw = hash.to_raw();
+        let mut low = match raw[0] {
+            0 => 0,
+            byte => self.fanout(byte - 1),
+        };
+        let mut high = self.fanout(raw[0]);
+        while low < high {
+            let middle = (low + high) / 2;
+            let start = INDEX_NAMES + middle * self.raw_len;
+            match self.data[start..start + self.raw_len].cmp(&raw) {
+                Ordering::Less => low = middle + 1,
+                Ordering::Greater => high = middle,
+                Ordering::Equal => return self.offset(middle).map(Some),
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// A pack of the object directories along with its `.idx`, both mapped in memory.
+struct Pack {
+    path: PathBuf,
+    index: PackIndex<Mmap>,
+    data: Mmap,
+    // The offsets of the objects in the pack, sorted when an entry size is first needed.
+    sorted_offsets: OnceLock<Vec<u64>>,
+    // Shared by the lookups, as objects of a pack are often deltas against the same bases.
+    base_cache: Mutex<DeltaBaseCache>,
+}
+
+impl Pack {
+    fn open(index_path: &Path) -> anyhow::Result<Self> {
+        let path = index_path.with_extension("pack");
+        let map = |path: &Path| -> anyhow::Result<Mmap> {
+            let file = fs::File::open(path).context(format!("opening {path:?}"))?;
+            let len = file.metadata()?.len() as usize;
+            ensure!(len > 0, "{path:?} is empty");
+            Mmap::map(&file, len).context(format!("mapping {path:?}"))
+        };
+        // The path along with the reason, only the outermost error is shown.
+        let index =
+            PackIndex::parse(map(index_path)?).map_err(|err| anyhow!("{err} in {index_path:?}"))?;
+        let data = map(&path)?;
+        ensure!(
+            data.len() >= 12 + index.raw_len && data[..4] == *b"PACK",
+            "{path:?} is not a pack"
+        );
+        Ok(Self {
+            path,
+            index,
+            data,
+            sorted_offsets: OnceLock::new(),
+            base_cache: Mutex::default(),
+        })
+    }
+
+    fn find(&self, hash: &GitHexHash) -> anyhow::Result<Option<usize>> {
+        Ok(self.index.find(hash)?.map(|offset| offset as usize))
+    }
+
+    /// Size of the entry at `offset`, header included: up to the next entry, or the checksum ending the pack.
+    fn entry_size(&self, offset: u64) -> anyhow::Result<u64> {
+        let sorted_offsets = match self.sorted_offsets.get() {
+            Some(sorted_offsets) => sorted_offsets,
+            None => {
+                let mut sorted_offsets = (0..self.index.len())
+                    .map(|position| self.index.offset(position))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                sorted_offsets.sort_unstable();
+                self.sorted_offsets.get_or_init(|| sorted_offsets)
+            }
+        };
+        let end = match sorted_offsets.partition_point(|&other| other <= offset) {
+            next if next < sorted_offsets.len() => sorted_offsets[next],
+            _ => (self.data.len() - self.index.raw_len) as u64,
+        };
+        ensure!(end > offset, "invalid offset {offset} in {:?}", self.path);
+        Ok(end - offset)
+    }
+
+    /// Read the object at `offset`, following its chain of deltas down to the base.
+    fn read_at(&self, offset: usize) -> anyhow::Result<(ObjectKind, Vec<u8>)> {
+        let mut cache = self
+            .base_cache
+            .lock()
+            // Only a shortcut, what a panicking thread left is still valid.
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        read_object(&self.data, offset, |hash| self.find(hash), Some(&mut cache))
+            .context(format!("reading {:?}", self.path))
+    }
+
+    /// The kind and size of the object at `offset`, without inflating more than the beginning of a delta: its
+    /// header has the size of the result, and the kind is the one of the base at the end of the chain.
+    fn header_at(&self, offset: usize) -> anyhow::Result<(ObjectKind, u64)> {
+        let mut header = read_entry_header(&self.data, offset)
+            .context(format!("reading offset {offset} of {:?}", self.path))?;
+        let size = match header.base {
+            None => header.size,
+            Some(_) => {
+                let mut delta = ZlibDecoder::new(&self.data[header.data_start..]);
+                let _base_size = read_size(&mut delta)?;
+                read_size(&mut delta)?
+            }
+        };
+        for _ in 0..MAX_DELTA_CHAIN {
+            let base = match header.base {
+                None => return Ok((object_kind(header.kind)?, size)),
+                Some(DeltaBase::Offset(base)) => base,
+                Some(DeltaBase::Hash(base)) => match self.find(&base)? {
+                    Some(base) => base,
+                    None => return Ok((ObjectReader::header(&base)?.0, size)),
+                },
+            };
+            header = read_entry_header(&self.data, base)
+                .context(format!("reading offset {base} of {:?}", self.path))?;
+        }
+        bail!("delta chain too long in {:?}", self.path)
+    }
+}
+
+fn packs() -> anyhow::Result<Vec<&'static Pack>> {
+    if let Some(packs) = PACKS.get() {
+        return Ok(packs
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone());
+    }
+    let mut packs = Vec::new();
+    for objects_dir in repo::object_dirs()? {
+        let entries = match fs::read_dir(objects_dir.join("pack")) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let mut paths = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            // An index without its pack is left over by an interrupted command, git ignores it too.
+            if path.extension().is_some_and(|extension| extension == "idx")
+                && path.with_extension("pack").is_file()
+            {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        for path in paths {
+            packs.push(&*Box::leak(Box::new(Pack::open(&path)?)));
+        }
+    }
+    Ok(PACKS
+        .get_or_init(|| Mutex::new(packs))
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone())
+}
+
+// Make a pack written by this command visible to the lookups.
+fn add_pack(index_path: &Path) -> anyhow::Result<()> {
+    let Some(packs) = PACKS.get() else {
+        // Found along with the others on the first lookup.
+        return Ok(());
+    };
+    let pack = Box::leak(Box::new(Pack::open(index_path)?));
+    packs
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .push(pack);
+    Ok(())
+}
+
+/// The packed objects whose name starts with `prefix`, a lowercase hexadecimal prefix of at least 2 digits.
+pub(super) fn find_prefix(prefix: &str) -> anyhow::Result<Vec<GitHexHash>> {
+    let first = u8::from_str_radix(&prefix[..2], 16)?;
+    let mut found = Vec::new();
+    for pack in packs()? {
+        let index = &pack.index;
+        let mut low = match first {
+            0 => 0,
+            byte => index.fanout(byte - 1),
+        };
+        let end = index.fanout(first);
+        // The names are sorted: search the first one not before the prefix, the matching ones follow.
+        let mut high = end;
+        while low < high {
+            let middle = (low + high) / 2;
+            if index.hash(middle).as_str() < prefix {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        for position in low..end {
+            let hash = index.hash(position);
+            if !hash.as_str().starts_with(prefix) {
+                break;
+            }
+            found.push(hash);
+        }
+    }
+    Ok(found)
+}
+
+/// Whether an object is in one of the packs of the object directories.
+pub(super) fn contains(hash: &GitHexHash) -> anyhow::Result<bool> {
+    for pack in packs()? {
+        if pack.find(hash)?.is_some() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// The kind and content of an object from the packs, `None` if no pack has it.
+pub(super) fn read_packed(hash: &GitHexHash) -> anyhow::Result<Option<(ObjectKind, Vec<u8>)>> {
+    for pack in packs()? {
+        if let Some(offset) = pack.find(hash)? {
+            return pack.read_at(offset).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+/// The kind and size of an object from the packs, `None` if no pack has it.
+pub(super) fn read_packed_header(hash: &GitHexHash) -> anyhow::Result<Option<(ObjectKind, u64)>> {
+    for pack in packs()? {
+        if let Some(offset) = pack.find(hash)? {
+            return pack.header_at(offset).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+/// Size of the entry of an object in the packs, compressed and possibly a delta, `None` if no pack has it.
+pub(super) fn disk_size(hash: &GitHexHash) -> anyhow::Result<Option<u64>> {
+    for pack in packs()? {
+        if let Some(offset) = pack.index.find(hash)? {
+            return pack.entry_size(offset).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+/// Where an object was written in a pack, what the `.idx` records.
+pub(super) struct PackedEntry {
+    pub(super) hash: GitHexHash,
+    pub(super) offset: u64,
+    /// CRC32 of the whole entry in the pack, header included.
+    pub(super) crc32: u32,
+}
+
+/// Write the objects as a pack. Returns the entries and the pack checksum.
+///
+/// With a `window`, objects are stored as deltas against similar ones when it makes them smaller, see
+/// [`find_deltas`]. A base is always written before its deltas.
+pub(super) fn write_pack(
+    objects: &[GitHexHash],
+    paths: &[String],
+    window: usize,
+    depth: usize,
+    out: impl Write,
+) -> anyhow::Result<(Vec<PackedEntry>, GitHexHash)> {
+    let algorithm = HashAlgorithm::current()?;
+    let deltas = if window > 0 && depth > 0 {
+        find_deltas(objects, paths, window, depth)?
+    } else {
+        HashMap::new()
+    };
+    let mut out = HashingWriter {
+        inner: out,
+        hasher: algorithm.hasher(),
+        written: 0,
+    };
+    out.write_all(b"PACK")?;
+    out.write_all(&2u32.to_be_bytes())?;
+    out.write_all(&(objects.len() as u32).to_be_bytes())?;
+
+    let mut entries = Vec::with_capacity(objects.len());
+    let mut written = vec![false; objects.len()];
+    for position in 0..objects.len() {
+        // The object then its bases not written yet, written in the reverse order.
+        let mut chain = vec![position];
+        while let Some((base, _)) = deltas.get(chain.last().expect("not empty")) {
+            chain.push(*base);
+        }
+        for position in chain.into_iter().rev() {
+            if written[position] {
+                continue;
+            }
+            written[position] = true;
+            let hash = &objects[position];
+            let entry = match deltas.get(&position) {
+                Some((base, delta)) => {
+                    let mut entry = entry_header(OBJ_REF_DELTA, delta.len() as u64);
+                    entry.extend_from_slice(&objects[*base].to_raw());
+                    let mut encoder = ZlibEncoder::new(entry, Compression::default());
+                    encoder.write_all(delta)?;
+                    encoder.finish()?
+                }
+                None => {
+                    let object = ObjectReader::from_sha1(hash.clone())?;
+                    let kind = entry_type(object.kind());
+                    let mut data = Vec::new();
+                    object.into_body_reader().read_to_end(&mut data)?;
+                    let entry = entry_header(kind, data.len() as u64);
+                    let mut encoder = ZlibEncoder::new(entry, Compression::default());
+                    encoder.write_all(&data)?;
+                    encoder.finish()?
+                }
+            };
+            entries.push(PackedEntry {
+                hash: hash.clone(),
+                offset: out.written,
+                crc32: crc32fast::hash(&entry),
+            });
+            out.write_all(&entry)?;
+        }
+    }
+    let checksum = out.hasher.finalize();
+    out.inner.write_all(&checksum.to_raw())?;
+    out.inner.flush()?;
+    Ok((entries, checksum))
+}
+
+fn entry_type(kind: ObjectKind) -> u8 {
+    match kind {
+        ObjectKind::Commit => OBJ_COMMIT,
+        ObjectKind::Tree => OBJ_TREE,
+        ObjectKind::Blob => OBJ_BLOB,
+        ObjectKind::Tag => OBJ_TAG,
+    }
+}
+
+// The type and the size: 4 bits of size in the first byte then 7 bits per byte.
+fn entry_header(kind: u8, size: u64) -> Vec<u8> {
+    let mut header = Vec::new();
+    let mut size = size;
+    let mut byte = (kind << 4) | (size & 0x0f) as u8;
+    size >>= 4;
+    while size != 0 {
+        header.push(byte | 0x80);
+        byte = (size & 0x7f) as u8;
+        size >>= 7;
+    }
+    header.push(byte);
+    header
+}
+
+/// Choose which objects to store as deltas, like git's delta window: returns, for the position of an object in
+/// `objects`, the position of its base and the delta.
+///
+/// Objects are sorted by type, [`name_hash`] of their path, then decreasing size, so that similar objects end up
+/// close and deltas mostly remove data. Each one is tried against the `window` previous objects of its type,
+/// keeping the smallest delta. A delta is only kept when at most half the size of the object, and chains are at
+/// most `depth` long.
+fn find_deltas(
+    objects: &[GitHexHash],
+    paths: &[String],
+    window: usize,
+    depth: usize,
+) -> anyhow::Result<HashMap<usize, (usize, Vec<u8>)>> {
+    let mut order = Vec::with_capacity(objects.len());
+    for (position, hash) in objects.iter().enumerate() {
+        let (kind, size) = ObjectReader::header(hash)?;
+        let name_hash = paths.get(position).map_or(0, |path| name_hash(path));
+        order.push((entry_type(kind), name_hash, size, position));
+    }
+    order.sort_unstable_by(|a, b| {
+        (a.0, a.1)
+            .cmp(&(b.0, b.1))
+            .then(b.2.cmp(&a.2))
+            .then(a.3.cmp(&b.3))
+    });
+
+    let mut deltas = HashMap::new();
+    let mut depths = vec![0; objects.len()];
+    // The previous objects of the same type with their content, the most recent last.
+    let mut recent: VecDeque<(usize, DeltaSource)> = VecDeque::with_capacity(window + 1);
+    let mut recent_kind = None;
+    for (kind, _, _, position) in order {
+        if recent_kind != Some(kind) {
+            recent.clear();
+            recent_kind = Some(kind);
+        }
+        let mut data = Vec::new();
+        ObjectReader::from_sha1(objects[position].clone())?
+            .into_body_reader()
+            .read_to_end(&mut data)?;
+        let mut best: Option<(usize, Vec<u8>)> = None;
+        for (base, source) in recent.iter().rev() {
+            if depths[*base] >= depth {
+                continue;
+            }
+            let max_size = match &best {
+                Some((_, delta)) => delta.len() - 1,
+                None => data.len() / 2,
+            };
+            if let Some(delta) = create_delta(source, &data, max_size) {
+                best = Some((*base, delta));
+            }
+        }
+        if let Some((base, delta)) = best {
+            depths[position] = depths[base] + 1;
+            deltas.insert(position, (base, delta));
+        }
+        recent.push_back((position, DeltaSource::new(data)));
+        if recent.len() > window {
+            recent.pop_front();
+        }
+    }
+    Ok(deltas)
+}
+
+// The same as git: the last characters of the path count the most, so that the versions of a file sort together,
+// then the files with the same name or extension.
+fn name_hash(path: &str) -> u32 {
+    path.bytes()
+        .filter(|byte| !byte.is_ascii_whitespace())
+        .fold(0, |hash, byte| {
+            (hash >> 2).wrapping_add(u32::from(byte) << 24)
+        })
+}
+
+// The length of the blocks of the base a delta looks for in the target.
+const DELTA_BLOCK: usize = 16;
+
+/// An object deltas can be made against, indexed by blocks of [`DELTA_BLOCK`] bytes once for all the objects of
+/// the window.
+struct DeltaSource {
+    data: Vec<u8>,
+    /// Where each block starts, the first one when repeated.
+    blocks: HashMap<u128, usize>,
+}
+
+impl DeltaSource {
+    fn new(data: Vec<u8>) -> Self {
+        let mut blocks = HashMap::new();
+        for (i, block) in data.chunks_exact(DELTA_BLOCK).enumerate() {
+            blocks
+                .entry(u128::from_le_bytes(block.try_into().expect("a block")))
+                .or_insert(i * DELTA_BLOCK);
+        }
+        Self { data, blocks }
+    }
+}
+
+/// Encode `target` as a delta against `source`, the inverse of [`apply_delta`]. `None` when it would take more
+/// than `max_size` bytes.
+///
+/// Walking the target, a block found in the base is extended both ways as long as they match and becomes a copy,
+/// the bytes in between are inserted.
+fn create_delta(source: &DeltaSource, target: &[u8], max_size: usize) -> Option<Vec<u8>> {
+    let base = &source.data;
+    // Copies have 32 bits offsets.
+    if u32::try_from(base.len()).is_err() {
+        return None;
+    }
+
+    let mut delta = Vec::new();
+    write_size(&mut delta, base.len());
+    write_size(&mut delta, target.len());
+    let mut inserted = 0;
+    let mut pos = 0;
+    while pos < target.len() {
+        let found = target.get(pos..pos + DELTA_BLOCK).and_then(|block| {
+            source
+                .blocks
+                .get(&u128::from_le_bytes(block.try_into().expect("a block")))
+        });
+        let Some(&found) = found else {
+            pos += 1;
+            // Each 127 bytes inserted take one more for the instruction.
+            if delta.len() + (pos - inserted) * 128 / 127 > max_size {
+                return None;
+            }
+            continue;
+        };
+        let forward = base[found..]
+            .iter()
+            .zip(&target[pos..])
+            .take_while(|(a, b)| a == b)
+            .count();
+        let backward = base[..found]
+            .iter()
+            .rev()
+            .zip(target[inserted..pos].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        push_insert(&mut delta, &target[inserted..pos - backward]);
+        push_copy(&mut delta, found - backward, backward + forward);
+        pos += forward;
+        inserted = pos;
+        if delta.len() > max_size {
+            return None;
+        }
+    }
+    push_insert(&mut delta, &target[inserted..]);
+    (delta.len() <= max_size).then_some(delta)
+}
+
+// Sizes of the delta header: 7 bits per byte, least significant first.
+fn write_size(delta: &mut Vec<u8>, size: usize) {
+    let mut size = size;
+    while size >= 0x80 {
+        delta.push((size & 0x7f) as u8 | 0x80);
+        size >>= 7;
+    }
+    delta.push(size as u8);
+}
+
+// Inserting new data: the instruction is the length, at most 127 bytes.
+fn push_insert(delta: &mut Vec<u8>, data: &[u8]) {
+    for chunk in data.chunks(0x7f) {
+        delta.push(chunk.len() as u8);
+        delta.extend_from_slice(chunk);
+    }
+}
+
+// Copying from the base: only the non-zero bytes of the offset and the size are written, flagged in the
+// instruction. A size has 3 bytes, longer copies are split.
+fn push_copy(delta: &mut Vec<u8>, offset: usize, len: usize) {
+    let mut offset = offset;
+    let mut len = len;
+    while len > 0 {
+        let size = len.min(0xff_ffff);
+        let mut instruction = 0x80;
+        let mut bytes = Vec::with_capacity(7);
+        for i in 0..4 {
+            let byte = (offset >> (8 * i)) as u8;
+            if byte != 0 {
+                instruction |= 1 << i;
+                bytes.push(byte);
+            }
+        }
+        for i in 0..3 {
+            let byte = (size >> (8 * i)) as u8;
+            if byte != 0 {
+                instruction |= 1 << (4 + i);
+                bytes.push(byte);
+            }
+        }
+        delta.push(instruction);
+        delta.extend_from_slice(&bytes);
+        offset += size;
+        len -= size;
+    }
+}
+
+/// Write the version 2 `.idx` of a pack, to find its objects without reading it all.
+///
+/// It has a fan-out table counting the objects by first byte of their name, the sorted names, the CRC32 of
+/// each entry, their offsets (31 bits, larger ones in an extra table of 64 bits offsets), then the pack and
+/// index checksums.
+pub(super) fn write_index(
+    entries: &mut [PackedEntry],
+    pack_checksum: &GitHexHash,
+    out: impl Write,
+) -> anyhow::Result<()> {
+    let algorithm = HashAlgorithm::current()?;
+    let mut out = HashingWriter {
+        inner: out,
+        hasher: algorithm.hasher(),
+        written: 0,
+    };
+    entries.sort_unstable_by(|a, b| a.hash.as_str().cmp(b.hash.as_str()));
+    out.write_all(b"\xfftOc")?;
+    out.write_all(&2u32.to_be_bytes())?;
+    let mut fanout = [0u32; 256];
+    for entry in entries.iter() {
+        fanout[usize::from(entry.hash.to_raw()[0])] += 1;
+    }
+    let mut total = 0;
+    for count in fanout {
+        total += count;
+        out.write_all(&total.to_be_bytes())?;
+    }
+    for entry in entries.iter() {
+        out.write_all(&entry.hash.to_raw())?;
+    }
+    for entry in entries.iter() {
+        out.write_all(&entry.crc32.to_be_bytes())?;
+    }
+    let mut large_offsets = Vec::new();
+    for entry in entries.iter() {
+        let offset = match u32::try_from(entry.offset) {
+            Ok(offset) if offset & 0x8000_0000 == 0 => offset,
+            _ => {
+                large_offsets.push(entry.offset);
+                0x8000_0000 | (large_offsets.len() - 1) as u32
+            }
+        };
+        out.write_all(&offset.to_be_bytes())?;
+    }
+    for offset in large_offsets {
+        out.write_all(&offset.to_be_bytes())?;
+    }
+    out.write_all(&pack_checksum.to_raw())?;
+    let checksum = out.hasher.finalize();
+    out.inner.write_all(&checksum.to_raw())?;
+    out.inner.flush()?;
+    Ok(())
+}
+
+// Computes the checksum of what is written, and counts it to know the offsets.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Hasher,
+    written: u64,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_delta, create_delta, inflate, DeltaSource};
+    use flate2::{write::ZlibEncoder, Compression};
+    use std::io::Write;
+
+    // Deterministic bytes that don't repeat, unlike a pattern.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 33) as u8
+            })
+            .collect()
+    }
+
+    fn round_trip(base: &[u8], target: &[u8]) -> usize {
+        let source = DeltaSource::new(base.to_vec());
+        let delta = create_delta(&source, target, usize::MAX).expect("no size limit");
+        assert_eq!(apply_delta(base, &delta).unwrap(), target);
+        delta.len()
+    }
+
+    #[test]
+    fn identical() {
+        let data = noise(10_000, 1);
+        // The sizes and a single copy.
+        assert!(round_trip(&data, &data) < 16);
+    }
+
+    #[test]
+    fn edits() {
+        let base = noise(50_000, 2);
+        let mut inserted = base.clone();
+        inserted.splice(20_000..20_000, noise(300, 3));
+        assert!(round_trip(&base, &inserted) < 400);
+
+        let mut removed = base.clone();
+        removed.drain(10_000..30_000);
+        assert!(round_trip(&base, &removed) < 32);
+
+        let mut changed = base.clone();
+        for i in (0..changed.len()).step_by(5_000) {
+            changed[i] ^= 0xff;
+        }
+        round_trip(&base, &changed);
+
+        let (first, second) = base.split_at(25_000);
+        round_trip(&base, &[second, first].concat());
+    }
+
+    #[test]
+    fn edge_cases() {
+        round_trip(b"", b"");
+        round_trip(b"", b"only inserted");
+        round_trip(b"everything removed", b"");
+        round_trip(b"short", b"short");
+        round_trip(&noise(1_000, 4), &noise(1_000, 5));
+        // Longer than an insert instruction.
+        round_trip(b"", &noise(1_000, 6));
+    }
+
+    #[test]
+    fn long_copies() {
+        // Offsets and sizes using all of their bytes.
+        let base = noise(300_000, 7);
+        let target = [&base[70_000..], &base[..70_000], &base[65_536..131_072]].concat();
+        round_trip(&base, &target);
+    }
+
+    #[test]
+    fn max_size() {
+        let base = noise(10_000, 8);
+        let target = noise(10_000, 9);
+        let source = DeltaSource::new(base);
+        assert!(create_delta(&source, &target, 5_000).is_none());
+        assert!(create_delta(&source, &target, 20_000).is_some());
+    }
+
+    #[test]
+    fn corrupt_sizes() {
+        // A result of 2^62 bytes, refused before allocating it.
+        let huge = [0, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x40];
+        assert!(apply_delta(b"", &huge).is_err());
+        // More continuation bytes than fit in 64 bits.
+        let endless = [0xff; 12];
+        assert!(apply_delta(b"", &endless).is_err());
+    }
+
+    #[test]
+    fn inflated_size() {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[0; 100_000]).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let (inflated, len) = inflate(&compressed, 100_000).unwrap();
+        assert_eq!((inflated.len(), len), (100_000, compressed.len()));
+        assert!(inflate(&compressed, 5).is_err());
+        assert!(inflate(&compressed, 200_000).is_err());
+    }
+}
@@ -0,0 +1,51 @@
+use std::{
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+// Redrawing on every item would slow down the command more than the work itself.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A counter drawn on stderr like git's progress meters: `Hashing files: 42`, then `, done.` at the end.
+pub(super) struct Progress {
+    title: &'static str,
+    count: u64,
+    enabled: bool,
+    last_draw: Option<Instant>,
+}
+
+impl Progress {
+    pub(super) fn new(title: &'static str, enabled: bool) -> Self {
+        Self {
+            title,
+            count: 0,
+            enabled,
+            last_draw: None,
+        }
+    }
+
+    pub(super) fn tick(&mut self) {
+        self.count += 1;
+        if !self.enabled {
+            return;
+        }
+        let now = Instant::now();
+        if self.last_draw.map_or(true, |last_draw| {
+            now.duration_since(last_draw) >= REFRESH_INTERVAL
+        }) {
+            self.last_draw = Some(now);
+            self.draw("");
+        }
+    }
+
+    pub(super) fn finish(self) {
+        if self.enabled {
+            self.draw(", done.\n");
+        }
+    }
+
+    fn draw(&self, end: &str) {
+        // Progress is best effort, a closed stderr shouldn't fail the command.
+        let _ = write!(io::stderr(), "\r{}: {}{end}", self.title, self.count);
+    }
+}
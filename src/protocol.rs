@@ -0,0 +1,367 @@
+use crate::{
+    commit::Commit,
+    hash_serialized,
+    hex_hash::GitHexHash,
+    http,
+    pack::{self, PackIndex},
+    shallow,
+    tag::Tag,
+    tree::TreeEntries,
+    ObjectReader,
+};
+use anyhow::{bail, ensure, Context};
+use flate2::read::ZlibDecoder;
+use std::{
+    collections::HashSet,
+    io::{BufRead, BufReader, Read},
+};
+
+/// What a remote repository announces when we connect to it.
+pub(super) struct Advertisement {
+    pub(super) refs: Vec<(String, GitHexHash)>,
+    /// Features supported by the server, such as `ofs-delta` or `symref=HEAD:refs/heads/main`.
+    pub(super) capabilities: Vec<String>,
+    /// Served by a plain web server, the objects are downloaded with [`fetch_dumb`] rather than negotiated.
+    pub(super) dumb: bool,
+}
+
+impl Advertisement {
+    pub(super) fn has_capability(&self, name: &str) -> bool {
+        self.capabilities
+            .iter()
+            .any(|capability| capability == name)
+    }
+}
+
+/// Read a pkt-line: its length on 4 hexadecimal digits, the length included, then the data.
+///
+/// Returns `None` for a flush packet, `0000`, which ends a section.
+pub(super) fn read_pkt_line(reader: &mut impl Read) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    reader
+        .read_exact(&mut len)
+        .context("reading pkt-line length")?;
+    let len = std::str::from_utf8(&len)
+        .ok()
+        .and_then(|len| usize::from_str_radix(len, 16).ok())
+        .context(format!("invalid pkt-line length {len:?}"))?;
+    if len == 0 {
+        return Ok(None);
+    }
+    ensure!(len >= 4, "invalid pkt-line length {len}");
+    let mut data = vec![0; len - 4];
+    reader.read_exact(&mut data).context("reading pkt-line")?;
+    Ok(Some(data))
+}
+
+/// The refs of a remote repository, from the smart HTTP `info/refs` endpoint of `git-upload-pack`.
+///
+/// Plain web servers ignore the query and serve the `info/refs` file of the repository, the server speaks the
+/// dumb protocol when the content type isn't the one of the smart protocol.
+pub(super) fn discover_refs(url: &str) -> anyhow::Result<Advertisement> {
+    let service = "git-upload-pack";
+    let info_refs = format!("{}/info/refs?service={service}", url.trim_end_matches('/'));
+    let mut response = http::get(&info_refs)?;
+    let expected = format!("application/x-{service}-advertisement");
+    if response.content_type.as_deref() != Some(expected.as_str()) {
+        return read_info_refs(url, &mut response.body);
+    }
+
+    // The smart HTTP response starts with a `# service=<service>` section.
+    let header = read_pkt_line(&mut response.body)?.context("missing service header")?;
+    ensure!(
+        header.strip_suffix(b"\n").unwrap_or(&header) == format!("# service={service}").as_bytes(),
+        "invalid service header"
+    );
+    while read_pkt_line(&mut response.body)?.is_some() {}
+    read_ref_advertisement(&mut response.body)
+}
+
+// `<hash> <refname>` lines until a flush packet. The first line also has the server capabilities after a NUL.
+// An empty repository advertises a single `capabilities^{}` line with the null hash.
+fn read_ref_advertisement(reader: &mut impl Read) -> anyhow::Result<Advertisement> {
+    let mut refs = Vec::new();
+    let mut capabilities = Vec::new();
+    while let Some(line) = read_pkt_line(reader)? {
+        let line = match line.iter().position(|&byte| byte == 0) {
+            Some(nul) => {
+                let announced = std::str::from_utf8(&line[nul + 1..])?;
+                capabilities.extend(announced.split_whitespace().map(str::to_string));
+                &line[..nul]
+            }
+            None => &line[..],
+        };
+        let line = std::str::from_utf8(line)?.trim_end_matches('\n');
+        let (hash, name) = line
+            .split_once(' ')
+            .context(format!("invalid ref advertisement line: {line}"))?;
+        if name == "capabilities^{}" {
+            continue;
+        }
+        refs.push((name.to_string(), hash.try_into()?));
+    }
+    Ok(Advertisement {
+        refs,
+        capabilities,
+        dumb: false,
+    })
+}
+
+// The `info/refs` file written by `git update-server-info`: `<hash>\t<refname>` lines, annotated tags followed by
+// the object they point to as `<refname>^{}`. HEAD isn't listed, it's read from the `HEAD` file.
+fn read_info_refs(url: &str, reader: &mut impl BufRead) -> anyhow::Result<Advertisement> {
+    let mut refs: Vec<(String, GitHexHash)> = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let (hash, name) = line.split_once('\t').context(format!(
+            "{url} is not a git repository: invalid info/refs line: {line}"
+        ))?;
+        refs.push((name.to_string(), hash.try_into()?));
+    }
+    // Like with the smart protocol, what HEAD points to is told as a capability.
+    let mut capabilities = Vec::new();
+    if let Some(mut response) = http::get_if_found(&format!("{}/HEAD", url.trim_end_matches('/')))?
+    {
+        let mut head = String::new();
+        response.body.read_to_string(&mut head)?;
+        let head = head.trim_end();
+        let hash = match head.strip_prefix("ref: ") {
+            Some(target) => {
+                capabilities.push(format!("symref=HEAD:{target}"));
+                refs.iter()
+                    .find(|(name, _)| name == target)
+                    .map(|(_, hash)| hash.clone())
+            }
+            None => Some(head.try_into()?),
+        };
+        if let Some(hash) = hash {
+            refs.insert(0, ("HEAD".to_string(), hash));
+        }
+    }
+    Ok(Advertisement {
+        refs,
+        capabilities,
+        dumb: true,
+    })
+}
+
+/// Download the objects of a dumb server reachable from `wants`, returns how many there were.
+///
+/// Walking from the wanted objects, each missing one is downloaded as a loose object, otherwise the pack
+/// containing it is. The walk stops at the objects we already had.
+pub(super) fn fetch_dumb(url: &str, wants: &[GitHexHash]) -> anyhow::Result<usize> {
+    let url = url.trim_end_matches('/');
+    let mut packs = None;
+    let mut downloaded = HashSet::new();
+    let mut walked = HashSet::new();
+    let mut pending = wants.to_vec();
+    while let Some(hash) = pending.pop() {
+        if !walked.insert(hash.clone()) {
+            continue;
+        }
+        if !hash.exists()? {
+            download_object(url, &hash, &mut packs, &mut downloaded)?;
+        }
+        if !downloaded.contains(&hash) {
+            continue;
+        }
+        match ObjectReader::from_sha1(hash.clone())? {
+            ObjectReader::Blob(_) => {}
+            ObjectReader::Tree(reader) => {
+                for entry in TreeEntries::new(reader)? {
+                    let entry = entry?;
+                    // Gitlinks point to commits of another repository.
+                    if !entry.is_gitlink() {
+                        pending.push(entry.hash);
+                    }
+                }
+            }
+            ObjectReader::Commit(_) => {
+                let commit = Commit::read(&hash)?;
+                pending.push(commit.tree);
+                pending.extend(commit.parents);
+            }
+            ObjectReader::Tag(_) => pending.push(Tag::read(&hash)?.object),
+        }
+    }
+    Ok(downloaded.len())
+}
+
+// The packs of the server, listed in `objects/info/packs` as `P <name>.pack` lines, with their index. Only
+// fetched when an object isn't loose, and a pack is forgotten once downloaded.
+type RemotePacks = Option<Vec<(String, PackIndex<Vec<u8>>)>>;
+
+fn download_object(
+    url: &str,
+    hash: &GitHexHash,
+    packs: &mut RemotePacks,
+    downloaded: &mut HashSet<GitHexHash>,
+) -> anyhow::Result<()> {
+    let (dir, rest) = hash.as_str().split_at(2);
+    if let Some(response) = http::get_if_found(&format!("{url}/objects/{dir}/{rest}"))? {
+        let stored = hash_serialized(BufReader::new(ZlibDecoder::new(response.body)), true)
+            .context(format!("object {hash} from {url} is corrupt"))?;
+        ensure!(stored == *hash, "object {hash} from {url} is corrupt");
+        downloaded.insert(stored);
+        return Ok(());
+    }
+
+    let packs = match packs {
+        Some(packs) => packs,
+        None => packs.insert(list_packs(url)?),
+    };
+    let mut found = None;
+    for (i, (_, index)) in packs.iter().enumerate() {
+        if index.find(hash)?.is_some() {
+            found = Some(i);
+            break;
+        }
+    }
+    let Some(found) = found else {
+        bail!("Unable to find {hash} under {url}");
+    };
+    let (name, index) = packs.remove(found);
+    pack::receive(
+        &mut http::get(&format!("{url}/objects/pack/{name}.pack"))?.body,
+        0,
+    )
+    .context(format!("downloading {name}.pack"))?;
+    downloaded.extend((0..index.len()).map(|position| index.hash(position)));
+    Ok(())
+}
+
+fn list_packs(url: &str) -> anyhow::Result<Vec<(String, PackIndex<Vec<u8>>)>> {
+    let Some(response) = http::get_if_found(&format!("{url}/objects/info/packs"))? else {
+        return Ok(Vec::new());
+    };
+    let mut packs = Vec::new();
+    for line in response.body.lines() {
+        let line = line?;
+        let Some(name) = line
+            .strip_prefix("P ")
+            .and_then(|name| name.strip_suffix(".pack"))
+        else {
+            continue;
+        };
+        let mut index = Vec::new();
+        http::get(&format!("{url}/objects/pack/{name}.idx"))?
+            .body
+            .read_to_end(&mut index)
+            .context(format!("downloading {name}.idx"))?;
+        let index = PackIndex::parse(index).context(format!("reading {name}.idx"))?;
+        packs.push((name.to_string(), index));
+    }
+    Ok(packs)
+}
+
+/// The answer of `git-upload-pack` to a fetch request.
+pub(super) struct FetchedPack {
+    pub(super) pack: Box<dyn BufRead>,
+    /// With a depth, the commits at the boundary of the fetched history.
+    pub(super) shallow: Vec<GitHexHash>,
+    /// With a depth, the commits no longer at the boundary: their parents were fetched.
+    pub(super) unshallow: Vec<GitHexHash>,
+}
+
+/// What to ask `git-upload-pack` for.
+#[derive(Default)]
+pub(super) struct FetchRequest {
+    pub(super) wants: Vec<GitHexHash>,
+    /// Objects we already have, so the server doesn't send them nor their history.
+    pub(super) haves: Vec<GitHexHash>,
+    /// Only fetch the last `depth` commits.
+    pub(super) depth: Option<u32>,
+}
+
+/// Negotiate with `git-upload-pack` in a single round: we send everything we want and have then `done`.
+pub(super) fn fetch_pack(
+    url: &str,
+    advertisement: &Advertisement,
+    request: &FetchRequest,
+) -> anyhow::Result<FetchedPack> {
+    let depth = request.depth;
+    if depth.is_some() {
+        ensure!(
+            advertisement.has_capability("shallow"),
+            "Server does not support shallow clients"
+        );
+    }
+    // Without side-band, the pack follows the negotiation in the raw.
+    let capabilities = ["ofs-delta", "shallow", "include-tag"]
+        .into_iter()
+        .filter(|capability| advertisement.has_capability(capability))
+        .collect::<Vec<_>>();
+    let wants = &request.wants;
+    let haves = &request.haves;
+    let mut request = Vec::new();
+    for (i, want) in wants.iter().enumerate() {
+        // Capabilities are sent with the first want.
+        let line = if i == 0 {
+            format!("want {want} {}\n", capabilities.join(" "))
+        } else {
+            format!("want {want}\n")
+        };
+        write_pkt_line(&mut request, line.as_bytes());
+    }
+    // The server must not assume we have the parents of our shallow commits.
+    for commit in shallow::shallow_commits()? {
+        write_pkt_line(&mut request, format!("shallow {commit}\n").as_bytes());
+    }
+    if let Some(depth) = depth {
+        write_pkt_line(&mut request, format!("deepen {depth}\n").as_bytes());
+    }
+    request.extend_from_slice(b"0000");
+    for have in haves {
+        write_pkt_line(&mut request, format!("have {have}\n").as_bytes());
+    }
+    write_pkt_line(&mut request, b"done\n");
+
+    let service = "git-upload-pack";
+    let mut response = http::post(
+        &format!("{}/{service}", url.trim_end_matches('/')),
+        &format!("application/x-{service}-request"),
+        &format!("application/x-{service}-result"),
+        &request,
+    )?;
+    // A deepen request is answered first with the new shallow boundary: commits whose parents won't be sent,
+    // and ones of our boundary whose parents now are.
+    let mut shallow = Vec::new();
+    let mut unshallow = Vec::new();
+    if depth.is_some() {
+        while let Some(line) = read_pkt_line(&mut response.body)? {
+            let line = std::str::from_utf8(&line)?.trim_end_matches('\n');
+            if let Some(hash) = line.strip_prefix("shallow ") {
+                shallow.push(hash.try_into()?);
+            } else if let Some(hash) = line.strip_prefix("unshallow ") {
+                unshallow.push(hash.try_into()?);
+            } else {
+                bail!("unexpected shallow info: {line}");
+            }
+        }
+    }
+    // The negotiation ends with a common object acknowledged, or a NAK telling there is none, then the pack
+    // starts. `ACK <hash> <status>` lines come before with multi_ack, which we don't ask for.
+    loop {
+        let line = read_pkt_line(&mut response.body)?.context("unexpected flush packet")?;
+        let line = line.strip_suffix(b"\n").unwrap_or(&line);
+        if line == b"NAK" {
+            break;
+        }
+        let Some(ack) = line.strip_prefix(b"ACK ") else {
+            bail!("expected ACK or NAK, got {}", String::from_utf8_lossy(line));
+        };
+        if !ack.contains(&b' ') {
+            break;
+        }
+    }
+    Ok(FetchedPack {
+        pack: response.body,
+        shallow,
+        unshallow,
+    })
+}
+
+fn write_pkt_line(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(format!("{:04x}", data.len() + 4).as_bytes());
+    out.extend_from_slice(data);
+}
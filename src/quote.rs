@@ -0,0 +1,94 @@
+use std::borrow::Cow;
+
+/// Quote a path the way git does in its human readable outputs (`quote_c_style`).
+///
+/// When a path contains a double quote, a backslash or a control character, it is wrapped in double quotes and
+/// these characters are escaped, C style. When `quote_non_ascii` is set (`core.quotePath`, the default), bytes
+/// outside ASCII are also escaped in octal.
+pub(super) fn quote_path(path: &str, quote_non_ascii: bool) -> Cow<'_, str> {
+    let needs_quote = |byte: u8| {
+        byte == b'"'
+            || byte == b'\\'
+            || byte < 0x20
+            || byte == 0x7f
+            || (quote_non_ascii && byte >= 0x80)
+    };
+    if !path.bytes().any(needs_quote) {
+        return Cow::Borrowed(path);
+    }
+
+    let mut quoted = String::with_capacity(path.len() + 2);
+    quoted.push('"');
+    for (i, char) in path.char_indices() {
+        let byte = path.as_bytes()[i];
+        if !char.is_ascii() {
+            if quote_non_ascii {
+                for byte in &path.as_bytes()[i..i + char.len_utf8()] {
+                    quoted.push_str(&format!("\\{byte:03o}"));
+                }
+            } else {
+                quoted.push(char);
+            }
+            continue;
+        }
+        match byte {
+            0x07 => quoted.push_str("\\a"),
+            0x08 => quoted.push_str("\\b"),
+            b'\t' => quoted.push_str("\\t"),
+            b'\n' => quoted.push_str("\\n"),
+            0x0b => quoted.push_str("\\v"),
+            0x0c => quoted.push_str("\\f"),
+            b'\r' => quoted.push_str("\\r"),
+            b'"' => quoted.push_str("\\\""),
+            b'\\' => quoted.push_str("\\\\"),
+            byte if needs_quote(byte) => quoted.push_str(&format!("\\{byte:03o}")),
+            _ => quoted.push(char),
+        }
+    }
+    quoted.push('"');
+    Cow::Owned(quoted)
+}
+
+/// Like [`quote_path`], but a path with a space is also wrapped in double quotes, as git does in the formats where
+/// a space separates the fields (`QUOTE_PATH_QUOTE_SP`).
+pub(super) fn quote_path_with_space(path: &str, quote_non_ascii: bool) -> Cow<'_, str> {
+    match quote_path(path, quote_non_ascii) {
+        Cow::Borrowed(path) if path.contains(' ') => Cow::Owned(format!("\"{path}\"")),
+        quoted => quoted,
+    }
+}
+
+/// The path in a string quoted by [`quote_path`], None if it isn't properly quoted.
+pub(super) fn unquote_path(quoted: &str) -> Option<String> {
+    let inner = quoted.strip_prefix('"')?.strip_suffix('"')?;
+    let mut path = Vec::with_capacity(inner.len());
+    let mut bytes = inner.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            path.push(byte);
+            continue;
+        }
+        path.push(match bytes.next()? {
+            b'a' => 0x07,
+            b'b' => 0x08,
+            b't' => b'\t',
+            b'n' => b'\n',
+            b'v' => 0x0b,
+            b'f' => 0x0c,
+            b'r' => b'\r',
+            b'"' => b'"',
+            b'\\' => b'\\',
+            // Three octal digits.
+            first @ b'0'..=b'3' => {
+                let mut value = first - b'0';
+                for _ in 0..2 {
+                    let digit = bytes.next().filter(|digit| (b'0'..=b'7').contains(digit))?;
+                    value = value * 8 + (digit - b'0');
+                }
+                value
+            }
+            _ => return None,
+        });
+    }
+    String::from_utf8(path).ok()
+}
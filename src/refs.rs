@@ -0,0 +1,393 @@
+use crate::{
+    config,
+    date::GitDate,
+    hash::HashAlgorithm,
+    hex_hash::GitHexHash,
+    ident::{Identity, Role},
+    repo,
+};
+use anyhow::{bail, Context};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+// Same limit as git, it protects us from symbolic ref cycles.
+const MAX_SYMREF_DEPTH: usize = 5;
+
+/// A ref from `.git/packed-refs`.
+pub(super) struct PackedRef {
+    pub(super) name: String,
+    pub(super) hash: GitHexHash,
+    /// For annotated tags, the object the tag ultimately points to.
+    pub(super) peeled: Option<GitHexHash>,
+}
+
+/// Read a full ref name, following symbolic refs. Loose refs take precedence over packed ones.
+pub(super) fn read_ref(full_name: &str) -> anyhow::Result<Option<GitHexHash>> {
+    read_ref_at(repo::git_dir()?, full_name)
+}
+
+/// Like [`read_ref`] but in another repository, a submodule for instance.
+pub(super) fn read_ref_at(git_dir: &Path, full_name: &str) -> anyhow::Result<Option<GitHexHash>> {
+    let mut name = full_name.to_string();
+    for _ in 0..MAX_SYMREF_DEPTH {
+        match read_loose_ref(git_dir, &name)? {
+            Some(content) => match content.strip_prefix("ref: ") {
+                Some(target) => name = target.to_string(),
+                None => return Ok(Some(content.as_str().try_into()?)),
+            },
+            None => {
+                return Ok(read_packed_refs(git_dir)?
+                    .into_iter()
+                    .find(|packed| packed.name == name)
+                    .map(|packed| packed.hash))
+            }
+        }
+    }
+    bail!("symbolic ref {full_name} is nested too deeply")
+}
+
+// Returns the trimmed content of a loose ref file.
+fn read_loose_ref(git_dir: &Path, full_name: &str) -> anyhow::Result<Option<String>> {
+    let path = git_dir.join(full_name);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let content = fs::read_to_string(&path).context(format!("reading {path:?}"))?;
+    Ok(Some(content.trim_end().to_string()))
+}
+
+/// Parse `.git/packed-refs`.
+///
+/// The file starts with an optional `# pack-refs with: ...` header, then each line is `<hash> <refname>`.
+/// A line `^<hash>` gives the peeled value of the annotated tag on the previous line.
+pub(super) fn packed_refs() -> anyhow::Result<Vec<PackedRef>> {
+    read_packed_refs(repo::git_dir()?)
+}
+
+fn read_packed_refs(git_dir: &Path) -> anyhow::Result<Vec<PackedRef>> {
+    let path = git_dir.join("packed-refs");
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).context(format!("reading {path:?}")),
+    };
+
+    let mut refs: Vec<PackedRef> = Vec::new();
+    for line in content.lines() {
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        if let Some(peeled) = line.strip_prefix('^') {
+            let Some(last) = refs.last_mut() else {
+                bail!("unexpected peeled line in packed-refs: {line}");
+            };
+            last.peeled = Some(peeled.try_into()?);
+            continue;
+        }
+        let (hash, name) = line
+            .split_once(' ')
+            .context(format!("invalid packed-refs line: {line}"))?;
+        refs.push(PackedRef {
+            name: name.to_string(),
+            hash: hash.try_into()?,
+            peeled: None,
+        });
+    }
+    Ok(refs)
+}
+
+/// All the refs whose full name starts with `prefix` (e.g. `refs/heads/`), sorted by name.
+pub(super) fn list_refs(prefix: &str) -> anyhow::Result<Vec<(String, GitHexHash)>> {
+    let mut refs = BTreeMap::new();
+    for packed in packed_refs()? {
+        if packed.name.starts_with(prefix) {
+            refs.insert(packed.name, packed.hash);
+        }
+    }
+    // Inserted after the packed refs so loose refs override them.
+    let git_dir = repo::git_dir()?;
+    collect_loose_refs(git_dir, &git_dir.join("refs"), &mut refs)?;
+    refs.retain(|name, _| name.starts_with(prefix));
+    Ok(refs.into_iter().collect())
+}
+
+fn collect_loose_refs(
+    git_dir: &Path,
+    dir: &Path,
+    refs: &mut BTreeMap<String, GitHexHash>,
+) -> anyhow::Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_loose_refs(git_dir, &path, refs)?;
+            continue;
+        }
+        let name = path
+            .strip_prefix(git_dir)?
+            .to_str()
+            .context("ref name is not valid UTF-8")?
+            .to_string();
+        if let Some(hash) = read_ref(&name)? {
+            refs.insert(name, hash);
+        }
+    }
+    Ok(())
+}
+
+/// The ref HEAD points to, or `None` if HEAD is detached.
+pub(super) fn head_target() -> anyhow::Result<Option<String>> {
+    read_symbolic_ref("HEAD")
+}
+
+/// The ref a symbolic ref points to, or `None` if it directly points to an object.
+pub(super) fn read_symbolic_ref(name: &str) -> anyhow::Result<Option<String>> {
+    let content = read_loose_ref(repo::git_dir()?, name)?.context(format!("{name} not found"))?;
+    Ok(content.strip_prefix("ref: ").map(str::to_string))
+}
+
+/// The name of a ref without its namespace, `main` for `refs/heads/main`, as git shows them.
+pub(super) fn shorten_name(full_name: &str) -> &str {
+    ["refs/heads/", "refs/tags/", "refs/remotes/", "refs/"]
+        .iter()
+        .find_map(|prefix| full_name.strip_prefix(prefix))
+        .unwrap_or(full_name)
+}
+
+/// Point a ref to a new object, creating it if needed, and record the change in its reflog.
+pub(super) fn update_ref(full_name: &str, hash: &GitHexHash, message: &str) -> anyhow::Result<()> {
+    let old = read_ref(full_name)?;
+    write_ref_file(full_name, &format!("{hash}\n"))?;
+    if should_log(full_name)? {
+        append_reflog(full_name, old.as_ref(), hash, message)?;
+    }
+    // The reflog of HEAD follows the branch checked out.
+    if full_name != "HEAD" && head_target()?.as_deref() == Some(full_name) {
+        append_reflog("HEAD", old.as_ref(), hash, message)?;
+    }
+    Ok(())
+}
+
+/// Make `name` a symbolic ref pointing to the ref `target`, like HEAD pointing to the current branch.
+pub(super) fn write_symbolic_ref(name: &str, target: &str) -> anyhow::Result<()> {
+    write_ref_file(name, &format!("ref: {target}\n"))
+}
+
+/// Delete a ref, loose or packed, with its reflog. Returns what it pointed to, None if it didn't exist.
+pub(super) fn delete_ref(full_name: &str) -> anyhow::Result<Option<GitHexHash>> {
+    let Some(old) = read_ref(full_name)? else {
+        return Ok(None);
+    };
+    let git_dir = repo::git_dir()?;
+    remove_ref_file(git_dir, &git_dir.join(full_name))?;
+    remove_packed_ref(git_dir, full_name)?;
+    remove_ref_file(git_dir, &git_dir.join("logs").join(full_name))?;
+    Ok(Some(old))
+}
+
+/// Rename a ref pointing to `hash`, replacing `new_name` if it exists. Its reflog follows it, with an entry for
+/// the rename, and HEAD too if it pointed to the ref.
+pub(super) fn rename_ref(old_name: &str, new_name: &str, hash: &GitHexHash) -> anyhow::Result<()> {
+    let git_dir = repo::git_dir()?;
+    let log_path = git_dir.join("logs").join(old_name);
+    let log = match fs::read(&log_path) {
+        Ok(log) => Some(log),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err).context(format!("reading {log_path:?}")),
+    };
+    let moves_head = head_target()?.as_deref() == Some(old_name);
+    delete_ref(new_name)?;
+    // Deleted before writing the new one, which can be in a directory named like the old ref.
+    delete_ref(old_name)?;
+
+    if let Some(log) = log {
+        let path = git_dir.join("logs").join(new_name);
+        let parent = path.parent().context("reflog path without parent")?;
+        fs::create_dir_all(parent).context(format!("creating {parent:?}"))?;
+        fs::write(&path, log).context(format!("writing {path:?}"))?;
+    }
+    write_ref_file(new_name, &format!("{hash}\n"))?;
+    let message = format!("Branch: renamed {old_name} to {new_name}");
+    if should_log(new_name)? {
+        append_reflog(new_name, Some(hash), hash, &message)?;
+    }
+    if moves_head {
+        write_symbolic_ref("HEAD", new_name)?;
+        // Like git, HEAD's reflog shows the old branch going away then the new one appearing.
+        let null_hash = HashAlgorithm::current()?.null_hash();
+        append_reflog("HEAD", Some(hash), &null_hash, &message)?;
+        append_reflog("HEAD", None, hash, &message)?;
+    }
+    Ok(())
+}
+
+/// Whether `full_name` is a valid ref name, following the rules of `git check-ref-format`.
+pub(super) fn is_valid_name(full_name: &str) -> bool {
+    let forbidden = |char: char| char.is_ascii_control() || " ~^:?*[\\".contains(char);
+    !full_name.is_empty()
+        && full_name != "@"
+        && !full_name.contains("..")
+        && !full_name.contains("@{")
+        && !full_name.contains(forbidden)
+        && !full_name.ends_with('.')
+        && full_name.split('/').all(|component| {
+            !component.is_empty() && !component.starts_with('.') && !component.ends_with(".lock")
+        })
+}
+
+// Remove a loose ref or reflog file if it exists, then its parent directories left empty.
+fn remove_ref_file(git_dir: &Path, path: &Path) -> anyhow::Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).context(format!("removing {path:?}")),
+    }
+    let refs_dirs = [git_dir.join("refs"), git_dir.join("logs").join("refs")];
+    let mut dir = path.parent();
+    while let Some(parent) = dir.filter(|dir| !refs_dirs.iter().any(|refs_dir| refs_dir == dir)) {
+        // Fails when the directory isn't empty.
+        if fs::remove_dir(parent).is_err() {
+            break;
+        }
+        dir = parent.parent();
+    }
+    Ok(())
+}
+
+// Rewrite `packed-refs` without the ref and the peeled line following it, if it is there.
+fn remove_packed_ref(git_dir: &Path, full_name: &str) -> anyhow::Result<()> {
+    let path = git_dir.join("packed-refs");
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).context(format!("reading {path:?}")),
+    };
+    let mut kept = String::with_capacity(content.len());
+    let mut removing = false;
+    for line in content.lines() {
+        if line.starts_with('^') && removing {
+            continue;
+        }
+        removing = !line.starts_with('#')
+            && line
+                .split_once(' ')
+                .is_some_and(|(_, name)| name == full_name);
+        if !removing {
+            kept.push_str(line);
+            kept.push('\n');
+        }
+    }
+    if kept.len() == content.len() {
+        return Ok(());
+    }
+    write_ref_file("packed-refs", &kept)
+}
+
+/// A change of a ref, recorded in its reflog.
+pub(super) struct ReflogEntry {
+    /// The null hash when the ref was created.
+    pub(super) old: GitHexHash,
+    pub(super) new: GitHexHash,
+    /// What did the change, like `commit: <summary>` or `clone: from <url>`.
+    pub(super) message: String,
+}
+
+/// The reflog of a ref, oldest change first. Empty if the ref has none.
+///
+/// Each line of `.git/logs/<ref>` is `<old> <new> <name> <<email>> <timestamp> <offset>\t<message>`.
+pub(super) fn read_reflog(full_name: &str) -> anyhow::Result<Vec<ReflogEntry>> {
+    let path = repo::git_dir()?.join("logs").join(full_name);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).context(format!("reading {path:?}")),
+    };
+    content
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (old, rest) = line.split_once(' ').context("malformed reflog line")?;
+            let (new, rest) = rest.split_once(' ').context("malformed reflog line")?;
+            Ok(ReflogEntry {
+                old: old.try_into()?,
+                new: new.try_into()?,
+                message: rest
+                    .split_once('\t')
+                    .map(|(_, message)| message.to_string())
+                    .unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+// Like git with `core.logAllRefUpdates` left to its default, only branches, remote-tracking branches and HEAD
+// get a reflog, unless one was created for the ref by other means.
+fn should_log(full_name: &str) -> anyhow::Result<bool> {
+    let setting = config::config()?.get("core.logAllRefUpdates");
+    if setting.is_some_and(|value| value.eq_ignore_ascii_case("always")) {
+        return Ok(true);
+    }
+    if repo::git_dir()?.join("logs").join(full_name).is_file() {
+        return Ok(true);
+    }
+    let disabled = setting.is_some_and(|value| value.eq_ignore_ascii_case("false"));
+    Ok(!disabled
+        && (full_name == "HEAD"
+            || ["refs/heads/", "refs/remotes/", "refs/notes/"]
+                .iter()
+                .any(|prefix| full_name.starts_with(prefix))))
+}
+
+fn append_reflog(
+    full_name: &str,
+    old: Option<&GitHexHash>,
+    new: &GitHexHash,
+    message: &str,
+) -> anyhow::Result<()> {
+    let path = repo::git_dir()?.join("logs").join(full_name);
+    let parent = path.parent().context("reflog path without parent")?;
+    fs::create_dir_all(parent).context(format!("creating {parent:?}"))?;
+    let old = match old {
+        Some(old) => old.clone(),
+        None => HashAlgorithm::current()?.null_hash(),
+    };
+    let committer = Identity::resolve(Role::Committer)?;
+    let date = GitDate::from_env("GIT_COMMITTER_DATE")?;
+    // A message is a single line.
+    let message = message.lines().next().unwrap_or_default();
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .context(format!("opening {path:?}"))?;
+    writeln!(file, "{old} {new} {committer} {date}\t{message}").context(format!("writing {path:?}"))
+}
+
+// The new content is written in `<ref>.lock` then renamed so readers never see a partially written ref.
+fn write_ref_file(full_name: &str, content: &str) -> anyhow::Result<()> {
+    let path = repo::git_dir()?.join(full_name);
+    let parent = path.parent().context("ref path without parent")?;
+    fs::create_dir_all(parent).context(format!("creating {parent:?}"))?;
+    let lock = path.with_file_name(format!(
+        "{}.lock",
+        path.file_name()
+            .context("ref path without file name")?
+            .to_string_lossy()
+    ));
+    // `create_new` makes the lock exclusive: another process updating the ref at the same time fails here.
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&lock)
+        .context(format!("unable to create {lock:?}"))?;
+    file.write_all(content.as_bytes())
+        .context(format!("writing {lock:?}"))?;
+    fs::rename(&lock, &path).context(format!("updating {full_name}"))?;
+    Ok(())
+}
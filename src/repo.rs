@@ -0,0 +1,157 @@
+use crate::config;
+use anyhow::{bail, ensure, Context};
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+// The git directory is needed deep down in the object reading/writing code, so rather than threading it
+// through every function, we resolve it once per process.
+static GIT_DIR_OPTION: OnceLock<PathBuf> = OnceLock::new();
+static GIT_DIR: OnceLock<PathBuf> = OnceLock::new();
+static OBJECT_DIRS: OnceLock<Vec<PathBuf>> = OnceLock::new();
+static WORK_TREE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Override the git directory, as `git --git-dir` does. Must be called before any object access.
+pub(super) fn set_git_dir(path: PathBuf) {
+    let _ = GIT_DIR_OPTION.set(path);
+}
+
+/// The git directory explicitly requested by the user, if any.
+///
+/// Precedence is `--git-dir` then `GIT_DIR`.
+pub(super) fn explicit_git_dir() -> Option<PathBuf> {
+    GIT_DIR_OPTION
+        .get()
+        .cloned()
+        .or_else(|| env::var_os("GIT_DIR").map(PathBuf::from))
+}
+
+/// Location of the git directory: `--git-dir` > `GIT_DIR` > `.git` discovered from the current directory.
+pub(super) fn git_dir() -> anyhow::Result<&'static Path> {
+    if let Some(git_dir) = GIT_DIR.get() {
+        return Ok(git_dir);
+    }
+    let git_dir = match explicit_git_dir() {
+        Some(git_dir) => git_dir,
+        None => discover(&env::current_dir()?)?.1,
+    };
+    config::check_repository_format(&git_dir)?;
+    Ok(GIT_DIR.get_or_init(|| git_dir))
+}
+
+/// Top directory of the working tree: `GIT_WORK_TREE` > the current directory when the git directory is explicit
+/// > the directory containing the discovered `.git`.
+pub(super) fn work_tree() -> anyhow::Result<&'static Path> {
+    if let Some(work_tree) = WORK_TREE.get() {
+        return Ok(work_tree);
+    }
+    let current_dir = env::current_dir()?;
+    let work_tree = match env::var_os("GIT_WORK_TREE") {
+        Some(work_tree) => current_dir.join(work_tree),
+        None if explicit_git_dir().is_some() => current_dir,
+        None => discover(&current_dir)?.0.to_path_buf(),
+    };
+    Ok(WORK_TREE.get_or_init(|| work_tree))
+}
+
+/// Where objects are written: `GIT_OBJECT_DIRECTORY` or the `objects` directory of the repository.
+pub(super) fn objects_dir() -> anyhow::Result<PathBuf> {
+    match env::var_os("GIT_OBJECT_DIRECTORY") {
+        Some(objects_dir) => Ok(PathBuf::from(objects_dir)),
+        None => Ok(git_dir()?.join("objects")),
+    }
+}
+
+/// The index file: `GIT_INDEX_FILE`, used by scripts for temporary indexes, or the `index` of the git directory.
+pub(super) fn index_file() -> anyhow::Result<PathBuf> {
+    match env::var_os("GIT_INDEX_FILE") {
+        Some(index_file) => Ok(PathBuf::from(index_file)),
+        None => Ok(git_dir()?.join("index")),
+    }
+}
+
+/// Where objects are looked for: the objects directory followed by its alternates.
+pub(super) fn object_dirs() -> anyhow::Result<&'static [PathBuf]> {
+    if let Some(dirs) = OBJECT_DIRS.get() {
+        return Ok(dirs);
+    }
+    let objects_dir = objects_dir()?;
+    let mut dirs = vec![objects_dir.clone()];
+    collect_alternates(&objects_dir, 0, &mut dirs)?;
+    Ok(OBJECT_DIRS.get_or_init(|| dirs))
+}
+
+// Same limit as git, alternates can have alternates themselves.
+const MAX_ALTERNATES_DEPTH: usize = 5;
+
+// `info/alternates` lists one object directory per line, either absolute or relative to the object directory
+// containing the file.
+fn collect_alternates(
+    objects_dir: &Path,
+    depth: usize,
+    dirs: &mut Vec<PathBuf>,
+) -> anyhow::Result<()> {
+    if depth >= MAX_ALTERNATES_DEPTH {
+        return Ok(());
+    }
+    let path = objects_dir.join("info").join("alternates");
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).context(format!("reading {path:?}")),
+    };
+    for line in content.lines() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let alternate = objects_dir.join(line);
+        if dirs.contains(&alternate) {
+            continue;
+        }
+        dirs.push(alternate.clone());
+        collect_alternates(&alternate, depth + 1, dirs)?;
+    }
+    Ok(())
+}
+
+// Like git, we look for a `.git` in the current directory then in each of its parents.
+// Returns the directory containing it and the git directory it designates.
+fn discover(start: &Path) -> anyhow::Result<(&Path, PathBuf)> {
+    for dir in start.ancestors() {
+        if let Some(git_dir) = open_dot_git(&dir.join(".git"))? {
+            return Ok((dir, git_dir));
+        }
+    }
+    bail!("not a git repository (or any of the parent directories): .git")
+}
+
+/// The git directory designated by a `.git` path, if it exists.
+pub(super) fn open_dot_git(dot_git: &Path) -> anyhow::Result<Option<PathBuf>> {
+    if dot_git.is_dir() {
+        return Ok(Some(dot_git.to_path_buf()));
+    }
+    // Submodules and linked worktrees have a `.git` file pointing to the real git directory.
+    if dot_git.is_file() {
+        return read_gitfile(dot_git).map(Some);
+    }
+    Ok(None)
+}
+
+// A gitfile contains a single `gitdir: <path>` line, the path being relative to the gitfile location.
+fn read_gitfile(gitfile: &Path) -> anyhow::Result<PathBuf> {
+    let content = fs::read_to_string(gitfile).context(format!("reading {gitfile:?}"))?;
+    let Some(path) = content.strip_prefix("gitdir: ") else {
+        bail!("invalid gitfile format: {}", gitfile.display());
+    };
+    let path = Path::new(path.trim_end_matches(['\n', '\r']));
+    // `join` keeps `path` untouched if it's absolute.
+    let git_dir = gitfile.parent().unwrap_or(Path::new("")).join(path);
+    ensure!(
+        git_dir.is_dir(),
+        "not a git repository: {}",
+        git_dir.display()
+    );
+    Ok(git_dir)
+}
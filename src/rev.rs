@@ -0,0 +1,335 @@
+use crate::{
+    commit::Commit,
+    find_tree_entry,
+    hash::HashAlgorithm,
+    hex_hash::{GitHexHash, MIN_ABBREV_LEN},
+    index::Index,
+    mode::Mode,
+    pack, peel_to_commit, refs, repo,
+    tag::Tag,
+    ObjectKind, ObjectReader,
+};
+use anyhow::{bail, Context};
+use std::{collections::VecDeque, fs, io::Read};
+
+// Same limit as git on the number of symbolic links followed for one path, which stops the cycles.
+const MAX_SYMLINKS: usize = 40;
+
+/// The outcome of resolving a revision with [`resolve_following_symlinks`], as reported by
+/// `cat-file --batch --follow-symlinks`.
+pub(super) enum Followed {
+    Found(GitHexHash),
+    Missing,
+    /// A symbolic link of the path points to an entry the tree doesn't have.
+    Dangling,
+    /// Too many symbolic links to follow, they probably form a cycle.
+    Loop,
+    /// A component of the path that should be a directory is a file.
+    NotDir,
+    /// A symbolic link leads out of the tree, to this absolute path or path relative to the top of the tree.
+    OutOfTree(String),
+}
+
+/// The object named by a revision, like `git rev-parse`.
+///
+/// A revision is a full or abbreviated hash, a ref name (`HEAD`, `main`, `v1.0`, `origin/main`,
+/// `refs/heads/main`...), `<ref>@{<n>}` for the n-th previous value of a ref, followed by any number of
+/// `~<n>` (n-th first-parent ancestor), `^<n>` (n-th parent) and `^{<type>}` (the object of that type it leads
+/// to, `^{}` only following the tags). Then `<rev>:<path>` is an entry of the tree of a commit, and `:<path>` an
+/// entry of the index.
+pub(super) fn resolve(rev: &str) -> anyhow::Result<GitHexHash> {
+    let Some((rev, path)) = rev.split_once(':') else {
+        return resolve_rev(rev)?.context(format!("Not a valid object name {rev}"));
+    };
+    if rev.is_empty() {
+        let index = Index::read()?;
+        let entry = index.get(path).context(format!(
+            "path '{path}' does not exist (neither on disk nor in the index)"
+        ))?;
+        return Ok(entry.hash.clone());
+    }
+    let tree =
+        peel_to_tree(resolve_rev(rev)?.context(format!("Not a valid object name {rev}:{path}"))?)?;
+    // `HEAD:` is the tree itself.
+    if path.is_empty() {
+        return Ok(tree);
+    }
+    let entry = find_tree_entry(&tree, path.trim_end_matches('/'))?
+        .context(format!("path '{path}' does not exist in '{rev}'"))?;
+    Ok(entry.hash)
+}
+
+/// Like [`resolve`], but the symbolic links met in the path of `<rev>:<path>` are followed within the tree of
+/// the revision, as git does with `cat-file --follow-symlinks`.
+pub(super) fn resolve_following_symlinks(rev: &str) -> anyhow::Result<Followed> {
+    let Some((rev, path)) = rev.split_once(':').filter(|(rev, _)| !rev.is_empty()) else {
+        return Ok(resolve(rev).map_or(Followed::Missing, Followed::Found));
+    };
+    let Some(hash) = resolve_rev(rev)? else {
+        return Ok(Followed::Missing);
+    };
+    let mut trees = vec![peel_to_tree(hash)?];
+    let mut components: VecDeque<String> = path.split('/').map(str::to_string).collect();
+    let mut links = 0;
+    while let Some(component) = components.pop_front() {
+        match component.as_str() {
+            "" | "." => continue,
+            ".." if trees.len() == 1 => {
+                components.push_front(component);
+                return Ok(Followed::OutOfTree(Vec::from(components).join("/")));
+            }
+            ".." => {
+                trees.pop();
+                continue;
+            }
+            _ => {}
+        }
+        let tree = trees.last().context("no tree to walk")?;
+        let Some(entry) = find_tree_entry(tree, &component)? else {
+            // Missing when the path itself doesn't exist, dangling when a link leads nowhere.
+            return Ok(if links == 0 {
+                Followed::Missing
+            } else {
+                Followed::Dangling
+            });
+        };
+        match Mode::parse(&entry.mode)? {
+            Mode::Tree => trees.push(entry.hash),
+            Mode::Symlink => {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return Ok(Followed::Loop);
+                }
+                let mut target = String::new();
+                ObjectReader::from_sha1(entry.hash)?
+                    .into_body_reader()
+                    .read_to_string(&mut target)
+                    .context(format!("reading the target of {component}"))?;
+                if target.starts_with('/') {
+                    return Ok(Followed::OutOfTree(target));
+                }
+                // The target is relative to the directory of the link, the one on top of `trees`.
+                for target_component in target.rsplit('/') {
+                    components.push_front(target_component.to_string());
+                }
+            }
+            _ if components.iter().all(|component| component.is_empty()) => {
+                return Ok(Followed::Found(entry.hash))
+            }
+            _ => return Ok(Followed::NotDir),
+        }
+    }
+    Ok(Followed::Found(trees.pop().context("no tree to walk")?))
+}
+
+// A name, optionally `@{<n>}`, then the `~<n>` and `^<n>` navigation in the history.
+fn resolve_rev(rev: &str) -> anyhow::Result<Option<GitHexHash>> {
+    let (base, mut navigation) = rev.split_at(rev.find(['~', '^']).unwrap_or(rev.len()));
+    let hash = match base
+        .strip_suffix('}')
+        .and_then(|base| base.split_once("@{"))
+    {
+        Some((name, n)) => Some(reflog_value(name, n)?),
+        // `@` alone is a shortcut for HEAD.
+        None if base == "@" => resolve_name("HEAD")?,
+        None => resolve_name(base)?,
+    };
+    let Some(mut hash) = hash else {
+        return Ok(None);
+    };
+    while let Some(operator) = navigation.chars().next() {
+        if let Some(peeling) = navigation.strip_prefix("^{") {
+            let Some((kind, rest)) = peeling.split_once('}') else {
+                return Ok(None);
+            };
+            let Some(peeled) = peel(rev, hash, kind)? else {
+                return Ok(None);
+            };
+            hash = peeled;
+            navigation = rest;
+            continue;
+        }
+        let digits = navigation[1..]
+            .find(|c: char| !c.is_ascii_digit())
+            .map_or(&navigation[1..], |end| &navigation[1..1 + end]);
+        let n: usize = if digits.is_empty() {
+            1
+        } else {
+            digits.parse().context(format!("invalid number in {rev}"))?
+        };
+        navigation = &navigation[1 + digits.len()..];
+        hash = match operator {
+            '~' => {
+                let mut commit = peel_to_commit(hash)?;
+                for _ in 0..n {
+                    let parents = Commit::read(&commit)?.parents;
+                    commit = parents
+                        .into_iter()
+                        .next()
+                        .context(format!("{rev}: {commit} has no parent"))?;
+                }
+                commit
+            }
+            '^' if n == 0 => peel_to_commit(hash)?,
+            '^' => {
+                let commit = peel_to_commit(hash)?;
+                Commit::read(&commit)?
+                    .parents
+                    .into_iter()
+                    .nth(n - 1)
+                    .context(format!("{rev}: {commit} has no parent {n}"))?
+            }
+            _ => return Ok(None),
+        };
+    }
+    Ok(Some(hash))
+}
+
+// `^{<kind>}`: follow annotated tags, and from a commit its tree, until reaching an object of this kind. `^{}`
+// only follows the tags and `^{object}` takes the object as is. None for a kind git doesn't know.
+fn peel(rev: &str, mut hash: GitHexHash, kind: &str) -> anyhow::Result<Option<GitHexHash>> {
+    let wanted = match kind {
+        "" => None,
+        "object" => {
+            ObjectReader::header(&hash)?;
+            return Ok(Some(hash));
+        }
+        kind => match ObjectKind::parse(kind) {
+            Ok(kind) => Some(kind),
+            Err(_) => return Ok(None),
+        },
+    };
+    loop {
+        let object = ObjectReader::from_sha1(hash.clone())?;
+        let found = object.kind();
+        if wanted.map_or(found != ObjectKind::Tag, |wanted| wanted == found) {
+            return Ok(Some(hash));
+        }
+        hash = match object {
+            ObjectReader::Tag(reader) => {
+                Tag::parse(reader)
+                    .context(format!("parsing tag {hash}"))?
+                    .object
+            }
+            ObjectReader::Commit(_) => Commit::read(&hash)?.tree,
+            _ => bail!("{rev}: expected {kind} type, but the object dereferences to {found} type"),
+        };
+    }
+}
+
+// `<name>@{<n>}`: the value of the ref before its n last changes, according to its reflog. Without a name,
+// it's HEAD.
+fn reflog_value(name: &str, n: &str) -> anyhow::Result<GitHexHash> {
+    let name = if name.is_empty() { "HEAD" } else { name };
+    let n: usize = n.parse().context(format!(
+        "only numeric reflog entries are supported, not '{n}'"
+    ))?;
+    let full_name = dwim_ref(name)?.context(format!("Not a valid object name {name}"))?;
+    let reflog = refs::read_reflog(&full_name)?;
+    // Counting from the most recent entry, whose new value is the current one.
+    if let Some(entry) = reflog.iter().rev().nth(n) {
+        return Ok(entry.new.clone());
+    }
+    // Right before the oldest change, unless the ref didn't exist yet.
+    match reflog.first() {
+        Some(oldest)
+            if n == reflog.len() && oldest.old != HashAlgorithm::current()?.null_hash() =>
+        {
+            Ok(oldest.old.clone())
+        }
+        _ => bail!("log for '{name}' only has {} entries", reflog.len()),
+    }
+}
+
+// A full hash, a ref, then an abbreviated hash, in the order git tries them.
+fn resolve_name(name: &str) -> anyhow::Result<Option<GitHexHash>> {
+    if name.len() == HashAlgorithm::current()?.hex_len() {
+        if let Ok(hash) = GitHexHash::try_from(name) {
+            return Ok(Some(hash));
+        }
+    }
+    if let Some(hash) = resolve_ref(name)? {
+        return Ok(Some(hash));
+    }
+    expand_abbreviation(name)
+}
+
+/// The object a ref points to, its name being completed as in [`dwim_ref`].
+pub(super) fn resolve_ref(name: &str) -> anyhow::Result<Option<GitHexHash>> {
+    match dwim_ref(name)? {
+        Some(full_name) => refs::read_ref(&full_name),
+        None => Ok(None),
+    }
+}
+
+/// The full name of an existing ref, completing short names as git does: `main` is looked for as `refs/main`,
+/// `refs/tags/main`, `refs/heads/main`, `refs/remotes/main` then `refs/remotes/main/HEAD`.
+pub(super) fn dwim_ref(name: &str) -> anyhow::Result<Option<String>> {
+    // Keeps the lookup inside the refs directory.
+    if name.is_empty() || name.split('/').any(|component| component == "..") {
+        return Ok(None);
+    }
+    for candidate in [
+        name.to_string(),
+        format!("refs/{name}"),
+        format!("refs/tags/{name}"),
+        format!("refs/heads/{name}"),
+        format!("refs/remotes/{name}"),
+        format!("refs/remotes/{name}/HEAD"),
+    ] {
+        // Outside of `refs/`, only all-caps names like HEAD or FETCH_HEAD are refs.
+        let is_ref = candidate.starts_with("refs/")
+            || candidate
+                .bytes()
+                .all(|byte| byte.is_ascii_uppercase() || byte == b'_');
+        if is_ref && refs::read_ref(&candidate)?.is_some() {
+            return Ok(Some(candidate));
+        }
+    }
+    Ok(None)
+}
+
+// The only object whose hash starts with `prefix`, looking in all the object directories.
+fn expand_abbreviation(prefix: &str) -> anyhow::Result<Option<GitHexHash>> {
+    if prefix.len() < MIN_ABBREV_LEN || !prefix.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Ok(None);
+    }
+    let prefix = prefix.to_ascii_lowercase();
+    let (dir, rest) = prefix.split_at(2);
+    let mut found: Option<GitHexHash> = None;
+    for objects_dir in repo::object_dirs()? {
+        let Ok(entries) = fs::read_dir(objects_dir.join(dir)) else {
+            continue;
+        };
+        for entry in entries {
+            let name = entry?.file_name();
+            let Some(name) = name.to_str().filter(|name| name.starts_with(rest)) else {
+                continue;
+            };
+            let Ok(hash) = GitHexHash::try_from(format!("{dir}{name}").as_str()) else {
+                continue;
+            };
+            match &found {
+                // The same object can be in an alternate too.
+                Some(other) if *other != hash => bail!("short object ID {prefix} is ambiguous"),
+                _ => found = Some(hash),
+            }
+        }
+    }
+    for hash in pack::find_prefix(&prefix)? {
+        match &found {
+            // Or both loose and packed.
+            Some(other) if *other != hash => bail!("short object ID {prefix} is ambiguous"),
+            _ => found = Some(hash),
+        }
+    }
+    Ok(found)
+}
+
+/// Follow annotated tags and commits until reaching a tree.
+fn peel_to_tree(hash: GitHexHash) -> anyhow::Result<GitHexHash> {
+    if ObjectReader::header(&hash)?.0 == ObjectKind::Tree {
+        return Ok(hash);
+    }
+    Ok(Commit::read(&peel_to_commit(hash)?)?.tree)
+}
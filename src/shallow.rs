@@ -0,0 +1,52 @@
+use crate::{hex_hash::GitHexHash, repo};
+use anyhow::Context;
+use std::{collections::HashSet, fs, io};
+
+/// The boundary commits of a shallow repository, listed in `.git/shallow`: their parents weren't fetched.
+pub(super) fn shallow_commits() -> anyhow::Result<HashSet<GitHexHash>> {
+    let path = repo::git_dir()?.join("shallow");
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(err) => return Err(err).context(format!("reading {path:?}")),
+    };
+    content
+        .lines()
+        .map(GitHexHash::try_from)
+        .collect::<anyhow::Result<_>>()
+}
+
+/// Replace the shallow boundary, removing the file when the history is complete.
+fn write_shallow_commits(commits: &HashSet<GitHexHash>) -> anyhow::Result<()> {
+    let path = repo::git_dir()?.join("shallow");
+    if commits.is_empty() {
+        return match fs::remove_file(&path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                Err(err).context(format!("removing {path:?}"))
+            }
+            _ => Ok(()),
+        };
+    }
+    let mut lines = commits
+        .iter()
+        .map(|commit| format!("{commit}\n"))
+        .collect::<Vec<_>>();
+    lines.sort_unstable();
+    fs::write(&path, lines.concat()).context(format!("writing {path:?}"))
+}
+
+/// Move the shallow boundary after a fetch: `shallow` commits join it, `unshallow` ones leave it.
+pub(super) fn update_shallow_commits(
+    shallow: Vec<GitHexHash>,
+    unshallow: &[GitHexHash],
+) -> anyhow::Result<()> {
+    if shallow.is_empty() && unshallow.is_empty() {
+        return Ok(());
+    }
+    let mut commits = shallow_commits()?;
+    commits.extend(shallow);
+    for commit in unshallow {
+        commits.remove(commit);
+    }
+    write_shallow_commits(&commits)
+}
@@ -0,0 +1,497 @@
+use crate::{
+    changed_files, config, fetch_refspec,
+    hex_hash::GitHexHash,
+    is_regular_file, map_ref, merge, pathspec,
+    quote::{quote_path, quote_path_with_space},
+    refs, relative_path, repo, untracked_files, Index,
+};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write as _,
+    io::{self, Write},
+    path::Path,
+};
+
+/// How `status` shows the files.
+pub(super) enum Format {
+    /// Sections for people, with hints on what to do next.
+    Long,
+    /// `XY <path>` lines, git's stable format for scripts. With `nul_terminated`, the lines end with NUL and
+    /// the paths aren't quoted.
+    Porcelain { nul_terminated: bool },
+    /// An array of objects with the `path`, `index_status` and `worktree_status` of the files.
+    Json,
+}
+
+/// A file of the working tree that isn't in the state of HEAD, with its status letters as in
+/// `git status --porcelain`: `M` modified, `T` type changed, `A` added, `D` deleted, `R` renamed, `U` unmerged,
+/// `?` untracked and a space for no change.
+struct Entry {
+    path: String,
+    /// Where the file was renamed from in the index.
+    orig_path: Option<String>,
+    /// The change staged in the index, from HEAD.
+    index: char,
+    /// The change in the working tree, from the index.
+    worktree: char,
+    /// A conflict of a merge, the letters telling which sides changed the file: `DD`, `AU`, `UD`, `UA`, `DU`,
+    /// `AA` or `UU`.
+    unmerged: bool,
+}
+
+pub(super) fn status(format: Format) -> anyhow::Result<()> {
+    let index = Index::read()?;
+    let mut entries = tracked_entries(&index)?;
+    entries.extend(untracked_entries(&index)?);
+    let mut out = io::stdout().lock();
+    match format {
+        Format::Long => write_long(&mut out, &entries),
+        Format::Porcelain { nul_terminated } => write_porcelain(&mut out, &entries, nul_terminated),
+        Format::Json => write_json(&mut out, &entries),
+    }
+}
+
+// The files changed in the index or in the working tree, sorted by path, a renamed file by its new one.
+fn tracked_entries(index: &Index) -> anyhow::Result<Vec<Entry>> {
+    let (staged, _) = changed_files(index, true, |_| true)?;
+    let (unstaged, _) = changed_files(index, false, |_| true)?;
+    // The index and working tree letters, and whether the file is unmerged.
+    let mut letters: BTreeMap<&str, ([char; 2], bool)> = BTreeMap::new();
+    for (changes, side) in [(&staged, 0), (&unstaged, 1)] {
+        for (path, sides) in changes {
+            let letter = match sides {
+                Some([old, new]) if old == new => continue,
+                Some([None, _]) => 'A',
+                Some([_, None]) => 'D',
+                Some([Some((old, _)), Some((new, _))]) => {
+                    // Between a file, a symbolic link and a submodule, but not for the executable bit.
+                    if old != new && !(is_regular_file(*old) && is_regular_file(*new)) {
+                        'T'
+                    } else {
+                        'M'
+                    }
+                }
+                None => continue,
+            };
+            letters.entry(path).or_insert(([' ', ' '], false)).0[side] = letter;
+        }
+    }
+
+    // The sides of a conflict that are in the index: 1 for the base, 2 for ours and 4 for theirs.
+    let mut unmerged: BTreeMap<&str, u8> = BTreeMap::new();
+    for entry in index.entries.iter().filter(|entry| entry.stage != 0) {
+        *unmerged.entry(&entry.path).or_default() |= 1 << (entry.stage - 1);
+    }
+    for (path, stages) in unmerged {
+        let letter_pair = match stages {
+            1 => ['D', 'D'],
+            2 => ['A', 'U'],
+            3 => ['U', 'D'],
+            4 => ['U', 'A'],
+            5 => ['D', 'U'],
+            6 => ['A', 'A'],
+            _ => ['U', 'U'],
+        };
+        letters.insert(path, (letter_pair, true));
+    }
+
+    // Like git, a file added with the content of a deleted one is renamed. Only identical contents are
+    // detected.
+    let mut deleted = Vec::new();
+    for (path, ([index_letter, _], _)) in &letters {
+        if let (&'D', Some(Some([Some((_, hash)), None]))) = (index_letter, staged.get(*path)) {
+            deleted.push((*path, hash));
+        }
+    }
+    let mut renames = BTreeMap::new();
+    for (path, ([index_letter, _], _)) in &letters {
+        let Some(Some([None, Some((_, hash))])) = staged.get(*path) else {
+            continue;
+        };
+        if *index_letter != 'A' {
+            continue;
+        }
+        if let Some(position) = deleted.iter().position(|(_, deleted)| *deleted == hash) {
+            let (orig_path, _) = deleted.remove(position);
+            renames.insert(*path, orig_path);
+        }
+    }
+    let renamed_from: HashSet<&str> = renames.values().copied().collect();
+
+    Ok(letters
+        .into_iter()
+        .filter(|(path, _)| !renamed_from.contains(path))
+        .map(|(path, ([index_letter, worktree], unmerged))| {
+            let orig_path = renames.get(path).map(|orig_path| orig_path.to_string());
+            Entry {
+                path: path.to_string(),
+                index: if orig_path.is_some() {
+                    'R'
+                } else {
+                    index_letter
+                },
+                orig_path,
+                worktree,
+                unmerged,
+            }
+        })
+        .collect())
+}
+
+// The untracked files that aren't ignored, a directory without any tracked file shown as a whole as `dir/`.
+fn untracked_entries(index: &Index) -> anyhow::Result<Vec<Entry>> {
+    let mut tracked_dirs = HashSet::new();
+    for entry in &index.entries {
+        for (end, _) in entry.path.match_indices('/') {
+            tracked_dirs.insert(&entry.path[..end]);
+        }
+    }
+    let mut paths = untracked_files(index, true)?
+        .into_iter()
+        .map(|path| {
+            match path
+                .match_indices('/')
+                .find(|(end, _)| !tracked_dirs.contains(&path[..*end]))
+            {
+                Some((end, _)) => path[..=end].to_string(),
+                None => path,
+            }
+        })
+        .collect::<Vec<_>>();
+    paths.dedup();
+    Ok(paths
+        .into_iter()
+        .map(|path| Entry {
+            path,
+            orig_path: None,
+            index: '?',
+            worktree: '?',
+            unmerged: false,
+        })
+        .collect())
+}
+
+fn write_porcelain(
+    out: &mut impl Write,
+    entries: &[Entry],
+    nul_terminated: bool,
+) -> anyhow::Result<()> {
+    let quote_non_ascii = config::config()?
+        .get_bool("core.quotePath")?
+        .unwrap_or(true);
+    for entry in entries {
+        let (index, worktree) = (entry.index, entry.worktree);
+        if nul_terminated {
+            // The new path first, then the original one as a separate field.
+            write!(out, "{index}{worktree} {}\0", entry.path)?;
+            if let Some(orig_path) = &entry.orig_path {
+                write!(out, "{orig_path}\0")?;
+            }
+            continue;
+        }
+        write!(out, "{index}{worktree} ")?;
+        if let Some(orig_path) = &entry.orig_path {
+            write!(
+                out,
+                "{} -> ",
+                quote_path_with_space(orig_path, quote_non_ascii)
+            )?;
+        }
+        writeln!(
+            out,
+            "{}",
+            quote_path_with_space(&entry.path, quote_non_ascii)
+        )?;
+    }
+    Ok(())
+}
+
+fn write_json(out: &mut impl Write, entries: &[Entry]) -> anyhow::Result<()> {
+    write!(out, "[")?;
+    for (i, entry) in entries.iter().enumerate() {
+        let separator = if i == 0 { "" } else { "," };
+        write!(
+            out,
+            "{separator}\n  {{\"path\": {}, \"index_status\": {}, \"worktree_status\": {}",
+            json_string(&entry.path),
+            json_string(&entry.index.to_string()),
+            json_string(&entry.worktree.to_string())
+        )?;
+        if let Some(orig_path) = &entry.orig_path {
+            write!(out, ", \"orig_path\": {}", json_string(orig_path))?;
+        }
+        write!(out, "}}")?;
+    }
+    let end = if entries.is_empty() { "" } else { "\n" };
+    writeln!(out, "{end}]")?;
+    Ok(())
+}
+
+// A JSON string literal, with the quotes, backslashes and control characters escaped.
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", u32::from(c));
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+// Like `git status`: the branch, the state of a merge, then a section for the staged changes, the
+// conflicts, the changes not staged and the untracked files, the paths relative to the current directory.
+fn write_long(out: &mut impl Write, entries: &[Entry]) -> anyhow::Result<()> {
+    let quote_non_ascii = config::config()?
+        .get_bool("core.quotePath")?
+        .unwrap_or(true);
+    let base = pathspec(Path::new("."))?.1;
+    let show = |path: &str| -> String {
+        // A directory keeps its trailing slash, the current one being `./`.
+        let shown = match path.strip_suffix('/') {
+            Some(dir) => match relative_path(dir, &base) {
+                dir if dir.ends_with('/') => dir,
+                dir => format!("{dir}/"),
+            },
+            None => relative_path(path, &base),
+        };
+        quote_path(&shown, quote_non_ascii).into_owned()
+    };
+
+    let head = refs::read_ref("HEAD")?;
+    let branch = refs::head_target()?;
+    match (&branch, &head) {
+        (Some(branch), _) => writeln!(out, "On branch {}", refs::shorten_name(branch))?,
+        (None, Some(head)) => writeln!(out, "HEAD detached at {}", head.abbreviate(7)?)?,
+        (None, None) => {}
+    }
+    if let (Some(branch), Some(head)) = (&branch, &head) {
+        write_tracking(out, branch, head)?;
+    }
+    if head.is_none() {
+        writeln!(out, "\nNo commits yet\n")?;
+    }
+    let merging = repo::git_dir()?.join("MERGE_HEAD").exists();
+    let has_unmerged = entries.iter().any(|entry| entry.unmerged);
+    if merging && has_unmerged {
+        writeln!(out, "You have unmerged paths.")?;
+        writeln!(out, "  (fix conflicts and run \"git commit\")")?;
+        writeln!(out, "  (use \"git merge --abort\" to abort the merge)\n")?;
+    } else if merging {
+        writeln!(out, "All conflicts fixed but you are still merging.")?;
+        writeln!(out, "  (use \"git commit\" to conclude merge)\n")?;
+    }
+
+    let staged = entries
+        .iter()
+        .filter(|entry| !entry.unmerged && !matches!(entry.index, ' ' | '?'))
+        .collect::<Vec<_>>();
+    if !staged.is_empty() {
+        writeln!(out, "Changes to be committed:")?;
+        // While merging, the staged changes are the result of the merge: there is nothing to unstage.
+        if head.is_none() {
+            writeln!(out, "  (use \"git rm --cached <file>...\" to unstage)")?;
+        } else if !merging {
+            writeln!(out, "  (use \"git restore --staged <file>...\" to unstage)")?;
+        }
+        for entry in &staged {
+            let label = match entry.index {
+                'A' => "new file:",
+                'D' => "deleted:",
+                'R' => "renamed:",
+                'T' => "typechange:",
+                _ => "modified:",
+            };
+            match &entry.orig_path {
+                Some(orig_path) => writeln!(
+                    out,
+                    "\t{label:<12}{} -> {}",
+                    show(orig_path),
+                    show(&entry.path)
+                )?,
+                None => writeln!(out, "\t{label:<12}{}", show(&entry.path))?,
+            }
+        }
+        writeln!(out)?;
+    }
+
+    let unmerged = entries
+        .iter()
+        .filter(|entry| entry.unmerged)
+        .collect::<Vec<_>>();
+    if !unmerged.is_empty() {
+        writeln!(out, "Unmerged paths:")?;
+        if head.is_none() {
+            writeln!(out, "  (use \"git rm --cached <file>...\" to unstage)")?;
+        } else if !merging {
+            writeln!(out, "  (use \"git restore --staged <file>...\" to unstage)")?;
+        }
+        let both_deleted = unmerged
+            .iter()
+            .any(|entry| (entry.index, entry.worktree) == ('D', 'D'));
+        let deleted_by_one = unmerged
+            .iter()
+            .any(|entry| matches!((entry.index, entry.worktree), ('U', 'D') | ('D', 'U')));
+        let action = match (both_deleted, deleted_by_one) {
+            (_, true) => "add/rm <file>...\" as appropriate",
+            (true, false) => "rm <file>...\"",
+            (false, false) => "add <file>...\"",
+        };
+        writeln!(out, "  (use \"git {action} to mark resolution)")?;
+        for entry in &unmerged {
+            let label = match (entry.index, entry.worktree) {
+                ('D', 'D') => "both deleted:",
+                ('A', 'U') => "added by us:",
+                ('U', 'D') => "deleted by them:",
+                ('U', 'A') => "added by them:",
+                ('D', 'U') => "deleted by us:",
+                ('A', 'A') => "both added:",
+                _ => "both modified:",
+            };
+            writeln!(out, "\t{label:<17}{}", show(&entry.path))?;
+        }
+        writeln!(out)?;
+    }
+
+    let not_staged = entries
+        .iter()
+        .filter(|entry| !entry.unmerged && !matches!(entry.worktree, ' ' | '?'))
+        .collect::<Vec<_>>();
+    if !not_staged.is_empty() {
+        writeln!(out, "Changes not staged for commit:")?;
+        let add = if not_staged.iter().any(|entry| entry.worktree == 'D') {
+            "add/rm"
+        } else {
+            "add"
+        };
+        writeln!(
+            out,
+            "  (use \"git {add} <file>...\" to update what will be committed)"
+        )?;
+        writeln!(
+            out,
+            "  (use \"git restore <file>...\" to discard changes in working directory)"
+        )?;
+        for entry in &not_staged {
+            let label = match entry.worktree {
+                'D' => "deleted:",
+                'T' => "typechange:",
+                _ => "modified:",
+            };
+            writeln!(out, "\t{label:<12}{}", show(&entry.path))?;
+        }
+        writeln!(out)?;
+    }
+
+    let untracked = entries
+        .iter()
+        .filter(|entry| entry.index == '?')
+        .collect::<Vec<_>>();
+    if !untracked.is_empty() {
+        writeln!(out, "Untracked files:")?;
+        writeln!(
+            out,
+            "  (use \"git add <file>...\" to include in what will be committed)"
+        )?;
+        for entry in &untracked {
+            writeln!(out, "\t{}", show(&entry.path))?;
+        }
+        writeln!(out)?;
+    }
+
+    if !staged.is_empty() {
+        return Ok(());
+    }
+    if !not_staged.is_empty() || !unmerged.is_empty() {
+        writeln!(
+            out,
+            "no changes added to commit (use \"git add\" and/or \"git commit -a\")"
+        )?;
+    } else if !untracked.is_empty() {
+        writeln!(
+            out,
+            "nothing added to commit but untracked files present (use \"git add\" to track)"
+        )?;
+    } else if head.is_none() {
+        writeln!(
+            out,
+            "nothing to commit (create/copy files and use \"git add\" to track)"
+        )?;
+    } else {
+        writeln!(out, "nothing to commit, working tree clean")?;
+    }
+    Ok(())
+}
+
+// How the branch compares with its upstream, the remote-tracking branch of `branch.<name>.merge`.
+fn write_tracking(out: &mut impl Write, branch: &str, head: &GitHexHash) -> anyhow::Result<()> {
+    let config = config::config()?;
+    let name = refs::shorten_name(branch);
+    let (Some(remote), Some(merge_ref)) = (
+        config.get(&format!("branch.{name}.remote")),
+        config.get(&format!("branch.{name}.merge")),
+    ) else {
+        return Ok(());
+    };
+    // `.` is the repository itself: the upstream is a local branch.
+    let upstream = if remote == "." {
+        merge_ref.to_string()
+    } else {
+        let (_, source, destination) = fetch_refspec(remote)?;
+        let Some(upstream) = map_ref(&source, &destination, merge_ref) else {
+            return Ok(());
+        };
+        upstream
+    };
+    let upstream_name = refs::shorten_name(&upstream);
+    let Some(upstream_hash) = refs::read_ref(&upstream)? else {
+        writeln!(
+            out,
+            "Your branch is based on '{upstream_name}', but the upstream is gone."
+        )?;
+        writeln!(out, "  (use \"git branch --unset-upstream\" to fixup)\n")?;
+        return Ok(());
+    };
+    let commits = |n: usize| if n == 1 { "commit" } else { "commits" };
+    match merge::ahead_behind(head, &upstream_hash)? {
+        (0, 0) => writeln!(out, "Your branch is up to date with '{upstream_name}'.")?,
+        (ahead, 0) => {
+            writeln!(
+                out,
+                "Your branch is ahead of '{upstream_name}' by {ahead} {}.",
+                commits(ahead)
+            )?;
+            writeln!(out, "  (use \"git push\" to publish your local commits)")?;
+        }
+        (0, behind) => {
+            writeln!(
+                out,
+                "Your branch is behind '{upstream_name}' by {behind} {}, and can be fast-forwarded.",
+                commits(behind)
+            )?;
+            writeln!(out, "  (use \"git pull\" to update your local branch)")?;
+        }
+        (ahead, behind) => {
+            writeln!(out, "Your branch and '{upstream_name}' have diverged,")?;
+            writeln!(
+                out,
+                "and have {ahead} and {behind} different commits each, respectively."
+            )?;
+            writeln!(
+                out,
+                "  (use \"git pull\" to merge the remote branch into yours)"
+            )?;
+        }
+    }
+    writeln!(out)?;
+    Ok(())
+}
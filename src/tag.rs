@@ -0,0 +1,91 @@
+use crate::{commit, hex_hash::GitHexHash, ObjectKind, ObjectReader};
+use anyhow::{bail, Context};
+use std::io::BufRead;
+
+// The first lines of the signatures git appends to the message of signed tags: OpenPGP, X.509 then SSH.
+const SIGNATURE_STARTS: [&[u8]; 3] = [
+    b"-----BEGIN PGP SIGNATURE-----",
+    b"-----BEGIN SIGNED MESSAGE-----",
+    b"-----BEGIN SSH SIGNATURE-----",
+];
+
+/// An annotated tag object.
+///
+/// Like a commit, it is a list of `<name> <value>` headers, an empty line, then the message. Unlike commits,
+/// the signature isn't a header: it ends the message.
+pub(super) struct Tag {
+    /// The tagged object, often a commit but it can be any object, another tag included.
+    pub(super) object: GitHexHash,
+    /// The type of the tagged object, from the `type` header.
+    pub(super) kind: ObjectKind,
+    /// The name of the tag, from the `tag` header.
+    pub(super) name: String,
+    /// `<name> <<email>> <date>`, empty if missing as in some old tags.
+    pub(super) tagger: String,
+    /// Without the signature.
+    pub(super) message: String,
+    /// The ASCII armored signature, for signed tags.
+    pub(super) signature: Option<String>,
+}
+
+impl Tag {
+    pub(super) fn read(hash: &GitHexHash) -> anyhow::Result<Self> {
+        let ObjectReader::Tag(reader) = ObjectReader::from_sha1(hash.clone())? else {
+            bail!("{hash} is not a valid 'tag' object");
+        };
+        Self::parse(reader).context(format!("parsing tag {hash}"))
+    }
+
+    pub(super) fn parse(mut reader: impl BufRead) -> anyhow::Result<Self> {
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content)?;
+        let payload = signed_payload(&content);
+        let signature = match &content[payload.len()..] {
+            [] => None,
+            signature => Some(
+                String::from_utf8(signature.to_vec())
+                    .context("signature is not UTF-8")?
+                    .trim_end()
+                    .to_string(),
+            ),
+        };
+
+        let (headers, message) = commit::parse_headers(payload)?;
+        let mut object = None;
+        let mut kind = None;
+        let mut name = None;
+        let mut tagger = String::new();
+        for (header, value) in headers {
+            match header.as_str() {
+                "object" => object = Some(value.as_str().try_into()?),
+                "type" => kind = Some(ObjectKind::parse(&value)?),
+                "tag" => name = Some(value),
+                "tagger" => tagger = value,
+                _ => {}
+            }
+        }
+        Ok(Self {
+            object: object.context("missing object header")?,
+            kind: kind.context("missing type header")?,
+            name: name.context("missing tag header")?,
+            tagger,
+            message,
+            signature,
+        })
+    }
+}
+
+/// The content of a tag without its signature, which is what was signed.
+///
+/// As with git, the signature starts at the last line that looks like the beginning of one.
+pub(super) fn signed_payload(content: &[u8]) -> &[u8] {
+    let mut end = content.len();
+    let mut start = 0;
+    for line in content.split_inclusive(|&byte| byte == b'\n') {
+        if SIGNATURE_STARTS.iter().any(|begin| line.starts_with(begin)) {
+            end = start;
+        }
+        start += line.len();
+    }
+    &content[..end]
+}
@@ -0,0 +1,85 @@
+use crate::{hash::HashAlgorithm, hex_hash::GitHexHash, mode::Mode};
+use anyhow::{ensure, Context};
+use std::{ffi::CStr, io::BufRead};
+
+/// An entry of a tree object.
+pub(super) struct TreeEntry {
+    /// Octal mode as encoded in the tree, without leading zero (`40000` for directories).
+    pub(super) mode: String,
+    pub(super) name: String,
+    pub(super) hash: GitHexHash,
+}
+
+impl TreeEntry {
+    pub(super) fn is_tree(&self) -> bool {
+        self.mode == Mode::Tree.as_str()
+    }
+
+    /// A submodule, pointing to a commit of another repository.
+    pub(super) fn is_gitlink(&self) -> bool {
+        self.mode == Mode::Gitlink.as_str()
+    }
+}
+
+/// Iterate over the entries of a tree object body.
+// Here there is not separator between the entries of the tree, they all start by a number but this could
+// be melted with the sha1 bytes, so we can't have a "split on separator" approach. In other words the format is not self describing.
+pub(super) struct TreeEntries<R> {
+    reader: R,
+    hash_len: usize,
+    mode_buf: Vec<u8>,
+    name_buf: Vec<u8>,
+}
+
+impl<R: BufRead> TreeEntries<R> {
+    pub(super) fn new(reader: R) -> anyhow::Result<Self> {
+        Ok(Self {
+            reader,
+            hash_len: HashAlgorithm::current()?.raw_len(),
+            mode_buf: Vec::with_capacity(6),
+            name_buf: Vec::new(),
+        })
+    }
+
+    // Each entry is <mode> <name>\0 <raw hash>. Only the first space is a separator: names can contain any
+    // byte but NUL, including spaces, tabs and new lines.
+    fn read_entry(&mut self) -> anyhow::Result<Option<TreeEntry>> {
+        self.name_buf.clear();
+        self.mode_buf.clear();
+        let n = self.reader.read_until(b' ', &mut self.mode_buf)?;
+        if n == 0 {
+            return Ok(None);
+        }
+        ensure!(self.mode_buf.ends_with(b" "), "truncated tree entry");
+
+        // Why they encode the mode in ASCII and not as an integer?
+        let mode = std::str::from_utf8(&self.mode_buf[..n - 1]).context("reading mode")?;
+
+        let n = self
+            .reader
+            .read_until(0, &mut self.name_buf)
+            .context("reading the header")?;
+        let name = CStr::from_bytes_with_nul(&self.name_buf[..n])
+            .context("reading name")?
+            .to_str()?;
+
+        let mut hash_buf = vec![0; self.hash_len];
+        self.reader
+            .read_exact(&mut hash_buf)
+            .context("reading hash")?;
+
+        Ok(Some(TreeEntry {
+            mode: mode.to_string(),
+            name: name.to_string(),
+            hash: GitHexHash::from_raw(&hash_buf),
+        }))
+    }
+}
+
+impl<R: BufRead> Iterator for TreeEntries<R> {
+    type Item = anyhow::Result<TreeEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_entry().transpose()
+    }
+}
@@ -0,0 +1,40 @@
+use std::{fmt::Display, sync::OnceLock};
+
+static VERBOSITY: OnceLock<Verbosity> = OnceLock::new();
+
+/// How much the commands report besides their results, from `--quiet` and `--verbose`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+pub(super) fn set(verbosity: Verbosity) {
+    let _ = VERBOSITY.set(verbosity);
+}
+
+pub(super) fn get() -> Verbosity {
+    VERBOSITY.get().copied().unwrap_or(Verbosity::Normal)
+}
+
+/// Report on stdout what a command did, like `rm 'file'`. Silenced by `--quiet`.
+pub(super) fn info(message: impl Display) {
+    if get() > Verbosity::Quiet {
+        println!("{message}");
+    }
+}
+
+/// Report on stderr how a command is going, like `Cloning into 'dir'...`. Silenced by `--quiet`.
+pub(super) fn status(message: impl Display) {
+    if get() > Verbosity::Quiet {
+        eprintln!("{message}");
+    }
+}
+
+/// Details on stderr, only with `--verbose`.
+pub(super) fn verbose(message: impl Display) {
+    if get() == Verbosity::Verbose {
+        eprintln!("{message}");
+    }
+}
@@ -0,0 +1,198 @@
+mod common;
+
+use common::Repo;
+use std::{
+    fs,
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    os::unix::fs::PermissionsExt,
+    process::Stdio,
+};
+
+// Commits growing a file, so that once packed most of its versions are deltas.
+fn packed_history() -> Repo {
+    let repo = Repo::new("cat-file");
+    for i in 1..=40 {
+        let lines: String = (0..i * 20).map(|line| format!("line {line}\n")).collect();
+        repo.write("file", lines);
+        repo.write(&format!("dir/{}", i % 3), format!("{i}\n"));
+        repo.git(&["add", "-A"]);
+        repo.git(&["commit", "-qm", &format!("commit {i}")]);
+    }
+    repo.git(&["gc", "-q"]);
+    assert!(repo.loose_objects().is_empty());
+    repo
+}
+
+#[test]
+fn batch_check_packed_deltas() {
+    let repo = packed_history();
+    let expected = repo.git(&["cat-file", "--batch-all-objects", "--batch-check"]);
+    let names: Vec<u8> = expected
+        .split(|&byte| byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .flat_map(|line| [&line[..40], b"\n"].concat())
+        .collect();
+    assert_eq!(
+        repo.ours_with_input(&["cat-file", "--batch-check"], &names),
+        expected
+    );
+    assert_eq!(
+        repo.ours_with_input(&["cat-file", "--batch"], &names),
+        repo.git_with_input(&["cat-file", "--batch"], &names)
+    );
+}
+
+#[test]
+fn batch_check_abbrev() {
+    let repo = packed_history();
+    // Loose objects too, for their prefixes to be looked at along with the packed ones.
+    repo.write("loose", "loose\n");
+    repo.git(&["add", "loose"]);
+    repo.git(&["commit", "-qm", "loose"]);
+    let all = repo.git(&[
+        "cat-file",
+        "--batch-all-objects",
+        "--batch-check=%(objectname)",
+    ]);
+    let all = String::from_utf8(all).unwrap();
+    for min_len in [4, 7, 12] {
+        let mut expected = String::new();
+        for name in all.lines() {
+            let short = repo.git(&["rev-parse", &format!("--short={min_len}"), name]);
+            let kind = repo.git(&["cat-file", "-t", name]);
+            expected.push_str(&format!(
+                "{} {}",
+                String::from_utf8(short).unwrap().trim_end(),
+                String::from_utf8(kind).unwrap()
+            ));
+        }
+        let abbrev = format!("--abbrev={min_len}");
+        let ours = repo.ours_with_input(
+            &[
+                "cat-file",
+                "--batch-check",
+                "--format=%(objectname) %(objecttype)",
+                &abbrev,
+            ],
+            all.as_bytes(),
+        );
+        assert_eq!(String::from_utf8(ours).unwrap(), expected, "{abbrev}");
+    }
+}
+
+#[test]
+fn blobs_are_written_byte_for_byte() {
+    let repo = Repo::new("cat-file-newlines");
+    for content in [&b"no final newline"[..], b"trailing\n\n\n", b"\n\n", b""] {
+        let hash = repo.git_with_input(&["hash-object", "-w", "--stdin"], content);
+        let hash = String::from_utf8(hash).unwrap();
+        let hash = hash.trim_end();
+        assert_eq!(repo.ours(&["cat-file", "-p", hash]), content);
+        let batch = repo.ours_with_input(&["cat-file", "--batch"], format!("{hash}\n").as_bytes());
+        assert_eq!(
+            batch,
+            repo.git_with_input(&["cat-file", "--batch"], format!("{hash}\n").as_bytes())
+        );
+    }
+}
+
+// Bigger than the memory our git is allowed, so it has to stream the blob.
+const LARGE_BLOB_SIZE: usize = 300 << 20;
+const MEMORY_LIMIT_KIB: usize = 200 << 10;
+
+// Numbered lines, so a misplaced chunk is noticed, that compress well enough not to take 300 MB of disk.
+fn large_blob_block() -> Vec<u8> {
+    (0..1000)
+        .flat_map(|line| format!("{line:07} synthetic line of a large blob\n").into_bytes())
+        .collect()
+}
+
+// Check that `reader` gives `len` bytes of the repeated `block`.
+fn check_repeated(reader: &mut impl Read, block: &[u8], len: usize) {
+    let mut buf = vec![0; 64 << 10];
+    let mut position = 0;
+    while position < len {
+        let n = reader
+            .read(&mut buf[..(len - position).min(64 << 10)])
+            .unwrap();
+        assert!(n > 0, "truncated at {position}");
+        for (i, byte) in buf[..n].iter().enumerate() {
+            assert_eq!(
+                *byte,
+                block[(position + i) % block.len()],
+                "at {}",
+                position + i
+            );
+        }
+        position += n;
+    }
+}
+
+#[test]
+fn streams_blob_larger_than_memory_limit() {
+    let repo = Repo::new("cat-file-large");
+    let block = large_blob_block();
+    let len = LARGE_BLOB_SIZE / block.len() * block.len();
+    {
+        let mut file = BufWriter::new(fs::File::create(repo.path("large")).unwrap());
+        for _ in 0..len / block.len() {
+            file.write_all(&block).unwrap();
+        }
+        file.flush().unwrap();
+    }
+    let hash = repo.git(&["hash-object", "-w", "large"]);
+    let hash = String::from_utf8(hash).unwrap().trim_end().to_string();
+    fs::remove_file(repo.path("large")).unwrap();
+
+    for batch in [false, true] {
+        let script = format!("ulimit -v {MEMORY_LIMIT_KIB} && exec \"$0\" \"$@\"");
+        let mut command = repo.command("sh");
+        command.args(["-c", &script, common::ours(), "cat-file"]);
+        if batch {
+            command.arg("--batch").stdin(Stdio::piped());
+        } else {
+            command.args(["-p", &hash]).stdin(Stdio::null());
+        }
+        let mut child = command.stdout(Stdio::piped()).spawn().unwrap();
+        if batch {
+            let mut stdin = child.stdin.take().unwrap();
+            writeln!(stdin, "{hash}").unwrap();
+        }
+        let mut out = BufReader::new(child.stdout.take().unwrap());
+        if batch {
+            let mut header = String::new();
+            out.read_line(&mut header).unwrap();
+            assert_eq!(header, format!("{hash} blob {len}\n"));
+        }
+        check_repeated(&mut out, &block, len);
+        let mut rest = Vec::new();
+        out.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, if batch { &b"\n"[..] } else { b"" });
+        assert!(child.wait().unwrap().success(), "batch: {batch}");
+    }
+}
+
+#[test]
+fn corrupt_pack_index_is_an_error() {
+    let repo = Repo::new("cat-file-corrupt-index");
+    repo.write("a", "a\n");
+    repo.git(&["add", "a"]);
+    repo.git(&["commit", "-qm", "a"]);
+    repo.git(&["repack", "-adq"]);
+    repo.git(&["prune-packed"]);
+    let index = fs::read_dir(repo.path(".git/objects/pack"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().is_some_and(|extension| extension == "idx"))
+        .unwrap();
+    // More objects starting with 00 than in the whole pack.
+    let mut data = fs::read(&index).unwrap();
+    data[8..12].copy_from_slice(&1000u32.to_be_bytes());
+    fs::set_permissions(&index, fs::Permissions::from_mode(0o644)).unwrap();
+    fs::write(&index, data).unwrap();
+
+    let output = repo.ours_output(&["cat-file", "-t", "0011223344556677889900112233445566778899"]);
+    assert_eq!(output.status.code(), Some(128));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("fatal: corrupt index"), "{stderr}");
+}
@@ -0,0 +1,147 @@
+// Helpers for the tests comparing our commands with git's, in throwaway repositories.
+#![allow(dead_code)]
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// A repository in a directory of its own, removed when dropped.
+pub struct Repo {
+    pub dir: PathBuf,
+}
+
+impl Repo {
+    /// An empty repository created by git.
+    pub fn new(name: &str) -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let dir = env::temp_dir().join(format!(
+            "codecrafters-git-test-{name}-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let repo = Self { dir };
+        repo.git(&["init", "-q"]);
+        repo
+    }
+
+    pub fn path(&self, path: &str) -> PathBuf {
+        self.dir.join(path)
+    }
+
+    pub fn write(&self, path: &str, content: impl AsRef<[u8]>) {
+        let path = self.path(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    /// Run git, asserting it succeeds, and returns its output.
+    pub fn git(&self, args: &[&str]) -> Vec<u8> {
+        run(command("git", &self.dir), args, None)
+    }
+
+    /// Run git with `input` on its standard input.
+    pub fn git_with_input(&self, args: &[&str], input: &[u8]) -> Vec<u8> {
+        run(command("git", &self.dir), args, Some(input))
+    }
+
+    /// Run our git, asserting it succeeds, and returns its output.
+    pub fn ours(&self, args: &[&str]) -> Vec<u8> {
+        run(command(ours(), &self.dir), args, None)
+    }
+
+    /// Run our git with `input` on its standard input.
+    pub fn ours_with_input(&self, args: &[&str], input: &[u8]) -> Vec<u8> {
+        run(command(ours(), &self.dir), args, Some(input))
+    }
+
+    /// Run our git, which may fail, and returns its status and outputs.
+    pub fn ours_output(&self, args: &[&str]) -> Output {
+        command(ours(), &self.dir)
+            .args(args)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap()
+    }
+
+    /// A command run in the repository, with the same environment as the others.
+    pub fn command(&self, program: &str) -> Command {
+        command(program, &self.dir)
+    }
+
+    /// Files of the object directory outside the packs.
+    pub fn loose_objects(&self) -> Vec<PathBuf> {
+        let mut objects = Vec::new();
+        for dir in fs::read_dir(self.path(".git/objects")).unwrap() {
+            let dir = dir.unwrap().path();
+            let name = dir.file_name().unwrap().to_str().unwrap().to_string();
+            if name.len() == 2 && name.chars().all(|c| c.is_ascii_hexdigit()) {
+                for object in fs::read_dir(&dir).unwrap() {
+                    objects.push(object.unwrap().path());
+                }
+            }
+        }
+        objects.sort();
+        objects
+    }
+}
+
+impl Drop for Repo {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+pub fn ours() -> &'static str {
+    env!("CARGO_BIN_EXE_codecrafters-git")
+}
+
+fn command(program: &str, dir: &Path) -> Command {
+    let mut command = Command::new(program);
+    // Same identity and dates for both, and nothing from the configuration of the machine.
+    command
+        .current_dir(dir)
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("GIT_CONFIG_GLOBAL", "/dev/null")
+        .env("HOME", dir)
+        .env("GIT_AUTHOR_NAME", "A U Thor")
+        .env("GIT_AUTHOR_EMAIL", "author@example.com")
+        .env("GIT_AUTHOR_DATE", "1700000000 +0000")
+        .env("GIT_COMMITTER_NAME", "C O Mitter")
+        .env("GIT_COMMITTER_EMAIL", "committer@example.com")
+        .env("GIT_COMMITTER_DATE", "1700000000 +0000");
+    command
+}
+
+fn run(mut command: Command, args: &[&str], input: Option<&[u8]>) -> Vec<u8> {
+    command
+        .args(args)
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let mut child = command.spawn().unwrap();
+    // Written from another thread so a large output can't block the child while we write.
+    let writer = input.map(|input| {
+        let mut stdin = child.stdin.take().unwrap();
+        let input = input.to_vec();
+        std::thread::spawn(move || std::io::Write::write_all(&mut stdin, &input))
+    });
+    let output = child.wait_with_output().unwrap();
+    if let Some(writer) = writer {
+        writer.join().unwrap().unwrap();
+    }
+    assert!(
+        output.status.success(),
+        "{command:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output.stdout
+}
@@ -0,0 +1,62 @@
+mod common;
+
+use common::Repo;
+use std::fs;
+
+// Both branches change the second line of `f`, only `other` changes `g`.
+fn diverged() -> Repo {
+    let repo = Repo::new("merge");
+    repo.write("f", "a\nb\nc\n");
+    repo.write("g", "x\n");
+    repo.git(&["add", "."]);
+    repo.git(&["commit", "-qm", "base"]);
+    repo.git(&["checkout", "-qb", "other"]);
+    repo.write("f", "a\nB\nc\n");
+    repo.write("g", "y\n");
+    repo.git(&["commit", "-qam", "other"]);
+    repo.git(&["checkout", "-q", "master"]);
+    repo.write("f", "a\nb2\nc\n");
+    repo.git(&["commit", "-qam", "master"]);
+    repo
+}
+
+#[test]
+fn conflict() {
+    let repo = diverged();
+    let output = repo.ours_output(&["merge", "other"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Auto-merging f\n\
+         CONFLICT (content): Merge conflict in f\n\
+         Automatic merge failed; fix conflicts and then commit the result.\n"
+    );
+    assert_eq!(
+        fs::read_to_string(repo.path("f")).unwrap(),
+        "a\n<<<<<<< HEAD\nb2\n=======\nB\n>>>>>>> other\nc\n"
+    );
+    assert_eq!(
+        repo.git(&["diff", "--name-only", "--diff-filter=U"]),
+        b"f\n"
+    );
+}
+
+#[test]
+fn local_changes_abort_before_reporting_conflicts() {
+    let repo = diverged();
+    repo.write("g", "dirty\n");
+    let output = repo.ours_output(&["merge", "other"]);
+    assert!(!output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains(
+            "Your local changes to the following files would be overwritten by merge:\n\tg\n"
+        ),
+        "{stderr}"
+    );
+    // Nothing changed.
+    assert_eq!(fs::read_to_string(repo.path("f")).unwrap(), "a\nb2\nc\n");
+    assert_eq!(repo.git(&["status", "--porcelain"]), b" M g\n");
+    assert!(!repo.path(".git/MERGE_HEAD").exists());
+}
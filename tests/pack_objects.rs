@@ -0,0 +1,54 @@
+mod common;
+
+use common::Repo;
+use std::fs;
+
+// Versions of a growing file, similar enough to be stored as deltas of each other.
+fn history() -> Repo {
+    let repo = Repo::new("pack-objects");
+    for i in 1..=30 {
+        let lines: String = (0..i * 30).map(|line| format!("line {line}\n")).collect();
+        repo.write("file", lines);
+        repo.write(&format!("dir/{}", i % 4), format!("{i}\n"));
+        repo.git(&["add", "-A"]);
+        repo.git(&["commit", "-qm", &format!("commit {i}")]);
+    }
+    repo
+}
+
+// Write a pack of all the objects, returns the path of the pack without its extension.
+fn pack(repo: &Repo, window: &str) -> String {
+    let objects = repo.git(&["rev-list", "--objects", "--all"]);
+    let base = repo.path(&format!("window-{window}")).display().to_string();
+    let hash = repo.ours_with_input(
+        &["pack-objects", "-q", &format!("--window={window}"), &base],
+        &objects,
+    );
+    format!("{base}-{}", String::from_utf8(hash).unwrap().trim_end())
+}
+
+#[test]
+fn deltas_are_read_by_git() {
+    let repo = history();
+    let whole = pack(&repo, "0");
+    let deltified = pack(&repo, "10");
+    let size = |pack: &str| fs::metadata(format!("{pack}.pack")).unwrap().len();
+    assert!(size(&deltified) * 2 < size(&whole));
+
+    // git indexes the pack itself, resolving the deltas, and finds the same index as ours.
+    let index = repo.path("git.idx").display().to_string();
+    repo.git(&["index-pack", "-o", &index, &format!("{deltified}.pack")]);
+    assert_eq!(
+        fs::read(&index).unwrap(),
+        fs::read(format!("{deltified}.idx")).unwrap()
+    );
+
+    let verified = repo.git(&["verify-pack", "-v", &format!("{deltified}.idx")]);
+    let verified = String::from_utf8(verified).unwrap();
+    let deltas = verified
+        .lines()
+        .filter(|line| line.split(' ').filter(|field| !field.is_empty()).count() == 7)
+        .count();
+    assert!(deltas > 20, "{verified}");
+    assert!(verified.contains("chain length = 1: "), "{verified}");
+}
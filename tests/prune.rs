@@ -0,0 +1,48 @@
+mod common;
+
+use common::Repo;
+
+// The first commit is packed, the second is loose but its tree shares a packed blob: the walk has to go through
+// both storages to know what is reachable.
+fn split_storage() -> Repo {
+    let repo = Repo::new("prune");
+    repo.write("a", "a\n");
+    repo.git(&["add", "a"]);
+    repo.git(&["commit", "-qm", "one"]);
+    repo.git(&["repack", "-adq"]);
+    repo.git(&["prune-packed"]);
+    repo.write("b", "b\n");
+    repo.git(&["add", "b"]);
+    repo.git(&["commit", "-qm", "two"]);
+    repo
+}
+
+#[test]
+fn keeps_objects_reachable_through_packs() {
+    let repo = split_storage();
+    let loose = repo.loose_objects();
+    assert_eq!(loose.len(), 3);
+
+    assert_eq!(repo.ours(&["prune", "-n"]), b"");
+    repo.ours(&["prune"]);
+    assert_eq!(repo.loose_objects(), loose);
+    repo.git(&["fsck", "--full", "--no-dangling"]);
+}
+
+#[test]
+fn removes_unreachable_loose_objects() {
+    let repo = split_storage();
+    let loose = repo.loose_objects();
+    let dangling = repo.git_with_input(&["hash-object", "-w", "--stdin"], b"dangling\n");
+    let dangling = String::from_utf8(dangling).unwrap();
+
+    let expected = repo.git(&["prune", "-n"]);
+    assert_eq!(
+        expected,
+        format!("{} blob\n", dangling.trim_end()).as_bytes()
+    );
+    assert_eq!(repo.ours(&["prune", "-n"]), expected);
+    repo.ours(&["prune"]);
+    assert_eq!(repo.loose_objects(), loose);
+    repo.git(&["fsck", "--full", "--no-dangling"]);
+}
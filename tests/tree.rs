@@ -0,0 +1,112 @@
+mod common;
+
+use common::Repo;
+
+// Names git keeps as they are, with the whitespace it must not trim nor split on.
+const WHITESPACE_NAMES: [&str; 6] = [
+    "with space",
+    " leading",
+    "trailing ",
+    "tab\tinside",
+    "trailing tab\t",
+    "dir with space/ inner ",
+];
+
+#[test]
+fn whitespace_in_names_round_trips() {
+    let repo = Repo::new("tree-whitespace");
+    for name in WHITESPACE_NAMES {
+        repo.write(name, format!("{name}\n"));
+    }
+    repo.git(&["add", "-A"]);
+    let tree = repo.git(&["write-tree"]);
+    assert_eq!(repo.ours(&["write-tree"]), tree);
+
+    let tree = String::from_utf8(tree).unwrap();
+    let tree = tree.trim_end();
+    for args in [
+        &[][..],
+        &["-r"],
+        &["-z"],
+        &["-r", "-z"],
+        &["--name-only", "-r"],
+    ] {
+        let args = [&["ls-tree"], args, &[tree]].concat();
+        assert_eq!(repo.ours(&args), repo.git(&args), "{args:?}");
+    }
+}
+
+#[test]
+fn ls_tree_matches_git() {
+    let repo = Repo::new("ls-tree");
+    repo.write("file", "file\n");
+    repo.write("script", "#!/bin/sh\n");
+    repo.write("dir/nested", "nested\n");
+    repo.write("dir/sub/deeper", "deeper\n");
+    repo.write("quoted\"name", "quoted\n");
+    repo.write("caf\u{e9}", "unicode\n");
+    std::os::unix::fs::symlink("file", repo.path("link")).unwrap();
+    repo.git(&["add", "-A"]);
+    repo.git(&["update-index", "--chmod=+x", "script"]);
+    // A submodule entry, without the submodule.
+    repo.git(&[
+        "update-index",
+        "--add",
+        "--cacheinfo",
+        "160000,ce013625030ba8dba906f756967f9e9ca394464a,module",
+    ]);
+    let tree = repo.git(&["write-tree"]);
+    let tree = String::from_utf8(tree).unwrap();
+    let tree = tree.trim_end();
+
+    // What git 2.39 prints: a tab before the names, which are quoted when needed.
+    let golden = "\
+        100644 blob 4de4f936336736200e7a59438ef4d31ed10f684d\t\"caf\\303\\251\"\n\
+        040000 tree b017a4ee0ec581b3d8473ab7798eaa4fa6a49045\tdir\n\
+        100644 blob f73f3093ff865c514c6c51f867e35f693487d0d3\tfile\n\
+        120000 blob 1a010b1c0f081b2e8901d55307a15c29ff30af0e\tlink\n\
+        160000 commit ce013625030ba8dba906f756967f9e9ca394464a\tmodule\n\
+        100644 blob b04fedbe6c307caf738551314749c0819e238e97\t\"quoted\\\"name\"\n\
+        100755 blob 1a2485251c33a70432394c93fb89330ef214bfc9\tscript\n";
+    assert_eq!(
+        String::from_utf8(repo.ours(&["ls-tree", tree])).unwrap(),
+        golden
+    );
+    for args in [
+        &[][..],
+        &["-r"],
+        &["-z"],
+        &["-r", "-z"],
+        &["--name-only"],
+        &["--object-only", "-r"],
+        &["--abbrev"],
+        &["--abbrev=10", "-r"],
+    ] {
+        let args = [&["ls-tree"], args, &[tree]].concat();
+        assert_eq!(repo.ours(&args), repo.git(&args), "{args:?}");
+    }
+    for path in ["dir", "dir/", "dir/sub/deeper", "missing"] {
+        let args = ["ls-tree", tree, path];
+        assert_eq!(repo.ours(&args), repo.git(&args), "{args:?}");
+    }
+}
+
+#[test]
+fn write_tree_stores_symlinks() {
+    let repo = Repo::new("tree-symlinks");
+    repo.write("a", "hi\n");
+    repo.write("dir/file", "file\n");
+    std::os::unix::fs::symlink("a", repo.path("link")).unwrap();
+    std::os::unix::fs::symlink("dir", repo.path("dir-link")).unwrap();
+    std::os::unix::fs::symlink("missing", repo.path("dangling")).unwrap();
+    repo.git(&["add", "-A"]);
+    let tree = repo.git(&["write-tree"]);
+    assert_eq!(repo.ours(&["write-tree"]), tree);
+
+    let tree = String::from_utf8(tree).unwrap();
+    let listing = repo.git(&["ls-tree", tree.trim_end(), "link"]);
+    assert_eq!(
+        listing,
+        b"120000 blob 2e65efe2a145dda7ee51d1741299f848e5bf752e\tlink\n"
+    );
+}